@@ -0,0 +1,74 @@
+use crate::types::*;
+use std::collections::VecDeque;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use std::sync::Mutex;
+
+/// Bounded record of finished transfers, optionally mirrored to a JSON Lines file
+/// that is never truncated.
+pub struct AuditLog {
+    records: Mutex<VecDeque<TransferRecord>>,
+    limit: usize,
+    file: Option<Mutex<File>>,
+}
+
+impl AuditLog {
+    pub fn new(limit: usize, path: Option<&Path>) -> Result<Self, String> {
+        let file = match path {
+            Some(path) => {
+                let file = OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .map_err(|e| format!("Failed to open audit file {}: {e}", path.display()))?;
+                Some(Mutex::new(file))
+            }
+            None => None,
+        };
+
+        Ok(AuditLog {
+            records: Mutex::new(VecDeque::new()),
+            limit,
+            file,
+        })
+    }
+
+    pub fn record(&self, record: TransferRecord) {
+        if let Some(file) = &self.file {
+            let written = serde_json::to_string(&record)
+                .map_err(std::io::Error::from)
+                .and_then(|line| writeln!(file.lock().unwrap(), "{line}"));
+            if let Err(e) = written {
+                tracing::warn!(error = %e, "Failed to append to audit file");
+            }
+        }
+
+        let mut records = self.records.lock().unwrap();
+        records.push_back(record);
+        while records.len() > self.limit {
+            records.pop_front();
+        }
+    }
+
+    /// Records matching the query, oldest first. `peer` matches the session id or
+    /// name of either side.
+    pub fn query(&self, query: &TransferHistoryQuery) -> Vec<TransferRecord> {
+        let involves = |record: &TransferRecord, peer: &str| {
+            record.host_session_id == peer
+                || record.requester_session_id == peer
+                || record.host_name.as_deref() == Some(peer)
+                || record.requester_name.as_deref() == Some(peer)
+        };
+
+        self.records
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|record| query.file_id.as_ref().is_none_or(|file_id| &record.file_id == file_id))
+            .filter(|record| query.peer.as_deref().is_none_or(|peer| involves(record, peer)))
+            .filter(|record| query.since.is_none_or(|since| record.ended_at >= since))
+            .cloned()
+            .collect()
+    }
+}
@@ -0,0 +1,747 @@
+use crate::fetch::Fetch;
+use crate::files;
+use crate::rooms::RoomState;
+use crate::types::*;
+use crate::websocket::LocalPeer;
+use crate::AppState;
+use base64::Engine;
+use futures_util::stream::{SplitSink, SplitStream};
+use futures_util::{SinkExt, StreamExt};
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+
+/// Delay before the first reconnect after the bridge drops; it doubles up to the maximum.
+const RECONNECT_DELAY_MIN: Duration = Duration::from_secs(1);
+const RECONNECT_DELAY_MAX: Duration = Duration::from_secs(60);
+/// How long the other server has to issue a token and answer the join.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+const PING_INTERVAL: Duration = Duration::from_secs(30);
+/// Messages from file transfers waiting for the bridge's socket
+const OUTGOING_QUEUE_CAPACITY: usize = 16;
+
+type RemoteSink = SplitSink<WebSocketStream<MaybeTlsStream<TcpStream>>, Message>;
+type RemoteStream = SplitStream<WebSocketStream<MaybeTlsStream<TcpStream>>>;
+
+/// Another ladex server, given as `http://host:port` or `ws://host:port`.
+#[derive(Debug, Clone)]
+pub struct BridgeUrl {
+    authority: String,
+}
+
+pub fn parse_bridge_url(value: &str) -> Result<BridgeUrl, String> {
+    let Some(rest) = ["http://", "ws://"].iter().find_map(|scheme| value.strip_prefix(scheme)) else {
+        return Err(if value.starts_with("https://") || value.starts_with("wss://") {
+            "bridges only support plain connections; use http:// or ws://".to_string()
+        } else {
+            format!("{value} is not an http:// or ws:// URL")
+        });
+    };
+    let authority = rest.trim_end_matches('/');
+    if authority.is_empty() || authority.contains('/') {
+        return Err(format!("{value} should name only the server, e.g. http://ladex.local:8080"));
+    }
+    Ok(BridgeUrl { authority: authority.to_string() })
+}
+
+impl BridgeUrl {
+    fn address(&self) -> String {
+        match self.authority.rsplit_once(':') {
+            Some((_, port)) if !port.contains(']') => self.authority.clone(),
+            _ => format!("{}:80", self.authority),
+        }
+    }
+}
+
+/// `--bridge` and the options that go with it.
+#[derive(Debug, Clone)]
+pub struct BridgeConfig {
+    pub url: BridgeUrl,
+    pub code: Option<String>,
+    pub chat: bool,
+}
+
+/// Name and instance id of a server, as its `ServerInfo` gives them.
+struct ServerIdentity {
+    name: String,
+    instance_id: String,
+}
+
+/// Joins the default room of another server as a peer and keeps it joined, reconnecting
+/// with backoff. Public files of either server are mirrored on the other with the bridge
+/// as their host, and downloads of them are passed across.
+pub async fn run(state: AppState, config: BridgeConfig) {
+    // One session id for the whole run, so that after a reconnect the bridge takes back
+    // the files orphaned on both sides when the connection dropped
+    let session_id = format!("bridge_{:016x}", rand::random::<u64>());
+    // What the other server gave for that session id, needed to take it up again there
+    let mut session_token = None;
+    let mut delay = RECONNECT_DELAY_MIN;
+    loop {
+        match connect(&config).await {
+            Ok(ws) => {
+                tracing::info!(remote = config.url.authority, "Bridge connected");
+                delay = RECONNECT_DELAY_MIN;
+                match Bridge::run(&state, &config, &session_id, &mut session_token, ws).await {
+                    Ok(()) => tracing::info!(remote = config.url.authority, "Bridge closed by the other server"),
+                    Err(e) => tracing::warn!(remote = config.url.authority, error = e, "Bridge disconnected"),
+                }
+            }
+            Err(e) => tracing::warn!(remote = config.url.authority, error = e, "Bridge could not connect"),
+        }
+        tokio::time::sleep(delay).await;
+        delay = (delay * 2).min(RECONNECT_DELAY_MAX);
+    }
+}
+
+async fn connect(config: &BridgeConfig) -> Result<WebSocketStream<MaybeTlsStream<TcpStream>>, String> {
+    let mut request = format!("ws://{}/ws", config.url.authority)
+        .into_client_request()
+        .map_err(|e| e.to_string())?;
+    if let Some(code) = &config.code {
+        let token = request_token(&config.url, code).await?;
+        let header = format!("Bearer {token}").parse().map_err(|_| "invalid token".to_string())?;
+        request.headers_mut().insert("authorization", header);
+    }
+    let (ws, _) = tokio::time::timeout(HANDSHAKE_TIMEOUT, tokio_tungstenite::connect_async(request))
+        .await
+        .map_err(|_| "timed out".to_string())?
+        .map_err(|e| e.to_string())?;
+    Ok(ws)
+}
+
+/// Exchanges the security code for a token with `POST /auth/token`.
+async fn request_token(url: &BridgeUrl, code: &str) -> Result<String, String> {
+    let body = serde_json::to_string(&AuthRequest { code: code.to_string() }).map_err(|e| e.to_string())?;
+    let request = format!(
+        "POST /auth/token HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        url.authority,
+        body.len(),
+    );
+    let exchange = async {
+        let mut stream = TcpStream::connect(url.address()).await?;
+        stream.write_all(request.as_bytes()).await?;
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).await?;
+        Ok::<_, std::io::Error>(response)
+    };
+    let response = tokio::time::timeout(HANDSHAKE_TIMEOUT, exchange)
+        .await
+        .map_err(|_| "token request timed out".to_string())?
+        .map_err(|e| e.to_string())?;
+
+    let response = String::from_utf8_lossy(&response);
+    let (head, body) = response.split_once("\r\n\r\n").ok_or("malformed token response")?;
+    if head.split_whitespace().nth(1) != Some("200") {
+        return Err("the other server refused the security code".to_string());
+    }
+    let token = serde_json::from_str::<AuthToken>(body).map_err(|e| format!("malformed token response: {e}"))?;
+    Ok(token.token)
+}
+
+/// Which of one side's files are announced on the other.
+#[derive(Default)]
+struct Mirror {
+    ids: HashSet<String>,
+    /// Last file list version seen on the side the files come from
+    version: Option<u64>,
+}
+
+enum MirrorUpdate {
+    Unchanged,
+    /// A diff was missed; the full list has to be asked for
+    Resync,
+    Change {
+        announce: Vec<FileMetadata>,
+        remove: Vec<String>,
+    },
+}
+
+impl Mirror {
+    /// What to announce and remove on the other side after a file list message from
+    /// the side being mirrored.
+    fn update(&mut self, msg: &ServerMessage, bridge: &SessionId) -> MirrorUpdate {
+        match msg {
+            ServerMessage::FileListUpdate { files, version, .. } => {
+                self.version = Some(*version);
+                let announce = files.iter().filter_map(|file| mirror_copy(file, bridge)).collect::<Vec<_>>();
+                let ids = announce.iter().map(|file| file.id.clone()).collect::<HashSet<_>>();
+                let remove = self.ids.difference(&ids).cloned().collect();
+                self.ids = ids;
+                MirrorUpdate::Change { announce, remove }
+            }
+            ServerMessage::FileListDiff { added, removed, updated, version } => {
+                if self.version.is_some_and(|last| *version != last + 1) {
+                    return MirrorUpdate::Resync;
+                }
+                self.version = Some(*version);
+                let mut remove = removed.iter().filter(|id| self.ids.remove(*id)).cloned().collect::<Vec<_>>();
+                let mut announce = Vec::new();
+                for file in added.iter().chain(updated) {
+                    match mirror_copy(file, bridge) {
+                        Some(copy) => {
+                            self.ids.insert(copy.id.clone());
+                            announce.push(copy);
+                        }
+                        // E.g. a file that was made private or locked
+                        None if self.ids.remove(&file.id) => remove.push(file.id.clone()),
+                        None => {}
+                    }
+                }
+                MirrorUpdate::Change { announce, remove }
+            }
+            ServerMessage::FileRemoved { file_id } if self.ids.remove(file_id) => MirrorUpdate::Change {
+                announce: Vec::new(),
+                remove: vec![file_id.clone()],
+            },
+            _ => MirrorUpdate::Unchanged,
+        }
+    }
+
+    /// Forgets files the other side did not accept, e.g. because its registry is full.
+    fn rejected(&mut self, rejected: &[RejectedFile]) {
+        for rejection in rejected {
+            tracing::warn!(file_id = rejection.file_id, code = rejection.code, "Bridged file was not accepted");
+            self.ids.remove(&rejection.file_id);
+        }
+    }
+
+    /// Forgets a file the other side listed under a new id, as another peer's file had
+    /// the one it is known by here. Requests for it would not reach the bridge's copy.
+    fn reassigned(&mut self, requested_id: &str) {
+        tracing::warn!(file_id = requested_id, "Bridged file's id was taken");
+        self.ids.remove(requested_id);
+    }
+}
+
+/// The file as announced on the other side, hosted by the bridge. Only public files
+/// that did not come over a bridge themselves are mirrored; locked and end-to-end
+/// encrypted files need more than the bridge can pass on.
+fn mirror_copy(file: &FileMetadata, bridge: &SessionId) -> Option<FileMetadata> {
+    if file.origin.is_some() || file.visibility.is_some() || file.locked || file.e2e || file.unavailable {
+        return None;
+    }
+    let mut copy = file.clone();
+    copy.uploader_id = bridge.clone();
+    copy.hosts = HashSet::from([bridge.clone()]);
+    copy.origin = Some(FileOrigin::Remote);
+    copy.downloaders = HashSet::new();
+    Some(copy)
+}
+
+/// A peer's chat message posted on the other side by the bridge, keeping where it was
+/// first posted. None for the bridge's own messages and for messages that would go back
+/// to the server they started on.
+fn relayed_message(message: &TextMessage, bridge: &SessionId, from: &ServerIdentity, to: &ServerIdentity) -> Option<ClientMessage> {
+    if message.kind != MessageKind::User || &message.sender_id == bridge {
+        return None;
+    }
+    let origin = message.origin.clone().unwrap_or_else(|| MessageOrigin {
+        instance_id: from.instance_id.clone(),
+        server_name: from.name.clone(),
+        sender_name: message.sender_name.clone(),
+    });
+    if origin.instance_id == to.instance_id {
+        return None;
+    }
+    Some(ClientMessage::TextMessage {
+        session_id: bridge.clone(),
+        content: message.content.clone(),
+        format: Some(message.format),
+        kind: None,
+        // Attachments and replies refer to ids the other side does not know
+        attachments: None,
+        reply_to: None,
+        client_ref: None,
+        origin: Some(origin),
+    })
+}
+
+/// One connection of the bridge: a peer of the other server over the socket, and a
+/// local peer of this one.
+struct Bridge<'a> {
+    state: &'a AppState,
+    config: &'a BridgeConfig,
+    session_id: SessionId,
+    local_identity: ServerIdentity,
+    remote_identity: ServerIdentity,
+    remote_tx: RemoteSink,
+    local: LocalPeer,
+    /// The other server's files announced here
+    remote_files: Mirror,
+    /// This server's files announced there
+    local_files: Mirror,
+    /// Local peers waiting for a file of the other server, by file id. The bridge is a
+    /// single peer there, so it fetches each file for one local peer at a time.
+    forwarding: HashMap<String, SessionId>,
+    outgoing: mpsc::Sender<ClientMessage>,
+}
+
+impl<'a> Bridge<'a> {
+    async fn run(
+        state: &'a AppState,
+        config: &'a BridgeConfig,
+        session_id: &SessionId,
+        session_token: &mut Option<String>,
+        ws: WebSocketStream<MaybeTlsStream<TcpStream>>,
+    ) -> Result<(), String> {
+        let (mut remote_tx, mut remote_rx) = ws.split();
+        let join = |name: String, preferred_chunk_size, session_token| ClientMessage::Join {
+            session_id: session_id.clone(),
+            name: Some(name),
+            user_agent: Some(format!("ladex-bridge/{}", env!("CARGO_PKG_VERSION"))),
+            room: None,
+            preferred_chunk_size,
+            capabilities: Some(HashSet::from([FILE_LIST_DIFF_CAPABILITY.to_string()])),
+            protocol_version: Some(PROTOCOL_VERSION),
+            auto_accept_downloads: Some(true),
+            notify_upload_activity: Some(false),
+            client_version: Some(env!("CARGO_PKG_VERSION").to_string()),
+            session_token,
+        };
+
+        // The smallest chunks there fit whatever chunk size a local peer negotiated, so
+        // they are passed on as they are
+        let remote_join = join(format!("{} (bridge)", state.server_name), Some(MIN_CHUNK_SIZE), session_token.clone());
+        send_json(&mut remote_tx, &remote_join).await?;
+        let (remote_identity, early) = tokio::time::timeout(HANDSHAKE_TIMEOUT, wait_for_server_info(&mut remote_rx))
+            .await
+            .map_err(|_| "no server_info from the other server".to_string())??;
+        if remote_identity.instance_id == state.instance_id {
+            return Err("--bridge points at this server".to_string());
+        }
+        for msg in &early {
+            if let ServerMessage::Welcome { session_token: token, .. } = msg {
+                *session_token = Some(token.clone());
+            }
+        }
+
+        let local = LocalPeer::join(state, join(format!("{} (bridge)", remote_identity.name), None, None)).await?;
+        let (outgoing, outgoing_rx) = mpsc::channel(OUTGOING_QUEUE_CAPACITY);
+        let mut bridge = Bridge {
+            state,
+            config,
+            session_id: session_id.clone(),
+            local_identity: ServerIdentity {
+                name: state.server_name.clone(),
+                instance_id: state.instance_id.clone(),
+            },
+            remote_identity,
+            remote_tx,
+            local,
+            remote_files: Mirror::default(),
+            local_files: Mirror::default(),
+            forwarding: HashMap::new(),
+            outgoing,
+        };
+
+        let result = bridge.serve(early, remote_rx, outgoing_rx).await;
+        bridge.local.leave(state).await;
+        result
+    }
+
+    async fn serve(
+        &mut self,
+        early: Vec<ServerMessage>,
+        mut remote_rx: RemoteStream,
+        // File transfers to the other side stop once this is dropped
+        mut outgoing_rx: mpsc::Receiver<ClientMessage>,
+    ) -> Result<(), String> {
+        for msg in early {
+            self.on_remote(msg).await?;
+        }
+        let mut ping = tokio::time::interval(PING_INTERVAL);
+        loop {
+            tokio::select! {
+                frame = remote_rx.next() => match frame {
+                    Some(Ok(Message::Text(text))) => match serde_json::from_str::<ServerMessage>(text.as_str()) {
+                        Ok(msg) => self.on_remote(msg).await?,
+                        Err(e) => tracing::debug!(error = %e, "Unreadable message over the bridge"),
+                    },
+                    Some(Ok(Message::Close(_))) | None => return Ok(()),
+                    Some(Ok(_)) => {}
+                    Some(Err(e)) => return Err(e.to_string()),
+                },
+                msg = self.local.recv() => match msg {
+                    Some(msg) => self.on_local(msg).await?,
+                    None => return Err("the local room closed".to_string()),
+                },
+                Some(msg) = outgoing_rx.recv() => self.send_remote(&msg).await?,
+                _ = ping.tick() => {
+                    let ping = ClientMessage::Ping { session_id: self.session_id.clone(), nonce: None };
+                    self.send_remote(&ping).await?;
+                }
+            }
+        }
+    }
+
+    async fn send_remote(&mut self, msg: &ClientMessage) -> Result<(), String> {
+        send_json(&mut self.remote_tx, msg).await
+    }
+
+    async fn send_local(&mut self, msg: ClientMessage) -> Result<(), String> {
+        self.local.send(self.state, msg).await
+    }
+
+    async fn on_remote(&mut self, msg: ServerMessage) -> Result<(), String> {
+        match self.remote_files.update(&msg, &self.session_id) {
+            MirrorUpdate::Unchanged => {}
+            MirrorUpdate::Resync => {
+                let request = ClientMessage::RequestFileList {
+                    session_id: self.session_id.clone(),
+                    offset: None,
+                    limit: None,
+                    sort: None,
+                };
+                return self.send_remote(&request).await;
+            }
+            MirrorUpdate::Change { announce, remove } => {
+                for files in announce.chunks(files::MAX_BATCH_FILES) {
+                    let batch = ClientMessage::FileUploadBatch {
+                        session_id: self.session_id.clone(),
+                        files: files.to_vec(),
+                    };
+                    self.send_local(batch).await?;
+                }
+                for file_id in remove {
+                    let removal = ClientMessage::RemoveFile {
+                        session_id: self.session_id.clone(),
+                        file_id: Some(file_id),
+                        folder_id: None,
+                        batch_id: None,
+                    };
+                    self.send_local(removal).await?;
+                }
+                return Ok(());
+            }
+        }
+
+        match msg {
+            // A peer there wants one of this server's files
+            ServerMessage::DownloadRequest { file_id, requester_session_id, chunk_size, .. } => {
+                let file = match self.local.room() {
+                    Some(room) if self.local_files.ids.contains(&file_id) => {
+                        room.files.read().await.get(&file_id).cloned().map(|file| (room.clone(), file))
+                    }
+                    _ => None,
+                };
+                let Some((room, file)) = file else {
+                    let deny = ClientMessage::DenyDownload {
+                        session_id: self.session_id.clone(),
+                        file_id,
+                        requester_session_id,
+                        reason: Some("the file is no longer shared".to_string()),
+                    };
+                    return self.send_remote(&deny).await;
+                };
+                tokio::spawn(send_file(
+                    self.state.clone(),
+                    room,
+                    file,
+                    self.session_id.clone(),
+                    requester_session_id,
+                    chunk_size,
+                    self.outgoing.clone(),
+                ));
+            }
+            ServerMessage::FileMetadata {
+                file_id,
+                file_name,
+                file_size,
+                mime_type,
+                total_chunks,
+                chunk_size,
+                compression,
+                relative_path,
+                ..
+            } => {
+                if let Some(requester) = self.forwarding.get(&file_id).cloned() {
+                    let metadata = ClientMessage::FileMetadata {
+                        session_id: self.session_id.clone(),
+                        file_id,
+                        file_name,
+                        file_size,
+                        mime_type,
+                        total_chunks,
+                        chunk_size: Some(chunk_size),
+                        target_session_id: requester,
+                        compression,
+                        relative_path,
+                        encrypted: false,
+                        nonce: None,
+                    };
+                    self.send_local(metadata).await?;
+                }
+            }
+            ServerMessage::FileChunk {
+                file_id,
+                chunk_index,
+                total_chunks,
+                offset,
+                len,
+                data,
+                compression,
+                ..
+            } => {
+                let last = chunk_index + 1 >= total_chunks;
+                let requester = match last {
+                    true => self.forwarding.remove(&file_id),
+                    false => self.forwarding.get(&file_id).cloned(),
+                };
+                if let Some(requester) = requester {
+                    let chunk = ClientMessage::FileChunk {
+                        session_id: self.session_id.clone(),
+                        file_id,
+                        chunk_index,
+                        total_chunks,
+                        offset,
+                        len,
+                        data,
+                        target_session_id: requester,
+                        compression,
+                        encrypted: false,
+                        nonce: None,
+                    };
+                    self.send_local(chunk).await?;
+                }
+            }
+            ServerMessage::DownloadDenied { file_id, reason, message, .. } => {
+                self.deny_forwarded(file_id, message.unwrap_or(reason)).await?;
+            }
+            ServerMessage::TransferAborted { file_id, reason, .. } => {
+                self.deny_forwarded(file_id, reason).await?;
+            }
+            ServerMessage::BatchUploadResult { rejected, .. } => self.local_files.rejected(&rejected),
+            ServerMessage::FileIdReassigned { requested_id, assigned_id } => {
+                self.local_files.reassigned(&requested_id);
+                let removal = ClientMessage::RemoveFile {
+                    session_id: self.session_id.clone(),
+                    file_id: Some(assigned_id),
+                    folder_id: None,
+                    batch_id: None,
+                };
+                self.send_local(removal).await?;
+            }
+            ServerMessage::TextMessage { message } if self.config.chat => {
+                if let Some(relayed) = relayed_message(&message, &self.session_id, &self.remote_identity, &self.local_identity) {
+                    self.send_local(relayed).await?;
+                }
+            }
+            ServerMessage::Kicked { reason, .. } => return Err(format!("kicked by the other server: {reason}")),
+            _ => {}
+        }
+        Ok(())
+    }
+
+    async fn on_local(&mut self, msg: ServerMessage) -> Result<(), String> {
+        match self.local_files.update(&msg, &self.session_id) {
+            MirrorUpdate::Unchanged => {}
+            MirrorUpdate::Resync => {
+                let request = ClientMessage::RequestFileList {
+                    session_id: self.session_id.clone(),
+                    offset: None,
+                    limit: None,
+                    sort: None,
+                };
+                return self.send_local(request).await;
+            }
+            MirrorUpdate::Change { announce, remove } => {
+                for files in announce.chunks(files::MAX_BATCH_FILES) {
+                    let batch = ClientMessage::FileUploadBatch {
+                        session_id: self.session_id.clone(),
+                        files: files.to_vec(),
+                    };
+                    self.send_remote(&batch).await?;
+                }
+                for file_id in remove {
+                    let removal = ClientMessage::RemoveFile {
+                        session_id: self.session_id.clone(),
+                        file_id: Some(file_id),
+                        folder_id: None,
+                        batch_id: None,
+                    };
+                    self.send_remote(&removal).await?;
+                }
+                return Ok(());
+            }
+        }
+
+        match msg {
+            // A local peer wants one of the other server's files
+            ServerMessage::DownloadRequest { file_id, requester_session_id, .. } => {
+                if self.forwarding.contains_key(&file_id) {
+                    let deny = ClientMessage::DenyDownload {
+                        session_id: self.session_id.clone(),
+                        file_id,
+                        requester_session_id,
+                        reason: Some("the file is being fetched over the bridge for another peer; try again shortly".to_string()),
+                    };
+                    return self.send_local(deny).await;
+                }
+                self.forwarding.insert(file_id.clone(), requester_session_id);
+                let request = ClientMessage::RequestDownload {
+                    session_id: self.session_id.clone(),
+                    file_id: Some(file_id),
+                    batch_id: None,
+                    password: None,
+                };
+                self.send_remote(&request).await?;
+            }
+            // The local peer left; chunks still coming for it are dropped
+            ServerMessage::TransferAborted { file_id, peer_session_id, .. }
+                if self.forwarding.get(&file_id) == Some(&peer_session_id) =>
+            {
+                self.forwarding.remove(&file_id);
+            }
+            ServerMessage::BatchUploadResult { rejected, .. } => self.remote_files.rejected(&rejected),
+            ServerMessage::FileIdReassigned { requested_id, assigned_id } => {
+                self.remote_files.reassigned(&requested_id);
+                let removal = ClientMessage::RemoveFile {
+                    session_id: self.session_id.clone(),
+                    file_id: Some(assigned_id),
+                    folder_id: None,
+                    batch_id: None,
+                };
+                self.send_remote(&removal).await?;
+            }
+            ServerMessage::TextMessage { message } if self.config.chat => {
+                if let Some(relayed) = relayed_message(&message, &self.session_id, &self.local_identity, &self.remote_identity) {
+                    self.send_remote(&relayed).await?;
+                }
+            }
+            ServerMessage::Kicked { reason, .. } => return Err(format!("kicked from this server: {reason}")),
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Tells the local peer waiting for a file that the other server will not send it.
+    async fn deny_forwarded(&mut self, file_id: String, reason: String) -> Result<(), String> {
+        let Some(requester) = self.forwarding.remove(&file_id) else {
+            return Ok(());
+        };
+        let deny = ClientMessage::DenyDownload {
+            session_id: self.session_id.clone(),
+            file_id,
+            requester_session_id: requester,
+            reason: Some(reason),
+        };
+        self.send_local(deny).await
+    }
+}
+
+async fn send_json(tx: &mut RemoteSink, msg: &ClientMessage) -> Result<(), String> {
+    let json = serde_json::to_string(msg).map_err(|e| e.to_string())?;
+    tx.send(Message::text(json)).await.map_err(|e| e.to_string())
+}
+
+/// Reads messages until the other server's `ServerInfo`, returning its identity and
+/// the messages that came before it.
+async fn wait_for_server_info(rx: &mut RemoteStream) -> Result<(ServerIdentity, Vec<ServerMessage>), String> {
+    let mut early = Vec::new();
+    while let Some(frame) = rx.next().await {
+        let Message::Text(text) = frame.map_err(|e| e.to_string())? else {
+            continue;
+        };
+        match serde_json::from_str::<ServerMessage>(text.as_str()) {
+            Ok(ServerMessage::ServerInfo { name, instance_id, .. }) => {
+                return Ok((ServerIdentity { name, instance_id }, early));
+            }
+            Ok(ServerMessage::Kicked { reason, .. }) => return Err(reason),
+            Ok(ServerMessage::Error { code, message, .. }) if code == "session_in_use" => return Err(message),
+            Ok(msg) => early.push(msg),
+            Err(_) => {}
+        }
+    }
+    Err("the other server closed the connection".to_string())
+}
+
+/// Sends one of this server's files to a peer of the other server, fetching it from
+/// its host here. Stops once the bridge's queue is closed.
+async fn send_file(
+    state: AppState,
+    room: RoomState,
+    file: Arc<FileMetadata>,
+    bridge: SessionId,
+    requester: SessionId,
+    chunk_size: u32,
+    outgoing: mpsc::Sender<ClientMessage>,
+) {
+    let chunk_size = negotiate_chunk_size(Some(chunk_size));
+    let result = match Fetch::start(&state, &room, &file.id, chunk_size).await {
+        Ok(mut fetch) => {
+            let result = stream_file(&mut fetch, &file, &bridge, &requester, chunk_size, &outgoing).await;
+            fetch.close(&state).await;
+            result
+        }
+        Err(e) => Err(e),
+    };
+    if let Err(e) = result {
+        tracing::info!(file_id = file.id, error = e, "Bridged download failed");
+        let _ = outgoing
+            .send(ClientMessage::DenyDownload {
+                session_id: bridge,
+                file_id: file.id.clone(),
+                requester_session_id: requester,
+                reason: Some(e),
+            })
+            .await;
+    }
+}
+
+async fn stream_file(
+    fetch: &mut Fetch,
+    file: &FileMetadata,
+    bridge: &SessionId,
+    requester: &SessionId,
+    chunk_size: u32,
+    outgoing: &mpsc::Sender<ClientMessage>,
+) -> Result<(), String> {
+    let closed = |_| "the bridge disconnected".to_string();
+    let mut chunk = fetch.next_chunk().await?;
+    let total_chunks = fetch.total_chunks().unwrap_or(0);
+    let metadata = ClientMessage::FileMetadata {
+        session_id: bridge.clone(),
+        file_id: file.id.clone(),
+        file_name: file.name.clone(),
+        file_size: file.size,
+        mime_type: file.mime_type.clone(),
+        total_chunks,
+        chunk_size: Some(chunk_size),
+        target_session_id: requester.clone(),
+        compression: None,
+        relative_path: file.relative_path.clone(),
+        encrypted: false,
+        nonce: None,
+    };
+    outgoing.send(metadata).await.map_err(closed)?;
+
+    let (mut chunk_index, mut offset) = (0, 0);
+    while let Some(data) = chunk {
+        let len = data.len();
+        let message = ClientMessage::FileChunk {
+            session_id: bridge.clone(),
+            file_id: file.id.clone(),
+            chunk_index,
+            total_chunks,
+            offset: Some(offset),
+            len: Some(len as u32),
+            data: base64::engine::general_purpose::STANDARD.encode(data),
+            target_session_id: requester.clone(),
+            compression: None,
+            encrypted: false,
+            nonce: None,
+        };
+        outgoing.send(message).await.map_err(closed)?;
+        chunk_index += 1;
+        offset += len as u64;
+        chunk = fetch.next_chunk().await?;
+    }
+    Ok(())
+}
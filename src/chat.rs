@@ -0,0 +1,335 @@
+use crate::rooms::{FileRegistry, RoomState};
+use crate::types::*;
+use crate::AppState;
+use sha1::{Digest, Sha1};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io::Write;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+pub const MAX_ATTACHMENTS: usize = 10;
+/// Sender id of the system messages the server writes into the chat.
+pub const SERVER_SENDER_ID: &str = "__server__";
+pub const DEFAULT_SEARCH_LIMIT: usize = 50;
+pub const MAX_SEARCH_LIMIT: usize = 200;
+/// Messages sent at join and per history page unless the client asks for a different number.
+pub const HISTORY_PAGE_SIZE: usize = 50;
+pub const MAX_HISTORY_PAGE: usize = 200;
+/// How long a `client_ref` is remembered for recognizing retried sends.
+const CLIENT_REF_WINDOW: Duration = Duration::from_secs(60);
+/// Start of the sender ids of messages posted with `POST /api/messages`. Peers cannot
+/// join with such an id.
+pub const API_SENDER_PREFIX: &str = "api_";
+/// Name shown for messages posted through the API without a `sender_name`
+pub const DEFAULT_API_SENDER_NAME: &str = "API";
+/// Messages one API sender may post per minute
+pub const MAX_API_MESSAGES_PER_MINUTE: usize = 20;
+
+#[derive(Debug)]
+pub enum ChatError {
+    Empty,
+    TooLong(usize),
+    TooManyAttachments,
+    UnknownAttachments(Vec<String>),
+    UnknownReply,
+    SystemKind,
+}
+
+impl ChatError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            ChatError::Empty => "empty_message",
+            ChatError::TooLong(_) => "message_too_long",
+            ChatError::TooManyAttachments => "too_many_attachments",
+            ChatError::UnknownAttachments(_) => "unknown_attachments",
+            ChatError::UnknownReply => "unknown_message",
+            ChatError::SystemKind => "forbidden",
+        }
+    }
+
+    pub fn message(&self) -> String {
+        match self {
+            ChatError::Empty => "Message must not be empty".to_string(),
+            ChatError::TooLong(limit) => format!("Messages are limited to {limit} bytes"),
+            ChatError::TooManyAttachments => format!("Messages can attach at most {MAX_ATTACHMENTS} files"),
+            ChatError::UnknownAttachments(ids) => format!("Unknown attached files: {}", ids.join(", ")),
+            ChatError::UnknownReply => "The message replied to does not exist".to_string(),
+            ChatError::SystemKind => "Only the server can send system messages".to_string(),
+        }
+    }
+
+    pub fn limit(&self) -> Option<usize> {
+        match self {
+            ChatError::TooLong(limit) => Some(*limit),
+            ChatError::TooManyAttachments => Some(MAX_ATTACHMENTS),
+            ChatError::Empty
+            | ChatError::UnknownAttachments(_)
+            | ChatError::UnknownReply
+            | ChatError::SystemKind => None,
+        }
+    }
+}
+
+/// Normalizes line endings to `\n`, drops control characters other than newlines and
+/// tabs and trims trailing whitespace; markdown also loses its raw HTML. The result
+/// must be non-empty and at most `max_length` bytes.
+pub fn sanitize_message(content: &str, format: MessageFormat, max_length: usize) -> Result<String, ChatError> {
+    let mut content = content
+        .replace("\r\n", "\n")
+        .replace('\r', "\n")
+        .chars()
+        .filter(|c| *c == '\n' || *c == '\t' || !c.is_control())
+        .collect::<String>();
+    if format == MessageFormat::Markdown {
+        content = strip_html(&content);
+    }
+    let content = content.trim_end();
+
+    if content.trim().is_empty() {
+        return Err(ChatError::Empty);
+    }
+    if content.len() > max_length {
+        return Err(ChatError::TooLong(max_length));
+    }
+    Ok(content.to_string())
+}
+
+/// Deduplicates attached file ids and checks that each names a file in the room that
+/// `sender` can see. No attachments are stored as None.
+pub fn check_attachments(
+    attachments: Option<Vec<String>>,
+    files: &FileRegistry,
+    sender: &str,
+) -> Result<Option<Vec<String>>, ChatError> {
+    let mut ids = attachments.unwrap_or_default();
+    let mut seen = HashSet::new();
+    ids.retain(|id| seen.insert(id.clone()));
+
+    if ids.len() > MAX_ATTACHMENTS {
+        return Err(ChatError::TooManyAttachments);
+    }
+    let unknown = ids.iter().filter(|id| !files.get(*id).is_some_and(|file| file.is_visible_to(Some(sender)))).cloned().collect::<Vec<_>>();
+    if !unknown.is_empty() {
+        return Err(ChatError::UnknownAttachments(unknown));
+    }
+    Ok(Some(ids).filter(|ids| !ids.is_empty()))
+}
+
+/// Removes HTML tags and comments from markdown, leaving code blocks, code spans and
+/// `<https://...>` autolinks alone. A `<` without a closing `>` on its line is kept.
+fn strip_html(markdown: &str) -> String {
+    let mut out = String::with_capacity(markdown.len());
+    let mut in_fence = false;
+
+    for line in markdown.split_inclusive('\n') {
+        let is_fence = line.trim_start().starts_with("```");
+        if is_fence {
+            in_fence = !in_fence;
+        }
+        if is_fence || in_fence {
+            out.push_str(line);
+            continue;
+        }
+
+        let mut in_code = false;
+        let mut rest = line;
+        while let Some(c) = rest.chars().next() {
+            if c == '`' {
+                in_code = !in_code;
+            } else if c == '<' && !in_code {
+                let tag = rest[1..].find('>').map(|end| &rest[1..end + 1]);
+                let is_html = tag.is_some_and(|tag| {
+                    let is_autolink = tag.contains("://") || (tag.contains('@') && !tag.contains(' '));
+                    let is_tag = tag.starts_with(|c: char| c.is_ascii_alphabetic() || matches!(c, '/' | '!' | '?'));
+                    is_tag && !is_autolink
+                });
+                if let (true, Some(tag)) = (is_html, tag) {
+                    rest = &rest[tag.len() + 2..];
+                    continue;
+                }
+            }
+            out.push(c);
+            rest = &rest[c.len_utf8()..];
+        }
+    }
+    out
+}
+
+/// Recently acknowledged `client_ref`s per sender, so a retried send is acked again
+/// with the original message instead of being stored twice.
+#[derive(Default)]
+pub struct ClientRefs {
+    refs: HashMap<(SessionId, String), (Instant, TextMessage)>,
+}
+
+impl ClientRefs {
+    /// The message previously stored for this sender and `client_ref`, if still remembered.
+    pub fn get(&mut self, sender_id: &SessionId, client_ref: &str) -> Option<TextMessage> {
+        self.refs.retain(|_, (seen, _)| seen.elapsed() < CLIENT_REF_WINDOW);
+        self.refs
+            .get(&(sender_id.clone(), client_ref.to_string()))
+            .map(|(_, message)| message.clone())
+    }
+
+    pub fn insert(&mut self, client_ref: String, message: &TextMessage) {
+        self.refs
+            .insert((message.sender_id.clone(), client_ref), (Instant::now(), message.clone()));
+    }
+}
+
+/// Messages recently posted through the REST API per sender, so that a script stuck
+/// in a loop cannot flood the room.
+#[derive(Default)]
+pub struct ApiSenders {
+    recent: Mutex<HashMap<SessionId, VecDeque<Instant>>>,
+}
+
+impl ApiSenders {
+    /// Notes a message about to be posted. False when the sender already posted
+    /// `MAX_API_MESSAGES_PER_MINUTE` in the last minute.
+    pub fn allow(&self, sender_id: &SessionId) -> bool {
+        let now = Instant::now();
+        let mut recent = self.recent.lock().unwrap();
+        recent.retain(|_, posted| {
+            while posted.front().is_some_and(|at| now.duration_since(*at) >= Duration::from_secs(60)) {
+                posted.pop_front();
+            }
+            !posted.is_empty()
+        });
+        let posted = recent.entry(sender_id.clone()).or_default();
+        if posted.len() >= MAX_API_MESSAGES_PER_MINUTE {
+            return false;
+        }
+        posted.push_back(now);
+        true
+    }
+}
+
+/// Sender id of messages posted through the API by the holder of `credential`, their
+/// auth token or else their address. It is hashed so the token never shows in the chat.
+pub fn api_sender_id(credential: &str) -> SessionId {
+    let digest = Sha1::digest(credential.as_bytes());
+    let hash = digest[..6].iter().map(|b| format!("{b:02x}")).collect::<String>();
+    format!("{API_SENDER_PREFIX}{hash}")
+}
+
+/// Case-insensitive substring search over message content and sender names, newest
+/// first. `before` is a message id; only messages older than it are considered.
+pub fn search_messages(
+    messages: &[TextMessage],
+    query: &str,
+    limit: Option<usize>,
+    before: Option<&str>,
+    include_system: bool,
+) -> Vec<SearchHit> {
+    let needle = query.to_lowercase();
+    let limit = limit.unwrap_or(DEFAULT_SEARCH_LIMIT).min(MAX_SEARCH_LIMIT);
+    let end = before
+        .and_then(|id| messages.iter().position(|m| m.id == id))
+        .unwrap_or(messages.len());
+
+    messages[..end]
+        .iter()
+        .enumerate()
+        .rev()
+        .filter(|(_, message)| include_system || message.kind != MessageKind::System)
+        .filter(|(_, message)| {
+            message.content.to_lowercase().contains(&needle)
+                || message
+                    .sender_name
+                    .as_ref()
+                    .is_some_and(|name| name.to_lowercase().contains(&needle))
+        })
+        .take(limit)
+        .map(|(index, message)| SearchHit {
+            message: message.clone(),
+            previous_id: index.checked_sub(1).map(|i| messages[i].id.clone()),
+            next_id: messages.get(index + 1).map(|m| m.id.clone()),
+        })
+        .collect()
+}
+
+/// Up to `limit` messages sent before the message `before`, or the newest ones, oldest
+/// first. Pages are keyed on message ids, so messages arriving between requests do not
+/// shift them. Returns None when `before` is not in the history.
+pub fn history_page(messages: &[TextMessage], before: Option<&str>, limit: Option<usize>) -> Option<HistoryPage> {
+    let limit = limit.unwrap_or(HISTORY_PAGE_SIZE).clamp(1, MAX_HISTORY_PAGE);
+    let end = match before {
+        Some(id) => messages.iter().position(|m| m.id == id)?,
+        None => messages.len(),
+    };
+    let start = end.saturating_sub(limit);
+    Some(HistoryPage {
+        messages: messages[start..end].to_vec(),
+        has_more: start > 0,
+    })
+}
+
+/// Stores a system message in the room's history and broadcasts it, unless
+/// `--no-system-messages` turned them off.
+pub async fn post_system_message(state: &AppState, room: &RoomState, content: String) {
+    if !state.system_messages {
+        return;
+    }
+
+    let message = TextMessage {
+        id: format!("msg_{SERVER_SENDER_ID}_{:016x}", rand::random::<u64>()),
+        content,
+        kind: MessageKind::System,
+        format: MessageFormat::Plain,
+        attachments: None,
+        reply_to: None,
+        sender_id: SERVER_SENDER_ID.to_string(),
+        sender_name: None,
+        sender_color: None,
+        timestamp: chrono::Utc::now(),
+        origin: None,
+    };
+    room.messages.write().await.push(message.clone());
+    room.send(ServerMessage::TextMessage { message });
+}
+
+/// Notes in the chat that files left the room, in one message however many there were.
+pub async fn post_files_removed(state: &AppState, room: &RoomState, names: &[String]) {
+    match names {
+        [] => {}
+        [name] => post_system_message(state, room, format!("{name} was removed")).await,
+        _ => post_system_message(state, room, format!("{} files were removed", names.len())).await,
+    }
+}
+
+/// How a peer is named in system messages.
+pub fn display_name(peer: &PeerInfo) -> String {
+    match &peer.name {
+        Some(name) => name.clone(),
+        None => format!("User {}", &peer.session_id[peer.session_id.len().saturating_sub(6)..]),
+    }
+}
+
+/// Direct replies to a message, oldest first, or None if there is no such message.
+pub fn replies(messages: &[TextMessage], message_id: &str) -> Option<Vec<TextMessage>> {
+    if !messages.iter().any(|message| message.id == message_id) {
+        return None;
+    }
+    let replies = messages
+        .iter()
+        .filter(|message| message.reply_to.as_deref() == Some(message_id))
+        .cloned()
+        .collect();
+    Some(replies)
+}
+
+/// Writes a human-readable `[timestamp] name: content` transcript, one message per line.
+pub fn write_transcript(messages: &[TextMessage], out: &mut Vec<u8>) -> std::io::Result<()> {
+    for message in messages {
+        let sender = message.sender_name.as_deref().unwrap_or(&message.sender_id);
+        writeln!(
+            out,
+            "[{}] {}: {}",
+            message.timestamp.format("%Y-%m-%d %H:%M:%S UTC"),
+            sender,
+            message.content,
+        )?;
+    }
+    Ok(())
+}
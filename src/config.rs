@@ -0,0 +1,168 @@
+use clap::error::ErrorKind;
+use clap::parser::ValueSource;
+use clap::{Arg, ArgAction, ArgMatches, Command};
+use std::ffi::OsString;
+use std::path::Path;
+
+/// Start of the environment variables that set options, e.g. `LADEX_MAX_FILES` for
+/// `--max-files`.
+pub const ENV_PREFIX: &str = "LADEX_";
+/// Options only taken from the command line
+const COMMAND_LINE_ONLY: &[&str] = &["config", "print_config", "diagnose", "help", "version"];
+/// Options `--print-config` does not show
+const SECRET_OPTIONS: &[&str] = &["code", "admin_token", "turn_secret", "bridge_code"];
+
+/// The command line with the options it leaves out filled in from `LADEX_*`
+/// environment variables, and then from the `--config` file. Also returns warnings
+/// about the file to log once logging is set up.
+pub fn resolve(command: &Command, cli: Vec<OsString>) -> Result<(ArgMatches, Vec<String>), String> {
+    let matches = command.clone().get_matches_from(&cli);
+    let mut file = match matches.get_one::<std::path::PathBuf>("config") {
+        Some(path) => load_file(path)?,
+        None => toml::Table::new(),
+    };
+
+    let mut argv = cli;
+    for arg in command.get_arguments() {
+        let id = arg.get_id().as_str();
+        if COMMAND_LINE_ONLY.contains(&id) {
+            continue;
+        }
+        let key = option_key(arg);
+        let from_file = file.remove(&key).or_else(|| file.remove(&key.replace('-', "_")));
+        if matches.value_source(id) == Some(ValueSource::CommandLine) {
+            continue;
+        }
+        let env_var = format!("{ENV_PREFIX}{}", key.to_uppercase().replace('-', "_"));
+        let (origin, values) = match (std::env::var(&env_var), from_file) {
+            (Ok(value), _) => (env_var, env_values(arg, &value)),
+            (Err(_), Some(value)) => (format!("config key {key}"), file_values(arg, value)),
+            (Err(_), None) => continue,
+        };
+        let values = values.map_err(|e| format!("{origin}: {e}"))?;
+        check_values(command, &values).map_err(|e| format!("{origin}: {e}"))?;
+        argv.extend(values);
+    }
+
+    let warnings = file.keys().map(|key| format!("Unknown key {key} in config file")).collect();
+    // E.g. an option from the file that needs another one nothing set
+    let matches = command.clone().get_matches_from(argv);
+    Ok((matches, warnings))
+}
+
+fn load_file(path: &Path) -> Result<toml::Table, String> {
+    let text = std::fs::read_to_string(path).map_err(|e| format!("Could not read config file {}: {e}", path.display()))?;
+    text.parse::<toml::Table>()
+        .map_err(|e| format!("Could not parse config file {}: {e}", path.display()))
+}
+
+/// The name of an option in the config file: its long flag without the leading dashes, or the
+/// name of a positional argument.
+fn option_key(arg: &Arg) -> String {
+    arg.get_long().map(str::to_string).unwrap_or_else(|| arg.get_id().to_string())
+}
+
+fn is_flag(arg: &Arg) -> bool {
+    matches!(arg.get_action(), ArgAction::SetTrue)
+}
+
+/// The command line argument that gives `arg` the value, or sets it for a flag.
+fn option_arg(arg: &Arg, value: Option<&str>) -> OsString {
+    match (arg.get_long(), value) {
+        (Some(long), Some(value)) => format!("--{long}={value}").into(),
+        (Some(long), None) => format!("--{long}").into(),
+        (None, value) => value.unwrap_or_default().into(),
+    }
+}
+
+fn env_values(arg: &Arg, value: &str) -> Result<Vec<OsString>, String> {
+    if !is_flag(arg) {
+        return Ok(vec![option_arg(arg, Some(value))]);
+    }
+    match value.to_ascii_lowercase().as_str() {
+        "1" | "true" | "yes" => Ok(vec![option_arg(arg, None)]),
+        "" | "0" | "false" | "no" => Ok(Vec::new()),
+        _ => Err(format!("expected true or false, got {value}")),
+    }
+}
+
+fn file_values(arg: &Arg, value: toml::Value) -> Result<Vec<OsString>, String> {
+    if is_flag(arg) {
+        return match value {
+            toml::Value::Boolean(true) => Ok(vec![option_arg(arg, None)]),
+            toml::Value::Boolean(false) => Ok(Vec::new()),
+            other => Err(format!("expected true or false, got {}", other.type_str())),
+        };
+    }
+    let repeatable = matches!(arg.get_action(), ArgAction::Append);
+    match value {
+        toml::Value::Array(values) if repeatable => values
+            .into_iter()
+            .map(|value| scalar(value).map(|value| option_arg(arg, Some(&value))))
+            .collect(),
+        value => Ok(vec![option_arg(arg, Some(&scalar(value)?))]),
+    }
+}
+
+fn scalar(value: toml::Value) -> Result<String, String> {
+    match value {
+        toml::Value::String(value) => Ok(value),
+        toml::Value::Integer(value) => Ok(value.to_string()),
+        toml::Value::Float(value) => Ok(value.to_string()),
+        toml::Value::Boolean(value) => Ok(value.to_string()),
+        other => Err(format!("expected a single value, got {}", other.type_str())),
+    }
+}
+
+/// Parses the arguments on their own, so a bad value is reported as coming from the
+/// environment or the file rather than the command line.
+fn check_values(command: &Command, values: &[OsString]) -> Result<(), String> {
+    let argv = std::iter::once(OsString::from(command.get_name())).chain(values.iter().cloned());
+    match command.clone().try_get_matches_from(argv) {
+        Ok(_) => Ok(()),
+        // Options that need others are checked once everything is merged
+        Err(e) if e.kind() == ErrorKind::MissingRequiredArgument => Ok(()),
+        Err(e) => Err(first_line(&e)),
+    }
+}
+
+fn first_line(error: &clap::Error) -> String {
+    let rendered = error.to_string();
+    let line = rendered.lines().next().unwrap_or_default();
+    line.strip_prefix("error: ").unwrap_or(line).to_string()
+}
+
+/// The merged configuration in the config file's format, with secrets redacted.
+pub fn print(command: &Command, matches: &ArgMatches) {
+    for arg in command.get_arguments() {
+        let id = arg.get_id().as_str();
+        if COMMAND_LINE_ONLY.contains(&id) {
+            continue;
+        }
+        let key = option_key(arg);
+        if is_flag(arg) {
+            println!("{key} = {}", matches.get_flag(id));
+            continue;
+        }
+        let Some(raw) = matches.get_raw(id) else {
+            continue;
+        };
+        if SECRET_OPTIONS.contains(&id) {
+            println!("{key} = \"<redacted>\"");
+            continue;
+        }
+        let values = raw.map(|value| toml_value(&value.to_string_lossy())).collect::<Vec<_>>();
+        match values.as_slice() {
+            [value] if !matches!(arg.get_action(), ArgAction::Append) => println!("{key} = {value}"),
+            values => println!("{key} = [{}]", values.join(", ")),
+        }
+    }
+}
+
+/// A value as TOML: integers bare, anything else as a string.
+fn toml_value(value: &str) -> String {
+    match value.parse::<i64>() {
+        Ok(number) if number.to_string() == value => value.to_string(),
+        _ => serde_json::to_string(value).unwrap_or_default(),
+    }
+}
@@ -0,0 +1,297 @@
+use crate::{mdns, network, STATIC_DIR};
+use sha2::{Digest, Sha256};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, TcpListener};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+
+/// How long each network probe may take
+const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+/// Embedded files the web client cannot work without
+const REQUIRED_STATIC_FILES: &[&str] = &[
+    "index.html",
+    "login.html",
+    "app.js",
+    "style.css",
+    "jszip.min.js",
+    "favicon.ico",
+    "manifest.webmanifest",
+];
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Outcome {
+    Pass,
+    /// Worth knowing, but the server can run
+    Warn,
+    Fail,
+}
+
+/// Results of `--diagnose`, printed as each check finishes.
+#[derive(Default)]
+pub struct Diagnosis {
+    failures: usize,
+}
+
+impl Diagnosis {
+    fn record(&mut self, outcome: Outcome, check: &str, detail: &str, hint: Option<&str>) {
+        let label = match outcome {
+            Outcome::Pass => "PASS",
+            Outcome::Warn => "WARN",
+            Outcome::Fail => "FAIL",
+        };
+        println!("[{label}] {check}: {detail}");
+        if let Some(hint) = hint.filter(|_| outcome != Outcome::Pass) {
+            println!("       hint: {hint}");
+        }
+        if outcome == Outcome::Fail {
+            self.failures += 1;
+        }
+    }
+
+    /// The checks that run before the server is set up: the listening port, the network
+    /// interfaces, mDNS and the embedded web client.
+    pub async fn preflight(bind: SocketAddr, server_name: &str) -> Diagnosis {
+        let mut diagnosis = Diagnosis::default();
+        diagnosis.check_bind(bind).await;
+        diagnosis.check_interfaces();
+        diagnosis.check_mdns(bind, server_name);
+        diagnosis.check_static_bundle();
+        diagnosis
+    }
+
+    async fn check_bind(&mut self, bind: SocketAddr) {
+        for addr in network::bind_addresses(bind) {
+            let check = format!("Listen on {addr}");
+            let error = match TcpListener::bind(addr) {
+                Ok(_) => {
+                    self.record(Outcome::Pass, &check, "the port is free", None);
+                    continue;
+                }
+                Err(e) => e,
+            };
+            if error.kind() != std::io::ErrorKind::AddrInUse {
+                let hint = match error.kind() {
+                    std::io::ErrorKind::PermissionDenied => "Ports below 1024 need elevated privileges; pick another with --bind",
+                    std::io::ErrorKind::AddrNotAvailable => "This machine has no such address; use [::] or one of the addresses listed below",
+                    _ => "Try another address or port with --bind",
+                };
+                self.record(Outcome::Fail, &check, &error.to_string(), Some(hint));
+                continue;
+            }
+            // Ask whoever holds the port whether it is a ladex server
+            let probe_ip = if addr.ip().is_unspecified() { IpAddr::V4(Ipv4Addr::LOCALHOST) } else { addr.ip() };
+            let probe = SocketAddr::new(probe_ip, addr.port());
+            let detail = match http_get(probe, "/api/server-info").await {
+                Ok((200, body)) => match serde_json::from_slice::<serde_json::Value>(&body) {
+                    Ok(info) if info.get("protocol_version").is_some() => format!(
+                        "ladex {} (\"{}\") is already running there",
+                        info["version"].as_str().unwrap_or("?"),
+                        info["name"].as_str().unwrap_or("?"),
+                    ),
+                    _ => "the port is used by another program".to_string(),
+                },
+                _ => "the port is used by another program".to_string(),
+            };
+            self.record(Outcome::Fail, &check, &detail, Some("Stop the other server or pick another port with --bind"));
+        }
+    }
+
+    fn check_interfaces(&mut self) {
+        let addresses = network::interface_addresses();
+        if addresses.is_empty() {
+            self.record(
+                Outcome::Warn,
+                "Network interfaces",
+                "no address other machines could reach",
+                Some("Connect to a network; inside a container or network namespace, publish the port to the host"),
+            );
+            return;
+        }
+        let private = addresses.iter().any(|(_, ip)| is_private(ip));
+        if private {
+            self.record(Outcome::Pass, "Network interfaces", &format!("{} addresses", addresses.len()), None);
+        } else {
+            self.record(
+                Outcome::Warn,
+                "Network interfaces",
+                "no private LAN address",
+                Some("Peers on the same network may still connect, but check the firewall before exposing a public address"),
+            );
+        }
+        for (interface, ip) in &addresses {
+            let kind = match ip {
+                IpAddr::V4(_) if is_private(ip) => "private (RFC 1918)",
+                IpAddr::V6(_) if is_private(ip) => "unique local",
+                _ => "public",
+            };
+            println!("       {interface}: {ip} ({kind})");
+        }
+    }
+
+    fn check_mdns(&mut self, bind: SocketAddr, server_name: &str) {
+        if !bind.ip().is_unspecified() {
+            self.record(Outcome::Pass, "mDNS", "skipped, --bind is a single address", None);
+            return;
+        }
+        // The advertisement is withdrawn again when dropped at the end of this check
+        match mdns::advertise(server_name, bind.port()) {
+            Some(advertisement) if advertisement.is_registered() => {
+                self.record(Outcome::Pass, "mDNS", &format!("registered {}.local", advertisement.hostname), None);
+            }
+            Some(advertisement) => self.record(
+                Outcome::Warn,
+                "mDNS",
+                &format!("could not register; relying on the system's responder for {}.local", advertisement.hostname),
+                Some("Allow UDP port 5353 through the firewall, or share an address from the list above"),
+            ),
+            None => self.record(
+                Outcome::Warn,
+                "mDNS",
+                "no usable .local name",
+                Some("Set --name to a valid host name, or share an address from the list above"),
+            ),
+        }
+    }
+
+    fn check_static_bundle(&mut self) {
+        let missing = REQUIRED_STATIC_FILES
+            .iter()
+            .filter(|path| STATIC_DIR.get_file(path).is_none_or(|file| file.contents().is_empty()))
+            .copied()
+            .collect::<Vec<_>>();
+        if !missing.is_empty() {
+            self.record(
+                Outcome::Fail,
+                "Web client",
+                &format!("missing or empty: {}", missing.join(", ")),
+                Some("The binary was built from an incomplete checkout; rebuild it from a clean one"),
+            );
+            return;
+        }
+        let mut hasher = Sha256::new();
+        for (path, contents) in static_files() {
+            hasher.update(path.as_bytes());
+            hasher.update(contents);
+        }
+        let digest = hasher.finalize().iter().map(|byte| format!("{byte:02x}")).collect::<String>();
+        self.record(Outcome::Pass, "Web client", &format!("{} files, sha256 {digest}", static_files().len()), None);
+    }
+
+    /// Talks to the server started on the loopback address `addr` with the real routes:
+    /// fetches the server info and every embedded file, then opens a WebSocket.
+    /// `token` authenticates the WebSocket when a security code is set.
+    pub async fn check_round_trip(&mut self, addr: SocketAddr, token: Option<&str>) {
+        let hint = Some("The server could not answer itself; check for a local firewall or a proxy intercepting localhost");
+        match http_get(addr, "/api/server-info").await {
+            Ok((200, _)) => self.record(Outcome::Pass, "HTTP on loopback", "server info answered", None),
+            Ok((status, _)) => self.record(Outcome::Fail, "HTTP on loopback", &format!("server info answered {status}"), hint),
+            Err(e) => self.record(Outcome::Fail, "HTTP on loopback", &e, hint),
+        }
+
+        let mut mismatched = Vec::new();
+        for (path, contents) in static_files() {
+            match http_get(addr, &format!("/static/{path}")).await {
+                Ok((200, body)) if body == contents => {}
+                _ => mismatched.push(path),
+            }
+        }
+        if mismatched.is_empty() {
+            self.record(Outcome::Pass, "Served web client", "every file matches the embedded one", None);
+        } else {
+            self.record(
+                Outcome::Fail,
+                "Served web client",
+                &format!("differs from the embedded files: {}", mismatched.join(", ")),
+                Some("Something between the server and the browser rewrites responses"),
+            );
+        }
+
+        match websocket_upgrade(addr, token).await {
+            Ok(()) => self.record(Outcome::Pass, "WebSocket on loopback", "upgrade completed", None),
+            Err(e) => self.record(
+                Outcome::Fail,
+                "WebSocket on loopback",
+                &e,
+                Some("Proxies and some security software block WebSocket upgrades; try without them"),
+            ),
+        }
+    }
+
+    /// Records that no loopback port was free for the round trip.
+    pub fn loopback_unavailable(&mut self, error: &std::io::Error) {
+        self.record(Outcome::Fail, "HTTP on loopback", &error.to_string(), Some("Check that the loopback interface is up"));
+    }
+
+    /// Prints the summary and exits, with status 1 if a check failed.
+    pub fn exit(self) -> ! {
+        if self.failures == 0 {
+            println!("All checks passed");
+            std::process::exit(0);
+        }
+        println!("{} checks failed", self.failures);
+        std::process::exit(1);
+    }
+}
+
+/// RFC 1918 IPv4 and unique local IPv6 addresses.
+fn is_private(ip: &IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(v4) => v4.is_private(),
+        IpAddr::V6(v6) => (v6.segments()[0] & 0xfe00) == 0xfc00,
+    }
+}
+
+/// A free port on the IPv4 loopback address for the temporary server.
+pub fn loopback_address() -> std::io::Result<SocketAddr> {
+    TcpListener::bind((Ipv4Addr::LOCALHOST, 0))?.local_addr()
+}
+
+/// Every embedded file with its path, in path order.
+fn static_files() -> Vec<(String, &'static [u8])> {
+    let mut files = STATIC_DIR
+        .files()
+        .map(|file| (file.path().to_string_lossy().replace('\\', "/"), file.contents()))
+        .collect::<Vec<_>>();
+    files.sort();
+    files
+}
+
+/// The status and body of `GET path`.
+async fn http_get(addr: SocketAddr, path: &str) -> Result<(u16, Vec<u8>), String> {
+    let request = format!("GET {path} HTTP/1.1\r\nHost: {addr}\r\nConnection: close\r\n\r\n");
+    let exchange = async {
+        let mut stream = TcpStream::connect(addr).await?;
+        stream.write_all(request.as_bytes()).await?;
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).await?;
+        Ok::<_, std::io::Error>(response)
+    };
+    let response = tokio::time::timeout(PROBE_TIMEOUT, exchange)
+        .await
+        .map_err(|_| "timed out".to_string())?
+        .map_err(|e| e.to_string())?;
+
+    let split = response.windows(4).position(|window| window == b"\r\n\r\n").ok_or("malformed response")?;
+    let head = String::from_utf8_lossy(&response[..split]);
+    let status = head
+        .split_whitespace()
+        .nth(1)
+        .and_then(|status| status.parse().ok())
+        .ok_or("malformed response")?;
+    Ok((status, response[split + 4..].to_vec()))
+}
+
+async fn websocket_upgrade(addr: SocketAddr, token: Option<&str>) -> Result<(), String> {
+    let mut request = format!("ws://{addr}/ws").into_client_request().map_err(|e| e.to_string())?;
+    if let Some(token) = token {
+        let header = format!("Bearer {token}").parse().map_err(|_| "invalid token".to_string())?;
+        request.headers_mut().insert("authorization", header);
+    }
+    let (mut ws, _) = tokio::time::timeout(PROBE_TIMEOUT, tokio_tungstenite::connect_async(request))
+        .await
+        .map_err(|_| "timed out".to_string())?
+        .map_err(|e| e.to_string())?;
+    let _ = ws.close(None).await;
+    Ok(())
+}
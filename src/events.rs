@@ -0,0 +1,83 @@
+use crate::rooms::RoomId;
+use crate::types::*;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+/// Events returned by one query when it does not ask for a number
+pub const DEFAULT_EVENT_QUERY_LIMIT: usize = 100;
+
+/// Bounded in-memory record of recent server events, for the admin events API.
+/// Recording only pushes onto the buffer under a short lock, so it is safe to call
+/// from message handling without awaiting anything.
+pub struct EventLog {
+    events: Mutex<VecDeque<ServerEvent>>,
+    limit: usize,
+    next_id: AtomicU64,
+}
+
+impl EventLog {
+    pub fn new(limit: usize) -> Self {
+        EventLog {
+            events: Mutex::new(VecDeque::with_capacity(limit.min(4096))),
+            limit,
+            next_id: AtomicU64::new(1),
+        }
+    }
+
+    pub fn record(&self, kind: EventKind, subject: EventSubject) {
+        if self.limit == 0 {
+            return;
+        }
+        let mut events = self.events.lock().unwrap();
+        events.push_back(ServerEvent {
+            id: self.next_id.fetch_add(1, Ordering::Relaxed),
+            at: chrono::Utc::now(),
+            kind,
+            subject,
+        });
+        while events.len() > self.limit {
+            events.pop_front();
+        }
+    }
+
+    /// The most recent events matching the query, oldest first.
+    pub fn query(&self, since: Option<chrono::DateTime<chrono::Utc>>, kinds: &[EventKind], limit: usize) -> Vec<ServerEvent> {
+        let events = self.events.lock().unwrap();
+        let mut matching = events
+            .iter()
+            .rev()
+            .filter(|event| since.is_none_or(|since| event.at >= since))
+            .filter(|event| kinds.is_empty() || kinds.contains(&event.kind))
+            .take(limit)
+            .cloned()
+            .collect::<Vec<_>>();
+        matching.reverse();
+        matching
+    }
+}
+
+impl EventSubject {
+    /// A file of a room, and why something happened to it.
+    pub fn file(room: &RoomId, file_id: &str, detail: &str) -> Self {
+        EventSubject {
+            room: Some(room.clone()),
+            file_id: Some(file_id.to_string()),
+            detail: Some(detail.to_string()),
+            ..Default::default()
+        }
+    }
+}
+
+/// Parses a comma-separated list of event kinds such as `joined,left`.
+pub fn parse_kinds(value: &str) -> Result<Vec<EventKind>, String> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|kind| !kind.is_empty())
+        .map(|kind| {
+            serde_json::from_value(serde_json::Value::String(kind.to_string()))
+                .map_err(|_| format!("Unknown event kind {kind}"))
+        })
+        .collect()
+}
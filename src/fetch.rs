@@ -0,0 +1,138 @@
+use crate::rooms::RoomState;
+use crate::transfers;
+use crate::types::*;
+use crate::websocket::{self, SessionHandle};
+use crate::AppState;
+use base64::Engine;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+/// Session ids the server uses when it downloads a file itself.
+pub const FETCH_SESSION_PREFIX: &str = "server_fetch_";
+/// How long a fetch waits for the host's next message before giving up.
+const FETCH_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+const FETCH_QUEUE_CAPACITY: usize = 16;
+
+pub fn is_fetch_session(session_id: &str) -> bool {
+    session_id.starts_with(FETCH_SESSION_PREFIX)
+}
+
+/// A download made by the server rather than a peer. It registers a session handle
+/// without joining the room, so the host sees an ordinary `DownloadRequest` and its
+/// metadata and chunks arrive here through the relay.
+pub struct Fetch {
+    session_id: SessionId,
+    control: mpsc::UnboundedReceiver<ServerMessage>,
+    chunks: mpsc::Receiver<ServerMessage>,
+    total_chunks: Option<u32>,
+    next_index: u32,
+}
+
+impl Fetch {
+    /// Asks the least busy host of the file to send it in chunks of `chunk_size`,
+    /// queueing behind the host's other transfers like any download.
+    pub async fn start(state: &AppState, room: &RoomState, file_id: &str, chunk_size: u32) -> Result<Fetch, String> {
+        let host_id = {
+            let files = room.files.read().await;
+            match files.get(file_id) {
+                Some(file) if file.unavailable => return Err("host disconnected".to_string()),
+                Some(file) if file.e2e => return Err("end-to-end encrypted files are only sent between peers".to_string()),
+                Some(file) => state.relay.transfers.least_busy_host(&file.hosts, |_| None),
+                None => return Err("file was removed".to_string()),
+            }
+        };
+        let Some(host_id) = host_id else {
+            return Err("no hosts available".to_string());
+        };
+
+        let session_id = format!("{FETCH_SESSION_PREFIX}{}", rand::random::<u64>());
+        let (control_tx, control) = mpsc::unbounded_channel();
+        let (chunks_tx, chunks) = mpsc::channel(FETCH_QUEUE_CAPACITY);
+        state.sessions.write().await.insert(session_id.clone(), SessionHandle {
+            tx: control_tx,
+            chunks: chunks_tx,
+            ip: None,
+            room: room.id.clone(),
+            counters: Arc::default(),
+            local: true,
+            notify_upload_activity: false,
+            credential: None,
+        });
+        let fetch = Fetch {
+            session_id: session_id.clone(),
+            control,
+            chunks,
+            total_chunks: None,
+            next_index: 0,
+        };
+
+        let pending = transfers::PendingDownload {
+            key: (file_id.to_string(), host_id, session_id),
+            room: room.id.clone(),
+            chunk_size,
+            tried_hosts: Default::default(),
+            http_relay: false,
+        };
+        // Hosts that approve each download are asked like for any peer
+        if let Some(ServerMessage::Error { message, .. }) = websocket::request_download(state, room, pending).await {
+            fetch.close(state).await;
+            return Err(message);
+        }
+        Ok(fetch)
+    }
+
+    /// The next chunk of file data in order, or None once the last chunk has arrived.
+    pub async fn next_chunk(&mut self) -> Result<Option<Vec<u8>>, String> {
+        if self.total_chunks.is_some_and(|total| self.next_index >= total) {
+            return Ok(None);
+        }
+
+        loop {
+            let msg = tokio::select! {
+                Some(msg) = self.control.recv() => msg,
+                Some(msg) = self.chunks.recv() => msg,
+                _ = tokio::time::sleep(FETCH_IDLE_TIMEOUT) => return Err("host stopped responding".to_string()),
+                else => return Err("fetch ended".to_string()),
+            };
+
+            match msg {
+                ServerMessage::FileMetadata { total_chunks, .. } => self.total_chunks = Some(total_chunks),
+                ServerMessage::FileChunk { chunk_index, total_chunks, data, compression, encrypted, .. } => {
+                    if compression.is_some() {
+                        return Err("host sent compressed data".to_string());
+                    }
+                    if encrypted {
+                        return Err("host sent encrypted data".to_string());
+                    }
+                    if chunk_index != self.next_index {
+                        return Err(format!("expected chunk {} but got {chunk_index}", self.next_index));
+                    }
+                    let data = base64::engine::general_purpose::STANDARD
+                        .decode(data)
+                        .map_err(|e| format!("invalid chunk data: {e}"))?;
+                    self.total_chunks = Some(total_chunks);
+                    self.next_index += 1;
+                    return Ok(Some(data));
+                }
+                ServerMessage::TransferAborted { .. } => return Err("host disconnected".to_string()),
+                ServerMessage::DownloadDenied { reason, .. } => return Err(format!("download denied ({reason})")),
+                // Sent when no other host is left to try after a timeout
+                ServerMessage::Error { message, .. } => return Err(message),
+                // Queue position updates and the like need no action
+                _ => {}
+            }
+        }
+    }
+
+    /// Known once the host's metadata or first chunk has arrived.
+    pub fn total_chunks(&self) -> Option<u32> {
+        self.total_chunks
+    }
+
+    /// Unregisters the fetch session, telling the host to stop if it is still sending.
+    pub async fn close(self, state: &AppState) {
+        state.sessions.write().await.remove(&self.session_id);
+        websocket::end_transfers(state, &self.session_id).await;
+    }
+}
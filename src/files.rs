@@ -0,0 +1,1165 @@
+use crate::chat;
+use crate::trash;
+use crate::rooms::{Audience, FileRegistry, RoomState};
+use crate::types::*;
+use crate::AppState;
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+pub const MAX_FILE_NAME_LENGTH: usize = 255;
+pub const MAX_MIME_TYPE_LENGTH: usize = 255;
+/// Mime type of files announced without a valid one
+pub const DEFAULT_MIME_TYPE: &str = "application/octet-stream";
+pub const MAX_DESCRIPTION_LENGTH: usize = 500;
+pub const MAX_RELATIVE_PATH_LENGTH: usize = 1024;
+pub const MAX_FOLDER_ID_LENGTH: usize = 64;
+/// Most files one `FileUploadBatch` may announce.
+pub const MAX_BATCH_FILES: usize = 500;
+/// Most files in one `FileListPage` or `/api/files` page.
+pub const MAX_FILE_LIST_PAGE: usize = 1000;
+/// Most peers a private file may be listed for.
+pub const MAX_VISIBILITY: usize = 100;
+pub const MAX_PASSWORD_LENGTH: usize = 256;
+/// How long a wrong file password is held before the error is sent, to slow guessing.
+pub const PASSWORD_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// A change to a room's file registry.
+#[derive(Debug, Default)]
+pub struct FileListChange {
+    pub added: Vec<Arc<FileMetadata>>,
+    pub removed: Vec<String>,
+    pub updated: Vec<Arc<FileMetadata>>,
+}
+
+impl FileListChange {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.updated.is_empty()
+    }
+}
+
+/// Broadcasts a change as a `FileListDiff`, plus a full `FileListUpdate` when some peers
+/// cannot apply diffs. Private files only reach their audience. Call this while still
+/// holding the files write lock so versions are published in the order the changes
+/// were made.
+pub fn publish_change(
+    room: &RoomState,
+    files: &FileRegistry,
+    change: FileListChange,
+    include_full_list: bool,
+) {
+    if change.is_empty() {
+        return;
+    }
+
+    let invalidated = room.remove_attachments(&change.removed);
+    let previous = room.swap_audiences(&change.added, &change.updated, &change.removed);
+    let version = room.next_file_list_version();
+    let mut messages = diff_variants(&change, &previous, version);
+    if include_full_list {
+        messages.extend(full_list_variants(files, version));
+    }
+
+    room.send_variants(messages);
+
+    for (message_id, file_ids) in invalidated {
+        room.send(ServerMessage::MessageAttachmentsInvalidated { message_id, file_ids });
+    }
+}
+
+/// The diff each peer gets. A file whose visibility changed is added for peers who can
+/// now see it and removed for those who no longer can; `previous` holds who the
+/// updated and removed private files were visible to before.
+fn diff_variants(
+    change: &FileListChange,
+    previous: &HashMap<String, HashSet<SessionId>>,
+    version: u64,
+) -> Vec<(ServerMessage, Audience)> {
+    let mut named = previous.values().flatten().cloned().collect::<HashSet<_>>();
+    for file in change.added.iter().chain(&change.updated) {
+        named.extend(file.audience().unwrap_or_default());
+    }
+
+    let was_visible = |file_id: &str, viewer: Option<&str>| match previous.get(file_id) {
+        Some(audience) => viewer.is_some_and(|viewer| audience.contains(viewer)),
+        None => true,
+    };
+    variants(named, |viewer| {
+        let mut added = change.added.iter().filter(|file| file.is_visible_to(viewer)).cloned().collect::<Vec<_>>();
+        let mut removed = change.removed.iter().filter(|file_id| was_visible(file_id, viewer)).cloned().collect::<Vec<_>>();
+        let mut updated = Vec::new();
+        for file in &change.updated {
+            match (was_visible(&file.id, viewer), file.is_visible_to(viewer)) {
+                (true, true) => updated.push(file.clone()),
+                (false, true) => added.push(file.clone()),
+                (true, false) => removed.push(file.id.clone()),
+                (false, false) => {}
+            }
+        }
+        ServerMessage::FileListDiff { added, removed, updated, version }
+    })
+}
+
+fn full_list_variants(files: &FileRegistry, version: u64) -> Vec<(ServerMessage, Audience)> {
+    let named = files.values().flat_map(|file| file.audience().unwrap_or_default()).collect();
+    variants(named, |viewer| {
+        let visible = visible_files(files, viewer).cloned().collect::<Vec<_>>();
+        ServerMessage::FileListUpdate { category_counts: category_counts(&visible), files: visible, version }
+    })
+}
+
+/// How many of `files` are in each category, leaving out empty categories.
+pub fn category_counts(files: &[Arc<FileMetadata>]) -> BTreeMap<FileCategory, usize> {
+    let mut counts = BTreeMap::new();
+    for file in files {
+        *counts.entry(file.category).or_default() += 1;
+    }
+    counts
+}
+
+/// Builds a message for each distinct view among the peers named by private files,
+/// and one for everyone else, who only see public files.
+fn variants(named: HashSet<SessionId>, view: impl Fn(Option<&str>) -> ServerMessage) -> Vec<(ServerMessage, Audience)> {
+    if named.is_empty() {
+        return vec![(view(None), Audience::Everyone)];
+    }
+
+    let mut groups: HashMap<String, (ServerMessage, HashSet<SessionId>)> = HashMap::new();
+    for session_id in &named {
+        let message = view(Some(session_id));
+        let key = serde_json::to_string(&message).unwrap_or_default();
+        groups.entry(key).or_insert_with(|| (message, HashSet::new())).1.insert(session_id.clone());
+    }
+
+    let mut variants = vec![(view(None), Audience::Except(Arc::new(named)))];
+    variants.extend(groups.into_values().map(|(message, sessions)| (message, Audience::Only(Arc::new(sessions)))));
+    variants
+}
+
+/// The files `viewer` may see: public ones, and private ones it is in the audience of.
+/// With no viewer, only public files.
+pub fn visible_files<'a>(
+    files: &'a FileRegistry,
+    viewer: Option<&'a str>,
+) -> impl Iterator<Item = &'a Arc<FileMetadata>> + 'a {
+    files.values().filter(move |file| file.is_visible_to(viewer))
+}
+
+/// The file list of a room as `viewer` sees it, for a peer that is joining or
+/// resynchronizing. Peers that fetch pages get only a summary once the list is longer
+/// than `page_size`.
+pub async fn file_list(room: &RoomState, viewer: &str, page_size: Option<usize>, paged: bool) -> ServerMessage {
+    let files = room.files.read().await;
+    let visible = visible_files(&files, Some(viewer)).cloned().collect::<Vec<_>>();
+    let version = room.file_list_version();
+    if page_size.is_some_and(|size| visible.len() > size) {
+        if paged {
+            return ServerMessage::FileListSummary {
+                total: visible.len(),
+                version,
+                category_counts: category_counts(&visible),
+            };
+        }
+        tracing::warn!(room = room.id, files = visible.len(), "Sending a full file list to a client that cannot fetch pages");
+    }
+    ServerMessage::FileListUpdate { category_counts: category_counts(&visible), files: visible, version }
+}
+
+/// Files per page when the client does not say: `page_size`, capped at `MAX_FILE_LIST_PAGE`.
+pub fn page_limit(limit: Option<usize>, page_size: Option<usize>) -> usize {
+    limit.or(page_size).unwrap_or(MAX_FILE_LIST_PAGE).min(MAX_FILE_LIST_PAGE)
+}
+
+/// Up to `limit` of the files `viewer` may see starting at `offset`, in `sort` order,
+/// only those in `category` if one is given.
+pub fn file_page(
+    files: &FileRegistry,
+    viewer: Option<&str>,
+    category: Option<FileCategory>,
+    sort: FileSort,
+    offset: usize,
+    limit: usize,
+) -> Vec<Arc<FileMetadata>> {
+    let mut sorted = visible_files(files, viewer)
+        .filter(|file| category.is_none_or(|category| file.category == category))
+        .collect::<Vec<_>>();
+    sorted.sort_by(|a, b| {
+        let order = match sort {
+            FileSort::Newest => b.uploaded_at.cmp(&a.uploaded_at),
+            FileSort::Oldest => a.uploaded_at.cmp(&b.uploaded_at),
+            FileSort::Name => a.name.to_lowercase().cmp(&b.name.to_lowercase()),
+            FileSort::Size => b.size.cmp(&a.size),
+        };
+        order.then_with(|| a.id.cmp(&b.id))
+    });
+    sorted.into_iter().skip(offset).take(limit).cloned().collect()
+}
+
+/// Limits on the file registry of each room.
+#[derive(Debug, Clone, Copy)]
+pub struct FileLimits {
+    pub max_files: Option<usize>,
+    pub max_total_size: Option<u64>,
+    pub evict_oldest: bool,
+}
+
+impl FileLimits {
+    fn fits(&self, count: usize, total_size: u64) -> bool {
+        self.max_files.is_none_or(|max| count <= max)
+            && self.max_total_size.is_none_or(|max| total_size <= max)
+    }
+}
+
+/// Checks whether `file` fits in the registry, evicting the uploader's oldest files when
+/// `evict_oldest` is set. Returns the ids to evict (already removed from `files`), or None
+/// if the file cannot be accepted. Call this while holding the files write lock so
+/// concurrent uploads cannot overshoot the limits.
+pub fn make_room(
+    files: &mut FileRegistry,
+    file: &FileMetadata,
+    limits: &FileLimits,
+) -> Option<Vec<Arc<FileMetadata>>> {
+    // A re-announcement replaces the existing entry, so it does not count twice
+    let others = files.values().filter(|existing| existing.id != file.id);
+    let mut count = others.clone().count() + 1;
+    let mut total_size = others.map(|existing| existing.size).sum::<u64>() + file.size;
+
+    if limits.fits(count, total_size) {
+        return Some(Vec::new());
+    }
+    if !limits.evict_oldest {
+        return None;
+    }
+
+    let mut candidates = files
+        .values()
+        .filter(|existing| existing.id != file.id && existing.uploader_id == file.uploader_id)
+        .collect::<Vec<_>>();
+    candidates.sort_by_key(|existing| existing.uploaded_at);
+
+    let mut evicted = Vec::new();
+    for candidate in candidates {
+        count -= 1;
+        total_size -= candidate.size;
+        evicted.push(candidate.id.clone());
+        if limits.fits(count, total_size) {
+            return Some(evicted.iter().filter_map(|file_id| files.remove(file_id)).collect());
+        }
+    }
+    None
+}
+
+/// Why an announced file was not added to the registry.
+#[derive(Debug)]
+pub enum UploadError {
+    InvalidName,
+    /// Another file has taken the id of a file being restored
+    IdTaken,
+    InvalidPath(String),
+    RegistryFull,
+    E2eNotSupported,
+    InvalidPassword,
+}
+
+impl UploadError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            UploadError::InvalidName => "invalid_name",
+            UploadError::IdTaken => "id_taken",
+            UploadError::InvalidPath(_) => "invalid_path",
+            UploadError::RegistryFull => "registry_full",
+            UploadError::E2eNotSupported => "capability_mismatch",
+            UploadError::InvalidPassword => "invalid_password",
+        }
+    }
+
+    pub fn message(&self) -> String {
+        match self {
+            UploadError::InvalidName => "File name must not be empty".to_string(),
+            UploadError::IdTaken => "Another file now has this id".to_string(),
+            UploadError::InvalidPath(path) => {
+                format!("relative_path must be a relative path without '..' segments: {path}")
+            }
+            UploadError::RegistryFull => "The shared file limit has been reached".to_string(),
+            UploadError::E2eNotSupported => {
+                "Join with the e2e capability to share end-to-end encrypted files".to_string()
+            }
+            UploadError::InvalidPassword => format!(
+                "password must be 1 to {MAX_PASSWORD_LENGTH} bytes and password_hash an Argon2 hash in PHC format"
+            ),
+        }
+    }
+}
+
+/// A file accepted into the registry.
+#[derive(Debug)]
+pub struct Upload {
+    pub file: Arc<FileMetadata>,
+    /// Whether it re-announced a file already in the registry
+    pub replaced: bool,
+    /// The uploader's older files removed to make room for it
+    pub evicted: Vec<Arc<FileMetadata>>,
+    /// The id the uploader announced, when another peer's file had it and the file was
+    /// given a new one
+    pub requested_id: Option<String>,
+}
+
+/// Cleans up the fields of an announced file that the client controls. Shared by
+/// `FileUpload` and `FileUploadBatch`, and so by the bridge and the shared directory,
+/// so all apply the same rules. The uploader, its hosts and the upload time are set
+/// here whatever the client claimed. `e2e` tells whether the uploader declared
+/// `E2E_CAPABILITY`, and `local` whether it is one of the server's own peers, the only
+/// ones trusted to say a file came from another server. Hashing a plaintext password
+/// is slow, so call this before taking the files lock.
+pub fn prepare_upload(
+    mut file: FileMetadata,
+    uploader: &SessionId,
+    default_ttl: Option<u64>,
+    e2e: bool,
+    local: bool,
+) -> Result<FileMetadata, UploadError> {
+    if file.e2e && !e2e {
+        return Err(UploadError::E2eNotSupported);
+    }
+    file.name = sanitize_file_name(&file.name).ok_or(UploadError::InvalidName)?;
+    file.mime_type = sanitize_mime_type(&file.mime_type);
+    file.category = FileCategory::detect(&file.mime_type, &file.name);
+    file.uploader_id = uploader.clone();
+    file.hosts = HashSet::from([uploader.clone()]);
+    file.uploaded_at = chrono::Utc::now();
+    if let Some(password) = file.password.take() {
+        if password.is_empty() || password.len() > MAX_PASSWORD_LENGTH {
+            return Err(UploadError::InvalidPassword);
+        }
+        file.password_hash = Some(hash_password(&password).ok_or(UploadError::InvalidPassword)?);
+    } else if file.password_hash.as_deref().is_some_and(|hash| !is_password_hash(hash)) {
+        return Err(UploadError::InvalidPassword);
+    }
+    file.locked = file.password_hash.is_some();
+    file.unavailable = false;
+    file.download_count = 0;
+    if let Some(path) = file.relative_path.take() {
+        match sanitize_relative_path(&path) {
+            Some(path) => file.relative_path = Some(path),
+            None => return Err(UploadError::InvalidPath(path)),
+        }
+    }
+    file.folder_id = file.folder_id.as_deref().and_then(sanitize_folder_id);
+    // Assigned by the caller for the batch the file arrived in
+    file.batch_id = None;
+    file.description = file.description.as_deref().and_then(sanitize_description);
+    file.visibility = file.visibility.take().map(sanitize_visibility);
+    // A peer claiming one would keep its file from ever being bridged
+    file.origin = file.origin.take().filter(|_| local);
+    if file.expires_at.is_none() {
+        file.expires_at = default_ttl.map(|ttl| chrono::Utc::now() + chrono::Duration::seconds(ttl as i64));
+    }
+    Ok(file)
+}
+
+/// Adds a prepared file to the registry if it fits within the limits. A re-announcement
+/// by the same uploader keeps the version and downloads counted so far; a file announced
+/// under another uploader's id gets a fresh id rather than taking over theirs. Call this
+/// while holding the files write lock.
+pub fn insert_upload(
+    files: &mut FileRegistry,
+    mut file: FileMetadata,
+    limits: &FileLimits,
+) -> Result<Upload, UploadError> {
+    let mut requested_id = None;
+    if files.get(&file.id).is_some_and(|existing| existing.uploader_id != file.uploader_id) {
+        requested_id = Some(std::mem::replace(&mut file.id, fresh_file_id(files)));
+    }
+    let evicted = make_room(files, &file, limits).ok_or(UploadError::RegistryFull)?;
+
+    file.version = 1;
+    file.display_suffix = None;
+    if let Some(existing) = files.get(&file.id) {
+        file.version = existing.version;
+        file.download_count = existing.download_count;
+        file.downloaders = existing.downloaders.clone();
+        if existing.name == file.name {
+            file.display_suffix = existing.display_suffix.clone();
+        }
+    }
+    if file.display_suffix.is_none() {
+        file.display_suffix = display_suffix(files, &file.id, &file.name);
+    }
+    let file = Arc::new(file);
+    let replaced = files.insert(file.id.clone(), file.clone()).is_some();
+    Ok(Upload { file, replaced, evicted, requested_id })
+}
+
+/// Puts a file back from the trash, hosted by `hosts`, if its id is still free and it
+/// fits within the limits; other files are never evicted for it. Call this while
+/// holding the files write lock.
+pub fn restore_file(
+    files: &mut FileRegistry,
+    file: &FileMetadata,
+    hosts: HashSet<SessionId>,
+    limits: &FileLimits,
+) -> Result<Arc<FileMetadata>, UploadError> {
+    if files.contains_key(&file.id) {
+        return Err(UploadError::IdTaken);
+    }
+    let limits = FileLimits { evict_oldest: false, ..*limits };
+    make_room(files, file, &limits).ok_or(UploadError::RegistryFull)?;
+
+    let mut file = file.clone();
+    file.hosts = hosts;
+    file.unavailable = false;
+    file.orphaned = None;
+    file.display_suffix = display_suffix(files, &file.id, &file.name);
+    let file = Arc::new(file);
+    files.insert(file.id.clone(), file.clone());
+    Ok(file)
+}
+
+/// An id for the files a peer shares in one action.
+pub fn new_batch_id() -> String {
+    format!("batch_{:032x}", rand::random::<u128>())
+}
+
+/// The batch a single upload at `now` joins: the open one while uploads keep coming
+/// within `window` of each other, else a new one, which is then kept in `open`.
+/// None when the window is 0.
+pub fn upload_batch(open: &mut Option<(String, Instant)>, window: Duration, now: Instant) -> Option<String> {
+    if window.is_zero() {
+        return None;
+    }
+    let batch_id = match open.take() {
+        Some((batch_id, last)) if now.duration_since(last) < window => batch_id,
+        _ => new_batch_id(),
+    };
+    *open = Some((batch_id.clone(), now));
+    Some(batch_id)
+}
+
+/// Ids of the batch's files that `viewer` could download, oldest first. Files that
+/// could not be served anyway are left out rather than each answered with an error.
+pub fn batch_members(files: &FileRegistry, batch_id: &str, viewer: Option<&str>, e2e: bool) -> Vec<String> {
+    let mut members = files
+        .values()
+        .filter(|file| file.batch_id.as_deref() == Some(batch_id))
+        .filter(|file| file.is_visible_to(viewer))
+        .filter(|file| !file.unavailable && (e2e || !file.e2e))
+        .map(|file| (file.uploaded_at, file.id.clone()))
+        .collect::<Vec<_>>();
+    members.sort();
+    members.into_iter().map(|(_, file_id)| file_id).collect()
+}
+
+/// Number and total size of the batch's files that are listed for everyone.
+pub fn batch_totals(files: &FileRegistry, batch_id: &str) -> (usize, u64) {
+    files
+        .values()
+        .filter(|file| file.batch_id.as_deref() == Some(batch_id) && file.visibility.is_none())
+        .fold((0, 0), |(count, size), file| (count + 1, size + file.size))
+}
+
+/// A random id no file in the registry has.
+fn fresh_file_id(files: &FileRegistry) -> String {
+    loop {
+        let id = format!("file_{:032x}", rand::random::<u128>());
+        if !files.contains_key(&id) {
+            return id;
+        }
+    }
+}
+
+/// The suffix that tells the file apart from the other files listed under `name`: none
+/// while the plain name is free, otherwise the lowest " (n)" not yet used.
+pub fn display_suffix(files: &FileRegistry, file_id: &str, name: &str) -> Option<String> {
+    let taken = files
+        .values()
+        .filter(|other| other.id != file_id && other.name == name)
+        .map(|other| other.display_suffix.as_deref())
+        .collect::<HashSet<_>>();
+    if !taken.contains(&None) {
+        return None;
+    }
+    (2..).map(|n| format!(" ({n})")).find(|suffix| !taken.contains(&Some(suffix.as_str())))
+}
+
+/// An Argon2 hash of `password` in PHC format, with a random salt.
+pub fn hash_password(password: &str) -> Option<String> {
+    let salt = SaltString::encode_b64(&rand::random::<[u8; 16]>()).ok()?;
+    let hash = Argon2::default().hash_password(password.as_bytes(), &salt).ok()?;
+    Some(hash.to_string())
+}
+
+fn is_password_hash(hash: &str) -> bool {
+    PasswordHash::new(hash).is_ok_and(|hash| hash.algorithm.as_str().starts_with("argon2"))
+}
+
+/// Whether `password` matches a file's `password_hash`. Runs on the blocking pool, as
+/// Argon2 is deliberately slow.
+pub async fn check_password(hash: String, password: String) -> bool {
+    if password.len() > MAX_PASSWORD_LENGTH {
+        return false;
+    }
+    tokio::task::spawn_blocking(move || {
+        PasswordHash::new(&hash).is_ok_and(|hash| Argon2::default().verify_password(password.as_bytes(), &hash).is_ok())
+    })
+    .await
+    .unwrap_or(false)
+}
+
+fn clean_text(value: &str, max_chars: usize) -> String {
+    value
+        .trim()
+        .chars()
+        .filter(|c| !c.is_control())
+        .take(max_chars)
+        .collect::<String>()
+        .trim_end()
+        .to_string()
+}
+
+/// Trims a file name, drops control characters, replaces path separators with `_` and
+/// caps its length, so a name can never point outside a download folder. Returns None
+/// if nothing is left, or only `.` or `..`.
+pub fn sanitize_file_name(name: &str) -> Option<String> {
+    let name = clean_text(&name.replace(['/', '\\'], "_"), MAX_FILE_NAME_LENGTH);
+    if name.is_empty() || name == "." || name == ".." {
+        None
+    } else {
+        Some(name)
+    }
+}
+
+/// The lowercased `type/subtype` of a mime type, without parameters, or
+/// `DEFAULT_MIME_TYPE` if it is not of that form.
+pub fn sanitize_mime_type(mime_type: &str) -> String {
+    let is_token = |part: &str| {
+        !part.is_empty() && part.chars().all(|c| c.is_ascii_graphic() && !"()<>@,;:\\\"/[]?=".contains(c))
+    };
+    let essence = mime_type.split(';').next().unwrap_or_default().trim();
+    match essence.split_once('/') {
+        Some((kind, subtype)) if essence.len() <= MAX_MIME_TYPE_LENGTH && is_token(kind) && is_token(subtype) => {
+            essence.to_ascii_lowercase()
+        }
+        _ => DEFAULT_MIME_TYPE.to_string(),
+    }
+}
+
+/// Trims a description, drops control characters other than newlines and caps its length.
+/// An empty description is stored as None.
+pub fn sanitize_description(description: &str) -> Option<String> {
+    let description = description
+        .trim()
+        .chars()
+        .filter(|c| *c == '\n' || !c.is_control())
+        .take(MAX_DESCRIPTION_LENGTH)
+        .collect::<String>()
+        .trim_end()
+        .to_string();
+    if description.is_empty() {
+        None
+    } else {
+        Some(description)
+    }
+}
+
+/// Trims a folder id and caps its length. An empty id is stored as None.
+pub fn sanitize_folder_id(folder_id: &str) -> Option<String> {
+    let folder_id = clean_text(folder_id, MAX_FOLDER_ID_LENGTH);
+    if folder_id.is_empty() {
+        None
+    } else {
+        Some(folder_id)
+    }
+}
+
+/// Trims the session ids a private file is listed for, dropping empty and repeated
+/// ones, and caps their number. An empty list leaves the file to its uploader.
+pub fn sanitize_visibility(session_ids: Vec<SessionId>) -> Vec<SessionId> {
+    let mut seen = HashSet::new();
+    session_ids
+        .into_iter()
+        .map(|session_id| session_id.trim().to_string())
+        .filter(|session_id| !session_id.is_empty() && seen.insert(session_id.clone()))
+        .take(MAX_VISIBILITY)
+        .collect()
+}
+
+/// Normalizes a path within a shared folder: backslashes become slashes and empty or
+/// `.` segments are dropped. Returns None for absolute paths, `..` segments, control
+/// characters, and paths that are empty or too long.
+pub fn sanitize_relative_path(path: &str) -> Option<String> {
+    let path = path.replace('\\', "/");
+    let is_absolute = path.starts_with('/') || path.split('/').next().is_some_and(|first| first.ends_with(':'));
+    if is_absolute || path.len() > MAX_RELATIVE_PATH_LENGTH || path.chars().any(char::is_control) {
+        return None;
+    }
+
+    let segments = path
+        .split('/')
+        .map(str::trim)
+        .filter(|segment| !segment.is_empty() && *segment != ".")
+        .collect::<Vec<_>>();
+    if segments.is_empty() || segments.contains(&"..") {
+        return None;
+    }
+    Some(segments.join("/"))
+}
+
+/// Removes every file matching `matches` under one lock, so a folder disappears for
+/// every peer at once, and moves them to the trash. Returns how many were removed.
+pub async fn remove_files(state: &AppState, room: &RoomState, matches: impl Fn(&FileMetadata) -> bool) -> usize {
+    let include_full_list = room.has_full_list_peers().await;
+    let mut files = room.files.write().await;
+    let removed = files
+        .values()
+        .filter(|file| matches(file))
+        .map(|file| file.id.clone())
+        .collect::<Vec<_>>();
+
+    let mut names = Vec::new();
+    let mut uploaders = HashSet::new();
+    for file_id in &removed {
+        tracing::info!(file_id, reason = "removed by uploader", "File removed");
+        state.events.record(EventKind::FileRemoved, EventSubject::file(&room.id, file_id, "removed by uploader"));
+        let Some(file) = files.remove(file_id) else {
+            continue;
+        };
+        state.relay.transfers.forget_file(file_id);
+        uploaders.insert(file.uploader_id.clone());
+        state.trash.put(&room.id, file.clone());
+        // Peers outside a private file's audience never heard of it
+        if file.visibility.is_none() {
+            names.push(file.name.clone());
+            room.send(ServerMessage::FileRemoved { file_id: file_id.clone() });
+        }
+    }
+
+    let count = removed.len();
+    let change = FileListChange {
+        removed,
+        ..Default::default()
+    };
+    publish_change(room, &files, change, include_full_list);
+    drop(files);
+    chat::post_files_removed(state, room, &names).await;
+    for uploader in &uploaders {
+        trash::notify(state, uploader).await;
+    }
+    count
+}
+
+/// Removes expired files, and files whose last host did not return within the orphan
+/// grace period, from every room. Expired files still being transferred are kept until
+/// the transfer completes or stalls. Files without hosts go to the trash.
+pub async fn remove_expired_files(state: &AppState) {
+    let now = chrono::Utc::now();
+    let rooms = {
+        let rooms = state.rooms.read().await;
+        rooms.values().cloned().collect::<Vec<_>>()
+    };
+
+    for room in rooms {
+        let include_full_list = room.has_full_list_peers().await;
+        let mut files = room.files.write().await;
+        let removed = files
+            .values()
+            .filter_map(|file| {
+                if file
+                    .orphaned
+                    .as_ref()
+                    .is_some_and(|orphaned| orphaned.since.elapsed() >= state.orphan_grace)
+                {
+                    Some((file.id.clone(), "no hosts left"))
+                } else if file.expires_at.is_some_and(|expires_at| expires_at <= now)
+                    && !state.relay.transfers.is_transferring(&file.id)
+                {
+                    Some((file.id.clone(), "expired"))
+                } else {
+                    None
+                }
+            })
+            .collect::<Vec<_>>();
+
+        let mut names = Vec::new();
+        let mut uploaders = HashSet::new();
+        for (file_id, reason) in &removed {
+            tracing::info!(file_id, reason, "File removed");
+            state.events.record(EventKind::FileRemoved, EventSubject::file(&room.id, file_id, reason));
+            let Some(file) = files.remove(file_id) else {
+                continue;
+            };
+            state.relay.transfers.forget_file(file_id);
+            // An expired file is meant to go for good
+            if *reason != "expired" {
+                uploaders.insert(file.uploader_id.clone());
+                state.trash.put(&room.id, file.clone());
+            }
+            if file.visibility.is_none() {
+                names.push(file.name.clone());
+                room.send(ServerMessage::FileRemoved { file_id: file_id.clone() });
+            }
+        }
+
+        let change = FileListChange {
+            removed: removed.into_iter().map(|(file_id, _)| file_id).collect(),
+            ..Default::default()
+        };
+        publish_change(&room, &files, change, include_full_list);
+        drop(files);
+        chat::post_files_removed(state, &room, &names).await;
+        for uploader in &uploaders {
+            trash::notify(state, uploader).await;
+        }
+    }
+}
+
+/// Makes `session_id` a host again of the files it was the last host of before
+/// disconnecting.
+pub async fn reattach_host(room: &RoomState, session_id: &SessionId) {
+    let include_full_list = room.has_full_list_peers().await;
+    let mut files = room.files.write().await;
+    let mut change = FileListChange::default();
+
+    for file in files.values_mut() {
+        if file.orphaned.as_ref().is_some_and(|orphaned| &orphaned.last_host == session_id) {
+            let updated = Arc::make_mut(file);
+            updated.orphaned = None;
+            updated.unavailable = false;
+            updated.hosts.insert(session_id.clone());
+            tracing::info!(file_id = updated.id, "File available again");
+            change.updated.push(file.clone());
+        }
+    }
+
+    publish_change(room, &files, change, include_full_list);
+}
+
+pub async fn registry_stats(state: &AppState) -> FileRegistryStats {
+    let rooms = {
+        let rooms = state.rooms.read().await;
+        rooms.values().cloned().collect::<Vec<_>>()
+    };
+
+    let mut stats = FileRegistryStats {
+        file_count: 0,
+        total_size: 0,
+        max_files: state.file_limits.max_files,
+        max_total_size: state.file_limits.max_total_size,
+    };
+    for room in rooms {
+        let files = room.files.read().await;
+        stats.file_count += files.len();
+        stats.total_size += files.values().map(|file| file.size).sum::<u64>();
+    }
+    stats
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// A public file of `size` bytes uploaded and hosted by `uploader`.
+    pub(crate) fn file(id: &str, uploader: &str, size: u64) -> FileMetadata {
+        serde_json::from_value(serde_json::json!({
+            "id": id,
+            "name": format!("{id}.txt"),
+            "size": size,
+            "mime_type": "text/plain",
+            "uploader_id": uploader,
+            "hosts": [uploader],
+            "uploaded_at": "2024-01-01T00:00:00Z",
+        }))
+        .unwrap()
+    }
+
+    pub(crate) fn registry(files: Vec<FileMetadata>) -> FileRegistry {
+        files.into_iter().map(|file| (file.id.clone(), Arc::new(file))).collect()
+    }
+
+    fn in_batch(file: FileMetadata, batch_id: &str) -> FileMetadata {
+        FileMetadata {
+            batch_id: Some(batch_id.to_string()),
+            ..file
+        }
+    }
+
+    #[test]
+    fn uploads_within_the_window_share_a_batch() {
+        let window = Duration::from_secs(2);
+        let start = Instant::now();
+        let mut open = None;
+        let first = upload_batch(&mut open, window, start).unwrap();
+        let second = upload_batch(&mut open, window, start + Duration::from_secs(1)).unwrap();
+        // The window runs from the latest upload, not the first
+        let third = upload_batch(&mut open, window, start + Duration::from_millis(2500)).unwrap();
+        assert_eq!(first, second);
+        assert_eq!(second, third);
+    }
+
+    #[test]
+    fn upload_after_the_window_starts_a_new_batch() {
+        let window = Duration::from_secs(2);
+        let start = Instant::now();
+        let mut open = None;
+        let first = upload_batch(&mut open, window, start).unwrap();
+        let second = upload_batch(&mut open, window, start + window).unwrap();
+        assert_ne!(first, second);
+        assert_eq!(open.unwrap().0, second);
+    }
+
+    #[test]
+    fn zero_window_turns_batching_off() {
+        let mut open = None;
+        assert_eq!(upload_batch(&mut open, Duration::ZERO, Instant::now()), None);
+        assert!(open.is_none());
+    }
+
+    #[test]
+    fn batch_totals_count_only_public_files() {
+        let private = FileMetadata {
+            visibility: Some(vec!["bob".to_string()]),
+            ..in_batch(file("c", "alice", 400), "batch_1")
+        };
+        let files = registry(vec![
+            in_batch(file("a", "alice", 100), "batch_1"),
+            in_batch(file("b", "alice", 200), "batch_1"),
+            private,
+            in_batch(file("d", "alice", 800), "batch_2"),
+            file("e", "alice", 1600),
+        ]);
+        assert_eq!(batch_totals(&files, "batch_1"), (2, 300));
+        assert_eq!(batch_totals(&files, "batch_3"), (0, 0));
+    }
+
+    #[test]
+    fn batch_expands_to_the_viewers_files_oldest_first() {
+        let later = |file: FileMetadata, seconds| FileMetadata {
+            uploaded_at: file.uploaded_at + chrono::Duration::seconds(seconds),
+            ..file
+        };
+        let private = FileMetadata {
+            visibility: Some(vec!["bob".to_string()]),
+            ..later(in_batch(file("private", "alice", 1), "batch_1"), 1)
+        };
+        let unavailable = FileMetadata {
+            unavailable: true,
+            ..in_batch(file("unavailable", "alice", 1), "batch_1")
+        };
+        let encrypted = FileMetadata {
+            e2e: true,
+            ..later(in_batch(file("encrypted", "alice", 1), "batch_1"), 3)
+        };
+        let files = registry(vec![
+            later(in_batch(file("second", "alice", 1), "batch_1"), 2),
+            in_batch(file("first", "alice", 1), "batch_1"),
+            private,
+            unavailable,
+            encrypted,
+            in_batch(file("other", "alice", 1), "batch_2"),
+        ]);
+
+        assert_eq!(batch_members(&files, "batch_1", Some("carol"), false), ["first", "second"]);
+        assert_eq!(batch_members(&files, "batch_1", Some("bob"), false), ["first", "private", "second"]);
+        assert_eq!(batch_members(&files, "batch_1", Some("carol"), true), ["first", "second", "encrypted"]);
+        assert!(batch_members(&files, "batch_3", Some("carol"), true).is_empty());
+    }
+
+    #[test]
+    fn sanitizes_file_names() {
+        let long = "a".repeat(10 * 1024);
+        let long_emoji = "🎉".repeat(10 * 1024);
+        let cases: &[(&str, Option<&str>)] = &[
+            ("report.pdf", Some("report.pdf")),
+            ("  report.pdf  ", Some("report.pdf")),
+            ("../../etc/passwd", Some(".._.._etc_passwd")),
+            ("..\\..\\windows\\system32", Some(".._.._windows_system32")),
+            ("/etc/passwd", Some("_etc_passwd")),
+            ("bad\u{0}na\u{1b}me\n.txt", Some("badname.txt")),
+            ("🎉 party 🎈.png", Some("🎉 party 🎈.png")),
+            ("日本語のファイル.txt", Some("日本語のファイル.txt")),
+            ("", None),
+            ("   ", None),
+            ("\u{7}\u{8}", None),
+            (".", None),
+            ("..", None),
+            (" .. ", None),
+        ];
+        for (name, expected) in cases {
+            assert_eq!(sanitize_file_name(name).as_deref(), *expected, "{name:?}");
+        }
+        assert_eq!(sanitize_file_name(&long), Some("a".repeat(MAX_FILE_NAME_LENGTH)));
+        // Capped in characters, so a multi-byte character is never cut in half
+        assert_eq!(sanitize_file_name(&long_emoji), Some("🎉".repeat(MAX_FILE_NAME_LENGTH)));
+    }
+
+    #[test]
+    fn sanitizes_mime_types() {
+        let long = format!("application/{}", "x".repeat(MAX_MIME_TYPE_LENGTH));
+        let cases: &[(&str, &str)] = &[
+            ("text/plain", "text/plain"),
+            ("Image/PNG", "image/png"),
+            ("text/html; charset=utf-8", "text/html"),
+            ("  application/json  ", "application/json"),
+            ("application/vnd.openxmlformats-officedocument.wordprocessingml.document", "application/vnd.openxmlformats-officedocument.wordprocessingml.document"),
+            ("", DEFAULT_MIME_TYPE),
+            ("text", DEFAULT_MIME_TYPE),
+            ("text/", DEFAULT_MIME_TYPE),
+            ("/plain", DEFAULT_MIME_TYPE),
+            ("text/plain/extra", DEFAULT_MIME_TYPE),
+            ("text/pl ain", DEFAULT_MIME_TYPE),
+            ("text/plain\r\nx-injected: 1", DEFAULT_MIME_TYPE),
+            ("text/<script>", DEFAULT_MIME_TYPE),
+            ("texte/plaîn", DEFAULT_MIME_TYPE),
+            (&long, DEFAULT_MIME_TYPE),
+        ];
+        for (mime_type, expected) in cases {
+            assert_eq!(sanitize_mime_type(mime_type), *expected, "{mime_type:?}");
+        }
+    }
+
+    #[test]
+    fn sanitizes_relative_paths() {
+        let cases: &[(&str, Option<&str>)] = &[
+            ("photos/2024/beach.jpg", Some("photos/2024/beach.jpg")),
+            ("photos\\2024\\beach.jpg", Some("photos/2024/beach.jpg")),
+            ("photos//./beach.jpg", Some("photos/beach.jpg")),
+            ("../beach.jpg", None),
+            ("photos/../../beach.jpg", None),
+            ("/etc/passwd", None),
+            ("C:/Windows/win.ini", None),
+            ("photos/\u{0}beach.jpg", None),
+            ("./.", None),
+            ("", None),
+        ];
+        for (path, expected) in cases {
+            assert_eq!(sanitize_relative_path(path).as_deref(), *expected, "{path:?}");
+        }
+        assert_eq!(sanitize_relative_path(&"a/".repeat(MAX_RELATIVE_PATH_LENGTH)), None);
+    }
+
+    #[test]
+    fn prepare_upload_overrides_what_the_client_claimed() {
+        let claimed = FileMetadata {
+            name: "../secret.txt".to_string(),
+            mime_type: "TEXT/PLAIN; charset=utf-8".to_string(),
+            hosts: HashSet::from(["mallory".to_string()]),
+            download_count: 99,
+            unavailable: true,
+            batch_id: Some("batch_other".to_string()),
+            ..file("file_1", "mallory", 10)
+        };
+        let prepared = prepare_upload(claimed, &"alice".to_string(), None, false, false).unwrap();
+        assert_eq!(prepared.name, ".._secret.txt");
+        assert_eq!(prepared.mime_type, "text/plain");
+        assert_eq!(prepared.uploader_id, "alice");
+        assert_eq!(prepared.hosts, HashSet::from(["alice".to_string()]));
+        assert_eq!(prepared.download_count, 0);
+        assert!(!prepared.unavailable);
+        assert_eq!(prepared.batch_id, None);
+    }
+
+    #[test]
+    fn prepare_upload_rejects_invalid_files() {
+        let alice = "alice".to_string();
+        let unnamed = FileMetadata {
+            name: " .. ".to_string(),
+            ..file("file_1", "alice", 10)
+        };
+        let escaping = FileMetadata {
+            relative_path: Some("../outside".to_string()),
+            ..file("file_1", "alice", 10)
+        };
+        let encrypted = FileMetadata {
+            e2e: true,
+            ..file("file_1", "alice", 10)
+        };
+        assert!(matches!(prepare_upload(unnamed, &alice, None, false, false), Err(UploadError::InvalidName)));
+        assert!(matches!(prepare_upload(escaping, &alice, None, false, false), Err(UploadError::InvalidPath(_))));
+        assert!(matches!(prepare_upload(encrypted.clone(), &alice, None, false, false), Err(UploadError::E2eNotSupported)));
+        assert!(prepare_upload(encrypted, &alice, None, true, false).is_ok());
+    }
+
+    #[test]
+    fn only_local_peers_may_mark_a_file_as_bridged() {
+        let alice = "alice".to_string();
+        let bridged = FileMetadata {
+            origin: Some(FileOrigin::Remote),
+            ..file("file_1", "alice", 10)
+        };
+        assert_eq!(prepare_upload(bridged.clone(), &alice, None, false, false).unwrap().origin, None);
+        assert_eq!(prepare_upload(bridged, &alice, None, false, true).unwrap().origin, Some(FileOrigin::Remote));
+    }
+
+    #[test]
+    fn counts_files_by_category() {
+        let categorized = |id: &str, category| {
+            Arc::new(FileMetadata {
+                category,
+                ..file(id, "alice", 1)
+            })
+        };
+        let files = [
+            categorized("a", FileCategory::Image),
+            categorized("b", FileCategory::Image),
+            categorized("c", FileCategory::Code),
+        ];
+        assert_eq!(
+            category_counts(&files),
+            BTreeMap::from([(FileCategory::Image, 2), (FileCategory::Code, 1)])
+        );
+        assert!(category_counts(&[]).is_empty());
+    }
+
+    #[test]
+    fn file_page_filters_by_category() {
+        let categorized = |id: &str, category, size| FileMetadata {
+            category,
+            ..file(id, "alice", size)
+        };
+        let private = FileMetadata {
+            visibility: Some(vec!["bob".to_string()]),
+            ..categorized("private", FileCategory::Image, 5)
+        };
+        let files = registry(vec![
+            categorized("small", FileCategory::Image, 1),
+            categorized("large", FileCategory::Image, 3),
+            categorized("code", FileCategory::Code, 2),
+            private,
+        ]);
+        let ids = |page: Vec<Arc<FileMetadata>>| page.iter().map(|file| file.id.clone()).collect::<Vec<_>>();
+
+        let images = file_page(&files, Some("carol"), Some(FileCategory::Image), FileSort::Size, 0, 10);
+        assert_eq!(ids(images), ["large", "small"]);
+        let images = file_page(&files, Some("bob"), Some(FileCategory::Image), FileSort::Size, 0, 10);
+        assert_eq!(ids(images), ["private", "large", "small"]);
+        let all = file_page(&files, None, None, FileSort::Size, 1, 1);
+        assert_eq!(ids(all), ["code"]);
+        assert!(file_page(&files, None, Some(FileCategory::Video), FileSort::Newest, 0, 10).is_empty());
+    }
+
+    const NO_LIMITS: FileLimits = FileLimits {
+        max_files: None,
+        max_total_size: None,
+        evict_oldest: false,
+    };
+
+    #[test]
+    fn taken_id_is_reassigned_for_another_uploader() {
+        let mut files = registry(vec![file("file_1", "alice", 10)]);
+        let upload = insert_upload(&mut files, file("file_1", "mallory", 20), &NO_LIMITS).unwrap();
+
+        assert_eq!(upload.requested_id.as_deref(), Some("file_1"));
+        assert_ne!(upload.file.id, "file_1");
+        assert!(!upload.replaced);
+        assert_eq!(files["file_1"].uploader_id, "alice");
+        assert_eq!(files["file_1"].size, 10);
+        assert_eq!(files[&upload.file.id].uploader_id, "mallory");
+    }
+
+    #[test]
+    fn reannouncement_by_the_uploader_keeps_the_id_and_counts() {
+        let counted = FileMetadata {
+            download_count: 3,
+            version: 2,
+            ..file("file_1", "alice", 10)
+        };
+        let mut files = registry(vec![counted]);
+        let upload = insert_upload(&mut files, file("file_1", "alice", 20), &NO_LIMITS).unwrap();
+
+        assert_eq!(upload.requested_id, None);
+        assert!(upload.replaced);
+        assert_eq!(upload.file.id, "file_1");
+        assert_eq!(upload.file.download_count, 3);
+        assert_eq!(upload.file.version, 2);
+        assert_eq!(files.len(), 1);
+    }
+
+    #[test]
+    fn same_names_get_the_lowest_free_suffix() {
+        let named = |id: &str, suffix: Option<&str>| FileMetadata {
+            name: "photo.jpg".to_string(),
+            display_suffix: suffix.map(str::to_string),
+            ..file(id, "alice", 1)
+        };
+        let files = registry(vec![]);
+        assert_eq!(display_suffix(&files, "new", "photo.jpg"), None);
+
+        let files = registry(vec![named("a", None)]);
+        assert_eq!(display_suffix(&files, "new", "photo.jpg").as_deref(), Some(" (2)"));
+        // A file does not clash with itself
+        assert_eq!(display_suffix(&files, "a", "photo.jpg"), None);
+        assert_eq!(display_suffix(&files, "new", "other.jpg"), None);
+
+        let files = registry(vec![named("a", None), named("b", Some(" (2)")), named("c", Some(" (4)"))]);
+        assert_eq!(display_suffix(&files, "new", "photo.jpg").as_deref(), Some(" (3)"));
+
+        // The plain name is used again once its file is gone
+        let files = registry(vec![named("b", Some(" (2)"))]);
+        assert_eq!(display_suffix(&files, "new", "photo.jpg"), None);
+    }
+
+    #[test]
+    fn inserted_files_keep_their_suffix_while_the_name_stays() {
+        let photo = |id: &str, uploader: &str| FileMetadata {
+            name: "photo.jpg".to_string(),
+            ..file(id, uploader, 1)
+        };
+        let mut files = registry(vec![]);
+        let first = insert_upload(&mut files, photo("a", "alice"), &NO_LIMITS).unwrap();
+        let second = insert_upload(&mut files, photo("b", "bob"), &NO_LIMITS).unwrap();
+        assert_eq!(first.file.display_suffix, None);
+        assert_eq!(second.file.display_suffix.as_deref(), Some(" (2)"));
+
+        let again = insert_upload(&mut files, photo("b", "bob"), &NO_LIMITS).unwrap();
+        assert_eq!(again.file.display_suffix.as_deref(), Some(" (2)"));
+        let renamed = FileMetadata {
+            name: "beach.jpg".to_string(),
+            ..photo("b", "bob")
+        };
+        let renamed = insert_upload(&mut files, renamed, &NO_LIMITS).unwrap();
+        assert_eq!(renamed.file.display_suffix, None);
+    }
+
+    /// Times the file list work of a 1,000-file room: listing it by sharing entries
+    /// against deep-copying them as before, and a host joining one file published as a
+    /// copy-on-write diff against publishing the full list. Run with
+    /// `cargo test --release file_list_benchmark -- --ignored --nocapture`.
+    #[test]
+    #[ignore]
+    fn file_list_benchmark() {
+        const FILES: usize = 1000;
+        const ROUNDS: u32 = 200;
+        let room = RoomState::new("main".to_string());
+        let mut files = registry((0..FILES).map(|n| file(&format!("file_{n}"), "alice", 1000)).collect());
+        let per_round = |f: &mut dyn FnMut()| {
+            let started = Instant::now();
+            for _ in 0..ROUNDS {
+                f();
+            }
+            started.elapsed() / ROUNDS
+        };
+
+        let shared = per_round(&mut || {
+            std::hint::black_box(visible_files(&files, None).cloned().collect::<Vec<_>>());
+        });
+        let copied = per_round(&mut || {
+            std::hint::black_box(files.values().map(|file| FileMetadata::clone(file)).collect::<Vec<_>>());
+        });
+        println!("Listing {FILES} files: {shared:?} sharing entries, {copied:?} copying them");
+
+        let diff = per_round(&mut || {
+            let file = files.get_mut("file_500").unwrap();
+            Arc::make_mut(file).hosts.insert("bob".to_string());
+            let change = FileListChange {
+                updated: vec![file.clone()],
+                ..Default::default()
+            };
+            publish_change(&room, &files, change, false);
+        });
+        let full = per_round(&mut || {
+            room.send_variants(full_list_variants(&files, room.next_file_list_version()));
+        });
+        println!("Adding a host to one of {FILES} files: {diff:?} as a diff, {full:?} as a full list");
+    }
+}
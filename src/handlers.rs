@@ -1,37 +1,83 @@
+use crate::chat;
+use crate::events;
+use crate::files;
+use crate::network;
+use crate::observers;
+use crate::peers;
+use crate::pipes;
+use crate::rooms;
+use crate::snippets;
 use crate::types::*;
+use crate::websocket;
+use crate::transfers;
+use crate::zip;
 use crate::AppState;
+use futures_util::Stream;
+use std::net::IpAddr;
+use std::sync::Arc;
+use tokio::sync::mpsc;
 use warp::{Rejection, Reply};
 
+/// Tells the login page what it needs without authentication: whether a code is
+/// required and its shape, the server name, whether the caller's cookie is valid and
+/// for how long, and roughly how many peers are connected (exactly, once authenticated).
+/// Never includes the code or the admin token.
 pub async fn check_auth_status(cookie: Option<String>, state: AppState) -> Result<impl Reply, Rejection> {
-    let is_authenticated = match state.security_code {
-        None => true, // No auth required
-        Some(_) => {
-            let expected_auth_value = format!("authenticated:{}", state.server_session_id);
-            cookie.as_ref()
-                .map(|c| c.contains(&format!("auth={expected_auth_value}")))
-                .unwrap_or(false)
+    let expires_at = cookie.as_deref().and_then(|cookie| state.auth_token_expiry(cookie));
+    let is_authenticated = state.security_code.is_none() || expires_at.is_some();
+
+    let peer_count = {
+        let rooms = state.rooms.read().await.values().cloned().collect::<Vec<_>>();
+        let mut count = 0;
+        for room in rooms {
+            count += room.peers.read().await.len();
         }
+        count
     };
     
     #[derive(serde::Serialize)]
     struct AuthStatusResponse {
         authenticated: bool,
         auth_required: bool,
+        server_name: String,
+        code_length: usize,
+        code_charset: &'static str,
+        cookie_valid: bool,
+        /// Seconds until the auth cookie expires
+        cookie_expires_in: Option<i64>,
+        /// Exact count, for authenticated callers only
+        peer_count: Option<usize>,
+        /// The count rounded to a multiple of 5
+        peer_count_rounded: usize,
     }
     
     let response = AuthStatusResponse {
         authenticated: is_authenticated,
         auth_required: state.security_code.is_some(),
+        server_name: state.server_name.clone(),
+        code_length: crate::SECURITY_CODE_LENGTH,
+        code_charset: "digits",
+        cookie_valid: expires_at.is_some(),
+        cookie_expires_in: expires_at.map(|expires_at| (expires_at - chrono::Utc::now()).num_seconds()),
+        peer_count: is_authenticated.then_some(peer_count),
+        peer_count_rounded: (peer_count + 2) / 5 * 5,
     };
     
     Ok(warp::reply::json(&response))
 }
 
-pub async fn get_peers(state: AppState) -> Result<impl Reply, Rejection> {
-    let peers = {
-        let peers = state.peers.read().await;
-        peers.values().cloned().collect::<Vec<_>>()
-    };
+pub async fn get_peers(query: RoomQuery, state: AppState) -> Result<impl Reply, Rejection> {
+    let mut peers = Vec::new();
+    if let Some(room) = state.room(&rooms::room_id(query.room.as_deref())).await {
+        let room_peers = {
+            let room_peers = room.peers.read().await;
+            room_peers.values().cloned().collect::<Vec<_>>()
+        };
+        for peer in room_peers {
+            let stats = peers::peer_stats(&state, &room, &peer).await;
+            peers.push(PeerWithStats { peer, stats });
+        }
+    }
 
     let stats = PeerStats {
         total_peers: peers.len(),
@@ -41,14 +87,732 @@ pub async fn get_peers(state: AppState) -> Result<impl Reply, Rejection> {
     Ok(warp::reply::json(&stats))
 }
 
-pub async fn authenticate(auth_req: AuthRequest, state: AppState) -> Result<Box<dyn Reply>, Rejection> {
-    let response = match state.security_code {
+pub async fn get_peer(session_id: SessionId, state: AppState) -> Result<Box<dyn Reply>, Rejection> {
+    let rooms = {
+        let rooms = state.rooms.read().await;
+        rooms.values().cloned().collect::<Vec<_>>()
+    };
+
+    for room in rooms {
+        let peer = room.peers.read().await.get(&session_id).cloned();
+        let Some(peer) = peer else {
+            continue;
+        };
+
+        let hosted_files = {
+            let files = room.files.read().await;
+            files::visible_files(&files, None).filter(|file| file.hosts.contains(&session_id)).cloned().collect()
+        };
+        let detail = PeerDetail {
+            room: room.id.clone(),
+            stats: peers::peer_stats(&state, &room, &peer).await,
+            hosted_files,
+            transfers: state.relay.transfers.transfers_of(&session_id),
+            disconnected: !state.sessions.read().await.contains_key(&session_id),
+            peer,
+        };
+        return Ok(Box::new(warp::reply::json(&detail)) as Box<dyn Reply>);
+    }
+
+    Ok(not_found("Peer not found"))
+}
+
+/// The room's public files in `sort` order, a page at a time when `offset` or `limit`
+/// is set, and only those in `category` if given. `X-Total-Count` carries the number of
+/// public files in the room that match.
+pub async fn get_files(query: FileListQuery, state: AppState) -> Result<impl Reply, Rejection> {
+    let sort = query.sort.unwrap_or_default();
+    let category = query.category;
+    let (files, total) = match state.room(&rooms::room_id(query.room.as_deref())).await {
+        Some(room) => {
+            let files = room.files.read().await;
+            let page = match (query.offset, query.limit) {
+                (None, None) => files::file_page(&files, None, category, sort, 0, usize::MAX),
+                (offset, limit) => {
+                    let limit = files::page_limit(limit, state.file_list_page_size);
+                    files::file_page(&files, None, category, sort, offset.unwrap_or(0), limit)
+                }
+            };
+            let total = files::visible_files(&files, None)
+                .filter(|file| category.is_none_or(|category| file.category == category))
+                .count();
+            (page, total)
+        }
+        None => (Vec::new(), 0),
+    };
+
+    Ok(warp::reply::with_header(warp::reply::json(&files), "X-Total-Count", total.to_string()))
+}
+
+/// A page of the room's chat history, the same pages `RequestHistory` returns.
+pub async fn get_messages(query: MessageHistoryQuery, state: AppState) -> Result<Box<dyn Reply>, Rejection> {
+    let page = match state.room(&rooms::room_id(query.room.as_deref())).await {
+        Some(room) => chat::history_page(&room.messages.read().await, query.before.as_deref(), query.limit),
+        None if query.before.is_none() => Some(HistoryPage::default()),
+        None => None,
+    };
+
+    match page {
+        Some(page) => Ok(Box::new(warp::reply::json(&page)) as Box<dyn Reply>),
+        None => Ok(not_found("Message not found")),
+    }
+}
+
+pub async fn search_messages(query: MessageSearchQuery, state: AppState) -> Result<Box<dyn Reply>, Rejection> {
+    let term = query.q.as_deref().map(str::trim).unwrap_or_default();
+    if term.is_empty() {
+        return Ok(bad_request("Query parameter q must not be empty"));
+    }
+
+    let hits = match state.room(&rooms::room_id(query.room.as_deref())).await {
+        Some(room) => {
+            let messages = room.messages.read().await;
+            chat::search_messages(&messages, term, query.limit, query.before.as_deref(), query.system.unwrap_or(true))
+        }
+        None => Vec::new(),
+    };
+
+    Ok(Box::new(warp::reply::json(&hits)) as Box<dyn Reply>)
+}
+
+pub async fn get_replies(message_id: String, query: RoomQuery, state: AppState) -> Result<Box<dyn Reply>, Rejection> {
+    let replies = match state.room(&rooms::room_id(query.room.as_deref())).await {
+        Some(room) => chat::replies(&room.messages.read().await, &message_id),
+        None => None,
+    };
+
+    match replies {
+        Some(replies) => Ok(Box::new(warp::reply::json(&replies)) as Box<dyn Reply>),
+        None => Ok(not_found("Message not found")),
+    }
+}
+
+pub async fn export_messages(query: MessageExportQuery, state: AppState) -> Result<Box<dyn Reply>, Rejection> {
+    let format = query.format.as_deref().unwrap_or("json");
+    if format != "json" && format != "txt" {
+        return Ok(bad_request("Query parameter format must be json or txt"));
+    }
+
+    // Snapshot the history so the lock is not held while the export is written
+    let mut messages = match state.room(&rooms::room_id(query.room.as_deref())).await {
+        Some(room) => room.messages.read().await.clone(),
+        None => Vec::new(),
+    };
+    if query.system == Some(false) {
+        messages.retain(|message| message.kind != MessageKind::System);
+    }
+
+    // Writing into an in-memory buffer cannot fail
+    let mut body = Vec::new();
+    if format == "json" {
+        let _ = serde_json::to_writer(&mut body, &messages);
+    } else {
+        let _ = chat::write_transcript(&messages, &mut body);
+    }
+
+    let (content_type, extension) = if format == "json" {
+        ("application/json", "json")
+    } else {
+        ("text/plain; charset=utf-8", "txt")
+    };
+    let disposition = format!(
+        "attachment; filename=\"ladex-chat-{}.{extension}\"",
+        chrono::Utc::now().format("%Y%m%d"),
+    );
+
+    let reply = warp::reply::with_header(
+        warp::reply::with_header(body, "content-type", content_type),
+        "content-disposition",
+        disposition,
+    );
+    Ok(Box::new(reply) as Box<dyn Reply>)
+}
+
+/// Streams the requested files as one ZIP archive, fetching each from a host as the
+/// archive is written. Unknown ids are reported in the archive's `_errors.txt`. Locked
+/// files need their password in `X-File-Password`.
+pub async fn download_zip(
+    request: ZipRequest,
+    password: Option<String>,
+    state: AppState,
+) -> Result<Box<dyn Reply>, Rejection> {
+    if request.file_ids.is_empty() {
+        return Ok(bad_request("file_ids must not be empty"));
+    }
+    let Some(room) = state.room(&rooms::room_id(request.room.as_deref())).await else {
+        return Ok(not_found("Room not found"));
+    };
+
+    let (files, errors) = {
+        let registry = room.files.read().await;
+        let mut files = Vec::new();
+        let mut errors = Vec::new();
+        for file_id in &request.file_ids {
+            // Private files are only offered to their audience, which this endpoint cannot tell apart
+            match registry.get(file_id).filter(|file| file.visibility.is_none()) {
+                Some(file) if !files.iter().any(|added: &Arc<FileMetadata>| &added.id == file_id) => files.push(file.clone()),
+                Some(_) => {}
+                None => errors.push(format!("{file_id}: no such file")),
+            }
+        }
+        (files, errors)
+    };
+    if let Some(file) = files.iter().find(|file| file.e2e) {
+        return Ok(bad_request(&format!(
+            "{} is end-to-end encrypted and can only be downloaded from a peer",
+            file.name
+        )));
+    }
+    for file in &files {
+        let Some(hash) = file.password_hash.clone() else {
+            continue;
+        };
+        let Some(password) = password.clone() else {
+            return Ok(unauthorized_with(&format!("{} needs a password in X-File-Password", file.name)));
+        };
+        if !files::check_password(hash, password).await {
+            tracing::info!(file_id = file.id, "Wrong file password");
+            state.events.record(EventKind::AuthFailed, EventSubject {
+                file_id: Some(file.id.clone()),
+                detail: Some("file password".to_string()),
+                ..Default::default()
+            });
+            tokio::time::sleep(files::PASSWORD_RETRY_DELAY).await;
+            return Ok(unauthorized_with(&format!("Wrong password for {}", file.name)));
+        }
+    }
+    // One entry is kept for the error report
+    if files.len() >= zip::MAX_ZIP_ENTRIES {
+        return Ok(bad_request(&format!("An archive holds at most {} files", zip::MAX_ZIP_ENTRIES - 1)));
+    }
+    if zip::min_archive_size(&files) > zip::MAX_ZIP_SIZE {
+        return Ok(bad_request("The selected files are too large for one archive"));
+    }
+
+    let (tx, rx) = mpsc::channel(4);
+    tokio::spawn(zip::stream_files(state, room, files, errors, tx));
+    let stream = futures_util::stream::unfold(rx, |mut rx| async move {
+        rx.recv().await.map(|chunk| (chunk, rx))
+    });
+
+    let disposition = format!(
+        "attachment; filename=\"ladex-{}.zip\"",
+        chrono::Utc::now().format("%Y%m%d-%H%M%S"),
+    );
+    let response = warp::http::Response::builder()
+        .header("content-type", "application/zip")
+        .header("content-disposition", disposition)
+        .body(warp::Body::wrap_stream(stream));
+    match response {
+        Ok(response) => Ok(Box::new(response) as Box<dyn Reply>),
+        Err(_) => Ok(bad_request("Could not build the archive response")),
+    }
+}
+
+/// Takes the host's side of a relay pipe: streams the request body, the raw file, on
+/// to the requester's `GET`. Answers once the whole file has gone through.
+pub async fn relay_upload<B>(
+    relay_id: String,
+    token: Option<String>,
+    query: RelayQuery,
+    ip: Option<IpAddr>,
+    body: impl Stream<Item = Result<B, warp::Error>> + Send,
+    state: AppState,
+) -> Result<Box<dyn Reply>, Rejection>
+where
+    B: bytes::Buf + Send,
+{
+    if !is_session_client(&state, &query.session_id, ip, token.as_deref()).await {
+        return Ok(forbidden());
+    }
+    let Some(pipe) = state.pipes.claim_upload(&relay_id, &query.session_id) else {
+        return Ok(not_found("No such relay pipe for this session"));
+    };
+    let key = pipe.key.clone();
+    let (file_id, host_id, requester_id) = &key;
+    let Some((room, file)) = relay_file(&state, host_id, file_id).await else {
+        return Ok(not_found("File not found"));
+    };
+
+    let details = {
+        let peers = room.peers.read().await;
+        transfers::TransferDetails {
+            file_name: Some(file.name.clone()),
+            size: Some(file.size),
+            host_name: peers.get(host_id).and_then(|peer| peer.name.clone()),
+            requester_name: peers.get(requester_id).and_then(|peer| peer.name.clone()),
+            chunk_size: Some(pipe.chunk_size),
+        }
+    };
+    state.relay.transfers.begin_transfer(&key, details);
+    let result = pipes::pump(&state.relay, &key, file.size, pipe.chunk_size, body, &pipe.end).await;
+
+    let response = match result {
+        Ok(()) => {
+            state.relay.transfers.finish_transfer(&key);
+            Box::new(warp::reply::json(&ApiResponse {
+                success: true,
+                message: None,
+            })) as Box<dyn Reply>
+        }
+        Err(message) => {
+            // Breaks off the requester's download rather than letting it end as if complete
+            let _ = pipe.end.try_send(Err(std::io::Error::other(message.clone())));
+            if state.relay.transfers.fail_transfer(&key, "relay pipe failed") {
+                for (recipient, counterpart) in [(host_id, requester_id), (requester_id, host_id)] {
+                    state.send_to(recipient, ServerMessage::TransferAborted {
+                        file_id: file_id.clone(),
+                        peer_session_id: counterpart.clone(),
+                        reason: "relay_failed".to_string(),
+                    }).await;
+                }
+            }
+            bad_request(&message)
+        }
+    };
+    websocket::start_queued_downloads(&state).await;
+    Ok(response)
+}
+
+/// Takes the requester's side of a relay pipe: the file as one response, streamed as
+/// the host uploads it.
+pub async fn relay_download(
+    relay_id: String,
+    token: Option<String>,
+    query: RelayQuery,
+    ip: Option<IpAddr>,
+    state: AppState,
+) -> Result<Box<dyn Reply>, Rejection> {
+    if !is_session_client(&state, &query.session_id, ip, token.as_deref()).await {
+        return Ok(forbidden());
+    }
+    let Some(pipe) = state.pipes.claim_download(&relay_id, &query.session_id) else {
+        return Ok(not_found("No such relay pipe for this session"));
+    };
+    let Some((_, file)) = relay_file(&state, &query.session_id, &pipe.key.0).await else {
+        return Ok(not_found("File not found"));
+    };
+
+    let stream = futures_util::stream::unfold(pipe.end, |mut rx| async move {
+        rx.recv().await.map(|chunk| (chunk, rx))
+    });
+    // A body that ends short of the length is cut off, so a failed relay never looks complete.
+    // The type is the host's claim, so the content is only ever saved, never rendered on
+    // this origin.
+    let response = warp::http::Response::builder()
+        .header("content-type", "application/octet-stream")
+        .header("content-disposition", attachment_disposition(&file.name))
+        .header("x-content-type-options", "nosniff")
+        .header("content-length", file.size)
+        .body(warp::Body::wrap_stream(stream));
+    match response {
+        Ok(response) => Ok(Box::new(response) as Box<dyn Reply>),
+        Err(_) => Ok(bad_request("Could not build the relay response")),
+    }
+}
+
+/// `Content-Disposition` saving a download under `name`, encoded as RFC 6266 asks so
+/// quotes and non-ASCII characters in peers' file names cannot break the header.
+fn attachment_disposition(name: &str) -> String {
+    let encoded = name
+        .bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'!' | b'#' | b'$' | b'&' | b'+' | b'-' | b'.' | b'^' | b'_' | b'`' | b'|' | b'~' => {
+                char::from(byte).to_string()
+            }
+            _ => format!("%{byte:02X}"),
+        })
+        .collect::<String>();
+    format!("attachment; filename*=UTF-8''{encoded}")
+}
+
+/// Whether a request comes from the client of `session_id`, so a relay pipe only serves
+/// its own peers and not whoever else learned its id: from the address the session
+/// connected from, with the token its WebSocket was accepted with, or its `session_token`
+/// from `Welcome` when the server needs none.
+async fn is_session_client(state: &AppState, session_id: &SessionId, ip: Option<IpAddr>, token: Option<&str>) -> bool {
+    let sessions = state.sessions.read().await;
+    let Some(handle) = sessions.get(session_id) else {
+        return false;
+    };
+    let Some(token) = token else {
+        return false;
+    };
+    let credential = match &handle.credential {
+        Some(credential) => crate::constant_time_eq(token.as_bytes(), credential.as_bytes()),
+        None => state.valid_session_token(session_id, token),
+    };
+    credential && (handle.ip.is_none() || handle.ip == ip)
+}
+
+/// The file a relay pipe carries, looked up in the room of one of its peers.
+async fn relay_file(state: &AppState, session_id: &SessionId, file_id: &str) -> Option<(rooms::RoomState, Arc<FileMetadata>)> {
+    let room_id = state.sessions.read().await.get(session_id)?.room.clone();
+    let room = state.room(&room_id).await?;
+    let file = room.files.read().await.get(file_id).cloned()?;
+    Some((room, file))
+}
+
+/// Streams a room's peer, file and transfer changes as Server-Sent Events to a
+/// dashboard that does not join as a peer, starting with a snapshot of the room.
+pub async fn stream_events(query: ObserverQuery, state: AppState) -> Result<Box<dyn Reply>, Rejection> {
+    let Some(slot) = observers::ObserverSlot::acquire(&state.observers, state.max_observers) else {
+        let response = ApiResponse {
+            success: false,
+            message: Some("Too many observers connected".to_string()),
+        };
+        return Ok(Box::new(warp::reply::with_status(
+            warp::reply::json(&response),
+            warp::http::StatusCode::SERVICE_UNAVAILABLE,
+        )));
+    };
+    let room = state.join_room(&rooms::room_id(query.room.as_deref())).await;
+    let chat = matches!(query.chat.as_deref(), Some("1" | "true"));
+    tracing::info!(room = room.id, chat, "Observer connected");
+    let stream = observers::event_stream(state, room, chat, slot);
+    let keep_alive = warp::sse::keep_alive().interval(observers::HEARTBEAT_INTERVAL).text("heartbeat");
+    Ok(Box::new(warp::sse::reply(keep_alive.stream(stream))))
+}
+
+/// Posts a chat message for a script or bot that has no WebSocket connection. The
+/// sender is identified by the caller's auth token, or their address when the server
+/// needs no code, and naming the sender takes the admin token.
+pub async fn post_message(
+    message_req: PostMessageRequest,
+    auth_cookie: Option<String>,
+    admin_token: Option<String>,
+    ip: Option<IpAddr>,
+    state: AppState,
+) -> Result<Box<dyn Reply>, Rejection> {
+    let sender_name = match message_req.sender_name.as_deref() {
+        Some(name) => {
+            let is_admin = admin_token.is_some_and(|token| crate::constant_time_eq(token.as_bytes(), state.admin_token.as_bytes()));
+            if !is_admin {
+                return Ok(unauthorized_with("Setting sender_name requires the admin token"));
+            }
+            match peers::sanitize_name(name) {
+                Some(name) => name,
+                None => return Ok(bad_request("sender_name must not be empty")),
+            }
+        }
+        None => chat::DEFAULT_API_SENDER_NAME.to_string(),
+    };
+    let format = message_req.format.unwrap_or_default();
+    let content = match chat::sanitize_message(&message_req.content, format, state.max_message_length) {
+        Ok(content) => content,
+        Err(e) => return Ok(bad_request(&e.message())),
+    };
+
+    let credential = match (&state.security_code, auth_cookie, ip) {
+        (Some(_), Some(token), _) => token,
+        (_, _, Some(ip)) => ip.to_string(),
+        _ => "anonymous".to_string(),
+    };
+    let sender_id = chat::api_sender_id(&credential);
+    if !state.api_senders.allow(&sender_id) {
+        let response = ApiResponse {
+            success: false,
+            message: Some(format!("At most {} messages per minute may be posted", chat::MAX_API_MESSAGES_PER_MINUTE)),
+        };
+        return Ok(Box::new(warp::reply::with_status(
+            warp::reply::json(&response),
+            warp::http::StatusCode::TOO_MANY_REQUESTS,
+        )));
+    }
+
+    let room = state.join_room(&rooms::room_id(message_req.room.as_deref())).await;
+    let message = TextMessage {
+        id: format!("msg_{sender_id}_{:016x}", rand::random::<u64>()),
+        content,
+        kind: MessageKind::User,
+        format,
+        attachments: None,
+        reply_to: None,
+        sender_id,
+        sender_name: Some(sender_name),
+        sender_color: None,
+        timestamp: chrono::Utc::now(),
+        origin: None,
+    };
+    room.messages.write().await.push(message.clone());
+    room.send(ServerMessage::TextMessage { message: message.clone() });
+    tracing::info!(room = room.id, sender_id = message.sender_id, "Message posted through the API");
+
+    Ok(Box::new(warp::reply::with_status(
+        warp::reply::json(&message),
+        warp::http::StatusCode::CREATED,
+    )))
+}
+
+pub async fn get_snippets(query: RoomQuery, state: AppState) -> Result<impl Reply, Rejection> {
+    let snippets = match state.room(&rooms::room_id(query.room.as_deref())).await {
+        Some(room) => snippets::snippet_list(&room).await,
+        None => Vec::new(),
+    };
+
+    Ok(warp::reply::json(&snippets))
+}
+
+pub async fn create_snippet(snippet_req: SnippetRequest, state: AppState) -> Result<Box<dyn Reply>, Rejection> {
+    let room = state.join_room(&rooms::room_id(snippet_req.room.as_deref())).await;
+    let created = snippets::create_snippet(
+        &room,
+        state.max_snippets,
+        "api".to_string(),
+        snippet_req.title,
+        snippet_req.content,
+    ).await;
+
+    match created {
+        Ok(snippet) => Ok(Box::new(warp::reply::with_status(
+            warp::reply::json(&snippet),
+            warp::http::StatusCode::CREATED,
+        )) as Box<dyn Reply>),
+        Err(e) => Ok(bad_request(&e.message())),
+    }
+}
+
+pub async fn get_stats(state: AppState) -> Result<impl Reply, Rejection> {
+    let stats = ServerStats {
+        relay: state.relay.stats(),
+        files: files::registry_stats(&state).await,
+        top_talkers: peers::top_talkers(&state).await,
+    };
+
+    Ok(warp::reply::json(&stats))
+}
+
+/// What the server supports, for clients to check before opening a WebSocket. Callers
+/// that have not logged in, with the `auth` cookie or a bearer token, only get the name,
+/// the versions and whether they need to.
+pub async fn get_server_info(
+    cookie: Option<String>,
+    authorization: Option<String>,
+    state: AppState,
+) -> Result<Box<dyn Reply>, Rejection> {
+    let public = PublicServerInfo {
+        name: state.server_name.clone(),
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        protocol_version: PROTOCOL_VERSION,
+        auth_required: state.security_code.is_some(),
+    };
+    let bearer = authorization.and_then(|header| header.strip_prefix("Bearer ").map(str::to_string));
+    let authenticated = state.security_code.is_none()
+        || [cookie, bearer].into_iter().flatten().any(|token| state.auth_token_expiry(&token).is_some());
+    if !authenticated {
+        return Ok(Box::new(warp::reply::json(&public)));
+    }
+
+    let info = ServerInfo {
+        public,
+        instance_id: state.instance_id.clone(),
+        features: SERVER_FEATURES.iter().map(|feature| feature.to_string()).collect(),
+        limits: state.server_limits(),
+        tls: false,
+        urls: network::access_urls(state.bind, state.mdns_host.as_deref()),
+        max_message_length: state.max_message_length,
+        motd: state.motd.read().await.clone(),
+    };
+    Ok(Box::new(warp::reply::json(&info)))
+}
+
+pub async fn set_motd(motd_req: MotdRequest, state: AppState) -> Result<Box<dyn Reply>, Rejection> {
+    let motd = match Motd::new(&motd_req.text) {
+        Ok(motd) => motd,
+        Err(e) => return Ok(bad_request(&e)),
+    };
+    *state.motd.write().await = motd.clone();
+    tracing::info!(set = motd.is_some(), "Message of the day changed");
+
+    if let Some(motd) = &motd {
+        let rooms = state.rooms.read().await;
+        for room in rooms.values() {
+            room.send(ServerMessage::Motd {
+                text: motd.text.clone(),
+                set_at: motd.set_at,
+            });
+        }
+    }
+
+    Ok(Box::new(warp::reply::json(&motd)) as Box<dyn Reply>)
+}
+
+/// Schedules a shutdown, announced to the rooms like one set with `--timeout`. A
+/// shutdown already due sooner is kept.
+pub async fn schedule_shutdown(query: ShutdownQuery, state: AppState) -> Result<Box<dyn Reply>, Rejection> {
+    let delay = std::time::Duration::from_secs(query.delay.unwrap_or(0));
+    let at = state.shutdown.schedule(delay);
+    let secs = at.saturating_duration_since(std::time::Instant::now()).as_secs();
+    tracing::info!(secs, "Shutdown scheduled through the API");
+    let response = ApiResponse {
+        success: true,
+        message: Some(format!("Shutting down in {secs} seconds")),
+    };
+    Ok(Box::new(warp::reply::json(&response)) as Box<dyn Reply>)
+}
+
+pub async fn kick_peer(session_id: SessionId, state: AppState) -> Result<Box<dyn Reply>, Rejection> {
+    if websocket::kick_session(&state, &session_id, websocket::CLOSE_KICKED, "Removed by the server operator").await {
+        let response = ApiResponse {
+            success: true,
+            message: None,
+        };
+        Ok(Box::new(warp::reply::json(&response)) as Box<dyn Reply>)
+    } else {
+        Ok(not_found("Peer not found"))
+    }
+}
+
+/// Relayed transfers in flight in the room, with their progress.
+pub async fn get_transfers(query: RoomQuery, state: AppState) -> Result<impl Reply, Rejection> {
+    let transfers = match state.room(&rooms::room_id(query.room.as_deref())).await {
+        Some(room) => websocket::live_transfers(&state, &room).await,
+        None => Vec::new(),
+    };
+    Ok(warp::reply::json(&transfers))
+}
+
+pub async fn get_transfer_history(query: TransferHistoryQuery, state: AppState) -> Result<impl Reply, Rejection> {
+    Ok(warp::reply::json(&state.audit.query(&query)))
+}
+
+pub async fn get_events(query: EventQuery, state: AppState) -> Result<Box<dyn Reply>, Rejection> {
+    let kinds = match query.kind.as_deref().map(events::parse_kinds).transpose() {
+        Ok(kinds) => kinds.unwrap_or_default(),
+        Err(e) => return Ok(bad_request(&e)),
+    };
+    let limit = query.limit.unwrap_or(events::DEFAULT_EVENT_QUERY_LIMIT);
+    Ok(Box::new(warp::reply::json(&state.events.query(query.since, &kinds, limit))))
+}
+
+pub async fn get_bans(state: AppState) -> Result<impl Reply, Rejection> {
+    let now = chrono::Utc::now();
+    let bans = {
+        let bans = state.bans.read().await;
+        bans.values()
+            .filter(|ban| !ban.is_expired(now))
+            .cloned()
+            .collect::<Vec<_>>()
+    };
+
+    Ok(warp::reply::json(&bans))
+}
+
+pub async fn add_ban(ban_req: BanRequest, state: AppState) -> Result<impl Reply, Rejection> {
+    let created_at = chrono::Utc::now();
+    let ban = Ban {
+        ip: ban_req.ip,
+        reason: ban_req.reason,
+        created_at,
+        expires_at: ban_req
+            .duration_secs
+            .map(|secs| created_at + chrono::Duration::seconds(secs as i64)),
+    };
+
+    {
+        let mut bans = state.bans.write().await;
+        bans.insert(ban.ip, ban.clone());
+    }
+
+    // Kick sessions already connected from the banned address
+    let banned_sessions = {
+        let sessions = state.sessions.read().await;
+        sessions.iter()
+            .filter(|(_, handle)| handle.ip == Some(ban.ip))
+            .map(|(id, _)| id.clone())
+            .collect::<Vec<_>>()
+    };
+    for id in &banned_sessions {
+        websocket::kick_session(&state, id, websocket::CLOSE_AUTH_REVOKED, "Banned by the server operator").await;
+    }
+
+    Ok(warp::reply::json(&ban))
+}
+
+pub async fn remove_ban(ip: IpAddr, state: AppState) -> Result<Box<dyn Reply>, Rejection> {
+    let removed = {
+        let mut bans = state.bans.write().await;
+        bans.remove(&ip)
+    };
+
+    match removed {
+        Some(_) => {
+            let response = ApiResponse {
+                success: true,
+                message: None,
+            };
+            Ok(Box::new(warp::reply::json(&response)) as Box<dyn Reply>)
+        }
+        None => Ok(not_found("Ban not found")),
+    }
+}
+
+pub fn not_found(message: &str) -> Box<dyn Reply> {
+    let response = ApiResponse {
+        success: false,
+        message: Some(message.to_string()),
+    };
+    Box::new(warp::reply::with_status(
+        warp::reply::json(&response),
+        warp::http::StatusCode::NOT_FOUND,
+    ))
+}
+
+pub fn bad_request(message: &str) -> Box<dyn Reply> {
+    let response = ApiResponse {
+        success: false,
+        message: Some(message.to_string()),
+    };
+    Box::new(warp::reply::with_status(
+        warp::reply::json(&response),
+        warp::http::StatusCode::BAD_REQUEST,
+    ))
+}
+
+pub fn unauthorized() -> Box<dyn Reply> {
+    unauthorized_with("Invalid or missing auth token")
+}
+
+pub fn unauthorized_with(message: &str) -> Box<dyn Reply> {
+    let response = ApiResponse {
+        success: false,
+        message: Some(message.to_string()),
+    };
+    Box::new(warp::reply::with_status(
+        warp::reply::json(&response),
+        warp::http::StatusCode::UNAUTHORIZED,
+    ))
+}
+
+pub fn forbidden() -> Box<dyn Reply> {
+    let response = ApiResponse {
+        success: false,
+        message: Some("Forbidden".to_string()),
+    };
+    Box::new(warp::reply::with_status(
+        warp::reply::json(&response),
+        warp::http::StatusCode::FORBIDDEN,
+    ))
+}
+
+pub async fn authenticate(auth_req: AuthRequest, ip: Option<IpAddr>, state: AppState) -> Result<Box<dyn Reply>, Rejection> {
+    if state.is_banned(ip).await {
+        tracing::warn!(ip = ?ip, "Authentication refused for banned address");
+        state.events.record(EventKind::AuthFailed, EventSubject {
+            ip,
+            detail: Some("banned".to_string()),
+            ..Default::default()
+        });
+        return Ok(forbidden());
+    }
+
+    let response = match &state.security_code {
         None => AuthResponse {
             success: true,
             message: None,
         },
         Some(required_code) => {
-            if auth_req.code == required_code {
+            if &auth_req.code == required_code {
                 AuthResponse {
                     success: true,
                     message: None,
@@ -63,9 +827,11 @@ pub async fn authenticate(auth_req: AuthRequest, state: AppState) -> Result<Box<
     };
 
     if response.success {
+        tracing::info!(ip = ?ip, "Authentication succeeded");
         let json_reply = warp::reply::json(&response);
-        let cookie_value = format!("authenticated:{}", state.server_session_id);
-        let cookie_header = format!("auth={cookie_value}; Path=/; Max-Age=86400; HttpOnly; SameSite=Strict");
+        let cookie_value = state.new_auth_token();
+        let max_age = crate::AUTH_TOKEN_TTL.as_secs();
+        let cookie_header = format!("auth={cookie_value}; Path=/; Max-Age={max_age}; HttpOnly; SameSite=Strict");
         let reply_with_cookie = warp::reply::with_header(
             json_reply,
             "Set-Cookie",
@@ -73,6 +839,12 @@ pub async fn authenticate(auth_req: AuthRequest, state: AppState) -> Result<Box<
         );
         Ok(Box::new(reply_with_cookie) as Box<dyn Reply>)
     } else {
+        tracing::warn!(ip = ?ip, "Authentication failed");
+        state.events.record(EventKind::AuthFailed, EventSubject {
+            ip,
+            detail: Some("security code".to_string()),
+            ..Default::default()
+        });
         let json_reply = warp::reply::json(&response);
         let reply_with_status = warp::reply::with_status(
             json_reply,
@@ -82,6 +854,41 @@ pub async fn authenticate(auth_req: AuthRequest, state: AppState) -> Result<Box<
     }
 }
 
+pub async fn issue_token(auth_req: AuthRequest, ip: Option<IpAddr>, state: AppState) -> Result<Box<dyn Reply>, Rejection> {
+    if state.is_banned(ip).await {
+        tracing::warn!(ip = ?ip, "Token refused for banned address");
+        state.events.record(EventKind::AuthFailed, EventSubject {
+            ip,
+            detail: Some("banned".to_string()),
+            ..Default::default()
+        });
+        return Ok(forbidden());
+    }
+    if state.security_code.as_ref().is_some_and(|code| &auth_req.code != code) {
+        tracing::warn!(ip = ?ip, "Token request with a wrong security code");
+        state.events.record(EventKind::AuthFailed, EventSubject {
+            ip,
+            detail: Some("security code".to_string()),
+            ..Default::default()
+        });
+        let response = AuthResponse {
+            success: false,
+            message: Some("Invalid security code".to_string()),
+        };
+        return Ok(Box::new(warp::reply::with_status(
+            warp::reply::json(&response),
+            warp::http::StatusCode::UNAUTHORIZED,
+        )));
+    }
+
+    tracing::info!(ip = ?ip, "Auth token issued");
+    let token = AuthToken {
+        token: state.new_auth_token(),
+        expires_at: chrono::Utc::now() + chrono::Duration::from_std(crate::AUTH_TOKEN_TTL).unwrap_or_default(),
+    };
+    Ok(Box::new(warp::reply::json(&token)) as Box<dyn Reply>)
+}
+
 pub async fn logout() -> Result<impl Reply, Rejection> {
     let response = AuthResponse {
         success: true,
@@ -95,4 +902,52 @@ pub async fn logout() -> Result<impl Reply, Rejection> {
         "auth=; Path=/; Max-Age=0; HttpOnly; SameSite=Strict",
     );
     Ok(reply_with_cookie)
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    const IP: Option<IpAddr> = Some(IpAddr::V4(Ipv4Addr::LOCALHOST));
+
+    async fn register(state: &AppState, session_id: &str, credential: Option<&str>) {
+        state.sessions.write().await.insert(session_id.to_string(), websocket::SessionHandle {
+            tx: mpsc::unbounded_channel().0,
+            chunks: mpsc::channel(1).0,
+            ip: IP,
+            room: "main".to_string(),
+            counters: Arc::default(),
+            local: false,
+            notify_upload_activity: true,
+            credential: credential.map(str::to_string),
+        });
+    }
+
+    #[tokio::test]
+    async fn relay_client_needs_the_token_its_socket_was_accepted_with() {
+        let state = AppState::for_tests(&[]);
+        register(&state, "alice", Some("authenticated:alice")).await;
+        let alice = "alice".to_string();
+
+        assert!(is_session_client(&state, &alice, IP, Some("authenticated:alice")).await);
+        // Anyone else on the same address, with a token of their own or none
+        assert!(!is_session_client(&state, &alice, IP, Some("authenticated:mallory")).await);
+        assert!(!is_session_client(&state, &alice, IP, None).await);
+        // The right token from another address
+        let elsewhere = Some(IpAddr::V4(Ipv4Addr::new(192, 168, 1, 2)));
+        assert!(!is_session_client(&state, &alice, elsewhere, Some("authenticated:alice")).await);
+        assert!(!is_session_client(&state, &"bob".to_string(), IP, Some("authenticated:alice")).await);
+    }
+
+    #[tokio::test]
+    async fn relay_client_of_an_open_server_needs_its_session_token() {
+        let state = AppState::for_tests(&[]);
+        register(&state, "alice", None).await;
+        register(&state, "bob", None).await;
+        let alice = "alice".to_string();
+
+        assert!(is_session_client(&state, &alice, IP, Some(&state.session_token("alice"))).await);
+        assert!(!is_session_client(&state, &alice, IP, Some(&state.session_token("bob"))).await);
+        assert!(!is_session_client(&state, &alice, IP, None).await);
+    }
+}
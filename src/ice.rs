@@ -0,0 +1,101 @@
+use crate::types::*;
+use base64::Engine;
+use hmac::{Hmac, Mac};
+use sha1::Sha1;
+use std::time::Duration;
+
+/// A TURN server from `--turn`. Without credentials of its own it is handed out with
+/// time-limited ones derived from `--turn-secret`.
+#[derive(Debug, Clone)]
+pub struct TurnServer {
+    pub url: String,
+    pub credentials: Option<(String, String)>,
+}
+
+/// Parses `url` or `url,username,password`; the password may contain commas.
+pub fn parse_turn_server(value: &str) -> Result<TurnServer, String> {
+    let mut parts = value.splitn(3, ',');
+    let url = parts.next().unwrap_or_default().trim().to_string();
+    if !url.starts_with("turn:") && !url.starts_with("turns:") {
+        return Err(format!("{url} is not a turn: or turns: URL"));
+    }
+    let credentials = match (parts.next(), parts.next()) {
+        (None, _) => None,
+        (Some(username), Some(password)) => Some((username.to_string(), password.to_string())),
+        (Some(_), None) => return Err("expected <url> or <url>,<username>,<password>".to_string()),
+    };
+    Ok(TurnServer { url, credentials })
+}
+
+pub fn parse_stun_server(value: &str) -> Result<String, String> {
+    let url = value.trim();
+    if url.starts_with("stun:") || url.starts_with("stuns:") {
+        Ok(url.to_string())
+    } else {
+        Err(format!("{url} is not a stun: or stuns: URL"))
+    }
+}
+
+/// STUN and TURN servers handed to clients for their WebRTC connections.
+#[derive(Debug, Clone, Default)]
+pub struct IceConfig {
+    pub stun: Vec<String>,
+    pub turn: Vec<TurnServer>,
+    /// Shared secret of the TURN REST credential scheme
+    pub turn_secret: Option<String>,
+    pub credential_ttl: Duration,
+}
+
+impl IceConfig {
+    /// Fails when a TURN server has no credentials and there is no secret to issue them.
+    pub fn validate(&self) -> Result<(), String> {
+        match self.turn.iter().find(|server| server.credentials.is_none()) {
+            Some(server) if self.turn_secret.is_none() => {
+                Err(format!("TURN server {} needs a username and password, or --turn-secret", server.url))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// The servers for one session. TURN servers without fixed credentials get a
+    /// username of `<expiry>:<session id>` and its HMAC-SHA1 under the secret as the
+    /// password, so the secret itself never reaches clients.
+    pub fn servers_for(&self, session_id: &SessionId) -> Vec<IceServerConfig> {
+        let mut servers = self
+            .stun
+            .iter()
+            .map(|url| IceServerConfig {
+                urls: vec![url.clone()],
+                username: None,
+                credential: None,
+            })
+            .collect::<Vec<_>>();
+
+        let expiry = chrono::Utc::now().timestamp() + self.credential_ttl.as_secs() as i64;
+        for server in &self.turn {
+            let credentials = match (&server.credentials, &self.turn_secret) {
+                (Some(credentials), _) => Some(credentials.clone()),
+                (None, Some(secret)) => {
+                    let username = format!("{expiry}:{session_id}");
+                    temporary_password(secret, &username).map(|password| (username, password))
+                }
+                (None, None) => None,
+            };
+            let Some((username, password)) = credentials else {
+                continue;
+            };
+            servers.push(IceServerConfig {
+                urls: vec![server.url.clone()],
+                username: Some(username),
+                credential: Some(password),
+            });
+        }
+        servers
+    }
+}
+
+fn temporary_password(secret: &str, username: &str) -> Option<String> {
+    let mut mac = Hmac::<Sha1>::new_from_slice(secret.as_bytes()).ok()?;
+    mac.update(username.as_bytes());
+    Some(base64::engine::general_purpose::STANDARD.encode(mac.finalize().into_bytes()))
+}
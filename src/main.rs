@@ -1,24 +1,61 @@
-use std::collections::HashMap;
-use std::net::SocketAddr;
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::atomic::AtomicUsize;
 use std::sync::Arc;
-use tokio::sync::{broadcast, RwLock};
+use std::time::Duration;
+use tokio::sync::RwLock;
 use warp::Filter;
-use clap::Parser;
+use clap::{CommandFactory, FromArgMatches, Parser};
 use rand::Rng;
+use base64::Engine;
+use hmac::{Hmac, Mac};
+use sha1::Sha1;
 
 mod types;
+mod rooms;
+mod relay;
+mod transfers;
+mod audit;
+mod bridge;
+mod chat;
+mod config;
+mod doctor;
+mod events;
+mod fetch;
+mod files;
+mod ice;
+mod logging;
+mod mdns;
+mod network;
+mod observers;
+mod peers;
+mod pipes;
+mod rate_limit;
+mod share;
+mod shutdown;
+mod snippets;
+mod trash;
+mod user_agent;
+mod version;
 mod websocket;
+mod zip;
 mod handlers;
 
 use types::*;
+use rooms::{RoomState, Rooms};
 use include_dir::{include_dir, Dir};
 
 // Embed the static directory at compile time
 static STATIC_DIR: Dir = include_dir!("$CARGO_MANIFEST_DIR/static");
 
-type Peers = Arc<RwLock<HashMap<SessionId, PeerInfo>>>;
-type Files = Arc<RwLock<HashMap<String, FileMetadata>>>;
-type Messages = Arc<RwLock<Vec<types::TextMessage>>>;
+/// Security codes are this many ASCII digits.
+pub const SECURITY_CODE_LENGTH: usize = 6;
+/// How long the cookie set by `/auth` stays valid.
+pub const AUTH_TOKEN_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+type Sessions = Arc<RwLock<HashMap<SessionId, websocket::SessionHandle>>>;
+type Bans = Arc<RwLock<HashMap<IpAddr, Ban>>>;
 
 #[derive(Parser)]
 #[command(name = "ladex")]
@@ -27,16 +64,397 @@ struct Args {
     code: Option<String>,
     #[arg(short = 's', long = "secure")]
     secure: bool,
+    /// File with one IP address per line to ban at startup
+    #[arg(long = "ban-file")]
+    ban_file: Option<PathBuf>,
+    /// Refuse connections whose remote address cannot be determined
+    #[arg(long = "ban-fail-closed")]
+    ban_fail_closed: bool,
+    /// Let peers download but not upload files or send chat messages
+    #[arg(long = "guest-readonly")]
+    guest_readonly: bool,
+    /// Seconds an empty room is kept before it is removed
+    #[arg(long = "room-idle-timeout", default_value_t = 300)]
+    room_idle_timeout: u64,
+    /// Token required in the X-Admin-Token header for admin endpoints; generated when omitted
+    #[arg(long = "admin-token")]
+    admin_token: Option<String>,
+    /// Maximum bytes per second forwarded through the chunk relay, across all transfers
+    #[arg(long = "relay-limit")]
+    relay_limit: Option<u64>,
+    /// Maximum bytes per second forwarded through the chunk relay for a single transfer
+    #[arg(long = "relay-limit-per-transfer")]
+    relay_limit_per_transfer: Option<u64>,
+    /// Downloads a single host serves at once; further requests wait in a queue
+    #[arg(long = "max-transfers-per-host", default_value_t = 2)]
+    max_transfers_per_host: usize,
+    /// Maximum number of relayed transfers running at once across the server
+    #[arg(long = "max-transfers")]
+    max_transfers: Option<usize>,
+    /// Maximum number of relayed transfers a peer takes part in at once
+    #[arg(long = "max-transfers-per-peer")]
+    max_transfers_per_peer: Option<usize>,
+    /// Reject downloads over --max-transfers or --max-transfers-per-peer instead of queueing them
+    #[arg(long = "reject-excess-transfers")]
+    reject_excess_transfers: bool,
+    /// Seconds a host that approves each download has to answer a request
+    #[arg(long = "approval-timeout", default_value_t = 60)]
+    approval_timeout: u64,
+    /// Seconds a host has to start sending a requested file before another host is tried
+    #[arg(long = "download-request-timeout", default_value_t = 30)]
+    download_request_timeout: u64,
+    /// Maximum number of text snippets kept per room
+    #[arg(long = "max-snippets", default_value_t = 100)]
+    max_snippets: usize,
+    /// Seconds until a shared file expires when the uploader does not set an expiry
+    #[arg(long = "default-file-ttl")]
+    default_file_ttl: Option<u64>,
+    /// Maximum number of shared files per room
+    #[arg(long = "max-files")]
+    max_files: Option<usize>,
+    /// Above this many files, clients that fetch pages get a summary instead of the full list
+    #[arg(long = "file-list-page-size")]
+    file_list_page_size: Option<usize>,
+    /// Maximum combined size in bytes of the shared files per room
+    #[arg(long = "max-total-size")]
+    max_total_size: Option<u64>,
+    /// When a limit is reached, drop the uploader's oldest files instead of rejecting the new one
+    #[arg(long = "evict-oldest")]
+    evict_oldest: bool,
+    /// Largest WebSocket message accepted from a client, in bytes
+    #[arg(long = "max-ws-message", default_value_t = 8 * 1024 * 1024)]
+    max_ws_message: usize,
+    /// Longest chat message accepted, in bytes
+    #[arg(long = "max-message-length", default_value_t = 8 * 1024)]
+    max_message_length: usize,
+    /// Most read-only observers streaming room events at once
+    #[arg(long = "max-observers", default_value_t = 16)]
+    max_observers: usize,
+    /// Don't note joins, leaves and shared or removed files in the chat
+    #[arg(long = "no-system-messages")]
+    no_system_messages: bool,
+    /// Keep peer IP addresses server-side instead of showing them to other peers
+    #[arg(long = "hide-ips")]
+    hide_ips: bool,
+    /// Take the client address from X-Forwarded-For, for use behind a reverse proxy
+    #[arg(long = "trust-proxy")]
+    trust_proxy: bool,
+    /// Minimum level of log events: trace, debug, info, warn or error
+    #[arg(long = "log-level", default_value = "info")]
+    log_level: tracing::Level,
+    /// Log as human-readable text or as one JSON object per line
+    #[arg(long = "log-format", value_enum, default_value_t = logging::LogFormat::Text)]
+    log_format: logging::LogFormat,
+    /// Log a summary line for every WebSocket connection when it closes
+    #[arg(long = "access-log")]
+    access_log: bool,
+    /// Address and port to listen on; [::] listens on all IPv4 and IPv6 interfaces
+    #[arg(long = "bind", default_value = "[::]:8080")]
+    bind: SocketAddr,
+    /// Seconds a file whose last host disconnected is kept in case the host reconnects
+    #[arg(long = "orphan-grace", default_value_t = 60)]
+    orphan_grace: u64,
+    /// How many chat, file and download messages a session may send before being slowed down
+    #[arg(long = "rate-profile", value_enum, default_value_t = rate_limit::RateProfile::Default)]
+    rate_profile: rate_limit::RateProfile,
+    /// Seconds between single file uploads of one peer within which they are grouped as
+    /// one batch; 0 groups only files announced together
+    #[arg(long = "batch-window", default_value_t = 3)]
+    batch_window: u64,
+    /// Seconds a removed file stays in the trash for its uploader to restore; 0 disables the trash
+    #[arg(long = "trash-retention", default_value_t = 300)]
+    trash_retention: u64,
+    /// Number of finished transfers kept in memory for the transfer history API
+    #[arg(long = "audit-limit", default_value_t = 1000)]
+    audit_limit: usize,
+    /// Also append every finished transfer to this JSON Lines file
+    #[arg(long = "audit-file")]
+    audit_file: Option<PathBuf>,
+    /// Number of recent server events kept in memory for the events API
+    #[arg(long = "event-limit", default_value_t = 1000)]
+    event_limit: usize,
+    /// Seconds without messages from a session before it is disconnected; off by default
+    #[arg(long = "idle-timeout")]
+    idle_timeout: Option<u64>,
+    /// Don't count the client's automatic pings as activity for --idle-timeout
+    #[arg(long = "idle-ignore-pings")]
+    idle_ignore_pings: bool,
+    /// Message of the day shown to every peer that joins
+    #[arg(long = "motd")]
+    motd: Option<String>,
+    /// Read the message of the day from this file
+    #[arg(long = "motd-file", conflicts_with = "motd")]
+    motd_file: Option<PathBuf>,
+    /// Name shown to clients and announced over mDNS (as <name>.local when it is a valid
+    /// host name); defaults to the machine's hostname
+    #[arg(long = "name")]
+    name: Option<String>,
+    /// STUN server offered to clients for WebRTC, e.g. stun:stun.example.com:3478; repeatable
+    #[arg(long = "stun", value_parser = ice::parse_stun_server)]
+    stun: Vec<String>,
+    /// TURN server offered to clients for WebRTC, as <url> or <url>,<username>,<password>;
+    /// repeatable
+    #[arg(long = "turn", value_parser = ice::parse_turn_server)]
+    turn: Vec<ice::TurnServer>,
+    /// Shared secret for issuing each client time-limited credentials for TURN servers
+    /// given without a username and password
+    #[arg(long = "turn-secret")]
+    turn_secret: Option<String>,
+    /// Seconds the issued TURN credentials stay valid
+    #[arg(long = "turn-credential-ttl", default_value_t = 24 * 60 * 60)]
+    turn_credential_ttl: u64,
+    /// Another ladex server to bridge to, e.g. http://10.0.2.5:8080. Each server then
+    /// lists the other's public files, and downloads of them go over the bridge.
+    #[arg(long = "bridge", value_parser = bridge::parse_bridge_url)]
+    bridge: Option<bridge::BridgeUrl>,
+    /// Security code of the server given with --bridge
+    #[arg(long = "bridge-code", requires = "bridge")]
+    bridge_code: Option<String>,
+    /// Also pass chat messages across the bridge
+    #[arg(long = "bridge-chat", requires = "bridge")]
+    bridge_chat: bool,
+    /// Directory whose files are shared in the main room by the server itself, kept in
+    /// step as files are added, changed or removed on disk
+    #[arg(long = "share-dir")]
+    share_dir: Option<PathBuf>,
+    /// Most files listed from --share-dir
+    #[arg(long = "share-dir-max-files", default_value_t = share::DEFAULT_SHARE_MAX_FILES, requires = "share_dir")]
+    share_dir_max_files: usize,
+    /// TOML file setting any of these options, keyed by their long names, e.g.
+    /// `max-files = 100`. Command line flags and LADEX_* environment variables such as
+    /// LADEX_MAX_FILES take precedence over it.
+    #[arg(long = "config")]
+    config: Option<PathBuf>,
+    /// Print the merged configuration in the config file's format, with secrets
+    /// redacted, and exit
+    #[arg(long = "print-config")]
+    print_config: bool,
+    /// Check the port, network interfaces, mDNS and the web client, then answer a
+    /// request and a WebSocket upgrade on loopback with these options, and exit;
+    /// non-zero if a check failed
+    #[arg(long = "diagnose")]
+    diagnose: bool,
+    /// Shut down this long after starting, e.g. 30m; peers are warned 10, 5 and 1
+    /// minutes before
+    #[arg(long = "timeout", value_parser = shutdown::parse_duration)]
+    timeout: Option<Duration>,
+    /// Turn away clients older than this version, e.g. 0.2.0
+    #[arg(long = "min-client-version", value_parser = version::Version::parse)]
+    min_client_version: Option<version::Version>,
+    /// Let clients that do not report a version join despite --min-client-version
+    #[arg(long = "allow-unversioned-clients")]
+    allow_unversioned_clients: bool,
+    /// Shut down once no peer has been connected for this long, e.g. 10m
+    #[arg(long = "shutdown-when-empty", value_parser = shutdown::parse_duration)]
+    shutdown_when_empty: Option<Duration>,
 }
 
 #[derive(Clone)]
 pub struct AppState {
-    pub peers: Peers,
-    pub files: Files,
-    pub messages: Messages,
-    pub tx: broadcast::Sender<ServerMessage>,
+    pub rooms: Rooms,
+    pub sessions: Sessions,
+    pub bans: Bans,
+    pub relay: Arc<relay::Relay>,
     pub security_code: Option<String>,
+    pub admin_token: String,
     pub server_session_id: String,
+    /// Public id of this server run; unlike `server_session_id` it is no secret
+    pub instance_id: String,
+    /// Key for the session tokens handed out in `Welcome`
+    pub session_secret: [u8; 32],
+    pub ban_fail_closed: bool,
+    pub guest_readonly: bool,
+    pub max_snippets: usize,
+    pub default_file_ttl: Option<u64>,
+    pub file_list_page_size: Option<usize>,
+    pub file_limits: files::FileLimits,
+    pub max_ws_message: usize,
+    pub max_observers: usize,
+    /// Observer streams currently open
+    pub observers: Arc<AtomicUsize>,
+    pub max_message_length: usize,
+    pub system_messages: bool,
+    pub hide_ips: bool,
+    pub access_log: bool,
+    pub bind: SocketAddr,
+    pub mdns_host: Option<String>,
+    pub server_name: String,
+    pub orphan_grace: Duration,
+    /// Longest gap between single uploads that still joins them into one batch
+    pub batch_window: Duration,
+    pub idle_timeout: Option<Duration>,
+    pub idle_ignore_pings: bool,
+    pub audit: Arc<audit::AuditLog>,
+    pub events: Arc<events::EventLog>,
+    pub motd: Arc<RwLock<Option<Motd>>>,
+    pub ice: Arc<ice::IceConfig>,
+    pub latency: Arc<peers::LatencyTable>,
+    pub api_senders: Arc<chat::ApiSenders>,
+    pub pipes: Arc<pipes::Pipes>,
+    pub shutdown: Arc<shutdown::ShutdownTimer>,
+    pub trash: Arc<trash::Trash>,
+    pub rate_limits: Arc<rate_limit::RateLimits>,
+    pub client_versions: Arc<version::ClientVersionPolicy>,
+}
+
+impl AppState {
+    pub fn server_limits(&self) -> ServerLimits {
+        ServerLimits {
+            max_message_length: self.max_message_length,
+            max_ws_message: self.max_ws_message,
+            max_files: self.file_limits.max_files,
+            max_total_size: self.file_limits.max_total_size,
+            min_chunk_size: MIN_CHUNK_SIZE,
+            max_chunk_size: MAX_CHUNK_SIZE,
+        }
+    }
+
+    pub async fn room(&self, id: &str) -> Option<RoomState> {
+        rooms::get_room(&self.rooms, id).await
+    }
+
+    pub async fn join_room(&self, id: &str) -> RoomState {
+        rooms::join_room(&self.rooms, id).await
+    }
+
+    /// Delivers a message to a single session. Returns false if it is not connected.
+    pub async fn send_to(&self, session_id: &SessionId, msg: ServerMessage) -> bool {
+        let sessions = self.sessions.read().await;
+        match sessions.get(session_id) {
+            Some(handle) => handle.tx.send(msg).is_ok(),
+            None => false,
+        }
+    }
+
+    /// A token for the `auth` cookie, tied to this server run and stamped with the time
+    /// it was issued.
+    pub fn new_auth_token(&self) -> String {
+        format!("authenticated:{}:{}", self.server_session_id, chrono::Utc::now().timestamp())
+    }
+
+    /// When an auth token expires, or None if it was not issued by this server run or
+    /// has already expired.
+    pub fn auth_token_expiry(&self, token: &str) -> Option<chrono::DateTime<chrono::Utc>> {
+        let (session_id, issued_at) = token.strip_prefix("authenticated:")?.rsplit_once(':')?;
+        if !constant_time_eq(session_id.as_bytes(), self.server_session_id.as_bytes()) {
+            return None;
+        }
+        let issued_at = chrono::DateTime::from_timestamp(issued_at.parse().ok()?, 0)?;
+        let expires_at = issued_at + chrono::Duration::from_std(AUTH_TOKEN_TTL).ok()?;
+        (expires_at > chrono::Utc::now()).then_some(expires_at)
+    }
+
+    /// The token proving `session_id` is held by the peer it was first given to. Session
+    /// ids are public, so only a peer with the token may take a departed session's id up again.
+    pub fn session_token(&self, session_id: &str) -> String {
+        let mut mac = Hmac::<Sha1>::new_from_slice(&self.session_secret).expect("HMAC takes keys of any length");
+        mac.update(session_id.as_bytes());
+        base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(mac.finalize().into_bytes())
+    }
+
+    pub fn valid_session_token(&self, session_id: &str, token: &str) -> bool {
+        constant_time_eq(token.as_bytes(), self.session_token(session_id).as_bytes())
+    }
+
+    /// Checks the remote address against the ban list. Unknown addresses are
+    /// refused only when `--ban-fail-closed` is set.
+    pub async fn is_banned(&self, ip: Option<IpAddr>) -> bool {
+        let ip = match ip {
+            Some(ip) => ip,
+            None => return self.ban_fail_closed,
+        };
+
+        let now = chrono::Utc::now();
+        let mut bans = self.bans.write().await;
+        match bans.get(&ip) {
+            Some(ban) if ban.is_expired(now) => {
+                bans.remove(&ip);
+                false
+            }
+            Some(_) => true,
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+impl AppState {
+    /// A server state as `main` builds it from these flags, without the background tasks.
+    pub(crate) fn for_tests(flags: &[&str]) -> AppState {
+        let args = Args::parse_from(std::iter::once("ladex").chain(flags.iter().copied()));
+        let audit = Arc::new(audit::AuditLog::new(args.audit_limit, None).unwrap());
+        let events = Arc::new(events::EventLog::new(args.event_limit));
+        AppState {
+            rooms: Arc::new(RwLock::new(HashMap::new())),
+            sessions: Arc::new(RwLock::new(HashMap::new())),
+            bans: Arc::new(RwLock::new(HashMap::new())),
+            relay: Arc::new(relay::Relay::new(
+                args.relay_limit,
+                args.relay_limit_per_transfer,
+                transfers::TransferTable::new(
+                    transfers::TransferLimits {
+                        per_host: args.max_transfers_per_host.max(1),
+                        total: args.max_transfers.map(|max| max.max(1)),
+                        per_peer: args.max_transfers_per_peer.map(|max| max.max(1)),
+                        reject_excess: args.reject_excess_transfers,
+                    },
+                    Duration::from_secs(args.approval_timeout),
+                    Duration::from_secs(args.download_request_timeout),
+                    audit.clone(),
+                    events.clone(),
+                    tokio::sync::mpsc::unbounded_channel().0,
+                ),
+            )),
+            security_code: args.code.clone(),
+            admin_token: "admin".to_string(),
+            server_session_id: "server_session_test".to_string(),
+            instance_id: "0000000000000000".to_string(),
+            session_secret: rand::random(),
+            ban_fail_closed: args.ban_fail_closed,
+            guest_readonly: args.guest_readonly,
+            max_snippets: args.max_snippets,
+            default_file_ttl: args.default_file_ttl,
+            file_list_page_size: args.file_list_page_size,
+            file_limits: files::FileLimits {
+                max_files: args.max_files,
+                max_total_size: args.max_total_size,
+                evict_oldest: args.evict_oldest,
+            },
+            max_ws_message: args.max_ws_message,
+            max_observers: args.max_observers,
+            observers: Arc::new(AtomicUsize::new(0)),
+            max_message_length: args.max_message_length,
+            system_messages: !args.no_system_messages,
+            hide_ips: args.hide_ips,
+            access_log: args.access_log,
+            bind: args.bind,
+            mdns_host: None,
+            server_name: "test".to_string(),
+            orphan_grace: Duration::from_secs(args.orphan_grace),
+            batch_window: Duration::from_secs(args.batch_window),
+            idle_timeout: args.idle_timeout.filter(|secs| *secs > 0).map(Duration::from_secs),
+            idle_ignore_pings: args.idle_ignore_pings,
+            audit,
+            events,
+            motd: Arc::new(RwLock::new(None)),
+            ice: Arc::new(ice::IceConfig {
+                stun: args.stun.clone(),
+                turn: args.turn.clone(),
+                turn_secret: None,
+                credential_ttl: Duration::from_secs(args.turn_credential_ttl),
+            }),
+            latency: Arc::new(peers::LatencyTable::default()),
+            api_senders: Arc::new(chat::ApiSenders::default()),
+            pipes: Arc::new(pipes::Pipes::default()),
+            shutdown: Arc::new(shutdown::ShutdownTimer::default()),
+            trash: Arc::new(trash::Trash::new(Duration::from_secs(args.trash_retention))),
+            rate_limits: Arc::new(rate_limit::RateLimits::new(args.rate_profile)),
+            client_versions: Arc::new(version::ClientVersionPolicy {
+                min_version: args.min_client_version.clone(),
+                allow_unversioned: args.allow_unversioned_clients,
+            }),
+        }
+    }
 }
 
 fn generate_random_code() -> String {
@@ -44,8 +462,44 @@ fn generate_random_code() -> String {
     format!("{:06}", rng.gen_range(100000..1000000))
 }
 
+fn generate_admin_token() -> String {
+    let mut rng = rand::thread_rng();
+    let bytes: [u8; 16] = rng.gen();
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
 fn validate_code(code: &str) -> bool {
-    code.len() == 6 && code.chars().all(|c| c.is_ascii_digit())
+    code.len() == SECURITY_CODE_LENGTH && code.chars().all(|c| c.is_ascii_digit())
+}
+
+fn load_ban_file(path: &PathBuf) -> Result<HashMap<IpAddr, Ban>, String> {
+    let contents = std::fs::read_to_string(path)
+        .map_err(|e| format!("Could not read ban file {}: {e}", path.display()))?;
+
+    let mut bans = HashMap::new();
+    for line in contents.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let ip: IpAddr = line
+            .parse()
+            .map_err(|_| format!("Invalid IP address in ban file: {line}"))?;
+        bans.insert(ip, Ban {
+            ip,
+            reason: Some("Listed in ban file".to_string()),
+            created_at: chrono::Utc::now(),
+            expires_at: None,
+        });
+    }
+    Ok(bans)
 }
 
 fn with_auth(state: AppState) -> impl Filter<Extract = (), Error = warp::Rejection> + Clone {
@@ -56,14 +510,7 @@ fn with_auth(state: AppState) -> impl Filter<Extract = (), Error = warp::Rejecti
             match state.security_code {
                 None => Ok(()),
                 Some(_) => match auth_cookie {
-                    Some(cookie) => {
-                        let expected_cookie = format!("authenticated:{}", state.server_session_id);
-                        if cookie == expected_cookie {
-                            Ok(())
-                        } else {
-                            Err(warp::reject::custom(AuthenticationRequired))
-                        }
-                    },
+                    Some(cookie) if state.auth_token_expiry(&cookie).is_some() => Ok(()),
                     _ => Err(warp::reject::custom(AuthenticationRequired)),
                 }
             }
@@ -71,38 +518,171 @@ fn with_auth(state: AppState) -> impl Filter<Extract = (), Error = warp::Rejecti
         .untuple_one()
 }
 
+/// Authentication for `/ws`. Besides the `auth` cookie, clients that cannot keep
+/// cookies may send a token from `POST /auth/token` as `Authorization: Bearer <token>`,
+/// or as `?token=<token>` where headers cannot be set; query strings end up in logs, so
+/// the header is preferred. Failing gets a 401 rather than the login redirect, which a
+/// WebSocket client could not follow. Extracts the token the request was accepted with,
+/// or the one it carries when the server needs none, so relay pipes can be bound to it.
+fn with_ws_auth(state: AppState) -> impl Filter<Extract = (Option<String>,), Error = warp::Rejection> + Clone {
+    warp::any()
+        .and(warp::cookie::optional("auth"))
+        .and(warp::header::optional::<String>("authorization"))
+        .and(warp::query::<TokenQuery>())
+        .and(warp::any().map(move || state.clone()))
+        .and_then(|auth_cookie: Option<String>, authorization: Option<String>, query: TokenQuery, state: AppState| async move {
+            let bearer = authorization.and_then(|header| header.strip_prefix("Bearer ").map(str::to_string));
+            let mut tokens = [bearer, query.token, auth_cookie].into_iter().flatten();
+            if state.security_code.is_none() {
+                return Ok(tokens.next());
+            }
+            match tokens.find(|token| state.auth_token_expiry(token).is_some()) {
+                Some(token) => Ok(Some(token)),
+                None => Err(warp::reject::custom(InvalidToken)),
+            }
+        })
+}
+
+fn with_admin(state: AppState) -> impl Filter<Extract = (), Error = warp::Rejection> + Clone {
+    warp::any()
+        .and(warp::header::optional::<String>("x-admin-token"))
+        .and(warp::any().map(move || state.clone()))
+        .and_then(|token: Option<String>, state: AppState| async move {
+            match token {
+                Some(token) if constant_time_eq(token.as_bytes(), state.admin_token.as_bytes()) => Ok(()),
+                Some(_) => {
+                    state.events.record(EventKind::AuthFailed, EventSubject {
+                        detail: Some("admin token".to_string()),
+                        ..Default::default()
+                    });
+                    Err(warp::reject::custom(AdminRequired))
+                }
+                None => Err(warp::reject::custom(AdminRequired)),
+            }
+        })
+        .untuple_one()
+}
+
+/// The client's address: the first `X-Forwarded-For` entry when the proxy is trusted
+/// and the header is present, otherwise the remote address of the connection.
+fn client_ip(trust_proxy: bool) -> impl Filter<Extract = (Option<IpAddr>,), Error = warp::Rejection> + Clone {
+    warp::addr::remote()
+        .and(warp::header::optional::<String>("x-forwarded-for"))
+        .map(move |remote: Option<SocketAddr>, forwarded_for: Option<String>| {
+            let forwarded = forwarded_for
+                .filter(|_| trust_proxy)
+                .and_then(|header| header.split(',').next()?.trim().parse::<IpAddr>().ok());
+            forwarded.or(remote.map(|addr| addr.ip()))
+        })
+}
+
 #[derive(Debug)]
 struct AuthenticationRequired;
 impl warp::reject::Reject for AuthenticationRequired {}
 
+#[derive(Debug)]
+struct AdminRequired;
+impl warp::reject::Reject for AdminRequired {}
+
+#[derive(Debug)]
+struct InvalidToken;
+impl warp::reject::Reject for InvalidToken {}
+
 async fn handle_rejection(err: warp::Rejection) -> Result<Box<dyn warp::Reply>, std::convert::Infallible> {
     if err.find::<AuthenticationRequired>().is_some() {
+        tracing::debug!("Unauthenticated request redirected to login");
         Ok(Box::new(warp::redirect::temporary(warp::http::Uri::from_static("/login"))) as Box<dyn warp::Reply>)
+    } else if err.find::<InvalidToken>().is_some() {
+        tracing::debug!("WebSocket connection without a valid auth token");
+        Ok(handlers::unauthorized())
+    } else if err.find::<AdminRequired>().is_some() {
+        tracing::warn!("Admin request rejected: missing or wrong admin token");
+        Ok(handlers::forbidden())
     } else {
+        tracing::error!(rejection = ?err, "Unhandled rejection");
         Ok(Box::new(warp::reply::with_status("Internal Server Error", warp::http::StatusCode::INTERNAL_SERVER_ERROR)) as Box<dyn warp::Reply>)
     }
 }
 
 async fn serve_login_page() -> Result<Box<dyn warp::Reply>, warp::Rejection> {
-    let lookup = "login.html".to_string();
-    if let Some(file) = STATIC_DIR.get_file(&lookup) {
-        let mime = mime_guess::from_path(&lookup).first_or_octet_stream().to_string();
-        let bytes = file.contents().to_vec();
-        Ok(Box::new(warp::reply::with_header(
-            warp::reply::html(bytes),
-            "content-type",
-            mime,
-        )) as Box<dyn warp::Reply>)
-    } else {
-        Err(warp::reject::not_found())
+    match static_file("login.html") {
+        Some(reply) => Ok(Box::new(reply) as Box<dyn warp::Reply>),
+        None => Err(warp::reject::not_found()),
     }
 }
 
+/// An embedded file's bytes as they are, with the content type of its extension.
+fn static_file(lookup: &str) -> Option<impl warp::Reply> {
+    let file = STATIC_DIR.get_file(lookup)?;
+    let mime = mime_guess::from_path(lookup).first_or_octet_stream().to_string();
+    Some(warp::reply::with_header(file.contents().to_vec(), "content-type", mime))
+}
+
+/// The embedded web app manifest, named after this server so installs of different
+/// servers can be told apart.
+fn web_app_manifest(server_name: &str) -> Option<impl warp::Reply> {
+    let file = STATIC_DIR.get_file("manifest.webmanifest")?;
+    let mut manifest = serde_json::from_slice::<serde_json::Value>(file.contents()).ok()?;
+    manifest["name"] = format!("LADEX - {server_name}").into();
+    manifest["short_name"] = server_name.into();
+    let body = serde_json::to_vec(&manifest).ok()?;
+    Some(warp::reply::with_header(body, "content-type", "application/manifest+json"))
+}
+
+/// The embedded files under /static/<path>, plus the ones browsers and crawlers fetch
+/// from the top level, often before login.
+fn static_routes(server_name: String) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    let static_route = warp::path("static")
+        .and(warp::path::tail())
+        .and_then(|tail: warp::filters::path::Tail| async move {
+            let lookup = tail.as_str().trim_start_matches('/').to_string();
+            let lookup = if lookup.is_empty() { "index.html".to_string() } else { lookup };
+            static_file(&lookup).ok_or_else(warp::reject::not_found)
+        });
+    let top_level_static = warp::path!("favicon.ico")
+        .map(|| "favicon.ico")
+        .or(warp::path!("robots.txt").map(|| "robots.txt"))
+        .unify()
+        .and(warp::get())
+        .and_then(|lookup: &'static str| async move { static_file(lookup).ok_or_else(warp::reject::not_found) });
+    let manifest_route = warp::path("manifest.webmanifest")
+        .and(warp::path::end())
+        .and(warp::get())
+        .and_then(move || {
+            let manifest = web_app_manifest(&server_name);
+            async move { manifest.ok_or_else(warp::reject::not_found) }
+        });
+    static_route.or(top_level_static).or(manifest_route)
+}
+
+fn websocket_route(state: AppState, trust_proxy: bool) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    warp::path("ws")
+        .and(with_ws_auth(state.clone()))
+        .and(warp::ws())
+        .and(client_ip(trust_proxy))
+        .and(warp::any().map(move || state.clone()))
+        .and_then(websocket::websocket_handler)
+}
+
 #[tokio::main]
 async fn main() {
-    tracing_subscriber::fmt::init();
-    
-    let args = Args::parse();
+    let command = Args::command();
+    let (matches, config_warnings) = match config::resolve(&command, std::env::args_os().collect()) {
+        Ok(resolved) => resolved,
+        Err(e) => {
+            eprintln!("error: {e}");
+            std::process::exit(2);
+        }
+    };
+    if matches.get_flag("print_config") {
+        config::print(&command, &matches);
+        return;
+    }
+    let args = Args::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+    logging::init(args.log_level, args.log_format);
+    for warning in config_warnings {
+        tracing::warn!("{warning}");
+    }
     
     // Handle security code logic
     let security_code = if args.secure {
@@ -113,29 +693,270 @@ async fn main() {
         if validate_code(&code) {
             Some(code)
         } else {
-            eprintln!("Error: Security code must be exactly 6 digits");
+            tracing::error!("Security code must be exactly 6 digits");
             std::process::exit(1);
         }
     } else {
         None
     };
     
-    let (tx, _rx) = broadcast::channel::<ServerMessage>(1000);
+    let bans = match &args.ban_file {
+        Some(path) => match load_ban_file(path) {
+            Ok(bans) => bans,
+            Err(e) => {
+                tracing::error!("{e}");
+                std::process::exit(1);
+            }
+        },
+        None => HashMap::new(),
+    };
+    
+    let audit = match audit::AuditLog::new(args.audit_limit, args.audit_file.as_deref()) {
+        Ok(audit) => Arc::new(audit),
+        Err(e) => {
+            tracing::error!("{e}");
+            std::process::exit(1);
+        }
+    };
+
+    let events = Arc::new(events::EventLog::new(args.event_limit));
+
+    let motd_text = match &args.motd_file {
+        Some(path) => match std::fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(e) => {
+                tracing::error!("Could not read MOTD file {}: {e}", path.display());
+                std::process::exit(1);
+            }
+        },
+        None => args.motd.clone().unwrap_or_default(),
+    };
+    let motd = match Motd::new(&motd_text) {
+        Ok(motd) => motd,
+        Err(e) => {
+            tracing::error!("{e}");
+            std::process::exit(1);
+        }
+    };
+
+    let ice = ice::IceConfig {
+        stun: args.stun.clone(),
+        turn: args.turn.clone(),
+        turn_secret: args.turn_secret.clone().filter(|secret| !secret.is_empty()),
+        credential_ttl: Duration::from_secs(args.turn_credential_ttl),
+    };
+    if let Err(e) = ice.validate() {
+        tracing::error!("{e}");
+        std::process::exit(1);
+    }
+
+    let share_dir = match &args.share_dir {
+        Some(dir) => match std::fs::canonicalize(dir) {
+            Ok(dir) if dir.is_dir() => Some(dir),
+            Ok(_) => {
+                tracing::error!("{} is not a directory", dir.display());
+                std::process::exit(1);
+            }
+            Err(e) => {
+                tracing::error!("Could not open shared directory {}: {e}", dir.display());
+                std::process::exit(1);
+            }
+        },
+        None => None,
+    };
+
+    let admin_token = match args.admin_token {
+        Some(token) if !token.is_empty() => token,
+        _ => {
+            let token = generate_admin_token();
+            println!("Generated admin token: {token}");
+            token
+        }
+    };
     
     let server_session_id = {
         let mut rng = rand::thread_rng();
         format!("server_session_{}", rng.gen::<u64>())
     };
-    
+
+    let server_name = args
+        .name
+        .as_deref()
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+        .map(str::to_string)
+        .or_else(mdns::system_hostname)
+        .unwrap_or_else(|| "ladex".to_string());
+
+    // With --diagnose the server is only started on loopback, after these checks
+    let diagnosis = match args.diagnose {
+        true => Some(doctor::Diagnosis::preflight(args.bind, &server_name).await),
+        false => None,
+    };
+
+    // A .local name only helps when other machines can connect at all
+    let mdns = if diagnosis.is_none() && args.bind.ip().is_unspecified() {
+        mdns::advertise(&server_name, args.bind.port())
+    } else {
+        None
+    };
+
+    let (upload_activity_tx, upload_activity_rx) = tokio::sync::mpsc::unbounded_channel();
     let app_state = AppState {
-        peers: Arc::new(RwLock::new(HashMap::new())),
-        files: Arc::new(RwLock::new(HashMap::new())),
-        messages: Arc::new(RwLock::new(Vec::new())),
-        tx,
+        rooms: Arc::new(RwLock::new(HashMap::new())),
+        sessions: Arc::new(RwLock::new(HashMap::new())),
+        bans: Arc::new(RwLock::new(bans)),
+        relay: Arc::new(relay::Relay::new(
+            args.relay_limit,
+            args.relay_limit_per_transfer,
+            transfers::TransferTable::new(
+                transfers::TransferLimits {
+                    per_host: args.max_transfers_per_host.max(1),
+                    total: args.max_transfers.map(|max| max.max(1)),
+                    per_peer: args.max_transfers_per_peer.map(|max| max.max(1)),
+                    reject_excess: args.reject_excess_transfers,
+                },
+                Duration::from_secs(args.approval_timeout),
+                Duration::from_secs(args.download_request_timeout),
+                audit.clone(),
+                events.clone(),
+                upload_activity_tx,
+            ),
+        )),
         security_code,
+        admin_token,
         server_session_id,
+        instance_id: format!("{:016x}", rand::random::<u64>()),
+        session_secret: rand::random(),
+        ban_fail_closed: args.ban_fail_closed,
+        guest_readonly: args.guest_readonly,
+        max_snippets: args.max_snippets,
+        default_file_ttl: args.default_file_ttl,
+        file_list_page_size: args.file_list_page_size,
+        file_limits: files::FileLimits {
+            max_files: args.max_files,
+            max_total_size: args.max_total_size,
+            evict_oldest: args.evict_oldest,
+        },
+        max_ws_message: args.max_ws_message,
+        max_observers: args.max_observers,
+        observers: Arc::new(AtomicUsize::new(0)),
+        max_message_length: args.max_message_length,
+        system_messages: !args.no_system_messages,
+        hide_ips: args.hide_ips,
+        access_log: args.access_log,
+        bind: args.bind,
+        mdns_host: mdns.as_ref().map(|mdns| mdns.hostname.clone()),
+        server_name,
+        orphan_grace: Duration::from_secs(args.orphan_grace),
+        batch_window: Duration::from_secs(args.batch_window),
+        idle_timeout: args.idle_timeout.filter(|secs| *secs > 0).map(Duration::from_secs),
+        idle_ignore_pings: args.idle_ignore_pings,
+        audit,
+        events,
+        motd: Arc::new(RwLock::new(motd)),
+        ice: Arc::new(ice),
+        latency: Arc::new(peers::LatencyTable::default()),
+        api_senders: Arc::new(chat::ApiSenders::default()),
+        pipes: Arc::new(pipes::Pipes::default()),
+        shutdown: Arc::new(shutdown::ShutdownTimer::default()),
+        trash: Arc::new(trash::Trash::new(Duration::from_secs(args.trash_retention))),
+        rate_limits: Arc::new(rate_limit::RateLimits::new(args.rate_profile)),
+        client_versions: Arc::new(version::ClientVersionPolicy {
+            min_version: args.min_client_version.clone(),
+            allow_unversioned: args.allow_unversioned_clients,
+        }),
     };
 
+    // Remove rooms that have been empty for longer than the idle timeout
+    let rooms_gc = app_state.rooms.clone();
+    let room_idle_timeout = Duration::from_secs(args.room_idle_timeout);
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(30));
+        loop {
+            interval.tick().await;
+            rooms::collect_idle_rooms(&rooms_gc, room_idle_timeout).await;
+        }
+    });
+
+    // Report peers that have gone quiet as idle
+    let rooms_activity = app_state.rooms.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(30));
+        loop {
+            interval.tick().await;
+            peers::mark_idle_peers(&rooms_activity).await;
+        }
+    });
+
+    // Remove files whose expiry has passed or whose hosts did not come back
+    let app_state_expiry = app_state.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(30));
+        loop {
+            interval.tick().await;
+            files::remove_expired_files(&app_state_expiry).await;
+            // Also picks up slots freed by removed files and stalled transfers
+            websocket::start_queued_downloads(&app_state_expiry).await;
+        }
+    });
+
+    // Withdraw download requests whose host did not approve or answer them in time
+    let app_state_approvals = app_state.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(5));
+        loop {
+            interval.tick().await;
+            websocket::expire_approvals(&app_state_approvals).await;
+            websocket::expire_download_requests(&app_state_approvals).await;
+            app_state_approvals.pipes.expire();
+            app_state_approvals.trash.sweep();
+        }
+    });
+
+    // Keep rooms with relayed transfers in flight up to date on their progress
+    let app_state_live = app_state.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(3));
+        let mut active_rooms = HashSet::new();
+        loop {
+            interval.tick().await;
+            active_rooms = websocket::broadcast_transfers(&app_state_live, &active_rooms).await;
+        }
+    });
+
+    if let Some(url) = args.bridge.clone() {
+        let config = bridge::BridgeConfig {
+            url,
+            code: args.bridge_code.clone(),
+            chat: args.bridge_chat,
+        };
+        tokio::spawn(bridge::run(app_state.clone(), config));
+    }
+
+    if let Some(dir) = share_dir {
+        let config = share::ShareConfig {
+            dir,
+            max_files: args.share_dir_max_files,
+        };
+        tokio::spawn(share::run(app_state.clone(), config));
+    }
+
+    // Shut down when --timeout runs out, the server stays empty or an admin asks for it
+    if let Some(timeout) = args.timeout {
+        app_state.shutdown.schedule(timeout);
+    }
+    tokio::spawn(shutdown::run(app_state.clone(), args.shutdown_when_empty));
+    tokio::spawn(websocket::deliver_upload_activity(app_state.clone(), upload_activity_rx));
+
+    // On Ctrl-C, tell clients the server is going away before exiting
+    let app_state_shutdown = app_state.clone();
+    tokio::spawn(async move {
+        if tokio::signal::ctrl_c().await.is_ok() {
+            shutdown::exit(&app_state_shutdown).await;
+        }
+    });
+
     // Login page route - not protected
     let app_state_login = app_state.clone();
     let login_route = warp::path("login")
@@ -154,11 +975,24 @@ async fn main() {
     // Auth endpoint
     let app_state_auth = app_state.clone();
     let auth_route = warp::path("auth")
+        .and(warp::path::end())
         .and(warp::post())
         .and(warp::body::json())
+        .and(client_ip(args.trust_proxy))
         .and(warp::any().map(move || app_state_auth.clone()))
         .and_then(handlers::authenticate);
 
+    // Exchanges the security code for a bearer token - not protected
+    let app_state_token = app_state.clone();
+    let token_route = warp::path("auth")
+        .and(warp::path("token"))
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(warp::body::json())
+        .and(client_ip(args.trust_proxy))
+        .and(warp::any().map(move || app_state_token.clone()))
+        .and_then(handlers::issue_token);
+
     // Logout endpoint - not protected
     let logout_route = warp::path("logout")
         .and(warp::post())
@@ -167,105 +1001,419 @@ async fn main() {
     // Auth status check endpoint - not protected
     let auth_status_route = warp::path("auth-status")
         .and(warp::get())
-        .and(warp::header::optional::<String>("cookie"))
+        .and(warp::cookie::optional("auth"))
         .and(warp::any().map({
             let app_state = app_state.clone();
             move || app_state.clone()
         }))
         .and_then(handlers::check_auth_status);
 
-    // Serve embedded static assets under /static/<path> - not protected
-    let static_route = warp::path("static")
-        .and(warp::path::tail())
-        .and_then(|tail: warp::filters::path::Tail| async move {
-            let lookup = tail.as_str().trim_start_matches('/').to_string();
-            let lookup = if lookup.is_empty() { "index.html".to_string() } else { lookup };
-            if let Some(file) = STATIC_DIR.get_file(&lookup) {
-                let mime = mime_guess::from_path(&lookup).first_or_octet_stream().to_string();
-                let bytes = file.contents().to_vec();
-                Ok::<_, warp::Rejection>(warp::reply::with_header(
-                    warp::reply::html(bytes),
-                    "content-type",
-                    mime,
-                ))
-            } else {
-                Err(warp::reject::not_found())
-            }
-        });
-    
+    // Server info endpoint - not protected; callers that have not logged in get less
+    let server_info_route = warp::path("api")
+        .and(warp::path("server-info"))
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(warp::cookie::optional("auth"))
+        .and(warp::header::optional::<String>("authorization"))
+        .and(warp::any().map({
+            let app_state = app_state.clone();
+            move || app_state.clone()
+        }))
+        .and_then(handlers::get_server_info);
+
+    // Embedded static assets, the favicon, robots.txt and the manifest - not protected
+    let static_assets = static_routes(app_state.server_name.clone());
+
     // Serve embedded index.html at root - protected
     let index = warp::path::end()
         .and(with_auth(app_state.clone()))
-        .and_then(|| async move {
-            let lookup = "index.html".to_string();
-            if let Some(file) = STATIC_DIR.get_file(&lookup) {
-                let mime = mime_guess::from_path(&lookup).first_or_octet_stream().to_string();
-                let bytes = file.contents().to_vec();
-                Ok::<_, warp::Rejection>(warp::reply::with_header(
-                    warp::reply::html(bytes),
-                    "content-type",
-                    mime,
-                ))
-            } else {
-                Err(warp::reject::not_found())
-            }
-        });
+        .and_then(|| async move { static_file("index.html").ok_or_else(warp::reject::not_found) });
 
     // WebSocket endpoint - protected
-    let app_state_ws = app_state.clone();
-    let websocket = warp::path("ws")
-        .and(with_auth(app_state.clone()))
-        .and(warp::ws())
-        .and(warp::any().map(move || app_state_ws.clone()))
-        .and_then(websocket::websocket_handler);
+    let websocket = websocket_route(app_state.clone(), args.trust_proxy);
+
+    // Relay pipes - protected like /ws, since the HTTP clients using them may not keep cookies
+    let app_state_relay_up = app_state.clone();
+    let relay_upload = warp::path("relay")
+        .and(warp::path::param::<String>())
+        .and(warp::path::end())
+        .and(warp::put())
+        .and(with_ws_auth(app_state.clone()))
+        .and(warp::query::<RelayQuery>())
+        .and(client_ip(args.trust_proxy))
+        .and(warp::body::stream())
+        .and(warp::any().map(move || app_state_relay_up.clone()))
+        .and_then(handlers::relay_upload);
+    let app_state_relay_down = app_state.clone();
+    let relay_download = warp::path("relay")
+        .and(warp::path::param::<String>())
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(with_ws_auth(app_state.clone()))
+        .and(warp::query::<RelayQuery>())
+        .and(client_ip(args.trust_proxy))
+        .and(warp::any().map(move || app_state_relay_down.clone()))
+        .and_then(handlers::relay_download);
 
     // API endpoints - protected
     let app_state_api = app_state.clone();
+    let app_state_peer = app_state.clone();
+    let app_state_files = app_state.clone();
+    let app_state_stats = app_state.clone();
+    let app_state_history = app_state.clone();
+    let app_state_events = app_state.clone();
+    let app_state_observers = app_state.clone();
+    let app_state_transfers = app_state.clone();
+    let app_state_zip = app_state.clone();
+    let app_state_messages = app_state.clone();
+    let app_state_post = app_state.clone();
+    let app_state_search = app_state.clone();
+    let app_state_export = app_state.clone();
+    let app_state_replies = app_state.clone();
+    let app_state_snippets = app_state.clone();
+    let app_state_snippet = app_state.clone();
+    let app_state_kick = app_state.clone();
+    let app_state_bans = app_state.clone();
+    let app_state_ban = app_state.clone();
+    let app_state_unban = app_state.clone();
+    let app_state_motd = app_state.clone();
+    let app_state_schedule = app_state.clone();
     let api = warp::path("api")
         .and(with_auth(app_state.clone()))
         .and(
             warp::path("peers")
+                .and(warp::path::end())
                 .and(warp::get())
+                .and(warp::query::<RoomQuery>())
                 .and(warp::any().map(move || app_state_api.clone()))
                 .and_then(handlers::get_peers)
+                .or(warp::path("peers")
+                    .and(warp::path::param::<SessionId>())
+                    .and(warp::path::end())
+                    .and(warp::get())
+                    .and(warp::any().map(move || app_state_peer.clone()))
+                    .and_then(handlers::get_peer))
+                .or(warp::path("files")
+                    .and(warp::path::end())
+                    .and(warp::get())
+                    .and(warp::query::<FileListQuery>())
+                    .and(warp::any().map(move || app_state_files.clone()))
+                    .and_then(handlers::get_files))
+                .or(warp::path("messages")
+                    .and(warp::path::end())
+                    .and(warp::get())
+                    .and(warp::query::<MessageHistoryQuery>())
+                    .and(warp::any().map(move || app_state_messages.clone()))
+                    .and_then(handlers::get_messages))
+                .or(warp::path("messages")
+                    .and(warp::path::end())
+                    .and(warp::post())
+                    .and(warp::body::json())
+                    .and(warp::cookie::optional("auth"))
+                    .and(warp::header::optional::<String>("x-admin-token"))
+                    .and(client_ip(args.trust_proxy))
+                    .and(warp::any().map(move || app_state_post.clone()))
+                    .and_then(handlers::post_message))
+                .or(warp::path("messages")
+                    .and(warp::path("search"))
+                    .and(warp::path::end())
+                    .and(warp::get())
+                    .and(warp::query::<MessageSearchQuery>())
+                    .and(warp::any().map(move || app_state_search.clone()))
+                    .and_then(handlers::search_messages))
+                .or(warp::path("messages")
+                    .and(warp::path("export"))
+                    .and(warp::path::end())
+                    .and(warp::get())
+                    .and(warp::query::<MessageExportQuery>())
+                    .and(warp::any().map(move || app_state_export.clone()))
+                    .and_then(handlers::export_messages))
+                .or(warp::path("messages")
+                    .and(warp::path::param::<String>())
+                    .and(warp::path("replies"))
+                    .and(warp::path::end())
+                    .and(warp::get())
+                    .and(warp::query::<RoomQuery>())
+                    .and(warp::any().map(move || app_state_replies.clone()))
+                    .and_then(handlers::get_replies))
+                .or(warp::path("zip")
+                    .and(warp::path::end())
+                    .and(warp::post())
+                    .and(warp::body::json())
+                    .and(warp::header::optional::<String>("x-file-password"))
+                    .and(warp::any().map(move || app_state_zip.clone()))
+                    .and_then(handlers::download_zip))
+                .or(warp::path("snippets")
+                    .and(warp::path::end())
+                    .and(warp::get())
+                    .and(warp::query::<RoomQuery>())
+                    .and(warp::any().map(move || app_state_snippets.clone()))
+                    .and_then(handlers::get_snippets))
+                .or(warp::path("snippets")
+                    .and(warp::path::end())
+                    .and(warp::post())
+                    .and(warp::body::json())
+                    .and(warp::any().map(move || app_state_snippet.clone()))
+                    .and_then(handlers::create_snippet))
+                .or(warp::path("stats")
+                    .and(warp::path::end())
+                    .and(warp::get())
+                    .and(warp::any().map(move || app_state_stats.clone()))
+                    .and_then(handlers::get_stats))
+                .or(warp::path("peers")
+                    .and(warp::path::param::<SessionId>())
+                    .and(warp::path::end())
+                    .and(warp::delete())
+                    .and(with_admin(app_state.clone()))
+                    .and(warp::any().map(move || app_state_kick.clone()))
+                    .and_then(handlers::kick_peer))
+                .or(warp::path("transfers")
+                    .and(warp::path("history"))
+                    .and(warp::path::end())
+                    .and(warp::get())
+                    .and(with_admin(app_state.clone()))
+                    .and(warp::query::<TransferHistoryQuery>())
+                    .and(warp::any().map(move || app_state_history.clone()))
+                    .and_then(handlers::get_transfer_history))
+                .or(warp::path("events")
+                    .and(warp::path::end())
+                    .and(warp::get())
+                    .and(with_admin(app_state.clone()))
+                    .and(warp::query::<EventQuery>())
+                    .and(warp::any().map(move || app_state_events.clone()))
+                    .and_then(handlers::get_events))
+                .or(warp::path("events")
+                    .and(warp::path("stream"))
+                    .and(warp::path::end())
+                    .and(warp::get())
+                    .and(warp::query::<ObserverQuery>())
+                    .and(warp::any().map(move || app_state_observers.clone()))
+                    .and_then(handlers::stream_events))
+                .or(warp::path("transfers")
+                    .and(warp::path::end())
+                    .and(warp::get())
+                    .and(warp::query::<RoomQuery>())
+                    .and(warp::any().map(move || app_state_transfers.clone()))
+                    .and_then(handlers::get_transfers))
+                .or(warp::path("bans")
+                    .and(warp::path::end())
+                    .and(warp::get())
+                    .and(with_admin(app_state.clone()))
+                    .and(warp::any().map(move || app_state_bans.clone()))
+                    .and_then(handlers::get_bans))
+                .or(warp::path("bans")
+                    .and(warp::path::end())
+                    .and(warp::post())
+                    .and(with_admin(app_state.clone()))
+                    .and(warp::body::json())
+                    .and(warp::any().map(move || app_state_ban.clone()))
+                    .and_then(handlers::add_ban))
+                .or(warp::path("bans")
+                    .and(warp::path::param::<IpAddr>())
+                    .and(warp::path::end())
+                    .and(warp::delete())
+                    .and(with_admin(app_state.clone()))
+                    .and(warp::any().map(move || app_state_unban.clone()))
+                    .and_then(handlers::remove_ban))
+                .or(warp::path("motd")
+                    .and(warp::path::end())
+                    .and(warp::put())
+                    .and(with_admin(app_state.clone()))
+                    .and(warp::body::json())
+                    .and(warp::any().map(move || app_state_motd.clone()))
+                    .and_then(handlers::set_motd))
+                .or(warp::path("shutdown")
+                    .and(warp::path::end())
+                    .and(warp::post())
+                    .and(with_admin(app_state.clone()))
+                    .and(warp::query::<ShutdownQuery>())
+                    .and(warp::any().map(move || app_state_schedule.clone()))
+                    .and_then(handlers::schedule_shutdown))
         );
 
     let cors = warp::cors()
         .allow_any_origin()
-        .allow_headers(vec!["content-type"])
+        .allow_headers(vec!["content-type", "x-admin-token"])
         .allow_methods(vec!["GET", "POST", "PUT", "DELETE"]);
 
     // IMPORTANT: More specific routes first, unprotected routes before protected ones
     let routes = login_route
         .or(auth_route)
+        .or(token_route)
         .or(logout_route)
         .or(auth_status_route)
-        .or(static_route)
+        .or(server_info_route)
+        .or(static_assets)
         .or(websocket)
+        .or(relay_upload)
+        .or(relay_download)
         .or(api)
         .or(index)
         .with(cors)
         .recover(handle_rejection);
 
-    let addr: SocketAddr = ([0, 0, 0, 0], 8080).into();
-    let local_ip = get_local_ip().unwrap_or_else(|| "YOUR_IP".to_string());
-    
-    println!("Access locally: http://localhost:8080");
-    println!("Access from network: http://{local_ip}:8080");
-    
-    warp::serve(routes)
-        .run(addr)
-        .await;
+    if let Some(mut diagnosis) = diagnosis {
+        match doctor::loopback_address() {
+            Ok(addr) => {
+                tokio::spawn(warp::serve(routes.clone()).run(addr));
+                let token = app_state.security_code.is_some().then(|| app_state.new_auth_token());
+                diagnosis.check_round_trip(addr, token.as_deref()).await;
+            }
+            Err(e) => diagnosis.loopback_unavailable(&e),
+        }
+        diagnosis.exit();
+    }
+
+    let addrs = network::bind_addresses(args.bind);
+    let port = args.bind.port();
+
+    tracing::info!(addrs = ?addrs, secure = app_state.security_code.is_some(), "Server started");
+    println!("LADEX server \"{}\"", app_state.server_name);
+    println!("Access locally: http://localhost:{port}");
+    let access_urls = network::access_urls(args.bind, app_state.mdns_host.as_deref());
+    if access_urls.is_empty() {
+        println!("Access from network: http://YOUR_IP:{port}");
+    }
+    for access_url in access_urls {
+        match access_url.interface {
+            Some(interface) => println!("Access from network: {} ({interface})", access_url.url),
+            None => println!("Access from network: {}", access_url.url),
+        }
+    }
+
+    futures_util::future::join_all(addrs.into_iter().map(|addr| warp::serve(routes.clone()).run(addr))).await;
 }
 
-fn get_local_ip() -> Option<String> {
-    use std::net::UdpSocket;
-    if let Ok(socket) = UdpSocket::bind("0.0.0.0:0") {
-        if socket.connect("8.8.8.8:80").is_ok() {
-            if let Ok(addr) = socket.local_addr() {
-                return Some(addr.ip().to_string());
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn get(path: &str) -> warp::http::Response<bytes::Bytes> {
+        warp::test::request()
+            .method("GET")
+            .path(path)
+            .reply(&static_routes("Office".to_string()))
+            .await
+    }
+
+    fn content_type(response: &warp::http::Response<bytes::Bytes>) -> &str {
+        response.headers()["content-type"].to_str().unwrap()
+    }
+
+    #[tokio::test]
+    async fn serves_embedded_files_unchanged() {
+        for (path, mime) in [
+            ("icon-192.png", "image/png"),
+            ("favicon.ico", "image/x-icon"),
+            ("app.js", "text/javascript"),
+            ("style.css", "text/css"),
+        ] {
+            let response = get(&format!("/static/{path}")).await;
+            assert_eq!(response.status(), 200, "{path}");
+            assert_eq!(response.body().as_ref(), STATIC_DIR.get_file(path).unwrap().contents(), "{path}");
+            assert_eq!(content_type(&response), mime, "{path}");
+        }
+    }
+
+    #[tokio::test]
+    async fn serves_index_for_the_static_root() {
+        let response = get("/static/").await;
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.body().as_ref(), STATIC_DIR.get_file("index.html").unwrap().contents());
+        assert_eq!(content_type(&response), "text/html");
+    }
+
+    #[tokio::test]
+    async fn serves_top_level_files_without_auth() {
+        let response = get("/favicon.ico").await;
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.body().as_ref(), STATIC_DIR.get_file("favicon.ico").unwrap().contents());
+        assert_eq!(content_type(&response), "image/x-icon");
+
+        let response = get("/robots.txt").await;
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.body().as_ref(), STATIC_DIR.get_file("robots.txt").unwrap().contents());
+        assert_eq!(content_type(&response), "text/plain");
+    }
+
+    #[tokio::test]
+    async fn names_the_manifest_after_the_server() {
+        let response = get("/manifest.webmanifest").await;
+        assert_eq!(response.status(), 200);
+        assert_eq!(content_type(&response), "application/manifest+json");
+        let manifest = serde_json::from_slice::<serde_json::Value>(response.body()).unwrap();
+        assert_eq!(manifest["name"], "LADEX - Office");
+        assert_eq!(manifest["short_name"], "Office");
+    }
+
+    #[tokio::test]
+    async fn rejects_missing_files() {
+        assert_eq!(get("/static/missing.js").await.status(), 404);
+        assert_eq!(get("/static/../Cargo.toml").await.status(), 404);
+    }
+
+    async fn connect(state: &AppState) -> warp::test::WsClient {
+        warp::test::ws().path("/ws").handshake(websocket_route(state.clone(), false)).await.unwrap()
+    }
+
+    /// Joins as `session_id`, returning once the server has welcomed the session.
+    async fn join(client: &mut warp::test::WsClient, session_id: &str) {
+        client.send_text(serde_json::json!({ "type": "join", "session_id": session_id }).to_string()).await;
+        loop {
+            let msg = client.recv().await.unwrap();
+            let msg = serde_json::from_str::<serde_json::Value>(msg.to_str().unwrap()).unwrap();
+            if msg["type"] == "welcome" {
+                return;
+            }
+        }
+    }
+
+    /// Reads messages until the server closes the socket, returning its close code and reason.
+    async fn close_frame(client: &mut warp::test::WsClient) -> (u16, String) {
+        loop {
+            let msg = client.recv().await.unwrap();
+            if let Some((code, reason)) = msg.close_frame() {
+                return (code, reason.to_string());
             }
         }
     }
-    None
+
+    #[tokio::test]
+    async fn kicked_sockets_close_with_the_kick_code() {
+        let state = AppState::for_tests(&[]);
+        let mut alice = connect(&state).await;
+        join(&mut alice, "alice").await;
+
+        websocket::kick_session(&state, &"alice".to_string(), websocket::CLOSE_KICKED, "Removed by the server operator").await;
+        assert_eq!(close_frame(&mut alice).await, (websocket::CLOSE_KICKED, "Removed by the server operator".to_string()));
+    }
+
+    #[tokio::test]
+    async fn shutting_down_closes_every_socket_with_the_shutdown_code() {
+        let state = AppState::for_tests(&[]);
+        let mut clients = Vec::new();
+        for session_id in ["alice", "bob"] {
+            let mut client = connect(&state).await;
+            join(&mut client, session_id).await;
+            clients.push(client);
+        }
+
+        websocket::disconnect_all(&state, websocket::CLOSE_SERVER_SHUTDOWN, "Server shutting down").await;
+        for client in &mut clients {
+            assert_eq!(close_frame(client).await, (websocket::CLOSE_SERVER_SHUTDOWN, "Server shutting down".to_string()));
+        }
+    }
+
+    #[tokio::test]
+    async fn sockets_that_never_join_are_closed() {
+        let state = AppState::for_tests(&[]);
+        let mut client = connect(&state).await;
+        tokio::time::pause();
+        let paused = tokio::time::Instant::now();
+
+        let (code, _) = close_frame(&mut client).await;
+        assert_eq!(code, websocket::CLOSE_PROTOCOL_VIOLATION);
+        // The ten seconds started at the handshake, just before the clock was paused
+        assert!(paused.elapsed() >= Duration::from_secs(9));
+    }
 }
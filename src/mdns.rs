@@ -0,0 +1,67 @@
+use mdns_sd::{ServiceDaemon, ServiceInfo};
+
+const SERVICE_TYPE: &str = "_http._tcp.local.";
+
+/// A `<hostname>.local` name the server is reachable at. Holds the mDNS responder,
+/// which answers on its own thread for as long as this is kept alive.
+pub struct Advertisement {
+    pub hostname: String,
+    daemon: Option<ServiceDaemon>,
+}
+
+impl Advertisement {
+    /// Whether the name was announced by this server rather than left to the system's
+    /// own responder.
+    pub fn is_registered(&self) -> bool {
+        self.daemon.is_some()
+    }
+}
+
+/// Announces the server under its display name, at `<name>.local` or the machine's
+/// hostname when the name is not a valid host label. If a custom host name cannot be
+/// announced this falls back to the hostname, which the system's own responder usually
+/// resolves. Names that are not valid in a URL are skipped without a warning.
+pub fn advertise(name: &str, port: u16) -> Option<Advertisement> {
+    let custom = Some(name.to_lowercase()).filter(|label| is_valid_label(label));
+    let hostname = custom.clone().or_else(system_hostname)?;
+    let daemon = register(name, &hostname, port);
+
+    if daemon.is_none() && custom.is_some() {
+        return system_hostname().map(|hostname| Advertisement { hostname, daemon: None });
+    }
+    Some(Advertisement { hostname, daemon })
+}
+
+fn register(instance: &str, hostname: &str, port: u16) -> Option<ServiceDaemon> {
+    let daemon = ServiceDaemon::new()
+        .map_err(|e| tracing::warn!(error = %e, "mDNS responder unavailable"))
+        .ok()?;
+    let info = ServiceInfo::new(SERVICE_TYPE, instance, &format!("{hostname}.local."), "", port, &[("path", "/")][..])
+        .ok()?
+        .enable_addr_auto();
+
+    match daemon.register(info) {
+        Ok(()) => {
+            tracing::info!(hostname, "Advertising over mDNS");
+            Some(daemon)
+        }
+        Err(e) => {
+            tracing::warn!(hostname, error = %e, "mDNS advertisement failed");
+            None
+        }
+    }
+}
+
+/// The machine's hostname without any domain suffix, lowercased.
+pub fn system_hostname() -> Option<String> {
+    let hostname = gethostname::gethostname().into_string().ok()?;
+    let label = hostname.split('.').next()?.to_lowercase();
+    is_valid_label(&label).then_some(label)
+}
+
+fn is_valid_label(label: &str) -> bool {
+    (1..=63).contains(&label.len())
+        && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        && !label.starts_with('-')
+        && !label.ends_with('-')
+}
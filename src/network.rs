@@ -0,0 +1,86 @@
+use crate::types::*;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener};
+
+/// Addresses to listen on for `--bind`. The unspecified IPv6 address means "all
+/// interfaces": on systems where an IPv6 socket also accepts IPv4 that is a single
+/// dual-stack listener, otherwise IPv4 gets its own listener, and hosts without IPv6
+/// fall back to IPv4 only.
+pub fn bind_addresses(bind: SocketAddr) -> Vec<SocketAddr> {
+    if bind.ip() != IpAddr::V6(Ipv6Addr::UNSPECIFIED) {
+        return vec![bind];
+    }
+
+    let ipv4 = SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), bind.port());
+    let Ok(probe) = TcpListener::bind((Ipv6Addr::UNSPECIFIED, 0)) else {
+        return vec![ipv4];
+    };
+    // A dual-stack socket also holds the IPv4 port, so binding it again fails
+    let dual_stack = match probe.local_addr() {
+        Ok(addr) => TcpListener::bind((Ipv4Addr::UNSPECIFIED, addr.port())).is_err(),
+        Err(_) => true,
+    };
+
+    if dual_stack {
+        vec![bind]
+    } else {
+        vec![bind, ipv4]
+    }
+}
+
+/// Addresses other machines may reach this one at, with their interface names,
+/// found by listing interfaces so it works without a route to the internet. Private
+/// IPv4 (RFC 1918) and unique local IPv6 addresses come first. Link-local addresses
+/// are skipped: their URLs need a zone id, which browsers do not accept.
+pub fn interface_addresses() -> Vec<(String, IpAddr)> {
+    let mut addresses: Vec<(String, IpAddr)> = if_addrs::get_if_addrs()
+        .unwrap_or_default()
+        .into_iter()
+        .map(|interface| (interface.name.clone(), interface.ip()))
+        .filter(|(_, ip)| !ip.is_loopback() && !is_link_local(ip))
+        .collect();
+    addresses.sort_by_key(|(_, ip)| match ip {
+        IpAddr::V4(v4) if v4.is_private() => 0,
+        IpAddr::V6(v6) if (v6.segments()[0] & 0xfe00) == 0xfc00 => 1,
+        IpAddr::V4(_) => 2,
+        IpAddr::V6(_) => 3,
+    });
+    addresses
+}
+
+/// URLs the server can be reached at from the network, the mDNS hostname first. A
+/// specific `--bind` address is the only one; an IPv4 wildcard leaves out IPv6
+/// interface addresses.
+pub fn access_urls(bind: SocketAddr, mdns_host: Option<&str>) -> Vec<AccessUrl> {
+    if !bind.ip().is_unspecified() {
+        return vec![AccessUrl {
+            url: url(bind.ip(), bind.port()),
+            interface: None,
+        }];
+    }
+
+    let hostname = mdns_host.map(|host| AccessUrl {
+        url: format!("http://{host}.local:{}", bind.port()),
+        interface: None,
+    });
+    hostname
+        .into_iter()
+        .chain(interface_addresses().into_iter()
+        .filter(|(_, ip)| bind.is_ipv6() || ip.is_ipv4())
+        .map(|(interface, ip)| AccessUrl {
+            url: url(ip, bind.port()),
+            interface: Some(interface),
+        }))
+        .collect()
+}
+
+fn is_link_local(ip: &IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(v4) => v4.is_link_local(),
+        IpAddr::V6(v6) => (v6.segments()[0] & 0xffc0) == 0xfe80,
+    }
+}
+
+/// `http://host:port`, with IPv6 literals in brackets.
+pub fn url(ip: IpAddr, port: u16) -> String {
+    format!("http://{}", SocketAddr::new(ip, port))
+}
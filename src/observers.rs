@@ -0,0 +1,144 @@
+use crate::files;
+use crate::rooms::{self, RoomState};
+use crate::types::*;
+use crate::websocket;
+use crate::AppState;
+use serde::Deserialize;
+use std::convert::Infallible;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::broadcast;
+use warp::sse::Event;
+
+/// Comment sent on an otherwise quiet stream so proxies keep it open
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(15);
+
+/// Broadcasts passed on to observers, as named events
+const OBSERVED_MESSAGES: &[&str] = &[
+    "peer_joined",
+    "peer_updated",
+    "peer_activity",
+    "peer_left",
+    "file_list_diff",
+    "file_added",
+    "file_updated",
+    "file_removed",
+    "transfer_list",
+];
+
+const CHAT_MESSAGES: &[&str] = &["text_message"];
+
+/// A place among the `--max-observers` streams, given back when the stream is dropped.
+pub struct ObserverSlot(Arc<AtomicUsize>);
+
+impl ObserverSlot {
+    /// None when all slots are taken.
+    pub fn acquire(count: &Arc<AtomicUsize>, max: usize) -> Option<Self> {
+        count
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |taken| (taken < max).then_some(taken + 1))
+            .ok()
+            .map(|_| ObserverSlot(count.clone()))
+    }
+}
+
+impl Drop for ObserverSlot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+#[derive(Deserialize)]
+struct MessageType {
+    #[serde(rename = "type")]
+    kind: String,
+}
+
+struct Observer {
+    state: AppState,
+    room: RoomState,
+    rx: broadcast::Receiver<rooms::Broadcast>,
+    chat: bool,
+    /// Set until the first snapshot is sent, and again after falling behind
+    needs_snapshot: bool,
+    _slot: ObserverSlot,
+}
+
+impl Observer {
+    async fn next_event(&mut self) -> Option<Event> {
+        if self.needs_snapshot {
+            self.needs_snapshot = false;
+            return Some(snapshot_event(&self.state, &self.room).await);
+        }
+        loop {
+            match self.rx.recv().await {
+                Ok(msg) => {
+                    if let Some(event) = self.event_for(&msg) {
+                        return Some(event);
+                    }
+                }
+                // Start over from the current state rather than leave a gap
+                Err(broadcast::error::RecvError::Lagged(_)) => {
+                    return Some(snapshot_event(&self.state, &self.room).await);
+                }
+                // The room was collected; the client reconnects to a fresh one
+                Err(broadcast::error::RecvError::Closed) => return None,
+            }
+        }
+    }
+
+    /// Observers see what a peer outside every private file's audience sees, keeping
+    /// the diff form of file list changes.
+    fn event_for(&self, msg: &rooms::Broadcast) -> Option<Event> {
+        if matches!(msg.audience, rooms::Audience::Only(_)) || msg.file_list == Some(rooms::FileListForm::Full) {
+            return None;
+        }
+        let kind = serde_json::from_str::<MessageType>(&msg.json).ok()?.kind;
+        let wanted = OBSERVED_MESSAGES.contains(&kind.as_str()) || (self.chat && CHAT_MESSAGES.contains(&kind.as_str()));
+        wanted.then(|| Event::default().event(kind).data(msg.json.to_string()))
+    }
+}
+
+/// The room as it is now, without private files.
+async fn snapshot_event(state: &AppState, room: &RoomState) -> Event {
+    // Read before the state, so nothing broadcast up to `seq` can be missing from it
+    let seq = room.seq();
+    let file_list_version = room.file_list_version();
+    let peers = room.peers.read().await.values().cloned().collect();
+    let files = files::visible_files(&*room.files.read().await, None).cloned().collect();
+    let snapshot = ObserverSnapshot {
+        room: room.id.clone(),
+        seq,
+        peers,
+        files,
+        file_list_version,
+        transfers: websocket::live_transfers(state, room).await,
+    };
+    Event::default()
+        .event("snapshot")
+        .json_data(&snapshot)
+        .unwrap_or_else(|_| Event::default().comment("snapshot unavailable"))
+}
+
+/// Room events for a read-only observer that is not a peer. The stream owns its
+/// broadcast receiver and slot, so both are released as soon as the client goes away
+/// and warp drops it.
+pub fn event_stream(
+    state: AppState,
+    room: RoomState,
+    chat: bool,
+    slot: ObserverSlot,
+) -> impl futures_util::Stream<Item = Result<Event, Infallible>> + Send + 'static {
+    let observer = Observer {
+        rx: room.subscribe(),
+        state,
+        room,
+        chat,
+        needs_snapshot: true,
+        _slot: slot,
+    };
+    futures_util::stream::unfold(observer, |mut observer| async move {
+        let event = observer.next_event().await?;
+        Some((Ok(event), observer))
+    })
+}
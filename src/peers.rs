@@ -0,0 +1,360 @@
+use crate::rooms::{RoomState, Rooms};
+use crate::types::*;
+use crate::AppState;
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+pub const MAX_NAME_LENGTH: usize = 32;
+/// Minimum time between two renames of the same session.
+pub const RENAME_INTERVAL: Duration = Duration::from_secs(10);
+/// A peer that sends nothing for this long is reported idle.
+const IDLE_THRESHOLD: Duration = Duration::from_secs(120);
+/// `last_seen` is refreshed at most this often, so chunk traffic does not contend on the peers lock.
+pub const LAST_SEEN_RESOLUTION: Duration = Duration::from_secs(1);
+/// Peer pings one session may send another per second.
+const MAX_PEER_PINGS_PER_SEC: usize = 4;
+/// A relayed peer ping not answered within this long is forgotten.
+const PEER_PING_TIMEOUT: Duration = Duration::from_secs(10);
+/// Seconds covered by the recent traffic figures.
+const TRAFFIC_WINDOW_SECS: usize = 60;
+/// Sessions listed as top talkers in the server stats.
+const TOP_TALKERS: usize = 5;
+
+/// Activity counters for one session, updated as its messages are handled.
+#[derive(Debug, Default)]
+pub struct PeerCounters {
+    pub files_uploaded: AtomicU64,
+    pub bytes_sent: AtomicU64,
+    pub bytes_received: AtomicU64,
+    pub messages_sent: AtomicU64,
+    pub recent_sent: TrafficWindow,
+    pub recent_received: TrafficWindow,
+}
+
+impl PeerCounters {
+    /// Counts chunk bytes the session sent through the relay.
+    pub fn record_sent(&self, bytes: u64) {
+        self.bytes_sent.fetch_add(bytes, Ordering::Relaxed);
+        self.recent_sent.add(bytes);
+    }
+
+    /// Counts chunk bytes relayed to the session.
+    pub fn record_received(&self, bytes: u64) {
+        self.bytes_received.fetch_add(bytes, Ordering::Relaxed);
+        self.recent_received.add(bytes);
+    }
+}
+
+/// Bytes over the last minute in one-second buckets of atomics, so the chunk path
+/// never takes a lock. A bucket is cleared when first used in a new second; bytes
+/// added while another thread clears it can be lost, which an estimate can afford.
+#[derive(Debug)]
+pub struct TrafficWindow {
+    started: Instant,
+    seconds: [AtomicU64; TRAFFIC_WINDOW_SECS],
+    bytes: [AtomicU64; TRAFFIC_WINDOW_SECS],
+}
+
+impl Default for TrafficWindow {
+    fn default() -> Self {
+        TrafficWindow {
+            started: Instant::now(),
+            seconds: std::array::from_fn(|_| AtomicU64::new(0)),
+            bytes: std::array::from_fn(|_| AtomicU64::new(0)),
+        }
+    }
+}
+
+impl TrafficWindow {
+    pub fn add(&self, bytes: u64) {
+        let second = self.started.elapsed().as_secs();
+        let slot = second as usize % TRAFFIC_WINDOW_SECS;
+        if self.seconds[slot].swap(second, Ordering::Relaxed) != second {
+            self.bytes[slot].store(0, Ordering::Relaxed);
+        }
+        self.bytes[slot].fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// Bytes added in the last minute.
+    pub fn total(&self) -> u64 {
+        let second = self.started.elapsed().as_secs();
+        (0..TRAFFIC_WINDOW_SECS)
+            .filter(|&slot| second.saturating_sub(self.seconds[slot].load(Ordering::Relaxed)) < TRAFFIC_WINDOW_SECS as u64)
+            .map(|slot| self.bytes[slot].load(Ordering::Relaxed))
+            .sum()
+    }
+}
+
+/// Peer pings relayed by the server, and the round trips they measured.
+#[derive(Default)]
+pub struct LatencyTable {
+    entries: Mutex<LatencyEntries>,
+}
+
+#[derive(Default)]
+struct LatencyEntries {
+    /// When each initiator last pinged each target, for rate limiting
+    recent: HashMap<(SessionId, SessionId), VecDeque<Instant>>,
+    /// Pings relayed and not answered yet, by initiator, target and nonce
+    pending: HashMap<(SessionId, SessionId, u64), Instant>,
+    /// Last round trip from the server through the other peer, per unordered pair
+    rtt: HashMap<(SessionId, SessionId), Duration>,
+}
+
+impl LatencyTable {
+    /// Notes a ping about to be relayed. False when the initiator already pinged the
+    /// target too often in the last second.
+    pub fn start_ping(&self, from: &SessionId, to: &SessionId, nonce: u64) -> bool {
+        let now = Instant::now();
+        let mut entries = self.entries.lock().unwrap();
+        entries.pending.retain(|_, sent| now.duration_since(*sent) < PEER_PING_TIMEOUT);
+        let recent = entries.recent.entry((from.clone(), to.clone())).or_default();
+        while recent.front().is_some_and(|sent| now.duration_since(*sent) >= Duration::from_secs(1)) {
+            recent.pop_front();
+        }
+        if recent.len() >= MAX_PEER_PINGS_PER_SEC {
+            return false;
+        }
+        recent.push_back(now);
+        entries.pending.insert((from.clone(), to.clone(), nonce), now);
+        true
+    }
+
+    /// Notes the pong to a relayed ping, returning the round trip the server saw through
+    /// the target. None for a pong to a ping the server did not relay or has forgotten.
+    pub fn finish_ping(&self, initiator: &SessionId, target: &SessionId, nonce: u64) -> Option<Duration> {
+        let mut entries = self.entries.lock().unwrap();
+        let sent = entries.pending.remove(&(initiator.clone(), target.clone(), nonce))?;
+        let rtt = sent.elapsed();
+        entries.rtt.insert(unordered(initiator, target), rtt);
+        Some(rtt)
+    }
+
+    /// The last round trip measured between the two peers, if any.
+    pub fn rtt(&self, a: &SessionId, b: &SessionId) -> Option<Duration> {
+        self.entries.lock().unwrap().rtt.get(&unordered(a, b)).copied()
+    }
+
+    pub fn forget_session(&self, session_id: &SessionId) {
+        let mut entries = self.entries.lock().unwrap();
+        entries.recent.retain(|(from, to), _| from != session_id && to != session_id);
+        entries.pending.retain(|(from, to, _), _| from != session_id && to != session_id);
+        entries.rtt.retain(|(a, b), _| a != session_id && b != session_id);
+    }
+}
+
+fn unordered(a: &SessionId, b: &SessionId) -> (SessionId, SessionId) {
+    if a <= b {
+        (a.clone(), b.clone())
+    } else {
+        (b.clone(), a.clone())
+    }
+}
+
+/// The peer's counters plus the figures derived from the room.
+pub async fn peer_stats(state: &AppState, room: &RoomState, peer: &PeerInfo) -> PeerCounterStats {
+    let files_hosted = {
+        let files = room.files.read().await;
+        files.values().filter(|file| file.hosts.contains(&peer.session_id)).count()
+    };
+    let counters = {
+        let sessions = state.sessions.read().await;
+        sessions.get(&peer.session_id).map(|handle| handle.counters.clone())
+    };
+    let counter = |field: fn(&PeerCounters) -> &AtomicU64| {
+        counters.as_ref().map_or(0, |counters| field(counters).load(Ordering::Relaxed))
+    };
+
+    PeerCounterStats {
+        files_hosted,
+        files_uploaded: counter(|c| &c.files_uploaded),
+        bytes_sent: counter(|c| &c.bytes_sent),
+        bytes_received: counter(|c| &c.bytes_received),
+        messages_sent: counter(|c| &c.messages_sent),
+        bytes_sent_last_minute: counters.as_ref().map_or(0, |counters| counters.recent_sent.total()),
+        bytes_received_last_minute: counters.as_ref().map_or(0, |counters| counters.recent_received.total()),
+        connected_secs: (chrono::Utc::now() - peer.connected_at).num_seconds().max(0) as u64,
+    }
+}
+
+/// The connected sessions that relayed the most bytes in the last minute, busiest first.
+pub async fn top_talkers(state: &AppState) -> Vec<TopTalker> {
+    let mut talkers = {
+        let sessions = state.sessions.read().await;
+        sessions
+            .iter()
+            .map(|(session_id, handle)| TopTalker {
+                session_id: session_id.clone(),
+                name: None,
+                room: handle.room.clone(),
+                bytes_sent_last_minute: handle.counters.recent_sent.total(),
+                bytes_received_last_minute: handle.counters.recent_received.total(),
+                bytes_sent: handle.counters.bytes_sent.load(Ordering::Relaxed),
+                bytes_received: handle.counters.bytes_received.load(Ordering::Relaxed),
+            })
+            .filter(|talker| talker.bytes_sent_last_minute + talker.bytes_received_last_minute > 0)
+            .collect::<Vec<_>>()
+    };
+    talkers.sort_by_key(|talker| std::cmp::Reverse(talker.bytes_sent_last_minute + talker.bytes_received_last_minute));
+    talkers.truncate(TOP_TALKERS);
+
+    for talker in &mut talkers {
+        if let Some(room) = state.room(&talker.room).await {
+            talker.name = room.peers.read().await.get(&talker.session_id).and_then(|peer| peer.name.clone());
+        }
+    }
+    talkers
+}
+
+/// Whether a connected session declared `capability` in its `Join`.
+pub async fn peer_supports(state: &AppState, session_id: &SessionId, capability: &str) -> bool {
+    let room_id = match state.sessions.read().await.get(session_id) {
+        Some(handle) => handle.room.clone(),
+        None => return false,
+    };
+    match state.room(&room_id).await {
+        Some(room) => room
+            .peers
+            .read()
+            .await
+            .get(session_id)
+            .is_some_and(|peer| peer.capabilities.contains(capability)),
+        None => false,
+    }
+}
+
+/// Trims a requested display name, drops control characters and caps its length.
+/// Returns `None` for names that end up empty.
+pub fn sanitize_name(name: &str) -> Option<String> {
+    let name: String = name
+        .trim()
+        .chars()
+        .filter(|c| !c.is_control())
+        .take(MAX_NAME_LENGTH)
+        .collect();
+    let name = name.trim_end();
+    (!name.is_empty()).then(|| name.to_string())
+}
+
+/// Makes `name` unique among the other peers of a room by appending " (2)", " (3)", ...
+/// Names are compared case-insensitively.
+pub fn unique_name(peers: &HashMap<SessionId, PeerInfo>, session_id: &SessionId, name: String) -> String {
+    let taken = |candidate: &str| {
+        peers.values().any(|peer| {
+            &peer.session_id != session_id
+                && peer.name.as_deref().is_some_and(|n| n.to_lowercase() == candidate.to_lowercase())
+        })
+    };
+    if !taken(&name) {
+        return name;
+    }
+    (2..)
+        .map(|n| format!("{name} ({n})"))
+        .find(|candidate| !taken(candidate))
+        .unwrap_or(name)
+}
+
+/// Picks a color hue (0-359) for a joining peer: the one furthest from the hues of
+/// the peers already in the room, starting from a hash of the session id so that
+/// ties, and an empty room, resolve the same way every time.
+pub fn assign_hue(peers: &HashMap<SessionId, PeerInfo>, session_id: &SessionId) -> u16 {
+    let preferred = (session_id
+        .bytes()
+        .fold(2166136261u32, |hash, b| (hash ^ b as u32).wrapping_mul(16777619))
+        % 360) as u16;
+    let distance = |a: u16, b: u16| {
+        let d = a.abs_diff(b);
+        d.min(360 - d)
+    };
+
+    (0..360)
+        .map(|offset| (preferred + offset) % 360)
+        .max_by_key(|&hue| {
+            let nearest = peers
+                .values()
+                .filter(|peer| &peer.session_id != session_id)
+                .map(|peer| distance(hue, peer.color_hue))
+                .min()
+                .unwrap_or(180);
+            // Prefer hues closer to the hashed one among equally spread candidates
+            (nearest, std::cmp::Reverse(distance(hue, preferred)))
+        })
+        .unwrap_or(preferred)
+}
+
+/// Marks peers that have been silent past the idle threshold and announces each
+/// transition. Peers become active again on their next message.
+pub async fn mark_idle_peers(rooms: &Rooms) {
+    let rooms = {
+        let rooms = rooms.read().await;
+        rooms.values().cloned().collect::<Vec<_>>()
+    };
+    let cutoff = chrono::Utc::now() - chrono::Duration::from_std(IDLE_THRESHOLD).unwrap_or_default();
+
+    for room in rooms {
+        let mut peers = room.peers.write().await;
+        for peer in peers.values_mut() {
+            if peer.activity == ActivityState::Active && peer.last_seen < cutoff {
+                peer.activity = ActivityState::Idle;
+                room.send(ServerMessage::PeerActivity {
+                    session_id: peer.session_id.clone(),
+                    state: ActivityState::Idle,
+                });
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Times counting a relayed chunk for its sender and recipient, with and without
+    /// the last-minute windows, next to serializing the 64 KiB chunk the relay forwards.
+    /// Run with `cargo test --release traffic_counter_benchmark -- --ignored --nocapture`.
+    #[test]
+    #[ignore]
+    fn traffic_counter_benchmark() {
+        const CHUNKS: u32 = 1_000_000;
+        const BYTES: u64 = DEFAULT_CHUNK_SIZE as u64;
+        let (sender, recipient) = (PeerCounters::default(), PeerCounters::default());
+        let per_chunk = |rounds: u32, f: &mut dyn FnMut()| {
+            let started = Instant::now();
+            for _ in 0..rounds {
+                f();
+            }
+            started.elapsed() / rounds
+        };
+
+        let totals_only = per_chunk(CHUNKS, &mut || {
+            sender.bytes_sent.fetch_add(std::hint::black_box(BYTES), Ordering::Relaxed);
+            recipient.bytes_received.fetch_add(std::hint::black_box(BYTES), Ordering::Relaxed);
+        });
+        let windowed = per_chunk(CHUNKS, &mut || {
+            sender.record_sent(std::hint::black_box(BYTES));
+            recipient.record_received(std::hint::black_box(BYTES));
+        });
+        assert!(sender.recent_sent.total() > 0 && recipient.recent_received.total() > 0);
+
+        let chunk = ServerMessage::FileChunk {
+            file_id: "file_1".to_string(),
+            chunk_index: 0,
+            total_chunks: 1,
+            offset: Some(0),
+            len: Some(DEFAULT_CHUNK_SIZE),
+            data: "A".repeat(BYTES as usize * 4 / 3),
+            from_session_id: "alice".to_string(),
+            target_session_id: "bob".to_string(),
+            compression: None,
+            encrypted: false,
+            nonce: None,
+        };
+        let serialized = per_chunk(1000, &mut || {
+            std::hint::black_box(serde_json::to_string(&chunk).unwrap());
+        });
+
+        println!("Per chunk: {totals_only:?} counting totals, {windowed:?} with last-minute windows, {serialized:?} serializing it");
+    }
+}
@@ -0,0 +1,254 @@
+use crate::transfers::TransferKey;
+use crate::types::SessionId;
+use crate::relay::Relay;
+use bytes::Buf;
+use futures_util::{Stream, StreamExt};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+
+/// How long a pipe waits for the host's upload and the requester's download to both
+/// show up
+pub const PIPE_TIMEOUT: Duration = Duration::from_secs(60);
+/// Chunks held between the upload and the download, so a pipe never buffers more than
+/// this many times the transfer's chunk size
+const PIPE_CAPACITY: usize = 4;
+
+pub type PipeSender = mpsc::Sender<Result<Vec<u8>, std::io::Error>>;
+pub type PipeReceiver = mpsc::Receiver<Result<Vec<u8>, std::io::Error>>;
+
+/// A relayed transfer carried over two HTTP requests: the host's `PUT` of the file and
+/// the requester's `GET`, joined by a bounded channel.
+struct Pipe {
+    key: TransferKey,
+    chunk_size: u32,
+    opened: Instant,
+    /// Taken by the host's upload
+    tx: Option<PipeSender>,
+    /// Taken by the requester's download
+    rx: Option<PipeReceiver>,
+}
+
+/// One side of a pipe, claimed by the session it was opened for.
+pub struct PipeEnd<T> {
+    pub key: TransferKey,
+    pub chunk_size: u32,
+    pub end: T,
+}
+
+/// Relay pipes whose two sides have not both connected yet.
+#[derive(Default)]
+pub struct Pipes {
+    pipes: Mutex<HashMap<String, Pipe>>,
+}
+
+impl Pipes {
+    /// Opens a pipe for the transfer and returns its id, which is unguessable and only
+    /// told to the two peers.
+    pub fn open(&self, key: &TransferKey, chunk_size: u32) -> String {
+        let id = format!("{:032x}", rand::random::<u128>());
+        let (tx, rx) = mpsc::channel(PIPE_CAPACITY);
+        self.pipes.lock().unwrap().insert(id.clone(), Pipe {
+            key: key.clone(),
+            chunk_size,
+            opened: Instant::now(),
+            tx: Some(tx),
+            rx: Some(rx),
+        });
+        id
+    }
+
+    /// The sending side of the pipe, if `session_id` is the transfer's host and has not
+    /// claimed it yet.
+    pub fn claim_upload(&self, id: &str, session_id: &SessionId) -> Option<PipeEnd<PipeSender>> {
+        self.claim(id, |pipe| (&pipe.key.1 == session_id).then(|| pipe.tx.take()).flatten())
+    }
+
+    /// The receiving side of the pipe, if `session_id` is the transfer's requester and
+    /// has not claimed it yet.
+    pub fn claim_download(&self, id: &str, session_id: &SessionId) -> Option<PipeEnd<PipeReceiver>> {
+        self.claim(id, |pipe| (&pipe.key.2 == session_id).then(|| pipe.rx.take()).flatten())
+    }
+
+    fn claim<T>(&self, id: &str, take: impl FnOnce(&mut Pipe) -> Option<T>) -> Option<PipeEnd<T>> {
+        let mut pipes = self.pipes.lock().unwrap();
+        let pipe = pipes.get_mut(id)?;
+        let end = take(pipe)?;
+        let claimed = PipeEnd {
+            key: pipe.key.clone(),
+            chunk_size: pipe.chunk_size,
+            end,
+        };
+        // Both sides now hold their end; the id cannot be used again
+        if pipe.tx.is_none() && pipe.rx.is_none() {
+            pipes.remove(id);
+        }
+        Some(claimed)
+    }
+
+    /// Drops the pipes a side did not connect to in time. The side that did sees its
+    /// stream end early.
+    pub fn expire(&self) {
+        self.expire_at(Instant::now());
+    }
+
+    fn expire_at(&self, now: Instant) {
+        self.pipes.lock().unwrap().retain(|id, pipe| {
+            let live = now.duration_since(pipe.opened) < PIPE_TIMEOUT;
+            if !live {
+                let (file_id, from, to) = &pipe.key;
+                tracing::debug!(relay_id = id, file_id, from, to, "Relay pipe expired");
+            }
+            live
+        });
+    }
+}
+
+/// Copies the host's upload into the pipe in chunks of the transfer's size, counting
+/// and throttling each like a relayed `FileChunk`. Fails if the upload is not exactly
+/// `size` bytes, the requester goes away, or the transfer is cancelled meanwhile.
+pub async fn pump<B: Buf>(
+    relay: &Relay,
+    key: &TransferKey,
+    size: u64,
+    chunk_size: u32,
+    body: impl Stream<Item = Result<B, warp::Error>>,
+    tx: &PipeSender,
+) -> Result<(), String> {
+    let chunk_size = chunk_size.max(1) as usize;
+    let total_chunks = u32::try_from(size.div_ceil(chunk_size as u64)).unwrap_or(u32::MAX);
+    let mut chunk = Vec::with_capacity(chunk_size);
+    let mut sent = 0u64;
+    futures_util::pin_mut!(body);
+
+    while let Some(data) = body.next().await {
+        let mut data = data.map_err(|e| format!("Upload failed: {e}"))?;
+        while data.has_remaining() {
+            let take = data.chunk().len().min(chunk_size - chunk.len());
+            chunk.extend_from_slice(&data.chunk()[..take]);
+            data.advance(take);
+            if sent + chunk.len() as u64 > size {
+                return Err(format!("Upload is larger than the file's {size} bytes"));
+            }
+            if chunk.len() == chunk_size {
+                sent += send_chunk(relay, key, total_chunks, std::mem::take(&mut chunk), tx).await?;
+            }
+        }
+    }
+    if !chunk.is_empty() {
+        sent += send_chunk(relay, key, total_chunks, chunk, tx).await?;
+    }
+    if sent < size {
+        return Err(format!("Upload ended after {sent} of {size} bytes"));
+    }
+    Ok(())
+}
+
+async fn send_chunk(relay: &Relay, key: &TransferKey, total_chunks: u32, chunk: Vec<u8>, tx: &PipeSender) -> Result<u64, String> {
+    if !relay.transfers.has_transfer(key) {
+        return Err("Transfer was cancelled".to_string());
+    }
+    let bytes = chunk.len() as u64;
+    relay.throttle(key, bytes, total_chunks).await;
+    tx.send(Ok(chunk)).await.map_err(|_| "Requester stopped downloading".to_string())?;
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transfers::tests::{key, table};
+    use crate::transfers::TransferDetails;
+
+    fn relay() -> Relay {
+        Relay::new(None, None, table())
+    }
+
+    /// Pumps `parts` as the host's upload of a `size` byte file in 4 byte chunks, and
+    /// returns the outcome with the chunks the requester received.
+    async fn upload(relay: &Relay, size: u64, parts: &[&[u8]]) -> (Result<(), String>, Vec<Vec<u8>>) {
+        let pipes = Pipes::default();
+        let id = pipes.open(&key(), 4);
+        let tx = pipes.claim_upload(&id, &"host".to_string()).unwrap();
+        let mut rx = pipes.claim_download(&id, &"requester".to_string()).unwrap();
+        let body = futures_util::stream::iter(parts.iter().map(|part| Ok::<_, warp::Error>(bytes::Bytes::copy_from_slice(part))));
+        let result = pump(relay, &tx.key, size, tx.chunk_size, body, &tx.end).await;
+        drop(tx);
+        let mut received = Vec::new();
+        while let Some(chunk) = rx.end.recv().await {
+            received.push(chunk.unwrap());
+        }
+        (result, received)
+    }
+
+    #[tokio::test]
+    async fn pumps_the_upload_in_chunks_of_the_transfer_size() {
+        let relay = relay();
+        relay.transfers.begin_transfer(&key(), TransferDetails::default());
+        let (result, received) = upload(&relay, 10, &[b"abc", b"defghij"]).await;
+        assert_eq!(result, Ok(()));
+        assert_eq!(received, [b"abcd".to_vec(), b"efgh".to_vec(), b"ij".to_vec()]);
+    }
+
+    #[tokio::test]
+    async fn short_upload_fails() {
+        let relay = relay();
+        relay.transfers.begin_transfer(&key(), TransferDetails::default());
+        let (result, _) = upload(&relay, 10, &[b"abcdef"]).await;
+        assert_eq!(result, Err("Upload ended after 6 of 10 bytes".to_string()));
+    }
+
+    #[tokio::test]
+    async fn long_upload_fails_before_the_extra_bytes_are_sent() {
+        let relay = relay();
+        relay.transfers.begin_transfer(&key(), TransferDetails::default());
+        let (result, received) = upload(&relay, 10, &[b"abcdefgh", b"ijkl"]).await;
+        assert_eq!(result, Err("Upload is larger than the file's 10 bytes".to_string()));
+        assert_eq!(received.concat(), b"abcdefgh");
+    }
+
+    #[tokio::test]
+    async fn upload_of_a_cancelled_transfer_fails() {
+        let (result, received) = upload(&relay(), 4, &[b"abcd"]).await;
+        assert_eq!(result, Err("Transfer was cancelled".to_string()));
+        assert!(received.is_empty());
+    }
+
+    #[test]
+    fn each_side_is_claimed_once_by_its_own_peer() {
+        let pipes = Pipes::default();
+        let id = pipes.open(&key(), 4);
+        let (host, requester) = ("host".to_string(), "requester".to_string());
+
+        assert!(pipes.claim_upload(&id, &requester).is_none());
+        assert!(pipes.claim_download(&id, &host).is_none());
+        assert!(pipes.claim_upload(&id, &host).is_some());
+        assert!(pipes.claim_upload(&id, &host).is_none());
+        assert!(pipes.claim_download(&id, &requester).is_some());
+        assert!(pipes.claim_download(&id, &requester).is_none());
+        assert!(pipes.pipes.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn unknown_pipe_cannot_be_claimed() {
+        let pipes = Pipes::default();
+        pipes.open(&key(), 4);
+        assert!(pipes.claim_upload("0123", &"host".to_string()).is_none());
+    }
+
+    #[test]
+    fn unclaimed_pipes_expire_after_the_timeout() {
+        let pipes = Pipes::default();
+        let id = pipes.open(&key(), 4);
+        let half_claimed = pipes.open(&key(), 4);
+        assert!(pipes.claim_upload(&half_claimed, &"host".to_string()).is_some());
+
+        pipes.expire_at(Instant::now() + PIPE_TIMEOUT - Duration::from_secs(1));
+        assert_eq!(pipes.pipes.lock().unwrap().len(), 2);
+
+        pipes.expire_at(Instant::now() + PIPE_TIMEOUT);
+        assert!(pipes.claim_upload(&id, &"host".to_string()).is_none());
+        assert!(pipes.claim_download(&half_claimed, &"requester".to_string()).is_none());
+    }
+}
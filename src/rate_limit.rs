@@ -0,0 +1,289 @@
+use crate::types::*;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Rejected messages within `STRIKE_WINDOW` after which a session is disconnected
+const MAX_STRIKES: u32 = 50;
+const STRIKE_WINDOW: Duration = Duration::from_secs(60);
+
+/// How generous the message budgets are, set with `--rate-profile`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum RateProfile {
+    Relaxed,
+    Default,
+    Strict,
+}
+
+/// Kinds of client messages that share a budget. Chunk traffic is left to the relay's
+/// bandwidth limits and backpressure, and other messages are not limited.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum MessageClass {
+    Chat,
+    FileAnnouncement,
+    DownloadRequest,
+    Rename,
+}
+
+impl MessageClass {
+    pub fn of(msg: &ClientMessage) -> Option<MessageClass> {
+        match msg {
+            ClientMessage::TextMessage { .. } | ClientMessage::ShareSnippet { .. } => Some(MessageClass::Chat),
+            ClientMessage::FileUpload { .. }
+            | ClientMessage::FileUploadBatch { .. }
+            | ClientMessage::UpdateFileInfo { .. }
+            | ClientMessage::ReplaceFile { .. }
+            | ClientMessage::RemoveFile { .. }
+            | ClientMessage::RestoreFile { .. } => Some(MessageClass::FileAnnouncement),
+            ClientMessage::RequestDownload { .. } => Some(MessageClass::DownloadRequest),
+            ClientMessage::Rename { .. } => Some(MessageClass::Rename),
+            _ => None,
+        }
+    }
+
+    /// Messages allowed per period under the default profile, and the period.
+    fn default_budget(self) -> (u32, Duration) {
+        match self {
+            MessageClass::Chat => (5, Duration::from_secs(10)),
+            MessageClass::FileAnnouncement => (20, Duration::from_secs(60)),
+            MessageClass::DownloadRequest => (30, Duration::from_secs(60)),
+            MessageClass::Rename => (5, Duration::from_secs(60)),
+        }
+    }
+}
+
+/// A budget of messages per period: up to `capacity` at once, refilled evenly.
+#[derive(Debug, Clone, Copy)]
+pub struct Budget {
+    pub capacity: u32,
+    pub period: Duration,
+}
+
+impl Budget {
+    pub fn of(class: MessageClass, profile: RateProfile) -> Budget {
+        let (capacity, period) = class.default_budget();
+        let capacity = match profile {
+            RateProfile::Relaxed => capacity * 4,
+            RateProfile::Default => capacity,
+            RateProfile::Strict => (capacity / 2).max(1),
+        };
+        Budget { capacity, period }
+    }
+
+    fn refill_per_sec(&self) -> f64 {
+        f64::from(self.capacity) / self.period.as_secs_f64()
+    }
+}
+
+/// A token bucket that starts full.
+#[derive(Debug, Clone, Copy)]
+pub struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    pub fn new(budget: Budget, now: Instant) -> Bucket {
+        Bucket {
+            tokens: f64::from(budget.capacity),
+            updated: now,
+        }
+    }
+
+    /// Spends a token, or returns how long until one is available.
+    pub fn take(&mut self, budget: Budget, now: Instant) -> Result<(), Duration> {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * budget.refill_per_sec()).min(f64::from(budget.capacity));
+        self.updated = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            return Ok(());
+        }
+        Err(Duration::from_secs_f64((1.0 - self.tokens) / budget.refill_per_sec()))
+    }
+}
+
+#[derive(Default)]
+struct SessionLimits {
+    buckets: HashMap<MessageClass, Bucket>,
+    strikes: u32,
+    first_strike: Option<Instant>,
+}
+
+/// Why a message was refused.
+pub enum Limited {
+    /// Over budget; try again after this long
+    RetryAfter(Duration),
+    /// Over budget so often the session should be disconnected
+    Abusive,
+}
+
+/// Message budgets of each session.
+pub struct RateLimits {
+    profile: RateProfile,
+    sessions: Mutex<HashMap<SessionId, SessionLimits>>,
+}
+
+impl RateLimits {
+    pub fn new(profile: RateProfile) -> Self {
+        RateLimits {
+            profile,
+            sessions: Mutex::default(),
+        }
+    }
+
+    /// Spends from the budget of the message's class, if it has one.
+    pub fn check(&self, session_id: &SessionId, msg: &ClientMessage) -> Result<(), Limited> {
+        self.check_at(session_id, msg, Instant::now())
+    }
+
+    fn check_at(&self, session_id: &SessionId, msg: &ClientMessage, now: Instant) -> Result<(), Limited> {
+        let Some(class) = MessageClass::of(msg) else {
+            return Ok(());
+        };
+        let budget = Budget::of(class, self.profile);
+        let mut sessions = self.sessions.lock().unwrap();
+        let limits = sessions.entry(session_id.clone()).or_default();
+        let bucket = limits.buckets.entry(class).or_insert_with(|| Bucket::new(budget, now));
+        let Err(retry_after) = bucket.take(budget, now) else {
+            return Ok(());
+        };
+
+        if limits.first_strike.is_none_or(|at| now.duration_since(at) >= STRIKE_WINDOW) {
+            limits.first_strike = Some(now);
+            limits.strikes = 0;
+        }
+        limits.strikes += 1;
+        if limits.strikes >= MAX_STRIKES {
+            return Err(Limited::Abusive);
+        }
+        Err(Limited::RetryAfter(retry_after))
+    }
+
+    pub fn forget_session(&self, session_id: &SessionId) {
+        self.sessions.lock().unwrap().remove(session_id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(value: serde_json::Value) -> ClientMessage {
+        serde_json::from_value(value).unwrap()
+    }
+
+    fn chat() -> ClientMessage {
+        message(serde_json::json!({ "type": "text_message", "session_id": "alice", "content": "hi" }))
+    }
+
+    fn rename() -> ClientMessage {
+        message(serde_json::json!({ "type": "rename", "session_id": "alice", "new_name": "Alice" }))
+    }
+
+    fn ping() -> ClientMessage {
+        message(serde_json::json!({ "type": "ping", "session_id": "alice" }))
+    }
+
+    #[test]
+    fn classifies_messages() {
+        assert_eq!(MessageClass::of(&chat()), Some(MessageClass::Chat));
+        assert_eq!(MessageClass::of(&rename()), Some(MessageClass::Rename));
+        assert_eq!(MessageClass::of(&ping()), None);
+    }
+
+    #[test]
+    fn profiles_scale_the_default_budget() {
+        let capacity = |class, profile| Budget::of(class, profile).capacity;
+        assert_eq!(capacity(MessageClass::Chat, RateProfile::Default), 5);
+        assert_eq!(capacity(MessageClass::Chat, RateProfile::Relaxed), 20);
+        assert_eq!(capacity(MessageClass::Chat, RateProfile::Strict), 2);
+        assert_eq!(capacity(MessageClass::DownloadRequest, RateProfile::Strict), 15);
+        assert_eq!(Budget::of(MessageClass::Chat, RateProfile::Strict).period, Duration::from_secs(10));
+    }
+
+    #[test]
+    fn bucket_allows_a_burst_then_refills_evenly() {
+        let budget = Budget {
+            capacity: 2,
+            period: Duration::from_secs(10),
+        };
+        let start = Instant::now();
+        let mut bucket = Bucket::new(budget, start);
+        assert!(bucket.take(budget, start).is_ok());
+        assert!(bucket.take(budget, start).is_ok());
+        assert_eq!(bucket.take(budget, start), Err(Duration::from_secs(5)));
+
+        // One token comes back every 5 seconds
+        assert_eq!(bucket.take(budget, start + Duration::from_secs(2)), Err(Duration::from_secs(3)));
+        assert!(bucket.take(budget, start + Duration::from_secs(5)).is_ok());
+        assert!(bucket.take(budget, start + Duration::from_secs(5)).is_err());
+    }
+
+    #[test]
+    fn bucket_never_holds_more_than_its_capacity() {
+        let budget = Budget {
+            capacity: 2,
+            period: Duration::from_secs(10),
+        };
+        let start = Instant::now();
+        let mut bucket = Bucket::new(budget, start);
+        let later = start + Duration::from_secs(3600);
+        assert!(bucket.take(budget, later).is_ok());
+        assert!(bucket.take(budget, later).is_ok());
+        assert!(bucket.take(budget, later).is_err());
+    }
+
+    #[test]
+    fn classes_and_sessions_have_separate_budgets() {
+        let limits = RateLimits::new(RateProfile::Default);
+        let (alice, bob) = ("alice".to_string(), "bob".to_string());
+        let now = Instant::now();
+        for _ in 0..5 {
+            assert!(limits.check_at(&alice, &chat(), now).is_ok());
+        }
+        assert!(matches!(limits.check_at(&alice, &chat(), now), Err(Limited::RetryAfter(_))));
+        assert!(limits.check_at(&alice, &rename(), now).is_ok());
+        assert!(limits.check_at(&bob, &chat(), now).is_ok());
+        // Messages without a class are never limited
+        for _ in 0..100 {
+            assert!(limits.check_at(&alice, &ping(), now).is_ok());
+        }
+
+        limits.forget_session(&alice);
+        assert!(limits.check_at(&alice, &chat(), now).is_ok());
+    }
+
+    #[test]
+    fn repeated_strikes_within_the_window_are_abusive() {
+        let limits = RateLimits::new(RateProfile::Default);
+        let alice = "alice".to_string();
+        let now = Instant::now();
+        for _ in 0..5 {
+            assert!(limits.check_at(&alice, &chat(), now).is_ok());
+        }
+        for _ in 1..MAX_STRIKES {
+            assert!(matches!(limits.check_at(&alice, &chat(), now), Err(Limited::RetryAfter(_))));
+        }
+        assert!(matches!(limits.check_at(&alice, &chat(), now), Err(Limited::Abusive)));
+    }
+
+    #[test]
+    fn strikes_are_forgotten_after_the_window() {
+        let limits = RateLimits::new(RateProfile::Default);
+        let alice = "alice".to_string();
+        let start = Instant::now();
+        for _ in 0..5 {
+            assert!(limits.check_at(&alice, &chat(), start).is_ok());
+        }
+        for _ in 1..MAX_STRIKES {
+            assert!(limits.check_at(&alice, &chat(), start).is_err());
+        }
+        // A strike after the window starts a new count, even though the budget is spent
+        let later = start + STRIKE_WINDOW;
+        for _ in 0..5 {
+            assert!(limits.check_at(&alice, &chat(), later).is_ok());
+        }
+        assert!(matches!(limits.check_at(&alice, &chat(), later), Err(Limited::RetryAfter(_))));
+    }
+}
@@ -0,0 +1,241 @@
+use crate::transfers::{TransferKey, TransferTable};
+use crate::types::*;
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+const THROUGHPUT_WINDOW_SECS: u64 = 5;
+/// How long two peers whose direct connection failed are kept on the relay, in case
+/// the network changes.
+const RELAY_ONLY_COOLDOWN: Duration = Duration::from_secs(10 * 60);
+
+/// Token bucket that lets callers run into debt and then waits it off, so
+/// concurrent senders are delayed in arrival order rather than starved.
+pub struct TokenBucket {
+    rate: f64,
+    state: tokio::sync::Mutex<BucketState>,
+}
+
+struct BucketState {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    /// Creates a bucket refilling at `rate` bytes per second with a one second burst.
+    pub fn new(rate: u64) -> Self {
+        TokenBucket {
+            rate: rate as f64,
+            state: tokio::sync::Mutex::new(BucketState {
+                tokens: rate as f64,
+                last_refill: Instant::now(),
+            }),
+        }
+    }
+
+    pub async fn acquire(&self, bytes: u64) {
+        let wait = {
+            let mut state = self.state.lock().await;
+            let now = Instant::now();
+            let elapsed = now.duration_since(state.last_refill).as_secs_f64();
+            state.last_refill = now;
+            state.tokens = (state.tokens + elapsed * self.rate).min(self.rate);
+            state.tokens -= bytes as f64;
+            if state.tokens < 0.0 {
+                Duration::from_secs_f64(-state.tokens / self.rate)
+            } else {
+                Duration::ZERO
+            }
+        };
+
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+/// Bytes per second over a short sliding window of one-second buckets.
+pub struct ThroughputMeter {
+    started: Instant,
+    buckets: VecDeque<(u64, u64)>,
+}
+
+impl ThroughputMeter {
+    pub fn new() -> Self {
+        ThroughputMeter {
+            started: Instant::now(),
+            buckets: VecDeque::new(),
+        }
+    }
+
+    pub fn record(&mut self, bytes: u64) {
+        let second = self.started.elapsed().as_secs();
+        match self.buckets.back_mut() {
+            Some((bucket_second, total)) if *bucket_second == second => *total += bytes,
+            _ => self.buckets.push_back((second, bytes)),
+        }
+        self.evict(second);
+    }
+
+    pub fn rate(&mut self) -> u64 {
+        let second = self.started.elapsed().as_secs();
+        self.evict(second);
+        self.buckets.iter().map(|(_, bytes)| bytes).sum::<u64>() / THROUGHPUT_WINDOW_SECS
+    }
+
+    fn evict(&mut self, now: u64) {
+        while let Some((second, _)) = self.buckets.front() {
+            if second + THROUGHPUT_WINDOW_SECS <= now {
+                self.buckets.pop_front();
+            } else {
+                break;
+            }
+        }
+    }
+}
+
+/// Rate limiting and accounting for chunk payloads forwarded by the server.
+pub struct Relay {
+    limit: Option<u64>,
+    per_transfer_limit: Option<u64>,
+    limiter: Option<TokenBucket>,
+    meter: Mutex<ThroughputMeter>,
+    bytes_total: AtomicU64,
+    /// Peer pairs whose direct connection failed, until when they skip it
+    relay_only: Mutex<HashMap<(SessionId, SessionId), Instant>>,
+    p2p_fallbacks: AtomicU64,
+    pub transfers: TransferTable,
+}
+
+impl Relay {
+    pub fn new(limit: Option<u64>, per_transfer_limit: Option<u64>, transfers: TransferTable) -> Self {
+        Relay {
+            limit,
+            per_transfer_limit,
+            limiter: limit.map(TokenBucket::new),
+            meter: Mutex::new(ThroughputMeter::new()),
+            bytes_total: AtomicU64::new(0),
+            relay_only: Mutex::new(HashMap::new()),
+            p2p_fallbacks: AtomicU64::new(0),
+            transfers,
+        }
+    }
+
+    /// Waits until `bytes` may be forwarded for this transfer, then records them as
+    /// one of its `total_chunks` chunks. Returns true once the transfer has all of them.
+    pub async fn throttle(&self, key: &TransferKey, bytes: u64, total_chunks: u32) -> bool {
+        if let Some(rate) = self.per_transfer_limit {
+            self.transfers.limiter(key, rate).acquire(bytes).await;
+        }
+        if let Some(limiter) = self.transfers.requested_limiter(key) {
+            limiter.acquire(bytes).await;
+        }
+        if let Some(limiter) = &self.limiter {
+            limiter.acquire(bytes).await;
+        }
+
+        self.bytes_total.fetch_add(bytes, Ordering::Relaxed);
+        self.meter.lock().unwrap().record(bytes);
+        self.transfers.record_chunk(key, bytes, total_chunks)
+    }
+
+    /// Sends the two peers' transfers through the relay for a while, after their direct
+    /// connection failed.
+    pub fn mark_relay_only(&self, a: &SessionId, b: &SessionId) {
+        let now = Instant::now();
+        let mut relay_only = self.relay_only.lock().unwrap();
+        relay_only.retain(|_, until| *until > now);
+        relay_only.insert(Self::pair(a, b), now + RELAY_ONLY_COOLDOWN);
+    }
+
+    pub fn is_relay_only(&self, a: &SessionId, b: &SessionId) -> bool {
+        self.relay_only.lock().unwrap().get(&Self::pair(a, b)).is_some_and(|until| *until > Instant::now())
+    }
+
+    /// Counts a transfer moved to the relay because a direct connection failed or was skipped.
+    pub fn record_fallback(&self) {
+        self.p2p_fallbacks.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn pair(a: &SessionId, b: &SessionId) -> (SessionId, SessionId) {
+        if a <= b {
+            (a.clone(), b.clone())
+        } else {
+            (b.clone(), a.clone())
+        }
+    }
+
+    pub fn stats(&self) -> RelayStats {
+        let (active_transfers, queued_downloads) = self.transfers.counts();
+        let limits = self.transfers.limits();
+        RelayStats {
+            active_transfers,
+            queued_downloads,
+            max_transfers: limits.total,
+            max_transfers_per_peer: limits.per_peer,
+            bytes_total: self.bytes_total.load(Ordering::Relaxed),
+            throughput_bps: self.meter.lock().unwrap().rate(),
+            limit_bps: self.limit,
+            per_transfer_limit_bps: self.per_transfer_limit,
+            p2p_fallbacks: self.p2p_fallbacks.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transfers::tests::{key, table};
+    use crate::transfers::TransferDetails;
+
+    /// How long relaying `chunks` chunks of `bytes` each takes.
+    async fn relay_time(relay: &Relay, chunks: u32, bytes: u64) -> Duration {
+        let started = Instant::now();
+        for _ in 0..chunks {
+            relay.throttle(&key(), bytes, chunks).await;
+        }
+        started.elapsed()
+    }
+
+    #[tokio::test]
+    async fn requested_rate_caps_the_throughput() {
+        let relay = Relay::new(None, None, table());
+        relay.transfers.begin_transfer(&key(), TransferDetails::default());
+        assert!(relay.transfers.set_requested_rate(&key(), 100_000));
+
+        // The first second's worth passes at once, the rest at the requested rate
+        let elapsed = relay_time(&relay, 15, 10_000).await;
+        assert!(elapsed >= Duration::from_millis(450), "{elapsed:?}");
+        assert!(elapsed < Duration::from_secs(2), "{elapsed:?}");
+        assert_eq!(relay.stats().bytes_total, 150_000);
+    }
+
+    #[tokio::test]
+    async fn lifting_the_requested_rate_stops_the_wait() {
+        let relay = Relay::new(None, None, table());
+        relay.transfers.begin_transfer(&key(), TransferDetails::default());
+        assert!(relay.transfers.set_requested_rate(&key(), 1_000));
+        assert!(relay.transfers.set_requested_rate(&key(), 0));
+        assert!(relay_time(&relay, 10, 100_000).await < Duration::from_millis(100));
+    }
+
+    #[tokio::test]
+    async fn requested_rate_cannot_raise_the_per_transfer_limit() {
+        let relay = Relay::new(None, Some(100_000), table());
+        relay.transfers.begin_transfer(&key(), TransferDetails::default());
+        assert!(relay.transfers.set_requested_rate(&key(), 10_000_000));
+        let elapsed = relay_time(&relay, 15, 10_000).await;
+        assert!(elapsed >= Duration::from_millis(450), "{elapsed:?}");
+    }
+
+    #[tokio::test]
+    async fn token_bucket_waits_off_its_debt() {
+        let bucket = TokenBucket::new(1_000);
+        let started = Instant::now();
+        bucket.acquire(1_000).await;
+        assert!(started.elapsed() < Duration::from_millis(100));
+        bucket.acquire(200).await;
+        assert!(started.elapsed() >= Duration::from_millis(180), "{:?}", started.elapsed());
+    }
+}
@@ -0,0 +1,468 @@
+use crate::chat::ClientRefs;
+use crate::types::*;
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicU64, AtomicU8, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::{broadcast, RwLock};
+
+pub type RoomId = String;
+
+pub const DEFAULT_ROOM: &str = "main";
+const MAX_ROOM_NAME_LENGTH: usize = 64;
+
+pub type Peers = Arc<RwLock<HashMap<SessionId, PeerInfo>>>;
+/// Shared files by id. Entries are shared with the messages that list them and copied
+/// only when they change.
+pub type FileRegistry = HashMap<String, Arc<FileMetadata>>;
+pub type Files = Arc<RwLock<FileRegistry>>;
+pub type Messages = Arc<RwLock<Vec<TextMessage>>>;
+pub type Snippets = Arc<RwLock<HashMap<String, Snippet>>>;
+pub type Rooms = Arc<RwLock<HashMap<RoomId, RoomState>>>;
+
+/// A room broadcast, serialized once as a `SequencedMessage` and shared by every
+/// subscriber's writer.
+#[derive(Debug, Clone)]
+pub struct Broadcast {
+    pub json: Arc<str>,
+    /// Set for the two forms of a file list change, of which each peer keeps one
+    pub file_list: Option<FileListForm>,
+    pub audience: Audience,
+    /// None for broadcasts every subscriber gets, like the message of the day
+    pub topic: Option<Topic>,
+    /// Sessions the broadcast is about, which get it whatever their topics
+    pub parties: Vec<SessionId>,
+}
+
+/// A category of room broadcasts a peer can choose to receive with `Subscribe`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Topic {
+    Chat,
+    Files,
+    Peers,
+    Transfers,
+}
+
+impl Topic {
+    pub const ALL: [Topic; 4] = [Topic::Chat, Topic::Files, Topic::Peers, Topic::Transfers];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Topic::Chat => "chat",
+            Topic::Files => "files",
+            Topic::Peers => "peers",
+            Topic::Transfers => "transfers",
+        }
+    }
+
+    pub fn parse(name: &str) -> Option<Topic> {
+        Topic::ALL.into_iter().find(|topic| topic.name() == name)
+    }
+
+    /// The topic a broadcast belongs to, or None if it is for everyone.
+    fn of(message: &ServerMessage) -> Option<Topic> {
+        match message {
+            ServerMessage::TextMessage { .. }
+            | ServerMessage::MessageAttachmentsInvalidated { .. }
+            | ServerMessage::SnippetListUpdate { .. } => Some(Topic::Chat),
+            ServerMessage::FileListUpdate { .. }
+            | ServerMessage::FileListDiff { .. }
+            | ServerMessage::FileAdded { .. }
+            | ServerMessage::FileRemoved { .. }
+            | ServerMessage::FileUpdated { .. }
+            | ServerMessage::BatchAnnounced { .. } => Some(Topic::Files),
+            ServerMessage::PeerJoined { .. }
+            | ServerMessage::PeerLeft { .. }
+            | ServerMessage::PeerUpdated { .. }
+            | ServerMessage::PeerActivity { .. } => Some(Topic::Peers),
+            ServerMessage::TransferList { .. } | ServerMessage::DownloadRequest { .. } => Some(Topic::Transfers),
+            _ => None,
+        }
+    }
+
+    fn bit(self) -> u8 {
+        1 << self as u8
+    }
+}
+
+/// The sessions a broadcast concerns directly, such as the host asked to send a file.
+fn parties(message: &ServerMessage) -> Vec<SessionId> {
+    match message {
+        ServerMessage::DownloadRequest { from_session_id, requester_session_id, .. } => {
+            vec![from_session_id.clone(), requester_session_id.clone()]
+        }
+        ServerMessage::PeerUpdated { peer } => vec![peer.session_id.clone()],
+        _ => Vec::new(),
+    }
+}
+
+/// The topics one connection receives, changed by its reader and read by its writer.
+#[derive(Debug)]
+pub struct Topics(AtomicU8);
+
+impl Default for Topics {
+    fn default() -> Self {
+        Topics(AtomicU8::new(Topic::ALL.into_iter().map(Topic::bit).fold(0, |bits, bit| bits | bit)))
+    }
+}
+
+impl Topics {
+    pub fn contains(&self, topic: Topic) -> bool {
+        self.0.load(Ordering::Relaxed) & topic.bit() != 0
+    }
+
+    /// True when some topics are left out, so gaps in `seq` are to be expected.
+    pub fn is_narrowed(&self) -> bool {
+        !Topic::ALL.into_iter().all(|topic| self.contains(topic))
+    }
+
+    /// Replaces the topics, returning the ones that were not subscribed before.
+    pub fn set(&self, topics: &HashSet<Topic>) -> Vec<Topic> {
+        let bits = topics.iter().fold(0, |bits, topic| bits | topic.bit());
+        let previous = self.0.swap(bits, Ordering::Relaxed);
+        Topic::ALL.into_iter().filter(|topic| topics.contains(topic) && previous & topic.bit() == 0).collect()
+    }
+}
+
+/// Which subscribers a variant of a broadcast is for. The variants of one broadcast
+/// together reach every subscriber exactly once, so none of them sees a gap in `seq`.
+#[derive(Debug, Clone)]
+pub enum Audience {
+    Everyone,
+    Only(Arc<HashSet<SessionId>>),
+    Except(Arc<HashSet<SessionId>>),
+}
+
+impl Audience {
+    pub fn includes(&self, session_id: &str) -> bool {
+        match self {
+            Audience::Everyone => true,
+            Audience::Only(sessions) => sessions.contains(session_id),
+            Audience::Except(sessions) => !sessions.contains(session_id),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileListForm {
+    Full,
+    Diff,
+}
+
+/// Peers, files and chat history of one isolated room, with its own broadcast channel.
+#[derive(Clone)]
+pub struct RoomState {
+    pub id: RoomId,
+    pub peers: Peers,
+    pub files: Files,
+    pub messages: Messages,
+    pub snippets: Snippets,
+    pub client_refs: Arc<Mutex<ClientRefs>>,
+    /// Ids of the chat messages that attach each file
+    attachments: Arc<Mutex<HashMap<String, Vec<String>>>>,
+    /// Who each private file was last published to, so a change can tell peers that
+    /// lost sight of a file to remove it
+    audiences: Arc<Mutex<HashMap<String, HashSet<SessionId>>>>,
+    tx: broadcast::Sender<Broadcast>,
+    seq: Arc<Mutex<u64>>,
+    file_list_version: Arc<AtomicU64>,
+    empty_since: Arc<RwLock<Option<Instant>>>,
+}
+
+impl RoomState {
+    pub fn new(id: RoomId) -> Self {
+        let (tx, _rx) = broadcast::channel::<Broadcast>(1000);
+        RoomState {
+            id,
+            peers: Arc::new(RwLock::new(HashMap::new())),
+            files: Arc::new(RwLock::new(HashMap::new())),
+            messages: Arc::new(RwLock::new(Vec::new())),
+            snippets: Arc::new(RwLock::new(HashMap::new())),
+            client_refs: Arc::new(Mutex::new(ClientRefs::default())),
+            attachments: Arc::new(Mutex::new(HashMap::new())),
+            audiences: Arc::new(Mutex::new(HashMap::new())),
+            tx,
+            seq: Arc::new(Mutex::new(0)),
+            file_list_version: Arc::new(AtomicU64::new(0)),
+            empty_since: Arc::new(RwLock::new(None)),
+        }
+    }
+
+    /// Broadcasts to every peer in the room, stamped with the next sequence number.
+    pub fn send(&self, message: ServerMessage) {
+        self.send_alternatives(vec![message]);
+    }
+
+    /// Broadcasts several forms of one event under a single sequence number, so a
+    /// subscriber keeping only the form it understands sees no gap.
+    pub fn send_alternatives(&self, messages: Vec<ServerMessage>) {
+        self.send_variants(messages.into_iter().map(|message| (message, Audience::Everyone)).collect());
+    }
+
+    /// Like `send_alternatives`, with each form further split by who receives it.
+    pub fn send_variants(&self, messages: Vec<(ServerMessage, Audience)>) {
+        // Stamping and sending under one lock keeps the channel in sequence order
+        let mut seq = self.seq.lock().unwrap();
+        *seq += 1;
+        for (message, audience) in messages {
+            let file_list = match &message {
+                ServerMessage::FileListUpdate { .. } => Some(FileListForm::Full),
+                ServerMessage::FileListDiff { .. } => Some(FileListForm::Diff),
+                _ => None,
+            };
+            let topic = Topic::of(&message);
+            let parties = parties(&message);
+            let json = serde_json::to_string(&SequencedMessage { seq: *seq, message }).unwrap();
+            let _ = self.tx.send(Broadcast {
+                json: json.into(),
+                file_list,
+                audience,
+                topic,
+                parties,
+            });
+        }
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Broadcast> {
+        self.tx.subscribe()
+    }
+
+    pub fn seq(&self) -> u64 {
+        *self.seq.lock().unwrap()
+    }
+
+    pub fn file_list_version(&self) -> u64 {
+        self.file_list_version.load(Ordering::SeqCst)
+    }
+
+    pub fn next_file_list_version(&self) -> u64 {
+        self.file_list_version.fetch_add(1, Ordering::SeqCst) + 1
+    }
+
+    /// Remembers which files a chat message attaches.
+    pub fn add_attachments(&self, message_id: &str, file_ids: &[String]) {
+        let mut attachments = self.attachments.lock().unwrap();
+        for file_id in file_ids {
+            attachments.entry(file_id.clone()).or_default().push(message_id.to_string());
+        }
+    }
+
+    /// Forgets removed files, returning the messages that attached them with the ids
+    /// of their files that went away.
+    pub fn remove_attachments(&self, file_ids: &[String]) -> HashMap<String, Vec<String>> {
+        let mut attachments = self.attachments.lock().unwrap();
+        let mut invalidated: HashMap<String, Vec<String>> = HashMap::new();
+        for file_id in file_ids {
+            for message_id in attachments.remove(file_id).unwrap_or_default() {
+                invalidated.entry(message_id).or_default().push(file_id.clone());
+            }
+        }
+        invalidated
+    }
+
+    /// Records who the private files of a change are published to, returning who the
+    /// updated and removed ones were published to before. Files missing from the
+    /// result were public.
+    pub fn swap_audiences(
+        &self,
+        added: &[Arc<FileMetadata>],
+        updated: &[Arc<FileMetadata>],
+        removed: &[String],
+    ) -> HashMap<String, HashSet<SessionId>> {
+        let mut audiences = self.audiences.lock().unwrap();
+        let mut previous = HashMap::new();
+        for file in added.iter().chain(updated) {
+            let old = match file.audience() {
+                Some(audience) => audiences.insert(file.id.clone(), audience),
+                None => audiences.remove(&file.id),
+            };
+            previous.extend(old.map(|old| (file.id.clone(), old)));
+        }
+        for file_id in removed {
+            previous.extend(audiences.remove(file_id).map(|old| (file_id.clone(), old)));
+        }
+        previous
+    }
+
+    /// Whether any peer still needs full file lists instead of diffs.
+    pub async fn has_full_list_peers(&self) -> bool {
+        let peers = self.peers.read().await;
+        peers.values().any(|peer| !peer.receives_file_list_diffs())
+    }
+}
+
+/// Normalizes a client-supplied room name, falling back to the default room.
+pub fn room_id(room: Option<&str>) -> RoomId {
+    match room.map(str::trim) {
+        Some(room) if !room.is_empty() => room.chars().take(MAX_ROOM_NAME_LENGTH).collect(),
+        _ => DEFAULT_ROOM.to_string(),
+    }
+}
+
+pub async fn get_room(rooms: &Rooms, id: &str) -> Option<RoomState> {
+    let rooms = rooms.read().await;
+    rooms.get(id).cloned()
+}
+
+/// Returns the room, creating it on first use. Joining resets its idle clock.
+pub async fn join_room(rooms: &Rooms, id: &str) -> RoomState {
+    let mut rooms = rooms.write().await;
+    let room = rooms
+        .entry(id.to_string())
+        .or_insert_with(|| RoomState::new(id.to_string()))
+        .clone();
+    *room.empty_since.write().await = None;
+    room
+}
+
+/// Drops rooms that have had no peers for at least `idle_timeout`. The default room is kept.
+pub async fn collect_idle_rooms(rooms: &Rooms, idle_timeout: Duration) {
+    let mut rooms = rooms.write().await;
+    let mut to_remove = Vec::new();
+
+    for (id, room) in rooms.iter() {
+        if id == DEFAULT_ROOM {
+            continue;
+        }
+        // Observers streaming the room keep it alive as well as peers
+        let is_empty = room.peers.read().await.is_empty() && room.tx.receiver_count() == 0;
+        let mut empty_since = room.empty_since.write().await;
+        match (is_empty, *empty_since) {
+            (false, _) => *empty_since = None,
+            (true, None) => *empty_since = Some(Instant::now()),
+            (true, Some(since)) if since.elapsed() >= idle_timeout => to_remove.push(id.clone()),
+            (true, Some(_)) => {}
+        }
+    }
+
+    for id in &to_remove {
+        rooms.remove(id);
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::files::{self, FileListChange};
+
+    fn rooms() -> Rooms {
+        Arc::new(RwLock::new(HashMap::new()))
+    }
+
+    pub(crate) fn chat_message(content: &str) -> TextMessage {
+        TextMessage {
+            id: format!("msg_{content}"),
+            content: content.to_string(),
+            kind: MessageKind::default(),
+            format: MessageFormat::default(),
+            attachments: None,
+            reply_to: None,
+            sender_id: "alice".to_string(),
+            sender_name: None,
+            sender_color: None,
+            timestamp: chrono::Utc::now(),
+            origin: None,
+        }
+    }
+
+    fn received(rx: &mut broadcast::Receiver<Broadcast>) -> Vec<serde_json::Value> {
+        std::iter::from_fn(|| rx.try_recv().ok())
+            .map(|broadcast| serde_json::from_str(&broadcast.json).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn normalizes_room_names() {
+        assert_eq!(room_id(None), DEFAULT_ROOM);
+        assert_eq!(room_id(Some("  ")), DEFAULT_ROOM);
+        assert_eq!(room_id(Some(" team ")), "team");
+        assert_eq!(room_id(Some(&"x".repeat(100))).chars().count(), MAX_ROOM_NAME_LENGTH);
+    }
+
+    #[tokio::test]
+    async fn joining_the_same_name_shares_the_room() {
+        let rooms = rooms();
+        let first = join_room(&rooms, "team").await;
+        let second = join_room(&rooms, "team").await;
+        first.messages.write().await.push(chat_message("hello"));
+        assert_eq!(second.messages.read().await.len(), 1);
+        assert!(get_room(&rooms, "other").await.is_none());
+    }
+
+    #[tokio::test]
+    async fn rooms_keep_chat_and_files_apart() {
+        let rooms = rooms();
+        let team = join_room(&rooms, "team").await;
+        let other = join_room(&rooms, "other").await;
+        let mut team_rx = team.subscribe();
+        let mut other_rx = other.subscribe();
+
+        {
+            let file = Arc::new(files::tests::file("file_1", "alice", 10));
+            let mut registry = team.files.write().await;
+            registry.insert(file.id.clone(), file.clone());
+            let change = FileListChange {
+                added: vec![file],
+                ..Default::default()
+            };
+            files::publish_change(&team, &registry, change, false);
+        }
+        let message = chat_message("hello");
+        other.messages.write().await.push(message.clone());
+        other.send(ServerMessage::TextMessage { message });
+
+        assert!(team.files.read().await.contains_key("file_1"));
+        assert!(other.files.read().await.is_empty());
+        assert!(team.messages.read().await.is_empty());
+        assert_eq!(other.messages.read().await.len(), 1);
+
+        let team_received = received(&mut team_rx);
+        assert_eq!(team_received.len(), 1);
+        assert_eq!(team_received[0]["type"], "file_list_diff");
+        let other_received = received(&mut other_rx);
+        assert_eq!(other_received.len(), 1);
+        assert_eq!(other_received[0]["type"], "text_message");
+        assert_eq!(other_received[0]["message"]["content"], "hello");
+        // Each room numbers its own broadcasts
+        assert_eq!(team_received[0]["seq"], 1);
+        assert_eq!(other_received[0]["seq"], 1);
+        assert_eq!(team.file_list_version(), 1);
+        assert_eq!(other.file_list_version(), 0);
+    }
+
+    /// Broadcasts a 200-file list to 50 subscribers, serialized once and shared, and
+    /// compares that with serializing it for each subscriber as every connection's
+    /// writer used to. Run with
+    /// `cargo test --release broadcast_benchmark -- --ignored --nocapture`.
+    #[tokio::test]
+    #[ignore]
+    async fn broadcast_benchmark() {
+        const SUBSCRIBERS: usize = 50;
+        const ROUNDS: u32 = 200;
+        let room = RoomState::new(DEFAULT_ROOM.to_string());
+        let files = (0..200).map(|n| Arc::new(files::tests::file(&format!("file_{n}"), "alice", 1000)));
+        room.files.write().await.extend(files.map(|file| (file.id.clone(), file)));
+        let update = files::file_list(&room, "alice", None, false).await;
+        let mut subscribers = (0..SUBSCRIBERS).map(|_| room.subscribe()).collect::<Vec<_>>();
+
+        let started = Instant::now();
+        for _ in 0..ROUNDS {
+            room.send(update.clone());
+            for rx in &mut subscribers {
+                let json = rx.try_recv().unwrap().json;
+                std::hint::black_box(json.to_string());
+            }
+        }
+        let shared = started.elapsed() / ROUNDS;
+
+        let started = Instant::now();
+        for _ in 0..ROUNDS {
+            for _ in 0..SUBSCRIBERS {
+                std::hint::black_box(serde_json::to_string(&update).unwrap());
+            }
+        }
+        let per_subscriber = started.elapsed() / ROUNDS;
+
+        println!("{SUBSCRIBERS} subscribers, 200 files: {shared:?} serialized once, {per_subscriber:?} serialized per subscriber");
+    }
+}
@@ -0,0 +1,573 @@
+use crate::files;
+use crate::types::*;
+use crate::websocket::LocalPeer;
+use crate::AppState;
+use base64::Engine;
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use tokio::io::AsyncReadExt;
+use tokio::sync::mpsc;
+use tokio::task::AbortHandle;
+
+/// Session id of the peer hosting the shared directory. Clients cannot join with it.
+pub const SHARE_SESSION_ID: &str = "shared_dir";
+/// Files listed from the directory when `--share-dir-max-files` is not given
+pub const DEFAULT_SHARE_MAX_FILES: usize = 1000;
+/// Filesystem events are collected for this long before the directory is scanned again,
+/// so copying in many files costs one scan.
+const RESCAN_DELAY: Duration = Duration::from_millis(500);
+/// Delay before the directory's peer joins again after it was kicked or its room closed.
+const RESTART_DELAY: Duration = Duration::from_secs(10);
+/// Messages from file transfers waiting for the peer
+const OUTGOING_QUEUE_CAPACITY: usize = 16;
+
+/// `--share-dir` and the options that go with it.
+#[derive(Debug, Clone)]
+pub struct ShareConfig {
+    pub dir: PathBuf,
+    pub max_files: usize,
+}
+
+pub fn is_share_session(session_id: &str) -> bool {
+    session_id == SHARE_SESSION_ID
+}
+
+/// Size and modification time of a file on disk, which tell whether it changed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct DiskStamp {
+    size: u64,
+    modified: Option<SystemTime>,
+}
+
+/// A file of the directory, by its path relative to the directory.
+struct SharedFile {
+    id: String,
+    stamp: DiskStamp,
+    sha256: Option<String>,
+    /// In the room's registry. Cleared when the file was rejected, evicted or expired,
+    /// so the next scan announces it again.
+    listed: bool,
+}
+
+/// The result of hashing one file, for the stamp it had when hashing started.
+struct Hashed {
+    path: String,
+    stamp: DiskStamp,
+    sha256: String,
+}
+
+/// Hosts the files of a directory in the default room as a peer of its own, keeping
+/// the listing in step with the directory and serving downloads from disk. Rejoins
+/// after a delay if the peer is kicked.
+pub async fn run(state: AppState, config: ShareConfig) {
+    let (changed_tx, mut changed) = mpsc::unbounded_channel();
+    let watcher = notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+        // Reading files to serve them must not trigger a scan
+        if !matches!(event, Ok(notify::Event { kind: notify::EventKind::Access(_), .. })) {
+            let _ = changed_tx.send(());
+        }
+    });
+    let _watcher = match watcher {
+        Ok(mut watcher) => match notify::Watcher::watch(&mut watcher, &config.dir, notify::RecursiveMode::Recursive) {
+            Ok(()) => Some(watcher),
+            Err(e) => {
+                tracing::warn!(dir = %config.dir.display(), error = %e, "Cannot watch the shared directory; changes will not be picked up");
+                None
+            }
+        },
+        Err(e) => {
+            tracing::warn!(error = %e, "Cannot watch the shared directory; changes will not be picked up");
+            None
+        }
+    };
+
+    loop {
+        match Share::run(&state, &config, &mut changed).await {
+            Ok(()) => tracing::info!("Shared directory's room closed"),
+            Err(e) => tracing::warn!(error = e, "Shared directory stopped"),
+        }
+        tokio::time::sleep(RESTART_DELAY).await;
+    }
+}
+
+/// One session of the directory's peer.
+struct Share<'a> {
+    state: &'a AppState,
+    config: &'a ShareConfig,
+    local: LocalPeer,
+    files: HashMap<String, SharedFile>,
+    /// Files waiting to be hashed, sent to a task that hashes one at a time
+    hash_jobs: mpsc::UnboundedSender<(String, DiskStamp)>,
+    /// Downloads being read from disk, by file id and requester
+    sending: HashMap<(String, SessionId), AbortHandle>,
+    outgoing: mpsc::Sender<ClientMessage>,
+}
+
+impl<'a> Share<'a> {
+    async fn run(
+        state: &'a AppState,
+        config: &'a ShareConfig,
+        changed: &mut mpsc::UnboundedReceiver<()>,
+    ) -> Result<(), String> {
+        let name = config
+            .dir
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_else(|| "Shared folder".to_string());
+        let join = ClientMessage::Join {
+            session_id: SHARE_SESSION_ID.to_string(),
+            name: Some(name),
+            user_agent: Some(format!("ladex-share/{}", env!("CARGO_PKG_VERSION"))),
+            room: None,
+            preferred_chunk_size: None,
+            capabilities: Some(HashSet::from([FILE_LIST_DIFF_CAPABILITY.to_string()])),
+            protocol_version: Some(PROTOCOL_VERSION),
+            auto_accept_downloads: Some(true),
+            notify_upload_activity: Some(false),
+            client_version: Some(env!("CARGO_PKG_VERSION").to_string()),
+            session_token: None,
+        };
+        let local = LocalPeer::join(state, join).await?;
+
+        let (hash_jobs, hash_rx) = mpsc::unbounded_channel();
+        let (hashed_tx, hashed) = mpsc::unbounded_channel();
+        tokio::spawn(hash_files(config.dir.clone(), hash_rx, hashed_tx));
+        let (outgoing, outgoing_rx) = mpsc::channel(OUTGOING_QUEUE_CAPACITY);
+        let mut share = Share {
+            state,
+            config,
+            local,
+            files: HashMap::new(),
+            hash_jobs,
+            sending: HashMap::new(),
+            outgoing,
+        };
+
+        let result = share.serve(changed, hashed, outgoing_rx).await;
+        for (_, task) in share.sending.drain() {
+            task.abort();
+        }
+        share.local.leave(state).await;
+        result
+    }
+
+    async fn serve(
+        &mut self,
+        changed: &mut mpsc::UnboundedReceiver<()>,
+        mut hashed: mpsc::UnboundedReceiver<Hashed>,
+        mut outgoing_rx: mpsc::Receiver<ClientMessage>,
+    ) -> Result<(), String> {
+        // Events from before the first scan are covered by it
+        while changed.try_recv().is_ok() {}
+        self.rescan().await?;
+
+        let mut rescan_at = None;
+        loop {
+            let rescan = async {
+                match rescan_at {
+                    Some(at) => tokio::time::sleep_until(at).await,
+                    None => std::future::pending().await,
+                }
+            };
+            tokio::select! {
+                Some(()) = changed.recv() => {
+                    rescan_at.get_or_insert_with(|| tokio::time::Instant::now() + RESCAN_DELAY);
+                }
+                _ = rescan => {
+                    rescan_at = None;
+                    self.rescan().await?;
+                }
+                msg = self.local.recv() => match msg {
+                    Some(msg) => self.on_message(msg).await?,
+                    None => return Ok(()),
+                },
+                Some(hashed) = hashed.recv() => self.on_hashed(hashed).await?,
+                Some(msg) = outgoing_rx.recv() => self.send(msg).await?,
+            }
+        }
+    }
+
+    async fn send(&mut self, msg: ClientMessage) -> Result<(), String> {
+        self.local.send(self.state, msg).await
+    }
+
+    /// Scans the directory and brings the room's listing in step with it.
+    async fn rescan(&mut self) -> Result<(), String> {
+        let (dir, max_files) = (self.config.dir.clone(), self.config.max_files);
+        let scan = tokio::task::spawn_blocking(move || scan_dir(&dir, max_files))
+            .await
+            .map_err(|e| e.to_string())?;
+
+        let removed = self
+            .files
+            .keys()
+            .filter(|path| !scan.contains_key(*path))
+            .cloned()
+            .collect::<Vec<_>>();
+        for path in removed {
+            if let Some(file) = self.files.remove(&path) {
+                tracing::info!(path, "Shared file removed from disk");
+                self.stop_sending(&file.id);
+                if file.listed {
+                    let removal = ClientMessage::RemoveFile {
+                        session_id: SHARE_SESSION_ID.to_string(),
+                        file_id: Some(file.id),
+                        folder_id: None,
+                        batch_id: None,
+                    };
+                    self.send(removal).await?;
+                }
+            }
+        }
+
+        let mut announce = Vec::new();
+        for (path, stamp) in scan {
+            match self.files.get_mut(&path) {
+                Some(file) if file.stamp == stamp && file.listed => {}
+                // Changed content keeps the id, like a peer replacing its file
+                Some(file) if file.listed => {
+                    file.stamp = stamp;
+                    file.sha256 = None;
+                    let (id, replacement) = (file.id.clone(), FileReplacement {
+                        name: None,
+                        size: stamp.size,
+                        mime_type: mime_type(&path),
+                    });
+                    self.stop_sending(&id);
+                    let replace = ClientMessage::ReplaceFile {
+                        session_id: SHARE_SESSION_ID.to_string(),
+                        file_id: id,
+                        new_metadata: replacement,
+                    };
+                    self.send(replace).await?;
+                    let _ = self.hash_jobs.send((path, stamp));
+                }
+                Some(file) => {
+                    file.listed = true;
+                    if file.stamp != stamp {
+                        file.stamp = stamp;
+                        file.sha256 = None;
+                        let _ = self.hash_jobs.send((path.clone(), stamp));
+                    }
+                    announce.extend(metadata(&path, file));
+                }
+                None => {
+                    let file = SharedFile {
+                        id: format!("share_{:016x}", rand::random::<u64>()),
+                        stamp,
+                        sha256: None,
+                        listed: true,
+                    };
+                    announce.extend(metadata(&path, &file));
+                    self.files.insert(path.clone(), file);
+                    let _ = self.hash_jobs.send((path, stamp));
+                }
+            }
+        }
+        self.announce(announce).await
+    }
+
+    async fn announce(&mut self, announce: Vec<FileMetadata>) -> Result<(), String> {
+        for files in announce.chunks(files::MAX_BATCH_FILES) {
+            let batch = ClientMessage::FileUploadBatch {
+                session_id: SHARE_SESSION_ID.to_string(),
+                files: files.to_vec(),
+            };
+            self.send(batch).await?;
+        }
+        Ok(())
+    }
+
+    /// Announces the file again with its hash, unless it changed while being hashed.
+    async fn on_hashed(&mut self, hashed: Hashed) -> Result<(), String> {
+        let Some(file) = self.files.get_mut(&hashed.path) else {
+            return Ok(());
+        };
+        if file.stamp != hashed.stamp {
+            return Ok(());
+        }
+        file.sha256 = Some(hashed.sha256);
+        if !file.listed {
+            return Ok(());
+        }
+        let announce = metadata(&hashed.path, file).into_iter().collect();
+        self.announce(announce).await
+    }
+
+    async fn on_message(&mut self, msg: ServerMessage) -> Result<(), String> {
+        match msg {
+            ServerMessage::DownloadRequest { file_id, requester_session_id, chunk_size, .. } => {
+                let path = self.files.iter().find(|(_, file)| file.id == file_id && file.listed).map(|(path, _)| path.clone());
+                let Some(path) = path else {
+                    let deny = ClientMessage::DenyDownload {
+                        session_id: SHARE_SESSION_ID.to_string(),
+                        file_id,
+                        requester_session_id,
+                        reason: Some("the file is no longer in the shared directory".to_string()),
+                    };
+                    return self.send(deny).await;
+                };
+                let key = (file_id.clone(), requester_session_id.clone());
+                let task = tokio::spawn(send_file(
+                    self.config.dir.join(&path),
+                    path,
+                    file_id,
+                    requester_session_id,
+                    negotiate_chunk_size(Some(chunk_size)),
+                    self.outgoing.clone(),
+                ));
+                if let Some(previous) = self.sending.insert(key, task.abort_handle()) {
+                    previous.abort();
+                }
+            }
+            ServerMessage::TransferAborted { file_id, peer_session_id, .. } => {
+                if let Some(task) = self.sending.remove(&(file_id, peer_session_id)) {
+                    task.abort();
+                }
+            }
+            ServerMessage::BatchUploadResult { rejected, .. } => {
+                for rejection in rejected {
+                    tracing::warn!(file_id = rejection.file_id, code = rejection.code, "Shared file was not accepted");
+                    self.unlist(&rejection.file_id);
+                }
+            }
+            // Files are served by the id they were announced under, so one listed under
+            // another is taken back down
+            ServerMessage::FileIdReassigned { requested_id, assigned_id } => {
+                tracing::warn!(file_id = requested_id, "Shared file's id was taken");
+                self.unlist(&requested_id);
+                let removal = ClientMessage::RemoveFile {
+                    session_id: SHARE_SESSION_ID.to_string(),
+                    file_id: Some(assigned_id),
+                    folder_id: None,
+                    batch_id: None,
+                };
+                self.send(removal).await?;
+            }
+            ServerMessage::FileRemoved { file_id } => self.unlist(&file_id),
+            ServerMessage::FileListDiff { removed, .. } => {
+                for file_id in removed {
+                    self.unlist(&file_id);
+                }
+            }
+            ServerMessage::Kicked { reason, .. } => return Err(format!("kicked: {reason}")),
+            _ => {}
+        }
+        self.sending.retain(|_, task| !task.is_finished());
+        Ok(())
+    }
+
+    /// Notes that a file is no longer in the registry, e.g. because it expired.
+    fn unlist(&mut self, file_id: &str) {
+        if let Some(file) = self.files.values_mut().find(|file| file.id == file_id) {
+            file.listed = false;
+        }
+    }
+
+    fn stop_sending(&mut self, file_id: &str) {
+        self.sending.retain(|(id, _), task| {
+            if id == file_id {
+                task.abort();
+            }
+            id != file_id
+        });
+    }
+}
+
+/// The file as announced to the room, hosted by the directory's peer.
+fn metadata(path: &str, file: &SharedFile) -> Option<FileMetadata> {
+    let (folder, name) = match path.rsplit_once('/') {
+        Some((folder, name)) => (Some(folder), name),
+        None => (None, path),
+    };
+    let mime = mime_type(path);
+    Some(FileMetadata {
+        id: file.id.clone(),
+        name: files::sanitize_file_name(name)?,
+        size: file.stamp.size,
+        category: FileCategory::detect(&mime, name),
+        mime_type: mime,
+        uploader_id: SHARE_SESSION_ID.to_string(),
+        hosts: HashSet::from([SHARE_SESSION_ID.to_string()]),
+        uploaded_at: chrono::Utc::now(),
+        description: None,
+        expires_at: None,
+        relative_path: folder.map(|_| path.to_string()),
+        folder_id: None,
+        batch_id: None,
+        visibility: None,
+        unavailable: false,
+        orphaned: None,
+        e2e: false,
+        locked: false,
+        password_hash: None,
+        password: None,
+        version: 0,
+        download_count: 0,
+        downloaders: HashSet::new(),
+        origin: None,
+        sha256: file.sha256.clone(),
+        display_suffix: None,
+    })
+}
+
+fn mime_type(path: &str) -> String {
+    mime_guess::from_path(path).first_or_octet_stream().to_string()
+}
+
+/// The regular files under `dir` by relative path, at most `max_files` of them.
+/// Hidden entries and symbolic links are skipped, so nothing outside the directory
+/// is shared.
+fn scan_dir(dir: &Path, max_files: usize) -> HashMap<String, DiskStamp> {
+    let mut found = HashMap::new();
+    let mut pending = vec![(dir.to_path_buf(), String::new())];
+    while let Some((dir, prefix)) = pending.pop() {
+        let entries = match std::fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(e) => {
+                tracing::warn!(dir = %dir.display(), error = %e, "Cannot read shared directory");
+                continue;
+            }
+        };
+        let mut entries = entries.filter_map(Result::ok).collect::<Vec<_>>();
+        entries.sort_by_key(|entry| entry.file_name());
+        for entry in entries {
+            let Some(name) = entry.file_name().to_str().map(str::to_string) else {
+                continue;
+            };
+            if name.starts_with('.') || files::sanitize_file_name(&name).is_none() {
+                continue;
+            }
+            let Ok(meta) = entry.path().symlink_metadata() else {
+                continue;
+            };
+            let path = format!("{prefix}{name}");
+            if meta.is_dir() {
+                pending.push((entry.path(), format!("{path}/")));
+            } else if meta.is_file() {
+                if found.len() >= max_files {
+                    tracing::warn!(max_files, "Shared directory has more files than --share-dir-max-files; the rest are not listed");
+                    return found;
+                }
+                found.insert(path, DiskStamp {
+                    size: meta.len(),
+                    modified: meta.modified().ok(),
+                });
+            }
+        }
+    }
+    found
+}
+
+/// Hashes queued files one at a time on the blocking pool. Stops once the queue closes.
+async fn hash_files(dir: PathBuf, mut jobs: mpsc::UnboundedReceiver<(String, DiskStamp)>, hashed: mpsc::UnboundedSender<Hashed>) {
+    while let Some((path, stamp)) = jobs.recv().await {
+        let full_path = dir.join(&path);
+        let result = tokio::task::spawn_blocking(move || sha256_file(&full_path)).await;
+        match result {
+            Ok(Ok(sha256)) => {
+                if hashed.send(Hashed { path, stamp, sha256 }).is_err() {
+                    return;
+                }
+            }
+            Ok(Err(e)) => tracing::debug!(path, error = %e, "Cannot hash shared file"),
+            Err(_) => {}
+        }
+    }
+}
+
+fn sha256_file(path: &Path) -> std::io::Result<String> {
+    let mut file = std::fs::File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0; 64 * 1024];
+    loop {
+        let read = file.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+    }
+    Ok(hasher.finalize().iter().map(|b| format!("{b:02x}")).collect())
+}
+
+/// Reads a shared file from disk and sends it to `requester` in chunks, telling them
+/// if it cannot be read.
+async fn send_file(
+    full_path: PathBuf,
+    path: String,
+    file_id: String,
+    requester: SessionId,
+    chunk_size: u32,
+    outgoing: mpsc::Sender<ClientMessage>,
+) {
+    if let Err(e) = stream_file(&full_path, &path, &file_id, &requester, chunk_size, &outgoing).await {
+        tracing::info!(path, error = %e, "Shared file download failed");
+        let _ = outgoing
+            .send(ClientMessage::DenyDownload {
+                session_id: SHARE_SESSION_ID.to_string(),
+                file_id,
+                requester_session_id: requester,
+                reason: Some("the file could not be read".to_string()),
+            })
+            .await;
+    }
+}
+
+async fn stream_file(
+    full_path: &Path,
+    path: &str,
+    file_id: &str,
+    requester: &SessionId,
+    chunk_size: u32,
+    outgoing: &mpsc::Sender<ClientMessage>,
+) -> std::io::Result<()> {
+    let closed = |_| std::io::Error::other("the shared directory stopped");
+    let mut file = tokio::fs::File::open(full_path).await?;
+    let size = file.metadata().await?.len();
+    let total_chunks = size.div_ceil(chunk_size as u64) as u32;
+    let (folder, name) = match path.rsplit_once('/') {
+        Some((_, name)) => (true, name),
+        None => (false, path),
+    };
+    let metadata = ClientMessage::FileMetadata {
+        session_id: SHARE_SESSION_ID.to_string(),
+        file_id: file_id.to_string(),
+        file_name: name.to_string(),
+        file_size: size,
+        mime_type: mime_type(path),
+        total_chunks,
+        chunk_size: Some(chunk_size),
+        target_session_id: requester.clone(),
+        compression: None,
+        relative_path: folder.then(|| path.to_string()),
+        encrypted: false,
+        nonce: None,
+    };
+    outgoing.send(metadata).await.map_err(closed)?;
+
+    let mut buffer = vec![0; chunk_size as usize];
+    let mut offset = 0;
+    for chunk_index in 0..total_chunks {
+        let len = ((size - offset) as usize).min(buffer.len());
+        file.read_exact(&mut buffer[..len]).await?;
+        let chunk = ClientMessage::FileChunk {
+            session_id: SHARE_SESSION_ID.to_string(),
+            file_id: file_id.to_string(),
+            chunk_index,
+            total_chunks,
+            offset: Some(offset),
+            len: Some(len as u32),
+            data: base64::engine::general_purpose::STANDARD.encode(&buffer[..len]),
+            target_session_id: requester.clone(),
+            compression: None,
+            encrypted: false,
+            nonce: None,
+        };
+        outgoing.send(chunk).await.map_err(closed)?;
+        offset += len as u64;
+    }
+    Ok(())
+}
@@ -0,0 +1,129 @@
+use crate::types::*;
+use crate::websocket;
+use crate::AppState;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Time left at which a scheduled shutdown is announced to every room
+const COUNTDOWN_MARKS: [Duration; 3] = [
+    Duration::from_secs(10 * 60),
+    Duration::from_secs(5 * 60),
+    Duration::from_secs(60),
+];
+/// Longest a shutdown waits past its time for relayed transfers to finish
+const TRANSFER_GRACE: Duration = Duration::from_secs(60);
+/// How often the deadline, the peer count and the transfers are checked
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A duration such as `90` or `90s`, `30m` or `2h`.
+pub fn parse_duration(value: &str) -> Result<Duration, String> {
+    let value = value.trim();
+    let (number, unit) = match value.find(|c: char| !c.is_ascii_digit()) {
+        Some(at) => value.split_at(at),
+        None => (value, "s"),
+    };
+    let scale = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        _ => return Err(format!("{value} should be a number of seconds, or end in s, m or h")),
+    };
+    let number = number.parse::<u64>().map_err(|_| format!("{value} is not a duration"))?;
+    number
+        .checked_mul(scale)
+        .map(Duration::from_secs)
+        .ok_or_else(|| format!("{value} is too long"))
+}
+
+/// When the server is due to shut down, set by `--timeout` or the admin API.
+#[derive(Default)]
+pub struct ShutdownTimer {
+    deadline: Mutex<Option<Instant>>,
+}
+
+impl ShutdownTimer {
+    /// Shuts the server down after `delay`, unless it is already due sooner. Returns
+    /// when the shutdown now happens.
+    pub fn schedule(&self, delay: Duration) -> Instant {
+        let mut deadline = self.deadline.lock().unwrap();
+        let at = Instant::now() + delay;
+        let at = deadline.map_or(at, |current| current.min(at));
+        *deadline = Some(at);
+        at
+    }
+
+    pub fn deadline(&self) -> Option<Instant> {
+        *self.deadline.lock().unwrap()
+    }
+}
+
+/// Shuts the server down once its deadline passes, announcing it beforehand, or once it
+/// has had no peers for `when_empty`.
+pub async fn run(state: AppState, when_empty: Option<Duration>) {
+    let mut interval = tokio::time::interval(CHECK_INTERVAL);
+    let mut empty_since: Option<Instant> = None;
+    // The deadline counted down to, and the smallest mark announced for it
+    let mut announced: Option<(Instant, Duration)> = None;
+    loop {
+        interval.tick().await;
+
+        if let Some(when_empty) = when_empty {
+            if peer_count(&state).await > 0 {
+                empty_since = None;
+            } else if empty_since.get_or_insert_with(Instant::now).elapsed() >= when_empty {
+                tracing::info!(secs = when_empty.as_secs(), "No peers connected, shutting down");
+                shut_down(&state).await;
+            }
+        }
+
+        let Some(deadline) = state.shutdown.deadline() else {
+            continue;
+        };
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            tracing::info!("Scheduled shutdown reached");
+            shut_down(&state).await;
+        }
+        // A deadline set closer than a mark is announced straight away
+        let Some(mark) = COUNTDOWN_MARKS.into_iter().filter(|mark| remaining <= *mark).min() else {
+            continue;
+        };
+        if announced.is_some_and(|(at, smallest)| at == deadline && smallest <= mark) {
+            continue;
+        }
+        announced = Some((deadline, mark));
+        let seconds_remaining = remaining.as_secs_f64().round() as u64;
+        tracing::info!(seconds_remaining, "Announcing shutdown");
+        for room in state.rooms.read().await.values() {
+            room.send(ServerMessage::ShutdownCountdown { seconds_remaining });
+        }
+    }
+}
+
+/// Peers connected over a WebSocket; the server's own peers and HTTP fetches do not count.
+async fn peer_count(state: &AppState) -> usize {
+    state.sessions.read().await.values().filter(|handle| !handle.local).count()
+}
+
+/// Lets relayed transfers in flight finish, for a short while, then tells every client
+/// the server is going away and exits.
+pub async fn shut_down(state: &AppState) -> ! {
+    let (running, _) = state.relay.transfers.counts();
+    if running > 0 {
+        tracing::info!(running, "Waiting for transfers before shutting down");
+        let grace_ends = Instant::now() + TRANSFER_GRACE;
+        while state.relay.transfers.counts().0 > 0 && Instant::now() < grace_ends {
+            tokio::time::sleep(CHECK_INTERVAL).await;
+        }
+    }
+    exit(state).await
+}
+
+/// Tells every client the server is going away and exits, without waiting for transfers.
+pub async fn exit(state: &AppState) -> ! {
+    tracing::info!("Shutting down");
+    websocket::disconnect_all(state, websocket::CLOSE_SERVER_SHUTDOWN, "Server shutting down").await;
+    // Give the writers a moment to send the close frames
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    std::process::exit(0);
+}
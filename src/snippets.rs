@@ -0,0 +1,82 @@
+use crate::rooms::RoomState;
+use crate::types::*;
+use rand::Rng;
+
+pub const MAX_SNIPPET_LENGTH: usize = 64 * 1024;
+const MAX_SNIPPET_TITLE_LENGTH: usize = 200;
+
+#[derive(Debug)]
+pub enum SnippetError {
+    Empty,
+    TooLong,
+    Full,
+}
+
+impl SnippetError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            SnippetError::Empty => "empty_snippet",
+            SnippetError::TooLong => "snippet_too_long",
+            SnippetError::Full => "snippets_full",
+        }
+    }
+
+    pub fn message(&self) -> String {
+        match self {
+            SnippetError::Empty => "Snippet content must not be empty".to_string(),
+            SnippetError::TooLong => format!("Snippets are limited to {MAX_SNIPPET_LENGTH} bytes"),
+            SnippetError::Full => "The snippet board is full".to_string(),
+        }
+    }
+}
+
+/// Validates and stores a snippet in the room, then broadcasts the refreshed list.
+pub async fn create_snippet(
+    room: &RoomState,
+    max_snippets: usize,
+    creator_id: SessionId,
+    title: Option<String>,
+    content: String,
+) -> Result<Snippet, SnippetError> {
+    if content.trim().is_empty() {
+        return Err(SnippetError::Empty);
+    }
+    if content.len() > MAX_SNIPPET_LENGTH {
+        return Err(SnippetError::TooLong);
+    }
+
+    let title = title
+        .map(|title| title.trim().chars().take(MAX_SNIPPET_TITLE_LENGTH).collect::<String>())
+        .filter(|title| !title.is_empty());
+
+    let snippet = Snippet {
+        id: format!("snippet_{:016x}", rand::thread_rng().gen::<u64>()),
+        title,
+        content,
+        creator_id,
+        created_at: chrono::Utc::now(),
+    };
+
+    {
+        let mut snippets = room.snippets.write().await;
+        if snippets.len() >= max_snippets {
+            return Err(SnippetError::Full);
+        }
+        snippets.insert(snippet.id.clone(), snippet.clone());
+    }
+
+    broadcast_snippet_list(room).await;
+    Ok(snippet)
+}
+
+pub async fn snippet_list(room: &RoomState) -> Vec<Snippet> {
+    let snippets = room.snippets.read().await;
+    let mut list = snippets.values().cloned().collect::<Vec<_>>();
+    list.sort_by_key(|snippet| snippet.created_at);
+    list
+}
+
+pub async fn broadcast_snippet_list(room: &RoomState) {
+    let snippets = snippet_list(room).await;
+    room.send(ServerMessage::SnippetListUpdate { snippets });
+}
@@ -0,0 +1,1074 @@
+use crate::audit::AuditLog;
+use crate::events::EventLog;
+use crate::relay::{ThroughputMeter, TokenBucket};
+use crate::rooms::RoomId;
+use crate::types::*;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+
+/// Identifies one relayed transfer: file id, sending session, receiving session.
+pub type TransferKey = (String, SessionId, SessionId);
+
+/// A transfer with no chunk for this long is considered failed.
+const TRANSFER_STALL_TIMEOUT: Duration = Duration::from_secs(60);
+/// Live transfer rates are rounded to this many bytes per second.
+const RATE_ROUNDING: u64 = 1024;
+
+/// What the audit log records about a transfer besides its key. Chunks relayed
+/// without metadata first leave all of it unknown.
+#[derive(Debug, Clone, Default)]
+pub struct TransferDetails {
+    pub file_name: Option<String>,
+    pub size: Option<u64>,
+    pub host_name: Option<String>,
+    pub requester_name: Option<String>,
+    pub chunk_size: Option<u32>,
+}
+
+/// Progress of a transfer between its metadata and its last chunk.
+struct ActiveTransfer {
+    last_chunk: Instant,
+    bytes: u64,
+    started_at: chrono::DateTime<chrono::Utc>,
+    details: TransferDetails,
+    /// False while the slot is only reserved for a download the host has not begun
+    begun: bool,
+    chunks_done: u32,
+    total_chunks: Option<u32>,
+    meter: ThroughputMeter,
+    /// Cap the requester asked for with `SetTransferRate`, in bytes per second
+    requested_limiter: Option<Arc<TokenBucket>>,
+}
+
+impl ActiveTransfer {
+    fn new(details: TransferDetails) -> Self {
+        ActiveTransfer {
+            last_chunk: Instant::now(),
+            bytes: 0,
+            started_at: chrono::Utc::now(),
+            details,
+            begun: true,
+            chunks_done: 0,
+            total_chunks: None,
+            meter: ThroughputMeter::new(),
+            requested_limiter: None,
+        }
+    }
+
+    fn reserved() -> Self {
+        ActiveTransfer {
+            begun: false,
+            ..ActiveTransfer::new(TransferDetails::default())
+        }
+    }
+
+    fn is_stalled(&self) -> bool {
+        self.last_chunk.elapsed() >= TRANSFER_STALL_TIMEOUT
+    }
+}
+
+/// Limits on how many transfers the relay carries at once. Stalled transfers do not
+/// count towards any of them.
+#[derive(Debug, Clone, Copy)]
+pub struct TransferLimits {
+    /// Downloads a single host serves at once; more are always queued
+    pub per_host: usize,
+    /// Transfers across the whole server
+    pub total: Option<usize>,
+    /// Transfers a peer takes part in, as host or requester
+    pub per_peer: Option<usize>,
+    /// Reject requests over `total` or `per_peer` instead of queueing them
+    pub reject_excess: bool,
+}
+
+/// Which limit keeps a download from starting.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Limit {
+    Host,
+    Total,
+    Peer,
+}
+
+/// How a download request was admitted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Admission {
+    Start,
+    /// Waiting at this 1-based position in the host's queue
+    Queued(usize),
+    /// Over a server-wide or per-peer limit with `reject_excess` set
+    Rejected,
+}
+
+/// A download request routed, or waiting to be routed, to one host.
+#[derive(Debug, Clone)]
+pub struct PendingDownload {
+    pub key: TransferKey,
+    pub room: RoomId,
+    pub chunk_size: u32,
+    /// Hosts that did not answer this download earlier, so a retry skips them
+    pub tried_hosts: HashSet<SessionId>,
+    /// Both peers can stream the file through an HTTP relay pipe; set by
+    /// `websocket::request_download`
+    pub http_relay: bool,
+}
+
+/// A download request sent to its host, which has not answered with metadata or a
+/// chunk yet.
+struct ForwardedRequest {
+    pending: PendingDownload,
+    deadline: Instant,
+}
+
+/// A download waiting for its host to approve it.
+struct PendingApproval {
+    pending: PendingDownload,
+    details: TransferDetails,
+    requested_at: chrono::DateTime<chrono::Utc>,
+    deadline: Instant,
+}
+
+/// What draining the download queues produced.
+#[derive(Default)]
+pub struct QueueProgress {
+    /// Downloads given a slot, to be routed to their host now
+    pub started: Vec<PendingDownload>,
+    /// Downloads still waiting whose 1-based position changed
+    pub positions: Vec<(PendingDownload, usize)>,
+}
+
+/// Whether both sides of the transfer are known. Nothing is kept for other keys, as no
+/// disconnect would ever release it.
+fn has_both_parties((_, from, to): &TransferKey) -> bool {
+    !from.is_empty() && !to.is_empty()
+}
+
+/// Everything known about transfers that have not ended, under one lock so a transfer
+/// moves between states in one step. How busy a host or peer is gets counted from
+/// these entries rather than kept in counters that could drift.
+#[derive(Default)]
+struct Entries {
+    active: HashMap<TransferKey, ActiveTransfer>,
+    limiters: HashMap<TransferKey, Arc<TokenBucket>>,
+    /// Download requests waiting for a free slot on their host, in arrival order
+    queues: HashMap<SessionId, VecDeque<PendingDownload>>,
+    /// Hosts whose queue changed since positions were last reported
+    changed: HashSet<SessionId>,
+    approvals: HashMap<String, PendingApproval>,
+    /// When each approved download was approved, until it is audited
+    approved: HashMap<TransferKey, chrono::DateTime<chrono::Utc>>,
+    forwarded: HashMap<TransferKey, ForwardedRequest>,
+    /// Files each requester finished downloading over the relay, until it reports them
+    /// with `FileDownloaded`
+    completed: HashSet<(String, SessionId)>,
+}
+
+impl Entries {
+    fn insert_active(&mut self, key: TransferKey, transfer: ActiveTransfer) {
+        if has_both_parties(&key) {
+            self.active.insert(key, transfer);
+        } else {
+            let (file_id, from, to) = &key;
+            tracing::debug!(file_id, from, to, "Not tracking transfer with an unknown party");
+        }
+    }
+
+    /// Transfers holding a slot; stalled ones have given theirs up.
+    fn running(&self) -> impl Iterator<Item = &TransferKey> {
+        self.active.iter().filter(|(_, transfer)| !transfer.is_stalled()).map(|(key, _)| key)
+    }
+
+    fn blocking_limit(&self, limits: &TransferLimits, key: &TransferKey) -> Option<Limit> {
+        let (_, host, requester) = key;
+        let involves = |peer: &SessionId, (_, from, to): &TransferKey| from == peer || to == peer;
+
+        if self.running().filter(|(_, from, _)| from == host).count() >= limits.per_host {
+            Some(Limit::Host)
+        } else if limits.total.is_some_and(|max| self.running().count() >= max) {
+            Some(Limit::Total)
+        } else if limits.per_peer.is_some_and(|max| {
+            self.running().filter(|key| involves(host, key)).count() >= max
+                || self.running().filter(|key| involves(requester, key)).count() >= max
+        }) {
+            Some(Limit::Peer)
+        } else {
+            None
+        }
+    }
+
+    /// Removes the running transfers matching `matches`, returning their keys.
+    fn abandon(
+        &mut self,
+        matches: impl Fn(&TransferKey) -> bool,
+        outcome: TransferOutcome,
+        reason: &str,
+        records: &mut Vec<TransferRecord>,
+    ) -> Vec<TransferKey> {
+        let keys = self.active.keys().filter(|key| matches(key)).cloned().collect::<Vec<_>>();
+        keys.into_iter()
+            .filter_map(|key| {
+                let transfer = self.active.remove(&key)?;
+                let (file_id, from, to) = &key;
+                tracing::warn!(file_id, from, to, bytes = transfer.bytes, reason, "Transfer failed");
+                records.push(self.record(&key, transfer, outcome));
+                Some(key)
+            })
+            .collect()
+    }
+
+    /// Removes the approval requests matching `matches`, returning their downloads.
+    fn take_approvals(
+        &mut self,
+        matches: impl Fn(&PendingApproval) -> bool,
+        outcome: TransferOutcome,
+        records: &mut Vec<TransferRecord>,
+    ) -> Vec<PendingDownload> {
+        let ids = self.approvals.iter().filter(|(_, approval)| matches(approval)).map(|(id, _)| id.clone()).collect::<Vec<_>>();
+        let taken = ids.iter().filter_map(|id| self.approvals.remove(id)).collect::<Vec<_>>();
+        taken
+            .into_iter()
+            .map(|approval| {
+                let pending = approval.pending.clone();
+                records.push(self.approval_record(approval, outcome));
+                pending
+            })
+            .collect()
+    }
+
+    /// Removes queued downloads matching `matches`, returning them.
+    fn dequeue(&mut self, matches: impl Fn(&TransferKey) -> bool) -> Vec<TransferKey> {
+        let mut removed = Vec::new();
+        for (host, queue) in self.queues.iter_mut() {
+            let before = queue.len();
+            queue.retain(|pending| {
+                let keep = !matches(&pending.key);
+                if !keep {
+                    removed.push(pending.key.clone());
+                }
+                keep
+            });
+            if queue.len() != before {
+                self.changed.insert(host.clone());
+            }
+        }
+        self.queues.retain(|_, queue| !queue.is_empty());
+        for key in &removed {
+            self.approved.remove(key);
+        }
+        removed
+    }
+
+    /// Drops rate limiters and unanswered requests matching `matches`.
+    fn release(&mut self, matches: impl Fn(&TransferKey) -> bool) {
+        self.limiters.retain(|key, _| !matches(key));
+        self.forwarded.retain(|key, _| !matches(key));
+    }
+
+    /// Records a download that ended while waiting for approval.
+    fn approval_record(&mut self, approval: PendingApproval, outcome: TransferOutcome) -> TransferRecord {
+        let (file_id, from, to) = &approval.pending.key;
+        tracing::info!(file_id, from, to, ?outcome, "Download approval ended");
+        let transfer = ActiveTransfer {
+            started_at: approval.requested_at,
+            ..ActiveTransfer::new(approval.details)
+        };
+        self.record(&approval.pending.key, transfer, outcome)
+    }
+
+    fn record(&mut self, key: &TransferKey, transfer: ActiveTransfer, outcome: TransferOutcome) -> TransferRecord {
+        let (file_id, from, to) = key;
+        TransferRecord {
+            file_id: file_id.clone(),
+            file_name: transfer.details.file_name,
+            size: transfer.details.size,
+            host_session_id: from.clone(),
+            host_name: transfer.details.host_name,
+            requester_session_id: to.clone(),
+            requester_name: transfer.details.requester_name,
+            started_at: transfer.started_at,
+            ended_at: chrono::Utc::now(),
+            outcome,
+            bytes: transfer.bytes,
+            approved_at: self.approved.remove(key),
+        }
+    }
+}
+
+/// Lifecycle of every relayed transfer, from the request through approval, queueing
+/// and forwarding to its chunks, until it completes, fails or either side goes away.
+/// Ended transfers go to the audit log; starts and ends also go to the event log, and
+/// as `UploadActivity` notices to `activity` for the host.
+pub struct TransferTable {
+    limits: TransferLimits,
+    approval_timeout: Duration,
+    request_timeout: Duration,
+    entries: Mutex<Entries>,
+    audit: Arc<AuditLog>,
+    events: Arc<EventLog>,
+    activity: mpsc::UnboundedSender<(SessionId, ServerMessage)>,
+}
+
+impl TransferTable {
+    pub fn new(
+        limits: TransferLimits,
+        approval_timeout: Duration,
+        request_timeout: Duration,
+        audit: Arc<AuditLog>,
+        events: Arc<EventLog>,
+        activity: mpsc::UnboundedSender<(SessionId, ServerMessage)>,
+    ) -> Self {
+        TransferTable {
+            limits,
+            approval_timeout,
+            request_timeout,
+            entries: Mutex::new(Entries::default()),
+            audit,
+            events,
+            activity,
+        }
+    }
+
+    /// Runs `change` under the lock, then writes the records it produced to the audit
+    /// log once the lock is released.
+    fn update<T>(&self, change: impl FnOnce(&mut Entries, &mut Vec<TransferRecord>) -> T) -> T {
+        let mut records = Vec::new();
+        let result = change(&mut self.entries.lock().unwrap(), &mut records);
+        for record in records {
+            let (kind, event) = match record.outcome {
+                TransferOutcome::Complete => (EventKind::TransferFinished, UploadEvent::Completed),
+                _ => (EventKind::TransferFailed, UploadEvent::Failed),
+            };
+            let _ = self.activity.send((record.host_session_id.clone(), ServerMessage::UploadActivity {
+                file_id: record.file_id.clone(),
+                requester_name: record.requester_name.clone(),
+                event,
+            }));
+            self.events.record(kind, EventSubject {
+                session_id: Some(record.host_session_id.clone()),
+                file_id: Some(record.file_id.clone()),
+                detail: Some(format!("{} for {}", format!("{:?}", record.outcome).to_lowercase(), record.requester_session_id)),
+                ..Default::default()
+            });
+            self.audit.record(record);
+        }
+        result
+    }
+
+    pub fn limits(&self) -> TransferLimits {
+        self.limits
+    }
+
+    /// The rate limiter of one transfer, created at `rate` bytes per second on first use.
+    pub fn limiter(&self, key: &TransferKey, rate: u64) -> Arc<TokenBucket> {
+        if !has_both_parties(key) {
+            return Arc::new(TokenBucket::new(rate));
+        }
+        let mut entries = self.entries.lock().unwrap();
+        entries.limiters.entry(key.clone()).or_insert_with(|| Arc::new(TokenBucket::new(rate))).clone()
+    }
+
+    /// Caps a running transfer at what its requester asked for, or lifts the cap when
+    /// `rate` is 0. Returns false if the transfer is not running.
+    pub fn set_requested_rate(&self, key: &TransferKey, rate: u64) -> bool {
+        let mut entries = self.entries.lock().unwrap();
+        let Some(transfer) = entries.active.get_mut(key) else {
+            return false;
+        };
+        transfer.requested_limiter = (rate > 0).then(|| Arc::new(TokenBucket::new(rate)));
+        true
+    }
+
+    /// The cap the requester set on a transfer, if any.
+    pub fn requested_limiter(&self, key: &TransferKey) -> Option<Arc<TokenBucket>> {
+        self.entries.lock().unwrap().active.get(key)?.requested_limiter.clone()
+    }
+
+    /// Counts a relayed chunk of `bytes` towards its transfer of `total_chunks` chunks.
+    /// Returns true once all of them have been counted, whatever order they came in.
+    pub fn record_chunk(&self, key: &TransferKey, bytes: u64, total_chunks: u32) -> bool {
+        let mut entries = self.entries.lock().unwrap();
+        entries.forwarded.remove(key);
+        let (file_id, _, to) = key;
+        if !entries.active.contains_key(key) {
+            // A chunk straggling in after its transfer completed does not start another
+            if entries.completed.contains(&(file_id.clone(), to.clone())) {
+                return false;
+            }
+            entries.insert_active(key.clone(), ActiveTransfer::new(TransferDetails::default()));
+        }
+        let Some(transfer) = entries.active.get_mut(key) else {
+            return false;
+        };
+        transfer.last_chunk = Instant::now();
+        transfer.bytes += bytes;
+        transfer.begun = true;
+        transfer.chunks_done = transfer.chunks_done.saturating_add(1);
+        transfer.total_chunks = Some(total_chunks);
+        transfer.meter.record(bytes);
+        transfer.chunks_done >= total_chunks
+    }
+
+    /// Reserves a slot for the download, or queues it behind the host's earlier
+    /// requests when a limit is reached.
+    pub fn request_download(&self, pending: PendingDownload) -> Admission {
+        let mut entries = self.entries.lock().unwrap();
+        if entries.active.contains_key(&pending.key) {
+            return Admission::Start;
+        }
+
+        let host = pending.key.1.clone();
+        if let Some(index) = entries.queues.get(&host).and_then(|queue| queue.iter().position(|queued| queued.key == pending.key)) {
+            return Admission::Queued(index + 1);
+        }
+        let queued = entries.queues.get(&host).is_some_and(|queue| !queue.is_empty());
+        match entries.blocking_limit(&self.limits, &pending.key) {
+            None if !queued => {
+                entries.insert_active(pending.key, ActiveTransfer::reserved());
+                Admission::Start
+            }
+            Some(Limit::Total | Limit::Peer) if self.limits.reject_excess => {
+                entries.approved.remove(&pending.key);
+                Admission::Rejected
+            }
+            _ => {
+                let queue = entries.queues.entry(host).or_default();
+                queue.push_back(pending);
+                Admission::Queued(queue.len())
+            }
+        }
+    }
+
+    /// Starts waiting for the host to answer a download request routed to it.
+    pub fn forward(&self, pending: &PendingDownload) {
+        if !has_both_parties(&pending.key) {
+            return;
+        }
+        self.entries.lock().unwrap().forwarded.insert(pending.key.clone(), ForwardedRequest {
+            pending: pending.clone(),
+            deadline: Instant::now() + self.request_timeout,
+        });
+    }
+
+    /// Ends a request the host refused before answering it, freeing its slot. Returns
+    /// the download, or None when the request was already answered, refused or timed out.
+    pub fn deny(&self, key: &TransferKey) -> Option<PendingDownload> {
+        self.update(|entries, records| {
+            let request = entries.forwarded.remove(key)?;
+            entries.abandon(|active| active == key, TransferOutcome::Declined, "host refused", records);
+            Some(request.pending)
+        })
+    }
+
+    /// Ends the requests whose host did not answer in time, freeing their slots, and
+    /// returns their downloads.
+    pub fn expire_requests(&self) -> Vec<PendingDownload> {
+        let now = Instant::now();
+        self.update(|entries, records| {
+            let keys = entries
+                .forwarded
+                .iter()
+                .filter(|(_, request)| request.deadline <= now)
+                .map(|(key, _)| key.clone())
+                .collect::<HashSet<_>>();
+            if keys.is_empty() {
+                return Vec::new();
+            }
+            entries.abandon(|key| keys.contains(key), TransferOutcome::Expired, "host did not answer", records);
+            keys.iter().filter_map(|key| entries.forwarded.remove(key)).map(|request| request.pending).collect()
+        })
+    }
+
+    /// Holds a download until its host approves it, returning the id of the request.
+    pub fn request_approval(&self, pending: PendingDownload, details: TransferDetails) -> String {
+        let request_id = format!("approval_{}", rand::random::<u64>());
+        if has_both_parties(&pending.key) {
+            self.entries.lock().unwrap().approvals.insert(request_id.clone(), PendingApproval {
+                pending,
+                details,
+                requested_at: chrono::Utc::now(),
+                deadline: Instant::now() + self.approval_timeout,
+            });
+        }
+        request_id
+    }
+
+    /// Settles a request made to `host`, returning the download it was for. An approved
+    /// download is then admitted like any other; a declined one is audited. None when
+    /// there is no such request for this host, e.g. because it expired.
+    pub fn resolve_approval(&self, request_id: &str, host: &SessionId, approve: bool) -> Option<PendingDownload> {
+        self.update(|entries, records| {
+            if entries.approvals.get(request_id).is_none_or(|approval| &approval.pending.key.1 != host) {
+                return None;
+            }
+            let approval = entries.approvals.remove(request_id)?;
+            let pending = approval.pending.clone();
+            if approve {
+                entries.approved.insert(pending.key.clone(), chrono::Utc::now());
+            } else {
+                records.push(entries.approval_record(approval, TransferOutcome::Declined));
+            }
+            Some(pending)
+        })
+    }
+
+    /// Removes the requests whose host did not answer in time, returning their downloads.
+    pub fn expire_approvals(&self) -> Vec<PendingDownload> {
+        let now = Instant::now();
+        self.update(|entries, records| {
+            entries.take_approvals(|approval| approval.deadline <= now, TransferOutcome::Expired, records)
+        })
+    }
+
+    /// Starts queued downloads that fit within the limits again, e.g. after a transfer
+    /// finished, failed or stalled. Each host's queue is served in order.
+    pub fn drain_queues(&self) -> QueueProgress {
+        let mut entries = self.entries.lock().unwrap();
+        let mut progress = QueueProgress::default();
+
+        let hosts = entries.queues.keys().cloned().collect::<Vec<_>>();
+        for host in hosts {
+            while let Some(key) = entries.queues.get(&host).and_then(VecDeque::front).map(|pending| pending.key.clone()) {
+                if entries.blocking_limit(&self.limits, &key).is_some() {
+                    break;
+                }
+                if let Some(pending) = entries.queues.get_mut(&host).and_then(VecDeque::pop_front) {
+                    entries.insert_active(pending.key.clone(), ActiveTransfer::reserved());
+                    progress.started.push(pending);
+                    entries.changed.insert(host.clone());
+                }
+            }
+        }
+
+        for host in std::mem::take(&mut entries.changed) {
+            if let Some(queue) = entries.queues.get(&host) {
+                progress.positions.extend(queue.iter().cloned().zip(1..));
+            }
+        }
+        entries.queues.retain(|_, queue| !queue.is_empty());
+        progress
+    }
+
+    /// The host with the fewest running and queued downloads. Ties go to the host with
+    /// the shortest `rtt` to the requester, then to hosts whose round trip is unknown.
+    pub fn least_busy_host(&self, hosts: &HashSet<SessionId>, rtt: impl Fn(&SessionId) -> Option<Duration>) -> Option<SessionId> {
+        let entries = self.entries.lock().unwrap();
+        let load = |host: &SessionId| {
+            let queued = entries.queues.get(host).map_or(0, VecDeque::len);
+            queued + entries.running().filter(|(_, from, _)| from == host).count()
+        };
+        hosts.iter().min_by_key(|host| (load(host), rtt(host).unwrap_or(Duration::MAX))).cloned()
+    }
+
+    /// Records a transfer announced by its metadata, so its chunks are accepted.
+    pub fn begin_transfer(&self, key: &TransferKey, details: TransferDetails) {
+        let (file_id, from, to) = key;
+        tracing::info!(file_id, from, to, "Transfer started");
+        self.events.record(EventKind::TransferStarted, EventSubject {
+            session_id: Some(from.clone()),
+            file_id: Some(file_id.clone()),
+            detail: Some(format!("for {to}")),
+            ..Default::default()
+        });
+        let _ = self.activity.send((from.clone(), ServerMessage::UploadActivity {
+            file_id: file_id.clone(),
+            requester_name: details.requester_name.clone(),
+            event: UploadEvent::Started,
+        }));
+        let mut entries = self.entries.lock().unwrap();
+        entries.forwarded.remove(key);
+        entries.insert_active(key.clone(), ActiveTransfer::new(details));
+    }
+
+    pub fn details(&self, key: &TransferKey) -> Option<TransferDetails> {
+        self.entries.lock().unwrap().active.get(key).map(|transfer| transfer.details.clone())
+    }
+
+    pub fn has_transfer(&self, key: &TransferKey) -> bool {
+        self.entries.lock().unwrap().active.contains_key(key)
+    }
+
+    /// Ends a running transfer that broke off, e.g. when its relay pipe failed. Returns
+    /// whether it was still running.
+    pub fn fail_transfer(&self, key: &TransferKey, reason: &str) -> bool {
+        self.update(|entries, records| {
+            entries.release(|active| active == key);
+            !entries.abandon(|active| active == key, TransferOutcome::Failed, reason, records).is_empty()
+        })
+    }
+
+    pub fn finish_transfer(&self, key: &TransferKey) {
+        self.update(|entries, records| {
+            entries.limiters.remove(key);
+            if let Some(transfer) = entries.active.remove(key) {
+                let (file_id, from, to) = key;
+                tracing::info!(file_id, from, to, bytes = transfer.bytes, "Transfer complete");
+                entries.completed.insert((file_id.clone(), to.clone()));
+                records.push(entries.record(key, transfer, TransferOutcome::Complete));
+            }
+        })
+    }
+
+    /// Releases everything the session was party to: running transfers, rate limiters,
+    /// unanswered and unapproved requests and queued downloads. Returns the transfers
+    /// that were in flight or awaiting approval, and the queued downloads from the
+    /// session if it was their host, whose counterpart should be told.
+    pub fn on_peer_disconnected(&self, session_id: &SessionId) -> Vec<TransferKey> {
+        let involved = |(_, from, to): &TransferKey| from == session_id || to == session_id;
+        self.update(|entries, records| {
+            entries.release(involved);
+            let mut interrupted = entries.abandon(involved, TransferOutcome::Failed, "peer disconnected", records);
+            let unapproved = entries.take_approvals(|approval| involved(&approval.pending.key), TransferOutcome::Failed, records);
+            interrupted.extend(unapproved.into_iter().map(|pending| pending.key));
+            let dequeued = entries.dequeue(involved);
+            interrupted.extend(dequeued.into_iter().filter(|(_, from, _)| from == session_id));
+            entries.approved.retain(|key, _| !involved(key));
+            entries.completed.retain(|(_, to)| to != session_id);
+            entries.changed.remove(session_id);
+            interrupted
+        })
+    }
+
+    /// Drops per-transfer state and queued downloads for every transfer of the file.
+    pub fn forget_file(&self, file_id: &str) {
+        self.cancel_transfers(file_id, "file removed");
+        self.entries.lock().unwrap().completed.retain(|(id, _)| id != file_id);
+    }
+
+    /// Whether the requester finished downloading the file over the relay since it last
+    /// reported a download of it. Each completed download is only taken once.
+    pub fn take_completed(&self, file_id: &str, requester: &SessionId) -> bool {
+        self.entries.lock().unwrap().completed.remove(&(file_id.to_string(), requester.clone()))
+    }
+
+    /// Cancels the running, queued and unapproved transfers of the file, returning them.
+    pub fn cancel_transfers(&self, file_id: &str, reason: &str) -> Vec<TransferKey> {
+        let of_file = |(id, _, _): &TransferKey| id == file_id;
+        self.update(|entries, records| {
+            entries.release(of_file);
+            let mut cancelled = entries.abandon(of_file, TransferOutcome::Cancelled, reason, records);
+            cancelled.extend(entries.dequeue(of_file));
+            let unapproved = entries.take_approvals(|approval| of_file(&approval.pending.key), TransferOutcome::Cancelled, records);
+            cancelled.extend(unapproved.into_iter().map(|pending| pending.key));
+            cancelled
+        })
+    }
+
+    /// Whether chunks of the file are still flowing, i.e. a transfer has not completed or stalled.
+    pub fn is_transferring(&self, file_id: &str) -> bool {
+        self.entries.lock().unwrap().running().any(|(id, _, _)| id == file_id)
+    }
+
+    /// Transfers the session is sending or receiving, with seconds since their last chunk.
+    pub fn transfers_of(&self, session_id: &SessionId) -> Vec<TransferInfo> {
+        self.entries
+            .lock()
+            .unwrap()
+            .active
+            .iter()
+            .filter(|((_, from, to), _)| from == session_id || to == session_id)
+            .map(|((file_id, from, to), transfer)| TransferInfo {
+                file_id: file_id.clone(),
+                from_session_id: from.clone(),
+                to_session_id: to.clone(),
+                idle_secs: transfer.last_chunk.elapsed().as_secs(),
+            })
+            .collect()
+    }
+
+    /// Transfers the host has begun and that have not completed or stalled, with their
+    /// progress and recent rate.
+    pub fn live_transfers(&self) -> Vec<LiveTransfer> {
+        let mut entries = self.entries.lock().unwrap();
+        let mut transfers: Vec<LiveTransfer> = entries
+            .active
+            .iter_mut()
+            .filter(|(_, transfer)| transfer.begun && !transfer.is_stalled())
+            .map(|((file_id, from, to), transfer)| LiveTransfer {
+                file_id: file_id.clone(),
+                file_name: transfer.details.file_name.clone(),
+                host_session_id: from.clone(),
+                host_name: transfer.details.host_name.clone(),
+                requester_session_id: to.clone(),
+                requester_name: transfer.details.requester_name.clone(),
+                chunks_done: transfer.chunks_done,
+                total_chunks: transfer.total_chunks,
+                bytes_per_sec: (transfer.meter.rate() + RATE_ROUNDING / 2) / RATE_ROUNDING * RATE_ROUNDING,
+                started_at: transfer.started_at,
+            })
+            .collect();
+        transfers.sort_by_key(|transfer| transfer.started_at);
+        transfers
+    }
+
+    /// Transfers holding a slot, and downloads queued for one.
+    pub fn counts(&self) -> (usize, usize) {
+        let entries = self.entries.lock().unwrap();
+        (entries.running().count(), entries.queues.values().map(VecDeque::len).sum())
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    pub(crate) fn table() -> TransferTable {
+        let limits = TransferLimits {
+            per_host: 3,
+            total: None,
+            per_peer: None,
+            reject_excess: false,
+        };
+        TransferTable::new(
+            limits,
+            Duration::from_secs(60),
+            Duration::from_secs(60),
+            Arc::new(AuditLog::new(100, None).unwrap()),
+            Arc::new(EventLog::new(100)),
+            mpsc::unbounded_channel().0,
+        )
+    }
+
+    pub(crate) fn key() -> TransferKey {
+        ("file_1".to_string(), "host".to_string(), "requester".to_string())
+    }
+
+    /// A table whose requests and approvals are overdue as soon as they are made.
+    fn table_timing_out_at_once() -> TransferTable {
+        TransferTable::new(
+            table().limits(),
+            Duration::ZERO,
+            Duration::ZERO,
+            Arc::new(AuditLog::new(100, None).unwrap()),
+            Arc::new(EventLog::new(100)),
+            mpsc::unbounded_channel().0,
+        )
+    }
+
+    fn pending(file_id: &str, host: &str, requester: &str) -> PendingDownload {
+        PendingDownload {
+            key: (file_id.to_string(), host.to_string(), requester.to_string()),
+            room: "main".to_string(),
+            chunk_size: DEFAULT_CHUNK_SIZE,
+            tried_hosts: HashSet::new(),
+            http_relay: false,
+        }
+    }
+
+    /// Fills the host's three slots with downloads of `file_1` to `requester_1` to
+    /// `requester_3`, and queues a fourth to `requester_4`.
+    fn fill_host(table: &TransferTable) -> Vec<PendingDownload> {
+        let downloads = (1..=4).map(|n| pending("file_1", "host", &format!("requester_{n}"))).collect::<Vec<_>>();
+        for download in &downloads[..3] {
+            assert!(matches!(table.request_download(download.clone()), Admission::Start));
+            table.forward(download);
+        }
+        assert!(matches!(table.request_download(downloads[3].clone()), Admission::Queued(1)));
+        assert_eq!(table.counts(), (3, 1));
+        downloads
+    }
+
+    /// Asserts nothing is left of any transfer but the completed downloads waiting to
+    /// be reported.
+    fn assert_released(table: &TransferTable) {
+        assert_eq!(table.counts(), (0, 0));
+        let entries = table.entries.lock().unwrap();
+        assert!(entries.active.is_empty(), "active: {:?}", entries.active.keys().collect::<Vec<_>>());
+        assert!(entries.limiters.is_empty());
+        assert!(entries.queues.is_empty());
+        assert!(entries.approvals.is_empty());
+        assert!(entries.approved.is_empty());
+        assert!(entries.forwarded.is_empty());
+    }
+
+    #[test]
+    fn requested_rate_needs_a_running_transfer() {
+        let table = table();
+        assert!(!table.set_requested_rate(&key(), 1000));
+        assert!(table.requested_limiter(&key()).is_none());
+    }
+
+    #[test]
+    fn requested_rate_is_set_and_lifted_with_zero() {
+        let table = table();
+        table.begin_transfer(&key(), TransferDetails::default());
+        assert!(table.requested_limiter(&key()).is_none());
+
+        assert!(table.set_requested_rate(&key(), 1000));
+        assert!(table.requested_limiter(&key()).is_some());
+        assert!(table.set_requested_rate(&key(), 0));
+        assert!(table.requested_limiter(&key()).is_none());
+    }
+
+    #[test]
+    fn requested_rate_ends_with_the_transfer() {
+        let table = table();
+        table.begin_transfer(&key(), TransferDetails::default());
+        assert!(table.set_requested_rate(&key(), 1000));
+        table.finish_transfer(&key());
+        assert!(table.requested_limiter(&key()).is_none());
+        assert!(!table.set_requested_rate(&key(), 1000));
+    }
+
+    #[test]
+    fn requested_rate_is_kept_per_transfer() {
+        let table = table();
+        let other = ("file_2".to_string(), "host".to_string(), "requester".to_string());
+        table.begin_transfer(&key(), TransferDetails::default());
+        table.begin_transfer(&other, TransferDetails::default());
+        assert!(table.set_requested_rate(&key(), 1000));
+        assert!(table.requested_limiter(&other).is_none());
+    }
+
+    #[test]
+    fn transfer_is_complete_once_every_chunk_is_counted() {
+        let table = table();
+        table.begin_transfer(&key(), TransferDetails::default());
+        // The last index arriving first does not end the transfer
+        assert!(!table.record_chunk(&key(), 100, 3));
+        assert!(!table.record_chunk(&key(), 100, 3));
+        assert!(table.has_transfer(&key()));
+        assert!(table.record_chunk(&key(), 100, 3));
+    }
+
+    #[test]
+    fn late_chunks_do_not_start_another_transfer() {
+        let table = table();
+        table.begin_transfer(&key(), TransferDetails::default());
+        assert!(table.record_chunk(&key(), 100, 1));
+        table.finish_transfer(&key());
+
+        assert!(!table.record_chunk(&key(), 100, 1));
+        assert!(!table.has_transfer(&key()));
+        assert_eq!(table.counts(), (0, 0));
+    }
+
+    #[test]
+    fn completed_transfers_free_their_slots() {
+        let table = table();
+        let downloads = fill_host(&table);
+        for download in &downloads[..3] {
+            table.begin_transfer(&download.key, TransferDetails::default());
+            assert!(table.record_chunk(&download.key, 100, 1));
+            table.finish_transfer(&download.key);
+        }
+        assert_eq!(table.counts(), (0, 1));
+
+        let started = table.drain_queues().started;
+        assert_eq!(started.len(), 1);
+        assert_eq!(table.counts(), (1, 0));
+        table.finish_transfer(&started[0].key);
+        assert_released(&table);
+    }
+
+    #[test]
+    fn failed_transfers_free_their_slots() {
+        let table = table();
+        let downloads = fill_host(&table);
+        table.begin_transfer(&downloads[0].key, TransferDetails::default());
+        table.limiter(&downloads[0].key, 1000);
+        assert!(table.fail_transfer(&downloads[0].key, "relay pipe failed"));
+        assert!(!table.fail_transfer(&downloads[0].key, "relay pipe failed"));
+        assert_eq!(table.counts(), (2, 1));
+
+        for download in &downloads[1..3] {
+            assert!(table.fail_transfer(&download.key, "relay pipe failed"));
+        }
+        let started = table.drain_queues().started;
+        assert!(table.fail_transfer(&started[0].key, "relay pipe failed"));
+        assert_released(&table);
+    }
+
+    #[test]
+    fn denied_requests_free_their_slots() {
+        let table = table();
+        let downloads = fill_host(&table);
+        for download in &downloads[..3] {
+            assert!(table.deny(&download.key).is_some());
+            // A request is only refused once
+            assert!(table.deny(&download.key).is_none());
+        }
+        assert_eq!(table.counts(), (0, 1));
+
+        let started = table.drain_queues().started;
+        table.forward(&started[0]);
+        assert!(table.deny(&started[0].key).is_some());
+        assert_released(&table);
+    }
+
+    #[test]
+    fn expired_requests_and_approvals_free_their_slots() {
+        let table = table_timing_out_at_once();
+        let downloads = fill_host(&table);
+        table.request_approval(pending("file_2", "host", "requester_5"), TransferDetails::default());
+
+        assert_eq!(table.expire_requests().len(), 3);
+        assert_eq!(table.expire_approvals().len(), 1);
+        assert_eq!(table.counts(), (0, 1));
+
+        let started = table.drain_queues().started;
+        assert_eq!(started[0].key, downloads[3].key);
+        table.forward(&started[0]);
+        assert_eq!(table.expire_requests().len(), 1);
+        assert_released(&table);
+    }
+
+    #[test]
+    fn disconnecting_frees_every_slot_of_the_session() {
+        let table = table();
+        let downloads = fill_host(&table);
+        table.begin_transfer(&downloads[0].key, TransferDetails::default());
+        table.limiter(&downloads[0].key, 1000);
+        table.record_chunk(&downloads[0].key, 100, 10);
+        let request_id = table.request_approval(pending("file_2", "host", "requester_5"), TransferDetails::default());
+        table.request_approval(pending("file_3", "host", "requester_6"), TransferDetails::default());
+        assert!(table.resolve_approval(&request_id, &"host".to_string(), true).is_some());
+
+        let interrupted = table.on_peer_disconnected(&"host".to_string());
+        // Three running, one queued and one still awaiting approval
+        assert_eq!(interrupted.len(), 5);
+        assert_released(&table);
+    }
+
+    #[test]
+    fn disconnecting_requesters_free_their_slots_for_the_queue() {
+        let table = table();
+        let downloads = fill_host(&table);
+        table.on_peer_disconnected(&"requester_1".to_string());
+        assert_eq!(table.counts(), (2, 1));
+        assert_eq!(table.drain_queues().started.len(), 1);
+        assert_eq!(table.counts(), (3, 0));
+
+        for download in &downloads[1..] {
+            table.on_peer_disconnected(&download.key.2);
+        }
+        assert_released(&table);
+    }
+
+    #[test]
+    fn cancelling_frees_every_slot_of_the_file() {
+        let table = table();
+        let downloads = fill_host(&table);
+        table.begin_transfer(&downloads[0].key, TransferDetails::default());
+        table.limiter(&downloads[0].key, 1000);
+        table.request_approval(pending("file_1", "host", "requester_5"), TransferDetails::default());
+        let other = pending("file_2", "other_host", "requester_6");
+        assert!(matches!(table.request_download(other.clone()), Admission::Start));
+
+        let cancelled = table.cancel_transfers("file_1", "file removed");
+        assert_eq!(cancelled.len(), 5);
+        // Only the other file's transfer is left
+        assert_eq!(table.counts(), (1, 0));
+        table.cancel_transfers("file_2", "file removed");
+        assert_released(&table);
+    }
+
+    /// Applies random sequences of what peers and hosts do to a table with every limit
+    /// set, checking the limits hold throughout and that nothing is left once every
+    /// peer has gone.
+    #[test]
+    fn random_interleavings_leave_nothing_behind() {
+        use rand::rngs::StdRng;
+        use rand::seq::SliceRandom;
+        use rand::{Rng, SeedableRng};
+
+        const FILES: [&str; 3] = ["file_1", "file_2", "file_3"];
+        const PEERS: [&str; 5] = ["peer_1", "peer_2", "peer_3", "peer_4", "peer_5"];
+        let limits = TransferLimits {
+            per_host: 2,
+            total: Some(4),
+            per_peer: Some(3),
+            reject_excess: false,
+        };
+
+        for seed in 0..200 {
+            let mut rng = StdRng::seed_from_u64(seed);
+            let table = TransferTable::new(
+                limits,
+                Duration::from_secs(60),
+                Duration::from_secs(60),
+                Arc::new(AuditLog::new(100, None).unwrap()),
+                Arc::new(EventLog::new(100)),
+                mpsc::unbounded_channel().0,
+            );
+            let mut approvals = Vec::new();
+
+            for _ in 0..300 {
+                let file = *FILES.choose(&mut rng).unwrap();
+                let mut parties = PEERS.choose_multiple(&mut rng, 2);
+                let (host, requester) = (*parties.next().unwrap(), *parties.next().unwrap());
+                let download = pending(file, host, requester);
+                let running = table.entries.lock().unwrap().active.keys().cloned().collect::<Vec<_>>();
+
+                match rng.gen_range(0..10) {
+                    0 | 1 => {
+                        if table.request_download(download.clone()) == Admission::Start {
+                            table.forward(&download);
+                        }
+                    }
+                    2 => approvals.push(table.request_approval(download, TransferDetails::default())),
+                    3 => {
+                        if let Some(request_id) = approvals.pop() {
+                            let approved = table.resolve_approval(&request_id, &host.to_string(), rng.gen());
+                            if let Some(approved) = approved {
+                                table.request_download(approved);
+                            }
+                        }
+                    }
+                    4 => {
+                        if let Some(key) = running.choose(&mut rng) {
+                            table.begin_transfer(key, TransferDetails::default());
+                        }
+                    }
+                    5 => {
+                        if let Some(key) = running.choose(&mut rng) {
+                            if table.record_chunk(key, 100, rng.gen_range(1..4)) {
+                                table.finish_transfer(key);
+                            }
+                        }
+                    }
+                    6 => {
+                        if let Some(key) = running.choose(&mut rng) {
+                            if rng.gen() {
+                                table.fail_transfer(key, "relay pipe failed");
+                            } else {
+                                table.deny(key);
+                            }
+                        }
+                    }
+                    7 => {
+                        table.cancel_transfers(file, "file removed");
+                    }
+                    8 => {
+                        table.on_peer_disconnected(&host.to_string());
+                    }
+                    _ => {
+                        for started in table.drain_queues().started {
+                            table.forward(&started);
+                        }
+                    }
+                }
+
+                let entries = table.entries.lock().unwrap();
+                let running = entries.running().collect::<Vec<_>>();
+                assert!(running.len() <= 4, "seed {seed}: {} running", running.len());
+                for peer in PEERS {
+                    let hosting = running.iter().filter(|(_, from, _)| from == peer).count();
+                    let involved = running.iter().filter(|(_, from, to)| from == peer || to == peer).count();
+                    assert!(hosting <= 2, "seed {seed}: {peer} hosts {hosting}");
+                    assert!(involved <= 3, "seed {seed}: {peer} takes part in {involved}");
+                }
+                for queued in entries.queues.values().flatten() {
+                    assert!(!entries.active.contains_key(&queued.key), "seed {seed}: {:?} is queued and running", queued.key);
+                }
+            }
+
+            for peer in PEERS {
+                table.on_peer_disconnected(&peer.to_string());
+            }
+            assert!(table.drain_queues().started.is_empty());
+            assert_released(&table);
+            assert!(table.entries.lock().unwrap().completed.is_empty());
+        }
+    }
+}
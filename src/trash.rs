@@ -0,0 +1,103 @@
+use crate::types::*;
+use crate::AppState;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+struct Trashed {
+    room: String,
+    file: Arc<FileMetadata>,
+    /// Who hosted the file before it was removed, including the last host of an
+    /// orphaned file
+    hosts: HashSet<SessionId>,
+    removed_at: Instant,
+}
+
+/// Files removed by their uploader or for having no hosts left, kept for a while so
+/// the uploader can restore them.
+pub struct Trash {
+    retention: Duration,
+    files: Mutex<HashMap<String, Trashed>>,
+}
+
+impl Trash {
+    /// A trash that keeps files for `retention`; with zero, nothing is kept.
+    pub fn new(retention: Duration) -> Self {
+        Trash {
+            retention,
+            files: Mutex::default(),
+        }
+    }
+
+    pub fn put(&self, room: &str, file: Arc<FileMetadata>) {
+        if self.retention.is_zero() {
+            return;
+        }
+        let mut hosts = file.hosts.clone();
+        hosts.extend(file.orphaned.as_ref().map(|orphaned| orphaned.last_host.clone()));
+        self.files.lock().unwrap().insert(file.id.clone(), Trashed {
+            room: room.to_string(),
+            file,
+            hosts,
+            removed_at: Instant::now(),
+        });
+    }
+
+    /// Takes a file of `uploader` out of the trash of `room`, with who hosted it.
+    pub fn take(&self, room: &str, file_id: &str, uploader: &SessionId) -> Option<(Arc<FileMetadata>, HashSet<SessionId>)> {
+        let mut files = self.files.lock().unwrap();
+        files
+            .get(file_id)
+            .filter(|trashed| trashed.room == room && &trashed.file.uploader_id == uploader)?;
+        files.remove(file_id).map(|trashed| (trashed.file, trashed.hosts))
+    }
+
+    /// Puts back a file taken out by `take` that could not be restored after all.
+    pub fn put_back(&self, room: &str, file: Arc<FileMetadata>, hosts: HashSet<SessionId>) {
+        self.files.lock().unwrap().insert(file.id.clone(), Trashed {
+            room: room.to_string(),
+            file,
+            hosts,
+            removed_at: Instant::now(),
+        });
+    }
+
+    /// The files of `uploader` in the trash, soonest to go first.
+    pub fn files_of(&self, uploader: &SessionId) -> Vec<TrashedFile> {
+        let now = chrono::Utc::now();
+        let mut files = self
+            .files
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|trashed| &trashed.file.uploader_id == uploader)
+            .map(|trashed| {
+                let left = self.retention.saturating_sub(trashed.removed_at.elapsed());
+                TrashedFile {
+                    room: trashed.room.clone(),
+                    file: trashed.file.clone(),
+                    expires_at: now + chrono::Duration::from_std(left).unwrap_or_default(),
+                }
+            })
+            .collect::<Vec<_>>();
+        files.sort_by_key(|trashed| trashed.expires_at);
+        files
+    }
+
+    /// Empties files kept longer than the retention.
+    pub fn sweep(&self) {
+        self.files.lock().unwrap().retain(|file_id, trashed| {
+            let kept = trashed.removed_at.elapsed() < self.retention;
+            if !kept {
+                tracing::debug!(file_id, "Trashed file dropped");
+            }
+            kept
+        });
+    }
+}
+
+/// Tells `uploader` what of theirs is in the trash, if it is connected.
+pub async fn notify(state: &AppState, uploader: &SessionId) {
+    let files = state.trash.files_of(uploader);
+    state.send_to(uploader, ServerMessage::TrashList { files }).await;
+}
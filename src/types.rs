@@ -1,13 +1,150 @@
 use serde::{Deserialize, Serialize};
-use std::collections::HashSet;
+use std::collections::{BTreeMap, HashSet};
+use std::net::IpAddr;
+use std::sync::Arc;
 
 pub type SessionId = String;
 
+/// Version of the WebSocket protocol this server speaks. Clients that leave
+/// `Join::protocol_version` out are treated as version 1 and never receive
+/// behavior introduced later, such as `FileListDiff`.
+pub const PROTOCOL_VERSION: u32 = 2;
+
+/// Feature strings listed in `Welcome`.
+pub const SERVER_FEATURES: &[&str] = &[
+    "rooms",
+    "file_list_diff",
+    "file_list_pages",
+    "sequence_numbers",
+    "message_ack",
+    "peer_list",
+    "rename",
+    "compression",
+    "e2e",
+    "webrtc_signaling",
+    "http_relay",
+    "topics",
+];
+
+/// The highest version both sides speak.
+pub fn negotiate_protocol_version(client_version: Option<u32>) -> u32 {
+    client_version.unwrap_or(1).clamp(1, PROTOCOL_VERSION)
+}
+
+// Chunk size negotiation: a peer may state a `preferred_chunk_size` in `Join`. The
+// server clamps it to `MIN_CHUNK_SIZE..=MAX_CHUNK_SIZE` (falling back to
+// `DEFAULT_CHUNK_SIZE`), stores it on `PeerInfo`, and passes the requester's value to
+// the host in `DownloadRequest::chunk_size`. The host chunks accordingly, and the
+// `FileMetadata` it sends carries `chunk_size` and `total_chunks` so the receiver knows
+// what to expect. Each `FileChunk` states the `offset` and `len` of its raw data in the
+// file, so receivers can write chunks in place whatever order they arrive in; the
+// server fills both in for hosts that only send `chunk_index`.
+pub const MIN_CHUNK_SIZE: u32 = 4 * 1024;
+pub const MAX_CHUNK_SIZE: u32 = 4 * 1024 * 1024;
+pub const DEFAULT_CHUNK_SIZE: u32 = 64 * 1024;
+
+/// Compression identifiers accepted in `FileChunk::compression` and
+/// `FileMetadata::compression`. The server only relays the field; peers advertise
+/// which ones they can decode through `Join::capabilities` (e.g. "compression:gzip").
+pub const SUPPORTED_COMPRESSIONS: &[&str] = &["gzip", "zstd"];
+
+pub fn is_supported_compression(compression: &str) -> bool {
+    SUPPORTED_COMPRESSIONS.contains(&compression)
+}
+
+/// The capability a peer declares when it can decode `compression`.
+pub fn compression_capability(compression: &str) -> String {
+    format!("compression:{compression}")
+}
+
+/// Capability for peers that apply incremental `FileListDiff`s. Peers without it, or
+/// on protocol version 1, keep receiving a full `FileListUpdate` on every change.
+pub const FILE_LIST_DIFF_CAPABILITY: &str = "file_list_diff";
+
+/// Capability for peers that encrypt transfers end to end. Key exchange, encrypted
+/// chunks and files marked `e2e` are only accepted between peers that declared it.
+pub const E2E_CAPABILITY: &str = "e2e";
+
+/// Capability for peers that fetch large file lists a page at a time. Above
+/// `--file-list-page-size` files they get a `FileListSummary` instead of the full list.
+pub const FILE_LIST_PAGES_CAPABILITY: &str = "file_list_pages";
+
+/// Capability for peers that can send and fetch a file as one HTTP stream through
+/// `/relay/{relay_id}`. Downloads between two such peers carry a `relay_id`; the
+/// `FileChunk` path still works for them as a fallback.
+pub const HTTP_RELAY_CAPABILITY: &str = "http_relay";
+
+pub fn negotiate_chunk_size(preferred: Option<u32>) -> u32 {
+    preferred
+        .map(|size| size.clamp(MIN_CHUNK_SIZE, MAX_CHUNK_SIZE))
+        .unwrap_or(DEFAULT_CHUNK_SIZE)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PeerRole {
+    Full,
+    ReadOnly,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DeviceType {
+    Desktop,
+    Mobile,
+    Tablet,
+    Cli,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ActivityState {
+    Active,
+    Idle,
+}
+
+/// What a peer is running, derived from its user agent.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ClientInfo {
+    pub device_type: DeviceType,
+    pub os: Option<String>,
+    pub browser: Option<String>,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct PeerInfo {
     pub session_id: SessionId,
+    /// Display name, unique within the room
+    pub name: Option<String>,
+    /// Hue (0-359) clients should use for this peer, fixed for the session
+    pub color_hue: u16,
     pub connected_at: chrono::DateTime<chrono::Utc>,
+    /// When the peer last sent a message, to within a second
+    pub last_seen: chrono::DateTime<chrono::Utc>,
+    pub activity: ActivityState,
+    /// Raw user agent, kept for debugging; `client` is the parsed form
     pub user_agent: Option<String>,
+    pub client: Option<ClientInfo>,
+    /// Left out when the server runs with `--hide-ips`
+    pub ip: Option<IpAddr>,
+    pub role: PeerRole,
+    pub chunk_size: u32,
+    pub capabilities: HashSet<String>,
+    /// Negotiated at join; see `PROTOCOL_VERSION`
+    pub protocol_version: u32,
+    /// When false, each download from this peer waits for it to approve the request
+    pub auto_accept_downloads: bool,
+}
+
+impl PeerInfo {
+    pub fn receives_file_list_diffs(&self) -> bool {
+        self.protocol_version >= 2 && self.capabilities.contains(FILE_LIST_DIFF_CAPABILITY)
+    }
+
+    /// Paging relies on diffs to keep the pages a peer holds up to date.
+    pub fn receives_file_list_pages(&self) -> bool {
+        self.receives_file_list_diffs() && self.capabilities.contains(FILE_LIST_PAGES_CAPABILITY)
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -21,6 +158,51 @@ pub struct AuthResponse {
     pub message: Option<String>,
 }
 
+/// A bearer token for clients that cannot keep the `auth` cookie.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuthToken {
+    pub token: String,
+    pub expires_at: chrono::DateTime<chrono::Utc>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TokenQuery {
+    pub token: Option<String>,
+}
+
+/// The session a `/relay/{relay_id}` request is made for.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RelayQuery {
+    pub session_id: SessionId,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ApiResponse {
+    pub success: bool,
+    pub message: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Ban {
+    pub ip: IpAddr,
+    pub reason: Option<String>,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+    pub expires_at: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+impl Ban {
+    pub fn is_expired(&self, now: chrono::DateTime<chrono::Utc>) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BanRequest {
+    pub ip: IpAddr,
+    pub reason: Option<String>,
+    pub duration_secs: Option<u64>,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct FileMetadata {
     pub id: String,
@@ -30,15 +212,295 @@ pub struct FileMetadata {
     pub uploader_id: SessionId,
     pub hosts: HashSet<SessionId>,
     pub uploaded_at: chrono::DateTime<chrono::Utc>,
+    pub description: Option<String>,
+    /// When the file is removed from the registry; None keeps it until its hosts leave
+    pub expires_at: Option<chrono::DateTime<chrono::Utc>>,
+    /// Path within a shared folder, e.g. `photos/2024/a.jpg`; always relative and
+    /// without `.` or `..` segments
+    pub relative_path: Option<String>,
+    /// Shared by the files uploaded together as one folder
+    pub folder_id: Option<String>,
+    /// Set by the server, the same on files one peer shared in one action: one
+    /// `FileUploadBatch`, or `FileUpload`s sent within `--batch-window` of each other
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub batch_id: Option<String>,
+    /// Peers a private file is listed for besides its uploader; None lists it for everyone
+    #[serde(default)]
+    pub visibility: Option<Vec<SessionId>>,
+    /// Set while no host is connected. The file is kept for the orphan grace period
+    /// in case its last host reconnects.
+    #[serde(default)]
+    pub unavailable: bool,
+    #[serde(skip)]
+    pub orphaned: Option<Orphaned>,
+    /// The content is encrypted end to end and only ever served peer to peer
+    #[serde(default)]
+    pub e2e: bool,
+    /// Downloads need the file's password; set by the server from `password_hash`
+    #[serde(default)]
+    pub locked: bool,
+    /// Argon2 hash of the password in PHC format. Accepted from the uploader but
+    /// never sent to peers.
+    #[serde(default, skip_serializing)]
+    pub password_hash: Option<String>,
+    /// A plaintext password from the uploader, hashed and cleared on upload
+    #[serde(default, skip_serializing)]
+    pub password: Option<String>,
+    /// Starts at 1 and goes up each time the uploader replaces the file's content
+    #[serde(default)]
+    pub version: u32,
+    /// Number of distinct peers that downloaded the file
+    #[serde(default)]
+    pub download_count: u32,
+    #[serde(skip)]
+    pub downloaders: HashSet<SessionId>,
+    /// Set on files a bridge mirrored from another server. Bridges only pass on files
+    /// without it, so a file never travels back to where it came from.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub origin: Option<FileOrigin>,
+    /// Hex SHA-256 of the content, once the host has computed it. Set by the server
+    /// for files of the shared directory; a peer's announcement is passed on unchecked.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sha256: Option<String>,
+    /// Set by the server from the MIME type and name; whatever the uploader sent is ignored
+    #[serde(default)]
+    pub category: FileCategory,
+    /// Set by the server, e.g. " (2)", to tell apart files listed under the same name
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub display_suffix: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FileOrigin {
+    Remote,
+}
+
+/// Kind of content a file holds, for grouping files e.g. in tabs.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FileCategory {
+    Image,
+    Video,
+    Audio,
+    Document,
+    Archive,
+    Code,
+    #[default]
+    Other,
+}
+
+impl FileCategory {
+    /// The category of a file from its MIME type, or from its extension when the type
+    /// is generic, unknown or contradicted by the extension.
+    pub fn detect(mime_type: &str, name: &str) -> FileCategory {
+        let by_extension = name
+            .rsplit_once('.')
+            .and_then(|(_, extension)| FileCategory::from_extension(&extension.to_ascii_lowercase()));
+        match FileCategory::from_mime_type(mime_type) {
+            Some(category) if by_extension.is_none_or(|by_extension| by_extension == category) => category,
+            _ => by_extension.unwrap_or_default(),
+        }
+    }
+
+    fn from_mime_type(mime_type: &str) -> Option<FileCategory> {
+        let mime_type = mime_type.split(';').next().unwrap_or_default().trim().to_ascii_lowercase();
+        let (kind, subtype) = mime_type.split_once('/')?;
+        let category = match (kind, subtype) {
+            ("image", _) => FileCategory::Image,
+            ("video", _) => FileCategory::Video,
+            ("audio", _) => FileCategory::Audio,
+            ("text", "plain" | "markdown" | "csv" | "rtf")
+            | ("application", "pdf" | "rtf" | "msword" | "epub+zip" | "vnd.ms-excel" | "vnd.ms-powerpoint") => FileCategory::Document,
+            ("application", subtype)
+                if subtype.starts_with("vnd.openxmlformats-officedocument.") || subtype.starts_with("vnd.oasis.opendocument.") =>
+            {
+                FileCategory::Document
+            }
+            (
+                "application",
+                "zip" | "gzip" | "x-gzip" | "x-tar" | "x-bzip2" | "x-xz" | "zstd" | "x-7z-compressed" | "x-rar-compressed" | "vnd.rar",
+            ) => FileCategory::Archive,
+            ("text", _) | ("application", "json" | "javascript" | "xml" | "x-sh" | "x-httpd-php" | "sql" | "toml" | "yaml") => {
+                FileCategory::Code
+            }
+            _ => return None,
+        };
+        Some(category)
+    }
+
+    fn from_extension(extension: &str) -> Option<FileCategory> {
+        let category = match extension {
+            "jpg" | "jpeg" | "png" | "gif" | "webp" | "bmp" | "svg" | "ico" | "tif" | "tiff" | "heic" | "heif" | "avif" => {
+                FileCategory::Image
+            }
+            "mp4" | "m4v" | "mkv" | "mov" | "avi" | "webm" | "wmv" | "flv" | "mpg" | "mpeg" => FileCategory::Video,
+            "mp3" | "wav" | "flac" | "ogg" | "oga" | "opus" | "m4a" | "aac" | "wma" => FileCategory::Audio,
+            "pdf" | "doc" | "docx" | "xls" | "xlsx" | "ppt" | "pptx" | "odt" | "ods" | "odp" | "rtf" | "txt" | "md" | "csv"
+            | "epub" => FileCategory::Document,
+            "zip" | "tar" | "gz" | "tgz" | "bz2" | "xz" | "zst" | "7z" | "rar" | "iso" | "dmg" => FileCategory::Archive,
+            "rs" | "py" | "js" | "mjs" | "ts" | "jsx" | "tsx" | "java" | "kt" | "c" | "h" | "cc" | "cpp" | "hpp" | "cs" | "go"
+            | "rb" | "php" | "swift" | "sh" | "bash" | "zsh" | "ps1" | "lua" | "sql" | "html" | "htm" | "css" | "scss" | "json"
+            | "yaml" | "yml" | "toml" | "xml" => FileCategory::Code,
+            _ => return None,
+        };
+        Some(category)
+    }
+}
+
+/// Order of a file list page, shared by `RequestFileList` and `/api/files` so pages
+/// are the same either way. Ties are broken by file id.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FileSort {
+    #[default]
+    Newest,
+    Oldest,
+    Name,
+    Size,
+}
+
+/// A file of a `FileUploadBatch` that was not added, with the error it would have
+/// got as a single `FileUpload`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RejectedFile {
+    pub file_id: String,
+    pub code: String,
+    pub message: String,
+}
+
+impl FileMetadata {
+    /// The sessions a private file is listed for, its uploader included. None for a
+    /// file everyone can see.
+    pub fn audience(&self) -> Option<HashSet<SessionId>> {
+        self.visibility.as_ref().map(|peers| {
+            let mut audience = peers.iter().cloned().collect::<HashSet<_>>();
+            audience.insert(self.uploader_id.clone());
+            audience
+        })
+    }
+
+    pub fn is_visible_to(&self, session_id: Option<&str>) -> bool {
+        match &self.visibility {
+            None => true,
+            Some(peers) => session_id.is_some_and(|id| id == self.uploader_id || peers.iter().any(|peer| peer == id)),
+        }
+    }
+}
+
+/// Point in a transfer's life that its host is told about.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UploadEvent {
+    Started,
+    Completed,
+    Failed,
+}
+
+/// A removed file the uploader can still restore.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TrashedFile {
+    pub room: String,
+    pub file: Arc<FileMetadata>,
+    /// When it is gone for good
+    pub expires_at: chrono::DateTime<chrono::Utc>,
+}
+
+/// Server-side record of a file whose last host disconnected.
+#[derive(Debug, Clone)]
+pub struct Orphaned {
+    pub since: std::time::Instant,
+    pub last_host: SessionId,
+}
+
+/// Who wrote a chat message: a peer, or the server noting an event in the room.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MessageKind {
+    #[default]
+    User,
+    System,
+}
+
+/// What an `RtcSignal` carries between two peers setting up a WebRTC connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RtcSignalKind {
+    Offer,
+    Answer,
+    IceCandidate,
+}
+
+/// Where a chat message passed on by a bridge was first posted. It is kept as the
+/// message crosses further bridges, and a bridge drops messages coming back to the
+/// server they started on.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MessageOrigin {
+    /// `instance_id` of the server the message was first posted on
+    pub instance_id: String,
+    pub server_name: String,
+    pub sender_name: Option<String>,
+}
+
+/// How a chat message's content is meant to be rendered.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MessageFormat {
+    #[default]
+    Plain,
+    /// Markdown with raw HTML removed by the server
+    Markdown,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct TextMessage {
     pub id: String,
     pub content: String,
+    #[serde(default)]
+    pub kind: MessageKind,
+    #[serde(default)]
+    pub format: MessageFormat,
+    /// Ids of shared files the message refers to
+    #[serde(default)]
+    pub attachments: Option<Vec<String>>,
+    /// Id of the message this one answers
+    #[serde(default)]
+    pub reply_to: Option<String>,
     pub sender_id: SessionId,
     pub sender_name: Option<String>,
+    /// The sender's `color_hue` when the message was sent
+    pub sender_color: Option<u16>,
     pub timestamp: chrono::DateTime<chrono::Utc>,
+    /// Set when a bridge posted the message on behalf of a peer of another server
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub origin: Option<MessageOrigin>,
+}
+
+/// A text snippet shared with the room. Unlike files, the content travels with the
+/// listing and snippets outlive their creator's session.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Snippet {
+    pub id: String,
+    pub title: Option<String>,
+    pub content: String,
+    pub creator_id: SessionId,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+}
+
+/// A chat search match with the ids of its neighbours so clients can jump to it in context.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SearchHit {
+    pub message: TextMessage,
+    pub previous_id: Option<String>,
+    pub next_id: Option<String>,
+}
+
+/// A run of consecutive chat messages, oldest first.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct HistoryPage {
+    pub messages: Vec<TextMessage>,
+    /// Older messages remain before the first one
+    pub has_more: bool,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -47,18 +509,50 @@ pub enum ClientMessage {
     #[serde(rename = "join")]
     Join {
         session_id: SessionId,
+        /// Display name; made unique within the room by appending a number
+        name: Option<String>,
         user_agent: Option<String>,
+        /// Room to join; defaults to "main"
+        room: Option<String>,
+        preferred_chunk_size: Option<u32>,
+        capabilities: Option<HashSet<String>>,
+        protocol_version: Option<u32>,
+        /// Set to false to approve each download of this peer's files; defaults to true
+        auto_accept_downloads: Option<bool>,
+        /// Set to false to not get `UploadActivity` notices; defaults to true
+        notify_upload_activity: Option<bool>,
+        /// Version of the client software, checked against `--min-client-version`
+        client_version: Option<String>,
+        /// `session_token` from the `Welcome` of an earlier join with this session id;
+        /// needed to take the id up again while files, trash or snippets still refer to it
+        session_token: Option<String>,
     },
     #[serde(rename = "file_upload")]
     FileUpload {
         session_id: SessionId,
-        file: FileMetadata,
+        file: Box<FileMetadata>,
+    },
+    /// Announces several files at once; peers get a single file list change
+    #[serde(rename = "file_upload_batch")]
+    FileUploadBatch {
+        session_id: SessionId,
+        files: Vec<FileMetadata>,
     },
+    /// Downloads one file, or with `batch_id` instead of `file_id` every file of a batch
+    /// the requester can see, each requested from its own hosts.
     #[serde(rename = "request_download")]
     RequestDownload {
         session_id: SessionId,
-        file_id: String,
+        #[serde(default)]
+        file_id: Option<String>,
+        #[serde(default)]
+        batch_id: Option<String>,
+        /// Required for locked files
+        #[serde(default)]
+        password: Option<String>,
     },
+    /// The sender now holds the file and can host it. The file's download count only goes
+    /// up for a relayed download the server saw complete.
     #[serde(rename = "file_downloaded")]
     FileDownloaded {
         session_id: SessionId,
@@ -70,8 +564,19 @@ pub enum ClientMessage {
         file_id: String,
         chunk_index: u32,
         total_chunks: u32,
+        /// Byte offset of the chunk in the file; `chunk_index` times the chunk size if omitted
+        #[serde(default)]
+        offset: Option<u64>,
+        /// Length of the chunk before compression or encryption
+        #[serde(default)]
+        len: Option<u32>,
         data: String,
         target_session_id: SessionId,
+        compression: Option<String>,
+        /// `data` is encrypted with a key agreed through `KeyExchange`
+        #[serde(default)]
+        encrypted: bool,
+        nonce: Option<String>,
     },
     #[serde(rename = "file_metadata")]
     FileMetadata {
@@ -81,19 +586,260 @@ pub enum ClientMessage {
         file_size: u64,
         mime_type: String,
         total_chunks: u32,
+        /// Size of every chunk but the last; the target's negotiated size if omitted
+        #[serde(default)]
+        chunk_size: Option<u32>,
         target_session_id: SessionId,
+        compression: Option<String>,
+        relative_path: Option<String>,
+        #[serde(default)]
+        encrypted: bool,
+        nonce: Option<String>,
     },
     #[serde(rename = "ping")]
     Ping {
         session_id: SessionId,
+        /// Echoed back in the `Pong` so the client can match it to this ping
+        nonce: Option<u64>,
     },
     #[serde(rename = "text_message")]
     TextMessage {
         session_id: SessionId,
         content: String,
+        /// Plain text when left out
+        format: Option<MessageFormat>,
+        /// Only `user` is accepted; system messages come from the server alone
+        kind: Option<MessageKind>,
+        /// Ids of files in the room to attach
+        attachments: Option<Vec<String>>,
+        /// Id of an earlier message in the room this one answers
+        reply_to: Option<String>,
+        /// Correlation id echoed in `MessageAck`; resending with the same one is a retry
+        client_ref: Option<String>,
+        /// Set by bridges passing on a message from another server; ignored from any other client
+        #[serde(default)]
+        origin: Option<MessageOrigin>,
+    },
+    /// Edits the name and/or description of a file; only its uploader may do this.
+    /// Fields left out are unchanged, and an empty description clears it.
+    #[serde(rename = "update_file_info")]
+    UpdateFileInfo {
+        session_id: SessionId,
+        file_id: String,
+        description: Option<String>,
+        name: Option<String>,
+        /// A list makes the file private to those peers; null makes it public again
+        #[serde(default, deserialize_with = "present")]
+        visibility: Option<Option<Vec<SessionId>>>,
+    },
+    /// Publishes new content under an existing file id; only its uploader may do this.
+    #[serde(rename = "replace_file")]
+    ReplaceFile {
+        session_id: SessionId,
+        file_id: String,
+        new_metadata: FileReplacement,
+    },
+    /// Removes a file, or every file of a folder or batch at once; only their uploader
+    /// may do this.
+    #[serde(rename = "remove_file")]
+    RemoveFile {
+        session_id: SessionId,
+        file_id: Option<String>,
+        folder_id: Option<String>,
+        #[serde(default)]
+        batch_id: Option<String>,
+    },
+    /// Changes the display name. Messages already sent keep the old name.
+    #[serde(rename = "rename")]
+    Rename {
+        session_id: SessionId,
+        new_name: String,
+    },
+    /// Asks for a snapshot of the room after a gap in broadcast sequence numbers, or
+    /// whenever the client's view may have gone stale. At most one every few seconds.
+    #[serde(rename = "request_sync")]
+    RequestSync {
+        session_id: SessionId,
+        #[serde(default)]
+        last_seq: u64,
+    },
+    /// Asks for the full file list, e.g. after a gap in `FileListDiff` versions, or
+    /// for one page of it when `offset` or `limit` is set.
+    #[serde(rename = "request_file_list")]
+    RequestFileList {
+        session_id: SessionId,
+        offset: Option<usize>,
+        limit: Option<usize>,
+        sort: Option<FileSort>,
+    },
+    #[serde(rename = "share_snippet")]
+    ShareSnippet {
+        session_id: SessionId,
+        title: Option<String>,
+        content: String,
+    },
+    #[serde(rename = "delete_snippet")]
+    DeleteSnippet {
+        session_id: SessionId,
+        snippet_id: String,
+    },
+    #[serde(rename = "search_messages")]
+    SearchMessages {
+        session_id: SessionId,
+        query: String,
+    },
+    /// Handshake data for end-to-end encryption, relayed unchanged to the target.
+    #[serde(rename = "key_exchange")]
+    KeyExchange {
+        session_id: SessionId,
+        target_session_id: SessionId,
+        payload: serde_json::Value,
+    },
+    /// WebRTC signaling for a direct connection to the target, relayed unchanged. The
+    /// payload is the session description or ICE candidate as the browser produced it.
+    #[serde(rename = "rtc_signal")]
+    RtcSignal {
+        session_id: SessionId,
+        target_session_id: SessionId,
+        kind: RtcSignalKind,
+        /// The file the connection is for, if any
+        file_id: Option<String>,
+        payload: serde_json::Value,
+    },
+    /// The direct connection to the peer failed, so the file should come through the
+    /// relay instead. Either side may report it.
+    #[serde(rename = "p2p_failed")]
+    P2PFailed {
+        session_id: SessionId,
+        peer_session_id: SessionId,
+        file_id: String,
+        #[serde(default)]
+        reason: Option<String>,
+    },
+    /// Measures the round trip to another peer through the server. The target answers
+    /// the `PeerPing` it receives with a `PeerPong`.
+    #[serde(rename = "peer_ping")]
+    PeerPing {
+        session_id: SessionId,
+        target_session_id: SessionId,
+        nonce: u64,
+        /// The initiator's clock, echoed back unchanged
+        sent_at: chrono::DateTime<chrono::Utc>,
+    },
+    /// Answers a `PeerPing`; `target_session_id` is the peer that sent it, and the
+    /// other fields are copied from it.
+    #[serde(rename = "peer_pong")]
+    PeerPong {
+        session_id: SessionId,
+        target_session_id: SessionId,
+        nonce: u64,
+        sent_at: chrono::DateTime<chrono::Utc>,
+        ping_relayed_at: chrono::DateTime<chrono::Utc>,
+    },
+    /// Asks for the messages before `before_message_id`, or the newest ones.
+    #[serde(rename = "request_history")]
+    RequestHistory {
+        session_id: SessionId,
+        before_message_id: Option<String>,
+        limit: Option<usize>,
+    },
+    /// A host refusing a `DownloadRequest` it was sent, e.g. because the file is gone
+    /// from its device.
+    #[serde(rename = "deny_download")]
+    DenyDownload {
+        session_id: SessionId,
+        file_id: String,
+        requester_session_id: SessionId,
+        reason: Option<String>,
+    },
+    /// A host's answer to a `DownloadApprovalRequest`.
+    #[serde(rename = "approve_download")]
+    ApproveDownload {
+        session_id: SessionId,
+        request_id: String,
+        approve: bool,
+    },
+    /// Narrows the room broadcasts delivered to this session to the given topics:
+    /// `chat`, `files`, `peers` and `transfers`. All of them until this is sent.
+    /// Topics added later are caught up with a snapshot, such as the latest history
+    /// page for `chat`. Messages sent to this session alone always arrive.
+    #[serde(rename = "subscribe")]
+    Subscribe {
+        session_id: SessionId,
+        topics: HashSet<String>,
+    },
+    /// Puts back a file of this peer's from the trash, while one of the peers that hosted
+    /// it is still connected.
+    #[serde(rename = "restore_file")]
+    RestoreFile {
+        session_id: SessionId,
+        file_id: String,
+    },
+    /// Caps how fast the host relays one of this peer's downloads, in bytes per second,
+    /// on top of the server's own limits. 0 lifts the cap. Only the requester may set it.
+    #[serde(rename = "set_transfer_rate")]
+    SetTransferRate {
+        session_id: SessionId,
+        file_id: String,
+        /// The host serving the transfer
+        peer_session_id: SessionId,
+        max_bytes_per_sec: u64,
     },
 }
 
+impl ClientMessage {
+    /// The `type` tag, for logging.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            ClientMessage::Join { .. } => "join",
+            ClientMessage::FileUpload { .. } => "file_upload",
+            ClientMessage::FileUploadBatch { .. } => "file_upload_batch",
+            ClientMessage::RequestDownload { .. } => "request_download",
+            ClientMessage::FileDownloaded { .. } => "file_downloaded",
+            ClientMessage::FileChunk { .. } => "file_chunk",
+            ClientMessage::FileMetadata { .. } => "file_metadata",
+            ClientMessage::Ping { .. } => "ping",
+            ClientMessage::TextMessage { .. } => "text_message",
+            ClientMessage::UpdateFileInfo { .. } => "update_file_info",
+            ClientMessage::RemoveFile { .. } => "remove_file",
+            ClientMessage::ReplaceFile { .. } => "replace_file",
+            ClientMessage::Rename { .. } => "rename",
+            ClientMessage::RequestSync { .. } => "request_sync",
+            ClientMessage::RequestFileList { .. } => "request_file_list",
+            ClientMessage::ShareSnippet { .. } => "share_snippet",
+            ClientMessage::DeleteSnippet { .. } => "delete_snippet",
+            ClientMessage::SearchMessages { .. } => "search_messages",
+            ClientMessage::KeyExchange { .. } => "key_exchange",
+            ClientMessage::RtcSignal { .. } => "rtc_signal",
+            ClientMessage::P2PFailed { .. } => "p2p_failed",
+            ClientMessage::PeerPing { .. } => "peer_ping",
+            ClientMessage::PeerPong { .. } => "peer_pong",
+            ClientMessage::RequestHistory { .. } => "request_history",
+            ClientMessage::ApproveDownload { .. } => "approve_download",
+            ClientMessage::DenyDownload { .. } => "deny_download",
+            ClientMessage::Subscribe { .. } => "subscribe",
+            ClientMessage::RestoreFile { .. } => "restore_file",
+            ClientMessage::SetTransferRate { .. } => "set_transfer_rate",
+        }
+    }
+
+    /// Messages that publish new content; these are refused for read-only sessions.
+    pub fn requires_write_access(&self) -> bool {
+        matches!(
+            self,
+            ClientMessage::FileUpload { .. }
+                | ClientMessage::FileUploadBatch { .. }
+                | ClientMessage::TextMessage { .. }
+                | ClientMessage::UpdateFileInfo { .. }
+                | ClientMessage::RemoveFile { .. }
+                | ClientMessage::RestoreFile { .. }
+                | ClientMessage::ReplaceFile { .. }
+                | ClientMessage::ShareSnippet { .. }
+                | ClientMessage::DeleteSnippet { .. }
+        )
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(tag = "type")]
 pub enum ServerMessage {
@@ -102,6 +848,38 @@ pub enum ServerMessage {
         peer: PeerInfo,
         total_peers: usize,
     },
+    /// First reply to `Join`, sent to the joining peer only.
+    #[serde(rename = "welcome")]
+    Welcome {
+        server_version: String,
+        /// Negotiated version, at most what the client asked for
+        protocol_version: u32,
+        features: Vec<String>,
+        /// Longest chat message accepted, in bytes
+        max_message_length: usize,
+        /// STUN and TURN servers for WebRTC connections; empty when none are configured
+        ice_servers: Vec<IceServerConfig>,
+        /// Proves the session id belongs to this peer; sent back in `join` when reconnecting
+        session_token: String,
+    },
+    /// Everyone in the room when a peer joins, sent to that peer only. It includes the
+    /// joining peer itself, so the client also learns the role it was given.
+    #[serde(rename = "peer_list")]
+    PeerList {
+        peers: Vec<PeerInfo>,
+        total_peers: usize,
+    },
+    /// A peer went idle or became active again; only transitions are sent.
+    #[serde(rename = "peer_activity")]
+    PeerActivity {
+        session_id: SessionId,
+        state: ActivityState,
+    },
+    /// A peer's details changed, e.g. after `Rename`.
+    #[serde(rename = "peer_updated")]
+    PeerUpdated {
+        peer: PeerInfo,
+    },
     #[serde(rename = "peer_left")]
     PeerLeft {
         session_id: SessionId,
@@ -109,11 +887,42 @@ pub enum ServerMessage {
     },
     #[serde(rename = "file_list_update")]
     FileListUpdate {
-        files: Vec<FileMetadata>,
+        files: Vec<Arc<FileMetadata>>,
+        version: u64,
+        /// Files per category; categories without files are left out
+        #[serde(default)]
+        category_counts: BTreeMap<FileCategory, usize>,
+    },
+    /// Sent instead of `FileListUpdate` to peers that fetch pages, when the list is
+    /// larger than `--file-list-page-size`.
+    #[serde(rename = "file_list_summary")]
+    FileListSummary {
+        total: usize,
+        version: u64,
+        #[serde(default)]
+        category_counts: BTreeMap<FileCategory, usize>,
+    },
+    /// Reply to a `RequestFileList` with `offset` or `limit`.
+    #[serde(rename = "file_list_page")]
+    FileListPage {
+        files: Vec<Arc<FileMetadata>>,
+        offset: usize,
+        total: usize,
+        sort: FileSort,
+        version: u64,
+    },
+    /// Changes since the previous version. A client that sees a version other than
+    /// its last one plus one has missed an update and should send `RequestFileList`.
+    #[serde(rename = "file_list_diff")]
+    FileListDiff {
+        added: Vec<Arc<FileMetadata>>,
+        removed: Vec<String>,
+        updated: Vec<Arc<FileMetadata>>,
+        version: u64,
     },
     #[serde(rename = "file_added")]
     FileAdded {
-        file: FileMetadata,
+        file: Arc<FileMetadata>,
     },
     #[serde(rename = "file_removed")]
     FileRemoved {
@@ -124,15 +933,150 @@ pub enum ServerMessage {
         from_session_id: SessionId,
         file_id: String,
         requester_session_id: SessionId,
+        /// Chunk size the requester asked for; the host should chunk accordingly
+        chunk_size: u32,
+        /// Pipe the host may `PUT` the file to and the requester `GET` it from, only
+        /// told to the two of them
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        relay_id: Option<String>,
+    },
+    /// Chunks of this file are backing up at a slow receiver; the host should pause
+    /// between chunks
+    #[serde(rename = "slow_down")]
+    SlowDown {
+        file_id: String,
+    },
+    /// The host is busy; the download starts when `position` reaches the front
+    #[serde(rename = "download_queued")]
+    DownloadQueued {
+        file_id: String,
+        position: usize,
+    },
+    /// A queued download got its turn; the `DownloadRequest` follows
+    #[serde(rename = "download_starting")]
+    DownloadStarting {
+        file_id: String,
+        host_session_id: SessionId,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        relay_id: Option<String>,
+    },
+    /// Asks a host that does not auto-accept downloads whether to send a file; answered
+    /// with `ApproveDownload`. Withdrawn with `TransferAborted` if the requester leaves
+    /// or the request expires.
+    #[serde(rename = "download_approval_request")]
+    DownloadApprovalRequest {
+        request_id: String,
+        file_id: String,
+        requester_session_id: SessionId,
+        requester_name: Option<String>,
+    },
+    /// The download waits for the host to approve it
+    #[serde(rename = "download_pending")]
+    DownloadPending {
+        request_id: String,
+        file_id: String,
+        host_session_id: SessionId,
+    },
+    /// The host declined the download, or did not answer in time (`approval_expired`)
+    #[serde(rename = "download_denied")]
+    DownloadDenied {
+        file_id: String,
+        host_session_id: SessionId,
+        reason: String,
+        /// The host's own explanation, from `DenyDownload`
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        message: Option<String>,
+    },
+    /// Relayed transfers in progress in the room, sent every few seconds while any
+    /// are active and once more, empty, when the last one ends.
+    #[serde(rename = "transfer_list")]
+    TransferList {
+        transfers: Vec<LiveTransfer>,
+    },
+    /// The host did not answer the `DownloadRequest` in time. Another host is tried
+    /// when the file has one; otherwise a `download_failed` error follows.
+    #[serde(rename = "download_timeout")]
+    DownloadTimeout {
+        file_id: String,
+        host_session_id: SessionId,
+    },
+    /// Sent to the other side of an in-flight transfer when one side goes away, so
+    /// a host stops chunking or a downloader stops waiting.
+    #[serde(rename = "transfer_aborted")]
+    TransferAborted {
+        file_id: String,
+        peer_session_id: SessionId,
+        reason: String,
+    },
+    /// Sent to the uploader when the id it announced a file under belongs to another
+    /// peer's file; the file is listed under `assigned_id` instead.
+    #[serde(rename = "file_id_reassigned")]
+    FileIdReassigned {
+        requested_id: String,
+        assigned_id: String,
+    },
+    /// A peer shared several files in one action, for listing them as one group. Sent
+    /// once a batch has two files, and again with new totals as more join it. Counts
+    /// only the files listed for everyone.
+    #[serde(rename = "batch_announced")]
+    BatchAnnounced {
+        batch_id: String,
+        uploader: SessionId,
+        file_count: usize,
+        total_size: u64,
+    },
+    /// Reply to `FileUploadBatch`, sent to the uploader only.
+    #[serde(rename = "batch_upload_result")]
+    BatchUploadResult {
+        accepted: Vec<String>,
+        rejected: Vec<RejectedFile>,
+    },
+    /// A file's content was replaced; `file.version` tells the versions apart
+    #[serde(rename = "file_updated")]
+    FileUpdated {
+        file: Arc<FileMetadata>,
+    },
+    /// Sent to the host serving a relayed transfer as it starts and ends, unless it
+    /// joined with `notify_upload_activity` off.
+    #[serde(rename = "upload_activity")]
+    UploadActivity {
+        file_id: String,
+        requester_name: Option<String>,
+        event: UploadEvent,
+    },
+    /// The requester capped a relayed transfer this host is serving, so the host can
+    /// pace its chunks instead of having them held back by the server. `None` when the
+    /// cap was lifted.
+    #[serde(rename = "transfer_rate_hint")]
+    TransferRateHint {
+        file_id: String,
+        requester_session_id: SessionId,
+        max_bytes_per_sec: Option<u64>,
+    },
+    /// Sent to the uploader the first time each peer finishes downloading their file
+    #[serde(rename = "file_downloaded_notice")]
+    FileDownloadedNotice {
+        file_id: String,
+        by_session_id: SessionId,
+        by_name: Option<String>,
     },
     #[serde(rename = "file_chunk")]
     FileChunk {
         file_id: String,
         chunk_index: u32,
         total_chunks: u32,
+        /// Missing only when the server could not work it out for an older host
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        offset: Option<u64>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        len: Option<u32>,
         data: String,
         from_session_id: SessionId,
         target_session_id: SessionId,
+        compression: Option<String>,
+        #[serde(default)]
+        encrypted: bool,
+        nonce: Option<String>,
     },
     #[serde(rename = "file_metadata")]
     FileMetadata {
@@ -141,25 +1085,205 @@ pub enum ServerMessage {
         file_size: u64,
         mime_type: String,
         total_chunks: u32,
+        chunk_size: u32,
         from_session_id: SessionId,
         target_session_id: SessionId,
+        compression: Option<String>,
+        relative_path: Option<String>,
+        #[serde(default)]
+        encrypted: bool,
+        nonce: Option<String>,
+    },
+    /// A peer's `KeyExchange`, sent to its target only.
+    #[serde(rename = "key_exchange")]
+    KeyExchange {
+        from_session_id: SessionId,
+        payload: serde_json::Value,
+    },
+    /// A peer's `RtcSignal`, sent to its target only.
+    #[serde(rename = "rtc_signal")]
+    RtcSignal {
+        from_session_id: SessionId,
+        kind: RtcSignalKind,
+        file_id: Option<String>,
+        payload: serde_json::Value,
     },
+    /// Another peer's `PeerPing`, stamped with when the server relayed it.
+    #[serde(rename = "peer_ping")]
+    PeerPing {
+        from_session_id: SessionId,
+        nonce: u64,
+        sent_at: chrono::DateTime<chrono::Utc>,
+        relayed_at: chrono::DateTime<chrono::Utc>,
+    },
+    /// The answer to the recipient's `PeerPing`. `pong_relayed_at - ping_relayed_at` is
+    /// the round trip from the server through the other peer on the server's clock; the
+    /// rest of the recipient's own round trip is its two legs to the server.
+    #[serde(rename = "peer_pong")]
+    PeerPong {
+        from_session_id: SessionId,
+        nonce: u64,
+        sent_at: chrono::DateTime<chrono::Utc>,
+        ping_relayed_at: chrono::DateTime<chrono::Utc>,
+        pong_relayed_at: chrono::DateTime<chrono::Utc>,
+    },
+    /// Sent to both sides when a file moves to the relay because a direct connection
+    /// failed, or was skipped after failing recently. The host gets a `DownloadRequest`
+    /// for it as usual.
+    #[serde(rename = "falling_back_to_relay")]
+    FallingBackToRelay {
+        file_id: String,
+    },
+    /// Always sent to the session whose request failed, never to the room.
+    /// `code` is stable for clients to branch on; `message` is for display.
     #[serde(rename = "error")]
     Error {
+        code: String,
         message: String,
+        /// The limit that was exceeded, for errors about one
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        limit: Option<usize>,
+        /// How long to wait before trying again, for `rate_limited` errors
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        retry_after_ms: Option<u64>,
     },
+    /// Reply to the pinging session only; `server_time` lets it estimate clock offset.
     #[serde(rename = "pong")]
-    Pong,
+    Pong {
+        nonce: Option<u64>,
+        server_time: chrono::DateTime<chrono::Utc>,
+    },
     #[serde(rename = "text_message")]
     TextMessage {
         message: TextMessage,
     },
+    /// The most recent messages, sent at join. Older ones are fetched with `RequestHistory`.
     #[serde(rename = "message_history")]
     MessageHistory {
         messages: Vec<TextMessage>,
+        has_more: bool,
+        oldest_id: Option<String>,
+    },
+    /// Reply to `RequestHistory`, sent to the requester only.
+    #[serde(rename = "history_page")]
+    HistoryPage {
+        messages: Vec<TextMessage>,
+        has_more: bool,
+    },
+    /// Who the peer is talking to and what it accepts, sent to each joining peer.
+    #[serde(rename = "server_info")]
+    ServerInfo {
+        name: String,
+        version: String,
+        /// Random id of this server run, which bridges use to recognize their own messages
+        instance_id: String,
+        features: Vec<String>,
+        limits: ServerLimits,
+    },
+    /// The message of the day, sent to each joining peer and to everyone when it changes.
+    #[serde(rename = "motd")]
+    Motd {
+        text: String,
+        set_at: chrono::DateTime<chrono::Utc>,
+    },
+    /// Files attached to a chat message were removed from the room.
+    #[serde(rename = "message_attachments_invalidated")]
+    MessageAttachmentsInvalidated {
+        message_id: String,
+        file_ids: Vec<String>,
+    },
+    /// Sent to the author once a chat message has been stored.
+    #[serde(rename = "message_ack")]
+    MessageAck {
+        client_ref: Option<String>,
+        message_id: String,
+        timestamp: chrono::DateTime<chrono::Utc>,
+    },
+    /// Nothing was heard from the session for a while; it is disconnected unless it
+    /// sends something within `disconnect_in_secs`.
+    #[serde(rename = "idle_warning")]
+    IdleWarning {
+        disconnect_in_secs: u64,
+    },
+    /// Answers a `Join` from a client older than `--min-client-version`; the socket is
+    /// then closed with code 4006.
+    #[serde(rename = "upgrade_required")]
+    UpgradeRequired {
+        min_version: String,
+        download_hint: String,
+    },
+    /// The server is ending the connection; the socket is then closed with `code`.
+    #[serde(rename = "kicked")]
+    Kicked {
+        reason: String,
+        code: u16,
+    },
+    #[serde(rename = "snippet_list_update")]
+    SnippetListUpdate {
+        snippets: Vec<Snippet>,
+    },
+    #[serde(rename = "search_results")]
+    SearchResults {
+        query: String,
+        messages: Vec<SearchHit>,
+    },
+    /// The server shuts down in `seconds_remaining`, sent as the time approaches.
+    #[serde(rename = "shutdown_countdown")]
+    ShutdownCountdown {
+        seconds_remaining: u64,
+    },
+    /// The peer's files in the trash, sent to the uploader whenever they change and on
+    /// joining.
+    #[serde(rename = "trash_list")]
+    TrashList {
+        files: Vec<TrashedFile>,
+    },
+    /// Room snapshot answering `RequestSync`, taken at one point in time. Broadcasts up
+    /// to `seq` are reflected in it; later ones may already be too and are replayed on top.
+    #[serde(rename = "sync_state")]
+    SyncState {
+        seq: u64,
+        peers: Vec<PeerInfo>,
+        files: Vec<Arc<FileMetadata>>,
+        file_list_version: u64,
+        /// The latest page of the chat history, as in `MessageHistory`
+        messages: Vec<TextMessage>,
+        /// Older messages remain, to fetch with `RequestHistory`
+        #[serde(default)]
+        has_more: bool,
+        /// Relayed transfers in flight
+        #[serde(default)]
+        transfers: Vec<LiveTransfer>,
     },
 }
 
+/// A room broadcast stamped with the room's sequence number, serialized as the
+/// message with an extra `seq` field. Messages sent to a single session carry no `seq`.
+///
+/// A client that sees a `seq` other than its last one plus one has missed broadcasts
+/// and should send `RequestSync`. After the snapshot arrives, newer broadcasts are
+/// applied again on top of it: peer, file list and snippet list updates are
+/// idempotent on replay, while `text_message` must be deduplicated by message id and
+/// `download_request` should be ignored if already handled.
+///
+/// After a `Subscribe` leaving topics out, the broadcasts of those topics are gaps in
+/// `seq` as well; such a client is sent a `missed_broadcasts` error when it really
+/// missed some.
+#[derive(Debug, Clone, Serialize)]
+pub struct SequencedMessage {
+    pub seq: u64,
+    #[serde(flatten)]
+    pub message: ServerMessage,
+}
+
+/// What changes when a file is replaced; a missing name keeps the old one.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FileReplacement {
+    pub name: Option<String>,
+    pub size: u64,
+    pub mime_type: String,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct FileUploadRequest {
     pub name: String,
@@ -167,8 +1291,504 @@ pub struct FileUploadRequest {
     pub mime_type: String,
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RoomQuery {
+    pub room: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ObserverQuery {
+    pub room: Option<String>,
+    /// `1` to also stream chat messages
+    pub chat: Option<String>,
+}
+
+/// First event of an observer stream, and sent again when the observer fell behind.
+/// Broadcasts up to `seq` are reflected in it, and later ones may be too.
+#[derive(Debug, Clone, Serialize)]
+pub struct ObserverSnapshot {
+    pub room: String,
+    pub seq: u64,
+    pub peers: Vec<PeerInfo>,
+    pub files: Vec<Arc<FileMetadata>>,
+    pub file_list_version: u64,
+    pub transfers: Vec<LiveTransfer>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FileListQuery {
+    pub room: Option<String>,
+    pub offset: Option<usize>,
+    pub limit: Option<usize>,
+    pub sort: Option<FileSort>,
+    pub category: Option<FileCategory>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MessageHistoryQuery {
+    pub room: Option<String>,
+    pub before: Option<String>,
+    pub limit: Option<usize>,
+}
+
+/// Body of `POST /api/messages`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PostMessageRequest {
+    pub content: String,
+    /// Plain text when left out
+    pub format: Option<MessageFormat>,
+    /// Shown as the sender; needs the admin token
+    pub sender_name: Option<String>,
+    pub room: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MessageSearchQuery {
+    pub q: Option<String>,
+    pub limit: Option<usize>,
+    pub before: Option<String>,
+    pub room: Option<String>,
+    /// Leave out system messages when false
+    pub system: Option<bool>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MessageExportQuery {
+    pub format: Option<String>,
+    pub room: Option<String>,
+    /// Leave out system messages when false
+    pub system: Option<bool>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ZipRequest {
+    pub file_ids: Vec<String>,
+    pub room: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SnippetRequest {
+    pub title: Option<String>,
+    pub content: String,
+    pub room: Option<String>,
+}
+
+/// Per-peer counters; bytes are counted as relayed through the server.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PeerCounterStats {
+    pub files_hosted: usize,
+    pub files_uploaded: u64,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    pub messages_sent: u64,
+    pub bytes_sent_last_minute: u64,
+    pub bytes_received_last_minute: u64,
+    pub connected_secs: u64,
+}
+
+/// A session among the busiest on the relay over the last minute.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TopTalker {
+    pub session_id: SessionId,
+    pub name: Option<String>,
+    pub room: String,
+    pub bytes_sent_last_minute: u64,
+    pub bytes_received_last_minute: u64,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PeerWithStats {
+    #[serde(flatten)]
+    pub peer: PeerInfo,
+    pub stats: PeerCounterStats,
+}
+
+/// A relayed transfer that has started and not yet completed.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TransferInfo {
+    pub file_id: String,
+    pub from_session_id: SessionId,
+    pub to_session_id: SessionId,
+    pub idle_secs: u64,
+}
+
+/// One entry of an `RTCPeerConnection`'s `iceServers`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IceServerConfig {
+    pub urls: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub credential: Option<String>,
+}
+
+/// Progress of a relayed transfer, for the live transfer view. Names are omitted
+/// when unknown, e.g. for chunks relayed without metadata.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LiveTransfer {
+    pub file_id: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub file_name: Option<String>,
+    pub host_session_id: SessionId,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub host_name: Option<String>,
+    pub requester_session_id: SessionId,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub requester_name: Option<String>,
+    pub chunks_done: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub total_chunks: Option<u32>,
+    /// Over the last few seconds, rounded to whole KiB
+    pub bytes_per_sec: u64,
+    pub started_at: chrono::DateTime<chrono::Utc>,
+}
+
+/// Everything known about one peer, for debugging.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PeerDetail {
+    #[serde(flatten)]
+    pub peer: PeerInfo,
+    pub room: String,
+    pub stats: PeerCounterStats,
+    pub hosted_files: Vec<Arc<FileMetadata>>,
+    pub transfers: Vec<TransferInfo>,
+    /// The peer is still listed but its connection is gone, e.g. while it may reconnect
+    pub disconnected: bool,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct PeerStats {
     pub total_peers: usize,
-    pub peers: Vec<PeerInfo>,
+    pub peers: Vec<PeerWithStats>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RelayStats {
+    pub bytes_total: u64,
+    pub throughput_bps: u64,
+    pub limit_bps: Option<u64>,
+    pub per_transfer_limit_bps: Option<u64>,
+    /// Transfers currently holding a slot, including ones whose metadata is still due
+    pub active_transfers: usize,
+    pub queued_downloads: usize,
+    pub max_transfers: Option<usize>,
+    pub max_transfers_per_peer: Option<usize>,
+    /// Transfers moved to the relay after a direct WebRTC connection failed
+    pub p2p_fallbacks: u64,
+}
+
+/// Registry usage summed over all rooms; the limits apply to each room separately.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FileRegistryStats {
+    pub file_count: usize,
+    pub total_size: u64,
+    pub max_files: Option<usize>,
+    pub max_total_size: Option<u64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TransferOutcome {
+    Complete,
+    /// A peer disconnected before the last chunk
+    Failed,
+    /// The file was removed before the last chunk
+    Cancelled,
+    /// The host declined to approve or to send the download
+    Declined,
+    /// The host did not answer the approval or download request in time
+    Expired,
+}
+
+/// One finished relayed transfer, as kept in the audit log.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TransferRecord {
+    pub file_id: String,
+    pub file_name: Option<String>,
+    pub size: Option<u64>,
+    pub host_session_id: SessionId,
+    pub host_name: Option<String>,
+    pub requester_session_id: SessionId,
+    pub requester_name: Option<String>,
+    pub started_at: chrono::DateTime<chrono::Utc>,
+    pub ended_at: chrono::DateTime<chrono::Utc>,
+    pub outcome: TransferOutcome,
+    /// Chunk payload bytes relayed, as encoded on the wire
+    pub bytes: u64,
+    /// When the host approved the download, for hosts that approve each one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub approved_at: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TransferHistoryQuery {
+    pub file_id: Option<String>,
+    pub peer: Option<String>,
+    pub since: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EventKind {
+    Connected,
+    Disconnected,
+    Joined,
+    Left,
+    AuthFailed,
+    FileAdded,
+    FileRemoved,
+    TransferStarted,
+    TransferFinished,
+    TransferFailed,
+    Kicked,
+    Error,
+}
+
+/// What an event refers to. Files, transfers and sessions are named by id only; the
+/// chat and transfer history stay in their own stores.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct EventSubject {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub session_id: Option<SessionId>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub room: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ip: Option<IpAddr>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub file_id: Option<String>,
+    /// A short reason or error code
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+}
+
+/// One entry of the recent events log.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ServerEvent {
+    pub id: u64,
+    pub at: chrono::DateTime<chrono::Utc>,
+    pub kind: EventKind,
+    #[serde(flatten)]
+    pub subject: EventSubject,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EventQuery {
+    pub since: Option<chrono::DateTime<chrono::Utc>>,
+    /// Comma-separated event kinds
+    pub kind: Option<String>,
+    pub limit: Option<usize>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AccessUrl {
+    pub url: String,
+    pub interface: Option<String>,
+}
+
+/// Limits a client can check before sending something the server would reject.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ServerLimits {
+    pub max_message_length: usize,
+    pub max_ws_message: usize,
+    pub max_files: Option<usize>,
+    pub max_total_size: Option<u64>,
+    /// Bounds `preferred_chunk_size` is clamped to
+    #[serde(default)]
+    pub min_chunk_size: u32,
+    #[serde(default)]
+    pub max_chunk_size: u32,
+}
+
+/// What a client needs to know before logging in.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PublicServerInfo {
+    pub name: String,
+    pub version: String,
+    pub protocol_version: u32,
+    pub auth_required: bool,
+}
+
+/// What the server supports, and what clients need to point other devices at it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ServerInfo {
+    #[serde(flatten)]
+    pub public: PublicServerInfo,
+    pub instance_id: String,
+    pub features: Vec<String>,
+    pub limits: ServerLimits,
+    /// Whether the server itself serves HTTPS; it does not, so TLS is up to a reverse proxy
+    pub tls: bool,
+    pub urls: Vec<AccessUrl>,
+    pub max_message_length: usize,
+    pub motd: Option<Motd>,
+}
+
+pub const MAX_MOTD_LENGTH: usize = 4096;
+
+/// The operator's message of the day, shown to peers as they join.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Motd {
+    pub text: String,
+    pub set_at: chrono::DateTime<chrono::Utc>,
+}
+
+impl Motd {
+    /// A message set now from `text`; blank text means there is none.
+    pub fn new(text: &str) -> Result<Option<Motd>, String> {
+        let text = text.trim();
+        if text.len() > MAX_MOTD_LENGTH {
+            return Err(format!("The message of the day is limited to {MAX_MOTD_LENGTH} bytes"));
+        }
+        if text.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(Motd {
+            text: text.to_string(),
+            set_at: chrono::Utc::now(),
+        }))
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MotdRequest {
+    pub text: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ShutdownQuery {
+    /// Seconds until the shutdown; right away when missing
+    pub delay: Option<u64>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ServerStats {
+    pub relay: RelayStats,
+    pub files: FileRegistryStats,
+    pub top_talkers: Vec<TopTalker>,
+}
+
+/// Deserializes a field that is present, even as null, to Some, so a missing field
+/// and an explicit null can be told apart.
+fn present<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    T::deserialize(deserializer).map(Some)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detects_file_categories() {
+        use FileCategory::*;
+        let cases: &[(&str, &str, FileCategory)] = &[
+            // By MIME type
+            ("image/png", "photo.png", Image),
+            ("image/jpeg", "photo", Image),
+            ("video/mp4", "clip.mp4", Video),
+            ("audio/mpeg", "song.mp3", Audio),
+            ("application/pdf", "paper.pdf", Document),
+            ("application/vnd.openxmlformats-officedocument.wordprocessingml.document", "letter.docx", Document),
+            ("application/vnd.oasis.opendocument.spreadsheet", "sheet", Document),
+            ("text/plain", "notes", Document),
+            ("application/zip", "bundle.zip", Archive),
+            ("application/json", "data.json", Code),
+            ("text/x-rust", "main.rs", Code),
+            ("IMAGE/PNG; charset=binary", "photo", Image),
+            // By extension, when the MIME type is generic or unknown
+            ("application/octet-stream", "photo.JPG", Image),
+            ("application/octet-stream", "backup.tar.gz", Archive),
+            ("", "script.py", Code),
+            ("not a mime type", "movie.mkv", Video),
+            // By extension, when it contradicts the MIME type
+            ("text/plain", "main.rs", Code),
+            ("application/zip", "report.docx", Document),
+            // Neither tells
+            ("application/octet-stream", "data.bin", Other),
+            ("application/octet-stream", "README", Other),
+            ("", "", Other),
+        ];
+        for (mime_type, name, expected) in cases {
+            assert_eq!(FileCategory::detect(mime_type, name), *expected, "{mime_type:?} {name:?}");
+        }
+    }
+
+    #[test]
+    fn file_categories_serialize_in_snake_case() {
+        assert_eq!(serde_json::to_value(FileCategory::Image).unwrap(), "image");
+        assert_eq!(serde_json::from_value::<FileCategory>("archive".into()).unwrap(), FileCategory::Archive);
+    }
+
+    #[test]
+    fn chunk_size_is_clamped_to_the_supported_range() {
+        assert_eq!(negotiate_chunk_size(None), DEFAULT_CHUNK_SIZE);
+        assert_eq!(negotiate_chunk_size(Some(0)), MIN_CHUNK_SIZE);
+        assert_eq!(negotiate_chunk_size(Some(MIN_CHUNK_SIZE - 1)), MIN_CHUNK_SIZE);
+        assert_eq!(negotiate_chunk_size(Some(MIN_CHUNK_SIZE)), MIN_CHUNK_SIZE);
+        assert_eq!(negotiate_chunk_size(Some(256 * 1024)), 256 * 1024);
+        assert_eq!(negotiate_chunk_size(Some(MAX_CHUNK_SIZE)), MAX_CHUNK_SIZE);
+        assert_eq!(negotiate_chunk_size(Some(MAX_CHUNK_SIZE + 1)), MAX_CHUNK_SIZE);
+        assert_eq!(negotiate_chunk_size(Some(u32::MAX)), MAX_CHUNK_SIZE);
+    }
+
+    #[test]
+    fn compression_survives_a_round_trip() {
+        let chunk = serde_json::json!({
+            "type": "file_chunk",
+            "file_id": "file_1",
+            "chunk_index": 0,
+            "total_chunks": 1,
+            "data": "AAAA",
+            "from_session_id": "alice",
+            "target_session_id": "bob",
+            "compression": "zstd",
+            "encrypted": false,
+            "nonce": null,
+        });
+        let parsed = serde_json::from_value::<ServerMessage>(chunk.clone()).unwrap();
+        assert!(matches!(&parsed, ServerMessage::FileChunk { compression: Some(compression), .. } if compression == "zstd"));
+        assert_eq!(serde_json::to_value(&parsed).unwrap(), chunk);
+
+        let metadata = serde_json::json!({
+            "type": "file_metadata",
+            "session_id": "alice",
+            "file_id": "file_1",
+            "file_name": "notes.txt",
+            "file_size": 10,
+            "mime_type": "text/plain",
+            "total_chunks": 1,
+            "target_session_id": "bob",
+            "relative_path": null,
+        });
+        let parsed = serde_json::from_value::<ClientMessage>(metadata).unwrap();
+        assert!(matches!(parsed, ClientMessage::FileMetadata { compression: None, .. }));
+        let parsed = serde_json::to_value(&parsed).unwrap();
+        assert_eq!(parsed["compression"], serde_json::Value::Null);
+        assert!(matches!(serde_json::from_value(parsed).unwrap(), ClientMessage::FileMetadata { compression: None, .. }));
+    }
+
+    #[test]
+    fn only_known_compressions_are_supported() {
+        for compression in SUPPORTED_COMPRESSIONS {
+            assert!(is_supported_compression(compression));
+        }
+        for compression in ["", "brotli", "GZIP", "gzip "] {
+            assert!(!is_supported_compression(compression), "{compression:?}");
+        }
+        assert_eq!(compression_capability("gzip"), "compression:gzip");
+    }
+
+    #[test]
+    fn protocol_version_is_at_most_what_both_sides_speak() {
+        assert_eq!(negotiate_protocol_version(None), 1);
+        assert_eq!(negotiate_protocol_version(Some(0)), 1);
+        assert_eq!(negotiate_protocol_version(Some(1)), 1);
+        assert_eq!(negotiate_protocol_version(Some(PROTOCOL_VERSION)), PROTOCOL_VERSION);
+        assert_eq!(negotiate_protocol_version(Some(PROTOCOL_VERSION + 1)), PROTOCOL_VERSION);
+    }
 }
@@ -0,0 +1,69 @@
+use crate::types::*;
+
+/// User agent prefix sent by the headless command line client, e.g. `ladex-cli/0.1`.
+pub const CLI_USER_AGENT_PREFIX: &str = "ladex-cli";
+
+/// Derives device type, OS and browser from a user agent string. This only
+/// recognizes the common families well enough to label a peer in the UI.
+pub fn parse(user_agent: &str) -> ClientInfo {
+    if user_agent.starts_with(CLI_USER_AGENT_PREFIX) {
+        return ClientInfo {
+            device_type: DeviceType::Cli,
+            os: None,
+            browser: None,
+        };
+    }
+
+    ClientInfo {
+        device_type: device_type(user_agent),
+        os: os(user_agent).map(str::to_string),
+        browser: browser(user_agent).map(str::to_string),
+    }
+}
+
+fn device_type(ua: &str) -> DeviceType {
+    // Android tablets leave out "Mobile"
+    if ua.contains("iPad") || ua.contains("Tablet") || (ua.contains("Android") && !ua.contains("Mobile")) {
+        DeviceType::Tablet
+    } else if ua.contains("Mobi") || ua.contains("iPhone") || ua.contains("iPod") {
+        DeviceType::Mobile
+    } else {
+        DeviceType::Desktop
+    }
+}
+
+fn os(ua: &str) -> Option<&'static str> {
+    // iOS and Android user agents also mention "Mac OS X" and "Linux"
+    if ua.contains("iPhone") || ua.contains("iPad") || ua.contains("iPod") {
+        Some("iOS")
+    } else if ua.contains("Android") {
+        Some("Android")
+    } else if ua.contains("Windows") {
+        Some("Windows")
+    } else if ua.contains("CrOS") {
+        Some("ChromeOS")
+    } else if ua.contains("Mac OS X") || ua.contains("Macintosh") {
+        Some("macOS")
+    } else if ua.contains("Linux") {
+        Some("Linux")
+    } else {
+        None
+    }
+}
+
+fn browser(ua: &str) -> Option<&'static str> {
+    // Most browsers also claim to be Chrome and Safari, so check the specific ones first
+    if ua.contains("Edg/") || ua.contains("EdgA/") || ua.contains("EdgiOS/") {
+        Some("Edge")
+    } else if ua.contains("OPR/") || ua.contains("Opera") {
+        Some("Opera")
+    } else if ua.contains("Firefox/") || ua.contains("FxiOS/") {
+        Some("Firefox")
+    } else if ua.contains("Chrome/") || ua.contains("CriOS/") {
+        Some("Chrome")
+    } else if ua.contains("Safari/") {
+        Some("Safari")
+    } else {
+        None
+    }
+}
@@ -0,0 +1,170 @@
+use std::cmp::Ordering;
+use std::fmt;
+
+/// A client's `major.minor.patch` version. Missing parts count as 0, and build
+/// metadata after `+` is ignored; a pre-release such as `1.2.0-beta` comes before
+/// `1.2.0`, though pre-releases of the same version are not told apart.
+#[derive(Debug, Clone)]
+pub struct Version {
+    major: u64,
+    minor: u64,
+    patch: u64,
+    /// False for pre-releases, so they sort first
+    release: bool,
+    /// As given, for messages
+    text: String,
+}
+
+impl Version {
+    pub fn parse(value: &str) -> Result<Version, String> {
+        let text = value.trim();
+        let core = text.split('+').next().unwrap_or_default();
+        let (core, release) = match core.split_once('-') {
+            Some((core, _)) => (core, false),
+            None => (core, true),
+        };
+        let core = core.strip_prefix('v').unwrap_or(core);
+        let parts = core
+            .split('.')
+            .map(|part| part.parse::<u64>())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|_| format!("{text} is not a version like 1.2.3"))?;
+        let [major, minor, patch] = match parts.as_slice() {
+            [major] => [*major, 0, 0],
+            [major, minor] => [*major, *minor, 0],
+            [major, minor, patch] => [*major, *minor, *patch],
+            _ => return Err(format!("{text} is not a version like 1.2.3")),
+        };
+        Ok(Version {
+            major,
+            minor,
+            patch,
+            release,
+            text: text.to_string(),
+        })
+    }
+
+    fn key(&self) -> (u64, u64, u64, bool) {
+        (self.major, self.minor, self.patch, self.release)
+    }
+}
+
+impl PartialEq for Version {
+    fn eq(&self, other: &Self) -> bool {
+        self.key() == other.key()
+    }
+}
+
+impl Eq for Version {}
+
+impl PartialOrd for Version {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Version {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.key().cmp(&other.key())
+    }
+}
+
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.text)
+    }
+}
+
+/// Which client versions may join, set with `--min-client-version` and
+/// `--allow-unversioned-clients`.
+#[derive(Debug, Clone)]
+pub struct ClientVersionPolicy {
+    pub min_version: Option<Version>,
+    pub allow_unversioned: bool,
+}
+
+impl ClientVersionPolicy {
+    /// Whether a client that reported `client_version` may join. A version that cannot
+    /// be parsed counts as too old.
+    pub fn allows(&self, client_version: Option<&str>) -> bool {
+        let Some(min_version) = &self.min_version else {
+            return true;
+        };
+        match client_version {
+            Some(version) => Version::parse(version).is_ok_and(|version| version >= *min_version),
+            None => self.allow_unversioned,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(min_version: Option<&str>, allow_unversioned: bool) -> ClientVersionPolicy {
+        ClientVersionPolicy {
+            min_version: min_version.map(|version| Version::parse(version).unwrap()),
+            allow_unversioned,
+        }
+    }
+
+    #[test]
+    fn parses_short_prefixed_and_build_versions() {
+        assert_eq!(Version::parse("1").unwrap(), Version::parse("1.0.0").unwrap());
+        assert_eq!(Version::parse("1.2").unwrap(), Version::parse("1.2.0").unwrap());
+        assert_eq!(Version::parse("v1.2.3").unwrap(), Version::parse("1.2.3").unwrap());
+        assert_eq!(Version::parse(" 1.2.3+build.7 ").unwrap(), Version::parse("1.2.3").unwrap());
+        assert_eq!(Version::parse("v1.2.3").unwrap().to_string(), "v1.2.3");
+    }
+
+    #[test]
+    fn rejects_unparsable_versions() {
+        for value in ["", "latest", "1.x", "1.2.3.4", "-1.0", "1..2", "vv1.0"] {
+            assert!(Version::parse(value).is_err(), "{value:?} parsed");
+        }
+    }
+
+    #[test]
+    fn pre_release_sorts_before_release() {
+        let beta = Version::parse("1.2.0-beta").unwrap();
+        assert!(beta < Version::parse("1.2.0").unwrap());
+        assert!(beta > Version::parse("1.1.9").unwrap());
+        assert_eq!(beta, Version::parse("1.2.0-rc.1").unwrap());
+    }
+
+    #[test]
+    fn compares_parts_numerically() {
+        assert!(Version::parse("1.10.0").unwrap() > Version::parse("1.9.0").unwrap());
+        assert!(Version::parse("2.0.0").unwrap() > Version::parse("1.99.99").unwrap());
+    }
+
+    #[test]
+    fn allows_versions_at_or_above_the_minimum() {
+        let policy = policy(Some("1.2.0"), false);
+        assert!(!policy.allows(Some("1.1.9")));
+        assert!(policy.allows(Some("1.2.0")));
+        assert!(policy.allows(Some("v1.2.0")));
+        assert!(policy.allows(Some("1.3.0")));
+        assert!(!policy.allows(Some("1.2.0-beta")));
+        assert!(policy.allows(Some("1.3.0-beta")));
+    }
+
+    #[test]
+    fn unparsable_version_counts_as_too_old() {
+        assert!(!policy(Some("1.0.0"), true).allows(Some("latest")));
+    }
+
+    #[test]
+    fn missing_version_follows_allow_unversioned() {
+        assert!(!policy(Some("1.0.0"), false).allows(None));
+        assert!(policy(Some("1.0.0"), true).allows(None));
+    }
+
+    #[test]
+    fn no_minimum_allows_everyone() {
+        let policy = policy(None, false);
+        assert!(policy.allows(None));
+        assert!(policy.allows(Some("0.0.1")));
+        assert!(policy.allows(Some("latest")));
+    }
+}
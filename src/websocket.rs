@@ -1,190 +1,1165 @@
+use crate::chat;
+use crate::fetch;
+use crate::files;
+use crate::peers;
+use crate::rate_limit;
+use crate::share;
+use crate::rooms::{self, RoomId, RoomState};
+use crate::snippets;
+use crate::transfers;
+use crate::trash;
 use crate::types::*;
+use crate::user_agent;
 use crate::AppState;
 use futures_util::{SinkExt, StreamExt};
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::{broadcast, mpsc};
+use tracing::Instrument;
 use warp::ws::{WebSocket, Ws, Message};
 use warp::{Rejection, Reply};
 
-pub async fn websocket_handler(ws: Ws, state: AppState) -> Result<impl Reply, Rejection> {
-    Ok(ws.on_upgrade(move |socket| handle_websocket(socket, state)))
+/// Malformed frames tolerated from one connection before it is disconnected.
+const MAX_MALFORMED_MESSAGES: u32 = 20;
+/// How much of a malformed frame is echoed back in the error.
+const MAX_ECHOED_INPUT_CHARS: usize = 200;
+/// Longest explanation a peer may give, e.g. in `DenyDownload`.
+const MAX_REASON_CHARS: usize = 200;
+/// File chunks queued for one connection's socket. Chunks are bounded by the
+/// negotiated chunk size, so this also bounds the bytes buffered per connection.
+const CHUNK_QUEUE_CAPACITY: usize = 16;
+/// How long a chunk may wait for queue space before the host is asked to slow down.
+const SLOW_DOWN_AFTER: Duration = Duration::from_secs(2);
+/// How long a chunk may wait for queue space before the receiver is disconnected.
+const CHUNK_QUEUE_TIMEOUT: Duration = Duration::from_secs(30);
+/// Shortest time between two `RequestSync`s of a connection, as each copies the room
+const SYNC_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Close codes for connections the server ends, so clients can tell why.
+pub const CLOSE_AUTH_REVOKED: u16 = 4001;
+pub const CLOSE_KICKED: u16 = 4002;
+pub const CLOSE_PROTOCOL_VIOLATION: u16 = 4003;
+pub const CLOSE_SERVER_SHUTDOWN: u16 = 4004;
+pub const CLOSE_IDLE_TIMEOUT: u16 = 4005;
+pub const CLOSE_UPGRADE_REQUIRED: u16 = 4006;
+/// How long a new connection has to send `Join` before it is closed.
+const JOIN_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long before an idle disconnect the session is warned, at most half the timeout.
+const IDLE_WARNING_BEFORE: Duration = Duration::from_secs(60);
+/// How long a client's close is waited on for the handshake reply to go out.
+const CLOSE_ACK_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Handle to a joined session, used to reach its socket directly. Control messages
+/// go through `tx` and are written ahead of the bounded `chunks` queue, so they are
+/// never stuck behind file data.
+#[derive(Debug, Clone)]
+pub struct SessionHandle {
+    pub tx: mpsc::UnboundedSender<ServerMessage>,
+    pub chunks: mpsc::Sender<ServerMessage>,
+    pub ip: Option<IpAddr>,
+    pub room: RoomId,
+    pub counters: Arc<peers::PeerCounters>,
+    /// Driven from inside the server, e.g. by a bridge or an HTTP fetch
+    pub local: bool,
+    /// Whether it wants `UploadActivity` notices
+    pub notify_upload_activity: bool,
+    /// Auth token the connection was accepted with; None when the server needs none
+    pub credential: Option<String>,
+}
+
+/// Room broadcast feed handed to the writer task when a session joins.
+struct Subscription {
+    rx: broadcast::Receiver<rooms::Broadcast>,
+    session_id: SessionId,
+    file_list_diffs: bool,
+    /// Shared with the connection, which changes them on `Subscribe`
+    topics: Arc<rooms::Topics>,
+}
+
+impl Subscription {
+    async fn recv(&mut self) -> Result<rooms::Broadcast, broadcast::error::RecvError> {
+        loop {
+            let msg = self.rx.recv().await?;
+            if self.wants(&msg) {
+                return Ok(msg);
+            }
+        }
+    }
+
+    /// Each file list change is broadcast in both forms; keep the one this peer understands.
+    /// Variants meant for other peers, and topics it did not subscribe to, are dropped.
+    fn wants(&self, msg: &rooms::Broadcast) -> bool {
+        if !msg.audience.includes(&self.session_id) {
+            return false;
+        }
+        let subscribed = msg.topic.is_none_or(|topic| self.topics.contains(topic));
+        if !subscribed && !msg.parties.contains(&self.session_id) {
+            return false;
+        }
+        match msg.file_list {
+            Some(rooms::FileListForm::Full) => !self.file_list_diffs,
+            Some(rooms::FileListForm::Diff) => self.file_list_diffs,
+            None => true,
+        }
+    }
+}
+
+static NEXT_CONNECTION_ID: AtomicU64 = AtomicU64::new(1);
+
+/// Per-connection state threaded through message handling.
+struct Connection {
+    session_id: Option<SessionId>,
+    room: Option<RoomState>,
+    tx: mpsc::UnboundedSender<ServerMessage>,
+    chunks: mpsc::Sender<ServerMessage>,
+    subscribe: mpsc::UnboundedSender<Subscription>,
+    ip: Option<IpAddr>,
+    role: PeerRole,
+    malformed_messages: u32,
+    last_rename: Option<Instant>,
+    last_sync: Option<Instant>,
+    last_seen_recorded: Option<Instant>,
+    /// Last client message that counts against `--idle-timeout`
+    last_activity: Instant,
+    idle_warned: bool,
+    /// Declared `FILE_LIST_PAGES_CAPABILITY` at join
+    file_list_pages: bool,
+    /// Declared `E2E_CAPABILITY` at join
+    e2e: bool,
+    topics: Arc<rooms::Topics>,
+    counters: Arc<peers::PeerCounters>,
+    /// Driven by a `LocalPeer` rather than a socket
+    local: bool,
+    /// Auth token the socket was accepted with
+    credential: Option<String>,
+    /// Batch this session's single `FileUpload`s join, and when the last one arrived
+    upload_batch: Option<(String, Instant)>,
+}
+
+impl Connection {
+    /// A connection that has not joined yet, and the outbox its messages arrive in.
+    fn new(state: &AppState, ip: Option<IpAddr>) -> (Connection, Outbox) {
+        let (session_tx, messages) = mpsc::unbounded_channel::<ServerMessage>();
+        let (chunk_tx, chunks) = mpsc::channel::<ServerMessage>(CHUNK_QUEUE_CAPACITY);
+        let (subscribe_tx, subscribe) = mpsc::unbounded_channel::<Subscription>();
+        let conn = Connection {
+            session_id: None,
+            room: None,
+            tx: session_tx,
+            chunks: chunk_tx,
+            subscribe: subscribe_tx,
+            ip,
+            role: if state.guest_readonly { PeerRole::ReadOnly } else { PeerRole::Full },
+            malformed_messages: 0,
+            last_rename: None,
+            last_sync: None,
+            last_seen_recorded: None,
+            last_activity: Instant::now(),
+            idle_warned: false,
+            file_list_pages: false,
+            e2e: false,
+            topics: Arc::default(),
+            counters: Arc::default(),
+            local: false,
+            credential: None,
+            upload_batch: None,
+        };
+        let outbox = Outbox {
+            messages,
+            chunks,
+            subscribe,
+            subscription: None,
+        };
+        (conn, outbox)
+    }
+}
+
+/// Everything waiting to be delivered to one connection. Targeted control messages
+/// go first, then room broadcasts, then file chunks, which wait in a bounded queue.
+struct Outbox {
+    messages: mpsc::UnboundedReceiver<ServerMessage>,
+    chunks: mpsc::Receiver<ServerMessage>,
+    subscribe: mpsc::UnboundedReceiver<Subscription>,
+    subscription: Option<Subscription>,
+}
+
+enum Outgoing {
+    Message(Box<ServerMessage>),
+    /// Serialized once by the room for all of its subscribers
+    Broadcast(rooms::Broadcast),
+}
+
+impl Outbox {
+    /// The next message to deliver, or None once the room's channel has closed.
+    async fn next(&mut self) -> Option<Outgoing> {
+        loop {
+            // A lagged subscriber skips ahead; the client notices the gap in `seq`
+            tokio::select! {
+                biased;
+                Some(msg) = self.messages.recv() => return Some(Outgoing::Message(Box::new(msg))),
+                Some(subscription) = self.subscribe.recv() => self.subscription = Some(subscription),
+                result = recv_room(&mut self.subscription) => match result {
+                    Ok(msg) => return Some(Outgoing::Broadcast(msg)),
+                    // Gaps are normal for a client that left topics out, so it is told instead
+                    Err(broadcast::error::RecvError::Lagged(_))
+                        if self.subscription.as_ref().is_some_and(|subscription| subscription.topics.is_narrowed()) =>
+                    {
+                        return Some(Outgoing::Message(Box::new(ServerMessage::Error {
+                            code: "missed_broadcasts".to_string(),
+                            message: "Fell behind the room's broadcasts; send request_sync".to_string(),
+                            limit: None,
+                            retry_after_ms: None,
+                        })));
+                    }
+                    Err(broadcast::error::RecvError::Lagged(_)) => {}
+                    Err(broadcast::error::RecvError::Closed) => return None,
+                },
+                Some(msg) = self.chunks.recv() => return Some(Outgoing::Message(Box::new(msg))),
+                else => return None,
+            }
+        }
+    }
+
+    fn session_id(&self) -> Option<SessionId> {
+        self.subscription.as_ref().map(|subscription| subscription.session_id.clone())
+    }
 }
 
-pub async fn handle_websocket(ws: WebSocket, state: AppState) {
+/// A peer driven from inside the server rather than over a WebSocket, such as a
+/// bridge standing in for the peers of another server. Its messages go through the
+/// same handling as a connected client's.
+pub struct LocalPeer {
+    conn: Connection,
+    outbox: Outbox,
+}
+
+impl LocalPeer {
+    /// Joins with `join`, which should be a `ClientMessage::Join`.
+    pub async fn join(state: &AppState, join: ClientMessage) -> Result<LocalPeer, String> {
+        let (mut conn, outbox) = Connection::new(state, None);
+        // Started by the server itself, so `--guest-readonly` does not apply
+        conn.role = PeerRole::Full;
+        conn.local = true;
+        let mut peer = LocalPeer { conn, outbox };
+        peer.send(state, join).await?;
+        if peer.conn.session_id.is_none() {
+            return Err("join was not accepted".to_string());
+        }
+        Ok(peer)
+    }
+
+    pub async fn send(&mut self, state: &AppState, msg: ClientMessage) -> Result<(), String> {
+        handle_client_message(msg, state, &mut self.conn).await.map_err(|e| e.to_string())
+    }
+
+    /// The next message for this peer, room broadcasts included. None once its room
+    /// has closed.
+    pub async fn recv(&mut self) -> Option<ServerMessage> {
+        loop {
+            match self.outbox.next().await? {
+                Outgoing::Message(msg) => return Some(*msg),
+                Outgoing::Broadcast(msg) => {
+                    if let Ok(msg) = serde_json::from_str(&msg.json) {
+                        return Some(msg);
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn session_id(&self) -> Option<&SessionId> {
+        self.conn.session_id.as_ref()
+    }
+
+    pub fn room(&self) -> Option<&RoomState> {
+        self.conn.room.as_ref()
+    }
+
+    pub async fn leave(self, state: &AppState) {
+        if let (Some(id), Some(room)) = (&self.conn.session_id, &self.conn.room) {
+            cleanup_peer(state, room, id).await;
+        }
+    }
+}
+
+pub async fn websocket_handler(
+    credential: Option<String>,
+    ws: Ws,
+    ip: Option<IpAddr>,
+    state: AppState,
+) -> Result<Box<dyn Reply>, Rejection> {
+    if state.is_banned(ip).await {
+        return Ok(crate::handlers::forbidden());
+    }
+    // Only an auth token vouches for anything; without a security code there is none
+    let credential = credential.filter(|_| state.security_code.is_some());
+    let ws = ws
+        .max_message_size(state.max_ws_message)
+        .max_frame_size(state.max_ws_message);
+    // Every event of the connection carries its id, and its session id and name once joined
+    let span = tracing::info_span!(
+        "ws",
+        conn = NEXT_CONNECTION_ID.fetch_add(1, Ordering::Relaxed),
+        session_id = tracing::field::Empty,
+        name = tracing::field::Empty,
+    );
+    Ok(Box::new(ws.on_upgrade(move |socket| handle_websocket(socket, ip, credential, state).instrument(span))) as Box<dyn Reply>)
+}
+
+pub async fn handle_websocket(ws: WebSocket, ip: Option<IpAddr>, credential: Option<String>, state: AppState) {
+    tracing::info!(ip = ?ip, "WebSocket connected");
+    state.events.record(EventKind::Connected, EventSubject {
+        ip,
+        ..Default::default()
+    });
+    let connected_at = Instant::now();
     let (mut ws_tx, mut ws_rx) = ws.split();
-    let mut session_id: Option<SessionId> = None;
-    let mut rx = state.tx.subscribe();
+    let (mut conn, mut outbox) = Connection::new(&state, ip);
+    conn.credential = credential;
 
-    // Spawn a task to handle outgoing messages
-    let outgoing_task = tokio::spawn(async move {
-        while let Ok(msg) = rx.recv().await {
-            let json = serde_json::to_string(&msg).unwrap();
+    // Spawn a task to handle outgoing messages, both targeted at this session and
+    // broadcast to the room it has joined
+    let messages_out = Arc::new(AtomicU64::new(0));
+    let messages_sent = messages_out.clone();
+    let events = state.events.clone();
+    let mut outgoing_task = tokio::spawn(async move {
+        while let Some(outgoing) = outbox.next().await {
+            let (json, close) = match outgoing {
+                Outgoing::Message(msg) => {
+                    let subject = |detail: &str| EventSubject {
+                        session_id: outbox.session_id(),
+                        ip,
+                        detail: Some(detail.to_string()),
+                        ..Default::default()
+                    };
+                    let close = match msg.as_ref() {
+                        ServerMessage::Kicked { reason, code } => {
+                            events.record(EventKind::Kicked, subject(reason));
+                            Some((*code, reason.clone()))
+                        }
+                        ServerMessage::Error { code, .. } => {
+                            events.record(EventKind::Error, subject(code));
+                            None
+                        }
+                        ServerMessage::UpgradeRequired { .. } => {
+                            Some((CLOSE_UPGRADE_REQUIRED, "Client upgrade required".to_string()))
+                        }
+                        _ => None,
+                    };
+                    (serde_json::to_string(&msg).unwrap(), close)
+                }
+                Outgoing::Broadcast(msg) => (msg.json.to_string(), None),
+            };
             if ws_tx.send(Message::text(json)).await.is_err() {
                 break;
             }
+            messages_sent.fetch_add(1, Ordering::Relaxed);
+            if let Some((code, reason)) = close {
+                let _ = ws_tx.send(Message::close_with(code, reason)).await;
+                let _ = ws_tx.close().await;
+                break;
+            }
         }
-    });
+    }.instrument(tracing::Span::current()));
 
-    // Handle incoming messages
-    while let Some(result) = ws_rx.next().await {
+    // Handle incoming messages until the socket closes or the outgoing side shuts down
+    let mut messages_in: u64 = 0;
+    loop {
+        let idle_deadline = idle_deadline(&state, &conn);
+        let join_deadline = conn.session_id.is_none().then(|| connected_at + JOIN_TIMEOUT);
+        let result = tokio::select! {
+            result = ws_rx.next() => result,
+            _ = &mut outgoing_task => break,
+            _ = sleep_until(join_deadline) => {
+                tracing::info!(ip = ?ip, "No join received in time, disconnecting");
+                let _ = conn.tx.send(ServerMessage::Kicked {
+                    reason: "Send join within 10 seconds of connecting".to_string(),
+                    code: CLOSE_PROTOCOL_VIOLATION,
+                });
+                let _ = tokio::time::timeout(CLOSE_ACK_TIMEOUT, &mut outgoing_task).await;
+                break;
+            }
+            _ = sleep_until(idle_deadline) => {
+                if handle_idle(&state, &mut conn).await {
+                    // Let the writer send the close frame before the connection is dropped
+                    let _ = tokio::time::timeout(CLOSE_ACK_TIMEOUT, &mut outgoing_task).await;
+                    break;
+                }
+                continue;
+            }
+        };
         match result {
-            Ok(msg) => {
-                if let Ok(text) = msg.to_str() {
-                    if let Ok(client_msg) = serde_json::from_str::<ClientMessage>(text) {
-                        match handle_client_message(client_msg, &state, &mut session_id).await {
-                            Ok(_) => {}
-                            Err(e) => {
-                                let error_msg = ServerMessage::Error {
-                                    message: e.to_string(),
-                                };
-                                let _ = state.tx.send(error_msg);
-                            }
+            Some(Ok(msg)) if msg.is_text() => {
+                messages_in += 1;
+                record_activity(&mut conn).await;
+                let text = msg.to_str().unwrap_or_default();
+                match serde_json::from_str::<ClientMessage>(text) {
+                    Ok(client_msg) => {
+                        tracing::debug!(kind = client_msg.kind(), bytes = text.len(), "Message received");
+                        if !(state.idle_ignore_pings && matches!(client_msg, ClientMessage::Ping { .. })) {
+                            conn.last_activity = Instant::now();
+                            conn.idle_warned = false;
+                        }
+                        if let Err(e) = handle_client_message(client_msg, &state, &mut conn).await {
+                            tracing::warn!(error = %e, "Message handling failed");
+                            let _ = conn.tx.send(ServerMessage::Error {
+                                code: "internal".to_string(),
+                                message: e.to_string(),
+                                limit: None,
+                                retry_after_ms: None,
+                            });
                         }
                     }
+                    Err(e) => reject_malformed(&mut conn, malformed_message_error(text, &e)),
                 }
             }
-            Err(_) => break,
+            Some(Ok(msg)) if msg.is_binary() => reject_malformed(&mut conn, ServerMessage::Error {
+                code: "invalid_message".to_string(),
+                message: "Binary frames are not supported; send JSON text".to_string(),
+                limit: None,
+                retry_after_ms: None,
+            }),
+            Some(Ok(msg)) if msg.is_close() => {
+                let (code, reason) = msg.close_frame().unwrap_or((1005, ""));
+                tracing::info!(code, reason, "Client closed the connection");
+                // Reading on lets the WebSocket layer send its half of the closing handshake
+                let _ = tokio::time::timeout(CLOSE_ACK_TIMEOUT, ws_rx.next()).await;
+                break;
+            }
+            // Pings are answered by the WebSocket layer and pongs need no reply
+            Some(Ok(_)) => {}
+            Some(Err(_)) | None => break,
         }
     }
 
     // Cleanup when connection closes
-    if let Some(id) = session_id {
-        cleanup_peer(&state, &id).await;
+    if let (Some(id), Some(room)) = (&conn.session_id, &conn.room) {
+        cleanup_peer(&state, room, id).await;
+    }
+    tracing::info!(
+        ip = ?ip,
+        session_id = ?conn.session_id,
+        duration_secs = connected_at.elapsed().as_secs(),
+        "WebSocket disconnected"
+    );
+    state.events.record(EventKind::Disconnected, EventSubject {
+        session_id: conn.session_id.clone(),
+        ip,
+        ..Default::default()
+    });
+    if state.access_log {
+        tracing::info!(
+            target: "access",
+            ip = ?ip,
+            session_id = ?conn.session_id,
+            duration_secs = connected_at.elapsed().as_secs(),
+            messages_in,
+            messages_out = messages_out.load(Ordering::Relaxed),
+            bytes_relayed = conn.counters.bytes_sent.load(Ordering::Relaxed)
+                + conn.counters.bytes_received.load(Ordering::Relaxed),
+            "Connection closed"
+        );
     }
 
     outgoing_task.abort();
 }
 
+/// When the connection's idle timeout next needs attention: the warning, or once
+/// warned, the disconnect. None when `--idle-timeout` is off.
+fn idle_deadline(state: &AppState, conn: &Connection) -> Option<Instant> {
+    let timeout = state.idle_timeout?;
+    if conn.idle_warned {
+        Some(conn.last_activity + timeout)
+    } else {
+        Some(conn.last_activity + timeout - idle_warning(timeout))
+    }
+}
+
+fn idle_warning(timeout: Duration) -> Duration {
+    IDLE_WARNING_BEFORE.min(timeout / 2)
+}
+
+async fn sleep_until(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => tokio::time::sleep_until(deadline.into()).await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Warns an idle session, or disconnects it if it was already warned, returning true
+/// once disconnected. This is an operator policy on quiet users; dead connections are
+/// noticed by the socket itself.
+async fn handle_idle(state: &AppState, conn: &mut Connection) -> bool {
+    let Some(timeout) = state.idle_timeout else {
+        return false;
+    };
+    if !conn.idle_warned {
+        conn.idle_warned = true;
+        let _ = conn.tx.send(ServerMessage::IdleWarning {
+            disconnect_in_secs: idle_warning(timeout).as_secs(),
+        });
+        return false;
+    }
+
+    tracing::info!(session_id = ?conn.session_id, "Disconnecting idle session");
+    let reason = "Disconnected after being idle";
+    match &conn.session_id {
+        Some(session_id) => {
+            kick_session(state, session_id, CLOSE_IDLE_TIMEOUT, reason).await;
+        }
+        None => {
+            let _ = conn.tx.send(ServerMessage::Kicked {
+                reason: reason.to_string(),
+                code: CLOSE_IDLE_TIMEOUT,
+            });
+        }
+    }
+    true
+}
+
+/// Refreshes the peer's `last_seen` and announces its return if it had gone idle.
+async fn record_activity(conn: &mut Connection) {
+    let (Some(session_id), Some(room)) = (&conn.session_id, &conn.room) else {
+        return;
+    };
+    if conn.last_seen_recorded.is_some_and(|at| at.elapsed() < peers::LAST_SEEN_RESOLUTION) {
+        return;
+    }
+    conn.last_seen_recorded = Some(Instant::now());
+
+    let mut peers = room.peers.write().await;
+    if let Some(peer) = peers.get_mut(session_id) {
+        peer.last_seen = chrono::Utc::now();
+        if peer.activity == ActivityState::Idle {
+            peer.activity = ActivityState::Active;
+            room.send(ServerMessage::PeerActivity {
+                session_id: session_id.clone(),
+                state: ActivityState::Active,
+            });
+        }
+    }
+}
+
+/// Checks a relayed chunk against its transfer: the target must be a peer in the room,
+/// the file registered or announced with `FileMetadata`, and the data no larger than
+/// the chunk size the target negotiated.
+async fn check_chunk(
+    state: &AppState,
+    room: &RoomState,
+    transfer_key: &transfers::TransferKey,
+    chunk_index: u32,
+    total_chunks: u32,
+    data: &str,
+    expanded: bool,
+) -> Result<(), String> {
+    let (file_id, _, target_session_id) = transfer_key;
+    if total_chunks == 0 {
+        return Err("total_chunks must be at least 1".to_string());
+    }
+    if chunk_index >= total_chunks {
+        return Err(format!("chunk_index {chunk_index} is out of range for {total_chunks} chunks"));
+    }
+
+    let Some(chunk_size) = negotiated_chunk_size(state, room, target_session_id).await else {
+        return Err(format!("Peer {target_session_id} is not connected"));
+    };
+    let chunk_size = chunk_size as usize;
+    // Compressed or encrypted data may come out slightly larger than the raw chunk
+    let max_len = if expanded { chunk_size + chunk_size / 100 + 64 } else { chunk_size };
+    if base64_decoded_len(data) > max_len {
+        return Err(format!("Chunk data exceeds the negotiated chunk size of {chunk_size} bytes"));
+    }
+
+    if !room.files.read().await.contains_key(file_id) && !state.relay.transfers.has_transfer(transfer_key) {
+        return Err(format!("Unknown file or transfer {file_id}"));
+    }
+    Ok(())
+}
+
+/// The chunk size the target negotiated, or None when it is not connected.
+async fn negotiated_chunk_size(state: &AppState, room: &RoomState, target_session_id: &SessionId) -> Option<u32> {
+    match room.peers.read().await.get(target_session_id) {
+        Some(peer) => Some(peer.chunk_size),
+        // The server fetching a file itself is not a peer, and asks for the default size
+        None if fetch::is_fetch_session(target_session_id)
+            && state.sessions.read().await.contains_key(target_session_id) =>
+        {
+            Some(DEFAULT_CHUNK_SIZE)
+        }
+        None => None,
+    }
+}
+
+/// Where a chunk's raw data lies in the file, as far as known.
+#[derive(Debug, Clone, Copy)]
+struct ChunkRange {
+    offset: Option<u64>,
+    len: Option<u32>,
+}
+
+/// Completes the range a host stated for a checked chunk. `offset` and `len` are filled
+/// in for hosts that leave them out, from the transfer's chunk size and the file's size,
+/// and checked against the size of the file when it is known. `raw_len` is the decoded
+/// length of the data unless it is compressed or encrypted.
+async fn chunk_range(
+    state: &AppState,
+    room: &RoomState,
+    transfer_key: &transfers::TransferKey,
+    chunk_index: u32,
+    total_chunks: u32,
+    raw_len: Option<usize>,
+    stated: ChunkRange,
+) -> Result<ChunkRange, String> {
+    let (file_id, _, target_session_id) = transfer_key;
+    let details = state.relay.transfers.details(transfer_key).unwrap_or_default();
+    let file_size = room.files.read().await.get(file_id).map(|file| file.size).or(details.size);
+    let chunk_size = match details.chunk_size {
+        Some(chunk_size) => Some(chunk_size),
+        None => negotiated_chunk_size(state, room, target_session_id).await,
+    };
+
+    let offset = stated.offset.or_else(|| chunk_size.map(|size| u64::from(chunk_index) * u64::from(size)));
+    let len = match (stated.len, raw_len) {
+        (Some(len), _) => Some(len),
+        (None, Some(raw_len)) => u32::try_from(raw_len).ok(),
+        // Compressed or encrypted data only tells its raw length for full chunks
+        (None, None) if chunk_index.saturating_add(1) < total_chunks => chunk_size,
+        (None, None) => file_size.zip(offset).and_then(|(size, offset)| u32::try_from(size.checked_sub(offset)?).ok()),
+    };
+
+    if let Some(len) = len {
+        if let Some(raw_len) = raw_len.filter(|raw_len| *raw_len != len as usize) {
+            return Err(format!("len {len} does not match the {raw_len} bytes of data"));
+        }
+        if let Some((offset, size)) = offset.zip(file_size) {
+            if offset.saturating_add(u64::from(len)) > size {
+                return Err(format!("Chunk at offset {offset} with len {len} ends past the {size} byte file"));
+            }
+        }
+    }
+    Ok(ChunkRange { offset, len })
+}
+
+/// A peer's free-text reason, trimmed, without control characters and cut to length.
+fn clean_reason(reason: &str) -> String {
+    reason.trim().chars().filter(|c| !c.is_control()).take(MAX_REASON_CHARS).collect()
+}
+
+fn base64_decoded_len(data: &str) -> usize {
+    let padding = data.bytes().rev().take_while(|&b| b == b'=').count();
+    (data.len() / 4 * 3).saturating_sub(padding)
+}
+
+/// Reports a frame that could not be handled. Once a connection has sent too many,
+/// it is kicked; the writer closes the socket, which ends the read loop.
+fn reject_malformed(conn: &mut Connection, error: ServerMessage) {
+    conn.malformed_messages += 1;
+    let _ = conn.tx.send(error);
+    if conn.malformed_messages == MAX_MALFORMED_MESSAGES {
+        let _ = conn.tx.send(ServerMessage::Kicked {
+            reason: "Too many malformed messages".to_string(),
+            code: CLOSE_PROTOCOL_VIOLATION,
+        });
+    }
+}
+
+/// Whether `session_id` is registered to a connection other than `conn`.
+fn held_elsewhere(sessions: &HashMap<SessionId, SessionHandle>, session_id: &SessionId, conn: &Connection) -> bool {
+    sessions.get(session_id).is_some_and(|handle| !handle.tx.same_channel(&conn.tx))
+}
+
+/// Whether a departed `session_id` still has files, trash or snippets that joining with
+/// it would hand over.
+async fn has_leftovers(state: &AppState, room: &RoomState, session_id: &SessionId) -> bool {
+    let in_files = room.files.read().await.values().any(|file| {
+        file.uploader_id == *session_id
+            || file.hosts.contains(session_id)
+            || file.orphaned.as_ref().is_some_and(|orphaned| orphaned.last_host == *session_id)
+            || file.visibility.as_ref().is_some_and(|visibility| visibility.contains(session_id))
+    });
+    in_files
+        || room.snippets.read().await.values().any(|snippet| snippet.creator_id == *session_id)
+        || !state.trash.files_of(session_id).is_empty()
+}
+
+fn session_in_use(message: &str) -> ServerMessage {
+    ServerMessage::Error {
+        code: "session_in_use".to_string(),
+        message: message.to_string(),
+        limit: None,
+        retry_after_ms: None,
+    }
+}
+
+/// The display suffix a file gets when renamed to `name`, or None if the name stays.
+fn renamed_suffix(files: &rooms::FileRegistry, file_id: &str, name: Option<&str>) -> Option<Option<String>> {
+    let name = name.filter(|name| files.get(file_id).is_some_and(|file| file.name != *name))?;
+    Some(files::display_suffix(files, file_id, name))
+}
+
+fn invalid_path_error(path: &str) -> ServerMessage {
+    let error = files::UploadError::InvalidPath(path.to_string());
+    ServerMessage::Error {
+        code: error.code().to_string(),
+        message: error.message(),
+        limit: None,
+        retry_after_ms: None,
+    }
+}
+
+fn malformed_message_error(text: &str, error: &serde_json::Error) -> ServerMessage {
+    let message_type = serde_json::from_str::<serde_json::Value>(text)
+        .ok()
+        .and_then(|value| value.get("type")?.as_str().map(str::to_string));
+    let input: String = text.chars().take(MAX_ECHOED_INPUT_CHARS).collect();
+    let message = match message_type {
+        Some(message_type) => format!("Invalid {message_type} message: {error} (input: {input})"),
+        None => format!("Invalid message: {error} (input: {input})"),
+    };
+    ServerMessage::Error {
+        code: "invalid_message".to_string(),
+        message,
+        limit: None,
+        retry_after_ms: None,
+    }
+}
+
+async fn recv_room(
+    subscription: &mut Option<Subscription>,
+) -> Result<rooms::Broadcast, broadcast::error::RecvError> {
+    match subscription {
+        Some(subscription) => subscription.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
 async fn handle_client_message(
     msg: ClientMessage,
     state: &AppState,
-    session_id: &mut Option<SessionId>,
+    conn: &mut Connection,
 ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    // An outdated client is turned away before joining touches anything, the room included
+    if let ClientMessage::Join { client_version, .. } = &msg {
+        if !state.client_versions.allows(client_version.as_deref()) {
+            let min_version = state.client_versions.min_version.as_ref().map(|min| min.to_string()).unwrap_or_default();
+            tracing::info!(client_version, min_version, "Client too old, asking it to upgrade");
+            let _ = conn.tx.send(ServerMessage::UpgradeRequired {
+                download_hint: format!("Reload the page to get the current web client, or update your client to {min_version} or later"),
+                min_version,
+            });
+            return Ok(());
+        }
+    }
+
+    let room = match (&msg, &conn.room) {
+        (ClientMessage::Join { room, .. }, _) => {
+            state.join_room(&rooms::room_id(room.as_deref())).await
+        }
+        (_, Some(room)) => room.clone(),
+        // Nothing else is handled before joining, and trying counts as a malformed message
+        (_, None) => {
+            reject_malformed(conn, ServerMessage::Error {
+                code: "not_joined".to_string(),
+                message: "Send join before any other message".to_string(),
+                limit: None,
+                retry_after_ms: None,
+            });
+            return Ok(());
+        }
+    };
+
+    if conn.role == PeerRole::ReadOnly && msg.requires_write_access() {
+        let _ = conn.tx.send(ServerMessage::Error {
+            code: "read_only".to_string(),
+            message: "This session is read-only".to_string(),
+            limit: None,
+            retry_after_ms: None,
+        });
+        return Ok(());
+    }
+
+    // The server's own peers are trusted to pace themselves
+    if let Some(session_id) = conn.session_id.as_ref().filter(|_| !conn.local) {
+        match state.rate_limits.check(session_id, &msg) {
+            Ok(()) => {}
+            Err(rate_limit::Limited::RetryAfter(retry_after)) => {
+                let _ = conn.tx.send(ServerMessage::Error {
+                    code: "rate_limited".to_string(),
+                    message: format!("Too many {} messages, slow down", msg.kind()),
+                    limit: None,
+                    retry_after_ms: Some(retry_after.as_millis() as u64),
+                });
+                return Ok(());
+            }
+            Err(rate_limit::Limited::Abusive) => {
+                tracing::warn!(session_id, "Kicking a session that keeps exceeding its message budget");
+                let _ = conn.tx.send(ServerMessage::Kicked {
+                    reason: "Too many messages".to_string(),
+                    code: CLOSE_PROTOCOL_VIOLATION,
+                });
+                return Ok(());
+            }
+        }
+    }
+
     match msg {
         ClientMessage::Join {
             session_id: id,
+            name,
             user_agent,
+            room: _,
+            preferred_chunk_size,
+            capabilities,
+            protocol_version,
+            auto_accept_downloads,
+            notify_upload_activity,
+            client_version: _,
+            session_token,
         } => {
-            *session_id = Some(id.clone());
-            
-            let peer = PeerInfo {
+            if (share::is_share_session(&id) && !conn.local)
+                || id.starts_with(chat::API_SENDER_PREFIX)
+                || id == chat::SERVER_SENDER_ID
+            {
+                let _ = conn.tx.send(ServerMessage::Error {
+                    code: "reserved_session_id".to_string(),
+                    message: "This session id is reserved for the server".to_string(),
+                    limit: None,
+                    retry_after_ms: None,
+                });
+                return Ok(());
+            }
+
+            // Session ids are public, so taking one up that another connection holds, or
+            // that a departed peer left files or snippets under, needs its token
+            if held_elsewhere(&*state.sessions.read().await, &id, conn) {
+                let _ = conn.tx.send(session_in_use("Another connection is using this session id"));
+                return Ok(());
+            }
+            let owns_id = conn.local
+                || conn.session_id.as_ref() == Some(&id)
+                || session_token.as_deref().is_some_and(|token| state.valid_session_token(&id, token));
+            if !owns_id && has_leftovers(state, &room, &id).await {
+                let _ = conn.tx.send(session_in_use("This session id belongs to a peer that left; join with its session_token or a new id"));
+                return Ok(());
+            }
+
+            // A connection re-joining leaves its previous session behind first
+            if let (Some(old_id), Some(old_room)) = (conn.session_id.take(), conn.room.take()) {
+                cleanup_peer(state, &old_room, &old_id).await;
+            }
+
+            // Register the direct sender so this session can be reached individually. The id
+            // is checked again under the lock, in case another join took it in the meantime.
+            conn.counters = Arc::default();
+            {
+                let mut sessions = state.sessions.write().await;
+                if held_elsewhere(&sessions, &id, conn) {
+                    let _ = conn.tx.send(session_in_use("Another connection is using this session id"));
+                    return Ok(());
+                }
+                sessions.insert(id.clone(), SessionHandle {
+                    tx: conn.tx.clone(),
+                    chunks: conn.chunks.clone(),
+                    ip: conn.ip,
+                    room: room.id.clone(),
+                    counters: conn.counters.clone(),
+                    local: conn.local,
+                    notify_upload_activity: notify_upload_activity.unwrap_or(true),
+                    credential: conn.credential.clone(),
+                });
+            }
+
+            conn.session_id = Some(id.clone());
+            conn.room = Some(room.clone());
+
+            let protocol_version = negotiate_protocol_version(protocol_version);
+            let mut peer = PeerInfo {
                 session_id: id.clone(),
+                name: None,
+                color_hue: 0,
                 connected_at: chrono::Utc::now(),
+                last_seen: chrono::Utc::now(),
+                activity: ActivityState::Active,
+                client: user_agent.as_deref().map(user_agent::parse),
                 user_agent,
+                ip: if state.hide_ips { None } else { conn.ip },
+                role: conn.role,
+                chunk_size: negotiate_chunk_size(preferred_chunk_size),
+                capabilities: capabilities.unwrap_or_default(),
+                protocol_version,
+                auto_accept_downloads: auto_accept_downloads.unwrap_or(true),
             };
 
-            // Add peer to the map
-            let peers_count = {
-                let mut peers = state.peers.write().await;
+            conn.file_list_pages = peer.receives_file_list_pages();
+            conn.e2e = peer.capabilities.contains(E2E_CAPABILITY);
+            let _ = conn.subscribe.send(Subscription {
+                rx: room.subscribe(),
+                session_id: id.clone(),
+                file_list_diffs: peer.receives_file_list_diffs(),
+                topics: conn.topics.clone(),
+            });
+            let _ = conn.tx.send(ServerMessage::Welcome {
+                server_version: env!("CARGO_PKG_VERSION").to_string(),
+                protocol_version,
+                features: SERVER_FEATURES.iter().map(|feature| feature.to_string()).collect(),
+                max_message_length: state.max_message_length,
+                ice_servers: state.ice.servers_for(&id),
+                session_token: state.session_token(&id),
+            });
+            let _ = conn.tx.send(ServerMessage::ServerInfo {
+                name: state.server_name.clone(),
+                version: env!("CARGO_PKG_VERSION").to_string(),
+                instance_id: state.instance_id.clone(),
+                features: SERVER_FEATURES.iter().map(|feature| feature.to_string()).collect(),
+                limits: state.server_limits(),
+            });
+            if let Some(motd) = state.motd.read().await.clone() {
+                let _ = conn.tx.send(ServerMessage::Motd {
+                    text: motd.text,
+                    set_at: motd.set_at,
+                });
+            }
+            let trashed = state.trash.files_of(&id);
+            if !trashed.is_empty() {
+                let _ = conn.tx.send(ServerMessage::TrashList { files: trashed });
+            }
+
+            // Add peer to the map, snapshotting the roster it joins
+            let roster: Vec<PeerInfo> = {
+                let mut peers = room.peers.write().await;
+                peer.name = name
+                    .as_deref()
+                    .and_then(peers::sanitize_name)
+                    .map(|name| peers::unique_name(&peers, &id, name));
+                peer.color_hue = peers::assign_hue(&peers, &id);
                 peers.insert(id.clone(), peer.clone());
-                peers.len()
+                peers.values().cloned().collect()
             };
+            let peers_count = roster.len();
 
-            // Send current file list to the new peer
-            let files = {
-                let files = state.files.read().await;
-                files.values().cloned().collect()
-            };
-            
-            let _ = state.tx.send(ServerMessage::FileListUpdate { files });
-            
-            // Send message history to the new peer
-            let messages = {
-                let messages = state.messages.read().await;
-                messages.clone()
-            };
-            
-            if !messages.is_empty() {
-                let _ = state.tx.send(ServerMessage::MessageHistory { messages });
+            tracing::Span::current().record("session_id", id.as_str());
+            if let Some(name) = &peer.name {
+                tracing::Span::current().record("name", name.as_str());
             }
+            tracing::info!(room = room.id, "Peer joined");
+            state.events.record(EventKind::Joined, EventSubject {
+                session_id: Some(id.clone()),
+                room: Some(room.id.clone()),
+                ip: conn.ip,
+                ..Default::default()
+            });
+
+            // A host coming back within the orphan grace period serves its files again
+            files::reattach_host(&room, &id).await;
+
+            // Send the peers already present, and the new peer itself, to the new peer
+            let _ = conn.tx.send(ServerMessage::PeerList {
+                peers: roster,
+                total_peers: peers_count,
+            });
+
+            // Send the current file list, latest message history and shared snippets to the new peer
+            send_snapshot(state, conn, &room, rooms::Topic::Files).await;
+            send_snapshot(state, conn, &room, rooms::Topic::Chat).await;
 
             // Notify all peers about new peer
-            let _ = state.tx.send(ServerMessage::PeerJoined {
+            let joined = format!("{} joined", chat::display_name(&peer));
+            room.send(ServerMessage::PeerJoined {
                 peer,
                 total_peers: peers_count,
             });
+            chat::post_system_message(state, &room, joined).await;
         }
         ClientMessage::FileUpload { session_id: _, file } => {
-            // Add file to the registry
-            {
-                let mut files = state.files.write().await;
-                files.insert(file.id.clone(), file.clone());
+            let batch_id = single_upload_batch(state, conn);
+            let (_, rejected) = announce_files(state, conn, &room, vec![*file], batch_id).await;
+            if let Some(rejection) = rejected.into_iter().next() {
+                let _ = conn.tx.send(ServerMessage::Error {
+                    code: rejection.code,
+                    message: rejection.message,
+                    limit: None,
+                    retry_after_ms: None,
+                });
             }
-
-            // Send updated file list instead of individual file added message
-            let files = {
-                let files = state.files.read().await;
-                files.values().cloned().collect()
-            };
-            
-            let _ = state.tx.send(ServerMessage::FileListUpdate { files });
+        }
+        ClientMessage::FileUploadBatch { session_id: _, files } => {
+            if files.len() > files::MAX_BATCH_FILES {
+                let _ = conn.tx.send(ServerMessage::Error {
+                    code: "batch_too_large".to_string(),
+                    message: format!("A batch may announce at most {} files", files::MAX_BATCH_FILES),
+                    limit: Some(files::MAX_BATCH_FILES),
+                    retry_after_ms: None,
+                });
+                return Ok(());
+            }
+            let (accepted, rejected) = announce_files(state, conn, &room, files, Some(files::new_batch_id())).await;
+            let _ = conn.tx.send(ServerMessage::BatchUploadResult { accepted, rejected });
         }
         ClientMessage::RequestDownload {
-            session_id: requester_id,
+            session_id: _,
             file_id,
+            batch_id,
+            password,
         } => {
-            // Find a host for this file
-            let file_hosts = {
-                let files = state.files.read().await;
-                if let Some(file) = files.get(&file_id) {
-                    file.hosts.clone()
-                } else {
-                    HashSet::new()
+            let file_ids = match (file_id, batch_id) {
+                (Some(file_id), None) => vec![file_id],
+                (None, Some(batch_id)) => {
+                    let files = room.files.read().await;
+                    files::batch_members(&files, &batch_id, conn.session_id.as_deref(), conn.e2e)
+                }
+                _ => {
+                    let _ = conn.tx.send(ServerMessage::Error {
+                        code: "invalid_message".to_string(),
+                        message: "Give either file_id or batch_id".to_string(),
+                        limit: None,
+                        retry_after_ms: None,
+                    });
+                    return Ok(());
                 }
             };
-
-            // Pick the first available host (could be improved with load balancing)
-            if let Some(host_id) = file_hosts.iter().next() {
-                let _ = state.tx.send(ServerMessage::DownloadRequest {
-                    from_session_id: host_id.clone(),
-                    file_id,
-                    requester_session_id: requester_id,
-                });
-            } else {
-                let _ = state.tx.send(ServerMessage::Error {
-                    message: "No hosts available for this file".to_string(),
+            if file_ids.is_empty() {
+                let _ = conn.tx.send(ServerMessage::Error {
+                    code: "not_found".to_string(),
+                    message: "No file of this batch can be downloaded".to_string(),
+                    limit: None,
+                    retry_after_ms: None,
                 });
+                return Ok(());
+            }
+            // Each file goes to its own least busy host, and the hosts' transfer slots
+            // queue whatever they cannot serve yet, in this order
+            for file_id in file_ids {
+                request_file(state, conn, &room, file_id, password.clone()).await;
             }
         }
-        ClientMessage::FileDownloaded {
-            session_id: downloader_id,
-            file_id,
-        } => {
+        ClientMessage::FileDownloaded { session_id: _, file_id } => {
+            let downloader_id = conn.session_id.clone().unwrap_or_default();
+            // Only a download the relay saw finish is counted, so claims cannot inflate it
+            let completed = state.relay.transfers.take_completed(&file_id, &downloader_id);
+            let downloader_name = {
+                let peers = room.peers.read().await;
+                peers.get(&downloader_id).and_then(|peer| peer.name.clone())
+            };
+
             // Add downloader as a new host
-            {
-                let mut files = state.files.write().await;
-                if let Some(file) = files.get_mut(&file_id) {
-                    file.hosts.insert(downloader_id);
+            let include_full_list = room.has_full_list_peers().await;
+            let mut files = room.files.write().await;
+            let mut change = files::FileListChange::default();
+            let mut notify_uploader = None;
+            if let Some(entry) = files.get_mut(&file_id) {
+                let file = Arc::make_mut(entry);
+                // Re-downloads and the uploader's own downloads are not counted
+                let counted = completed && downloader_id != file.uploader_id && file.downloaders.insert(downloader_id.clone());
+                if counted {
+                    file.download_count += 1;
+                    notify_uploader = Some(file.uploader_id.clone());
+                }
+                let new_host = file.hosts.insert(downloader_id.clone());
+                if new_host {
+                    file.unavailable = false;
+                    file.orphaned = None;
+                }
+                if counted || new_host {
+                    change.updated.push(entry.clone());
                 }
             }
 
-            // Send updated file list
-            let files = {
-                let files = state.files.read().await;
-                files.values().cloned().collect()
-            };
-            
-            let _ = state.tx.send(ServerMessage::FileListUpdate { files });
+            files::publish_change(&room, &files, change, include_full_list);
+            drop(files);
+
+            if let Some(uploader_id) = notify_uploader {
+                state.send_to(&uploader_id, ServerMessage::FileDownloadedNotice {
+                    file_id,
+                    by_session_id: downloader_id,
+                    by_name: downloader_name,
+                }).await;
+            }
         }
-        ClientMessage::Ping { session_id: _ } => {
-            let _ = state.tx.send(ServerMessage::Pong);
+        ClientMessage::Ping { session_id: _, nonce } => {
+            let _ = conn.tx.send(ServerMessage::Pong {
+                nonce,
+                server_time: chrono::Utc::now(),
+            });
         }
         ClientMessage::FileChunk {
             session_id: _,
             file_id,
             chunk_index,
             total_chunks,
+            offset,
+            len,
             data,
             target_session_id,
+            compression,
+            encrypted,
+            nonce,
         } => {
-            // Forward the file chunk to the target session
-            let _ = state.tx.send(ServerMessage::FileChunk {
+            if reject_unsupported_compression(state, conn, &target_session_id, compression.as_deref()).await {
+                return Ok(());
+            }
+            if reject_encryption_mismatch(state, conn, &room, &file_id, &target_session_id, encrypted).await {
+                return Ok(());
+            }
+
+            let from_session_id = conn.session_id.clone().unwrap_or_default();
+            let transfer_key = (file_id.clone(), from_session_id.clone(), target_session_id.clone());
+            let expanded = compression.is_some() || encrypted;
+            let check = check_chunk(state, &room, &transfer_key, chunk_index, total_chunks, &data, expanded);
+            let raw_len = (!expanded).then(|| base64_decoded_len(&data));
+            let range = match check.await {
+                Ok(()) => {
+                    let stated = ChunkRange { offset, len };
+                    chunk_range(state, &room, &transfer_key, chunk_index, total_chunks, raw_len, stated).await
+                }
+                Err(message) => Err(message),
+            };
+            let ChunkRange { offset, len } = match range {
+                Ok(range) => range,
+                Err(message) => {
+                    reject_malformed(conn, ServerMessage::Error {
+                        code: "invalid_chunk".to_string(),
+                        message,
+                        limit: None,
+                        retry_after_ms: None,
+                    });
+                    return Ok(());
+                }
+            };
+
+            // Forward the file chunk to the target session, waiting for relay bandwidth if limited
+            // Chunks may arrive out of order, so the transfer ends with the last one counted
+            // rather than the one with the last index
+            let complete = state.relay.throttle(&transfer_key, data.len() as u64, total_chunks).await;
+            if complete {
+                state.relay.transfers.finish_transfer(&transfer_key);
+                start_queued_downloads(state).await;
+            }
+
+            let bytes = data.len() as u64;
+            let target = target_session_id.clone();
+            let delivered = forward_chunk(state, conn, ServerMessage::FileChunk {
                 file_id,
                 chunk_index,
                 total_chunks,
+                offset,
+                len,
                 data,
-                from_session_id: session_id.clone().unwrap_or_default(),
+                from_session_id,
                 target_session_id,
-            });
+                compression,
+                encrypted,
+                nonce,
+            }).await;
+            if delivered {
+                tracing::debug!(to = target, chunk_index, bytes, "Chunk forwarded");
+                conn.counters.record_sent(bytes);
+                if let Some(handle) = state.sessions.read().await.get(&target) {
+                    handle.counters.record_received(bytes);
+                }
+            }
         }
         ClientMessage::FileMetadata {
             session_id: _,
@@ -193,84 +1168,2370 @@ async fn handle_client_message(
             file_size,
             mime_type,
             total_chunks,
+            chunk_size,
             target_session_id,
+            compression,
+            relative_path,
+            encrypted,
+            nonce,
         } => {
-            // Forward the file metadata to the target session
-            let _ = state.tx.send(ServerMessage::FileMetadata {
+            if reject_unsupported_compression(state, conn, &target_session_id, compression.as_deref()).await {
+                return Ok(());
+            }
+            if reject_encryption_mismatch(state, conn, &room, &file_id, &target_session_id, encrypted).await {
+                return Ok(());
+            }
+            let relative_path = match relative_path {
+                Some(path) => match files::sanitize_relative_path(&path) {
+                    Some(path) => Some(path),
+                    None => {
+                        let _ = conn.tx.send(invalid_path_error(&path));
+                        return Ok(());
+                    }
+                },
+                None => None,
+            };
+            let negotiated = negotiated_chunk_size(state, &room, &target_session_id).await.unwrap_or(DEFAULT_CHUNK_SIZE);
+            let chunk_size = chunk_size.unwrap_or(negotiated);
+            if chunk_size == 0 || chunk_size > negotiated {
+                reject_malformed(conn, ServerMessage::Error {
+                    code: "invalid_chunk".to_string(),
+                    message: format!("chunk_size must be between 1 and the negotiated {negotiated} bytes"),
+                    limit: None,
+                    retry_after_ms: None,
+                });
+                return Ok(());
+            }
+
+            // Forward the file metadata to the target session, ahead of its chunks
+            let from_session_id = conn.session_id.clone().unwrap_or_default();
+            let transfer_key = (file_id.clone(), from_session_id.clone(), target_session_id.clone());
+            let details = {
+                let peers = room.peers.read().await;
+                transfers::TransferDetails {
+                    file_name: Some(file_name.clone()),
+                    size: Some(file_size),
+                    host_name: peers.get(&from_session_id).and_then(|peer| peer.name.clone()),
+                    requester_name: peers.get(&target_session_id).and_then(|peer| peer.name.clone()),
+                    chunk_size: Some(chunk_size),
+                }
+            };
+            let delivered = state.send_to(&target_session_id.clone(), ServerMessage::FileMetadata {
                 file_id,
                 file_name,
                 file_size,
                 mime_type,
                 total_chunks,
-                from_session_id: session_id.clone().unwrap_or_default(),
+                chunk_size,
+                from_session_id,
                 target_session_id,
-            });
+                compression,
+                relative_path,
+                encrypted,
+                nonce,
+            }).await;
+            if delivered {
+                state.relay.transfers.begin_transfer(&transfer_key, details);
+            }
         }
         ClientMessage::TextMessage {
-            session_id: sender_id,
+            session_id: _,
             content,
+            kind,
+            format,
+            attachments,
+            reply_to,
+            client_ref,
+            origin,
         } => {
-            let message = TextMessage {
-                id: format!("msg_{}_{}", sender_id, chrono::Utc::now().timestamp_millis()),
-                content,
-                sender_id: sender_id.clone(),
-                sender_name: None,
-                timestamp: chrono::Utc::now(),
+            // The sender is the connection, whatever session id the client put in the message
+            let sender_id = conn.session_id.clone().unwrap_or_default();
+            if kind == Some(MessageKind::System) {
+                let e = chat::ChatError::SystemKind;
+                let _ = conn.tx.send(ServerMessage::Error {
+                    code: e.code().to_string(),
+                    message: e.message(),
+                    limit: None,
+                    retry_after_ms: None,
+                });
+                return Ok(());
+            }
+            let format = format.unwrap_or_default();
+            let content = match chat::sanitize_message(&content, format, state.max_message_length) {
+                Ok(content) => content,
+                Err(e) => {
+                    let _ = conn.tx.send(ServerMessage::Error {
+                        code: e.code().to_string(),
+                        message: e.message(),
+                        limit: e.limit(),
+                        retry_after_ms: None,
+                    });
+                    return Ok(());
+                }
             };
-            {
-                let mut messages = state.messages.write().await;
-                messages.push(message.clone());
+            let attachments = match chat::check_attachments(attachments, &*room.files.read().await, &sender_id) {
+                Ok(attachments) => attachments,
+                Err(e) => {
+                    let _ = conn.tx.send(ServerMessage::Error {
+                        code: e.code().to_string(),
+                        message: e.message(),
+                        limit: e.limit(),
+                        retry_after_ms: None,
+                    });
+                    return Ok(());
+                }
+            };
+            let (sender_name, sender_color) = match room.peers.read().await.get(&sender_id) {
+                Some(peer) => (peer.name.clone(), Some(peer.color_hue)),
+                None => (None, None),
+            };
+            let (message, is_new) = {
+                // The history lock also serializes retries of the same client_ref
+                let mut messages = room.messages.write().await;
+                let mut client_refs = room.client_refs.lock().unwrap();
+                match client_ref.as_deref().and_then(|r| client_refs.get(&sender_id, r)) {
+                    Some(message) => (message, false),
+                    None => {
+                        if reply_to.as_ref().is_some_and(|id| !messages.iter().any(|m| &m.id == id)) {
+                            let e = chat::ChatError::UnknownReply;
+                            let _ = conn.tx.send(ServerMessage::Error {
+                                code: e.code().to_string(),
+                                message: e.message(),
+                                limit: None,
+                                retry_after_ms: None,
+                            });
+                            return Ok(());
+                        }
+                        let message = TextMessage {
+                            id: format!("msg_{}_{}", sender_id, chrono::Utc::now().timestamp_millis()),
+                            content,
+                            kind: MessageKind::User,
+                            format,
+                            attachments,
+                            reply_to,
+                            sender_id: sender_id.clone(),
+                            sender_name,
+                            sender_color,
+                            timestamp: chrono::Utc::now(),
+                            // Only the bridge's own peer may say a message came from elsewhere
+                            origin: origin.filter(|_| conn.local),
+                        };
+                        messages.push(message.clone());
+                        if let Some(attachments) = &message.attachments {
+                            room.add_attachments(&message.id, attachments);
+                        }
+                        if let Some(client_ref) = &client_ref {
+                            client_refs.insert(client_ref.clone(), &message);
+                        }
+                        (message, true)
+                    }
+                }
+            };
+
+            // A retry is acked again with the original message but not rebroadcast
+            if is_new {
+                conn.counters.messages_sent.fetch_add(1, Ordering::Relaxed);
+                room.send(ServerMessage::TextMessage { message: message.clone() });
+            }
+
+            let _ = conn.tx.send(ServerMessage::MessageAck {
+                client_ref,
+                message_id: message.id,
+                timestamp: message.timestamp,
+            });
+        }
+        ClientMessage::UpdateFileInfo {
+            session_id: _,
+            file_id,
+            description,
+            name,
+            visibility,
+        } => {
+            let name = match name {
+                Some(name) => match files::sanitize_file_name(&name) {
+                    Some(name) => Some(name),
+                    None => {
+                        let _ = conn.tx.send(ServerMessage::Error {
+                            code: "invalid_name".to_string(),
+                            message: "File name must not be empty".to_string(),
+                            limit: None,
+                            retry_after_ms: None,
+                        });
+                        return Ok(());
+                    }
+                },
+                None => None,
+            };
+
+            // The id stays the same so in-progress downloads are unaffected
+            let include_full_list = room.has_full_list_peers().await;
+            let mut files = room.files.write().await;
+            let suffix = renamed_suffix(&files, &file_id, name.as_deref());
+            let updated = match files.get_mut(&file_id) {
+                Some(entry) if Some(&entry.uploader_id) == conn.session_id.as_ref() => {
+                    let file = Arc::make_mut(entry);
+                    if let Some(name) = name {
+                        file.name = name;
+                    }
+                    if let Some(suffix) = suffix {
+                        file.display_suffix = suffix;
+                    }
+                    if let Some(description) = description {
+                        file.description = files::sanitize_description(&description);
+                    }
+                    if let Some(visibility) = visibility {
+                        file.visibility = visibility.map(files::sanitize_visibility);
+                    }
+                    entry.clone()
+                }
+                _ => {
+                    let _ = conn.tx.send(ServerMessage::Error {
+                        code: "not_uploader".to_string(),
+                        message: "Only the uploader can edit this file".to_string(),
+                        limit: None,
+                        retry_after_ms: None,
+                    });
+                    return Ok(());
+                }
+            };
+
+            let change = files::FileListChange {
+                updated: vec![updated],
+                ..Default::default()
+            };
+            files::publish_change(&room, &files, change, include_full_list);
+        }
+        ClientMessage::ReplaceFile {
+            session_id: _,
+            file_id,
+            new_metadata,
+        } => {
+            let name = match new_metadata.name.as_deref().map(files::sanitize_file_name) {
+                Some(None) => {
+                    let _ = conn.tx.send(ServerMessage::Error {
+                        code: "invalid_name".to_string(),
+                        message: "File name must not be empty".to_string(),
+                        limit: None,
+                        retry_after_ms: None,
+                    });
+                    return Ok(());
+                }
+                Some(name) => name,
+                None => None,
+            };
+            let uploader = conn.session_id.clone().unwrap_or_default();
+
+            let include_full_list = room.has_full_list_peers().await;
+            let mut files = room.files.write().await;
+            let suffix = renamed_suffix(&files, &file_id, name.as_deref());
+            let updated = match files.get_mut(&file_id) {
+                Some(entry) if entry.uploader_id == uploader => {
+                    let file = Arc::make_mut(entry);
+                    if let Some(name) = name {
+                        file.name = name;
+                    }
+                    if let Some(suffix) = suffix {
+                        file.display_suffix = suffix;
+                    }
+                    file.size = new_metadata.size;
+                    file.mime_type = files::sanitize_mime_type(&new_metadata.mime_type);
+                    file.category = FileCategory::detect(&file.mime_type, &file.name);
+                    file.version += 1;
+                    file.sha256 = None;
+                    // Earlier downloaders only have the old content
+                    file.hosts = HashSet::from([uploader.clone()]);
+                    file.unavailable = false;
+                    file.orphaned = None;
+                    entry.clone()
+                }
+                _ => {
+                    let _ = conn.tx.send(ServerMessage::Error {
+                        code: "not_uploader".to_string(),
+                        message: "Only the uploader can replace this file".to_string(),
+                        limit: None,
+                        retry_after_ms: None,
+                    });
+                    return Ok(());
+                }
+            };
+
+            tracing::info!(file_id, version = updated.version, size = updated.size, "File replaced");
+            // Private files only reach their audience through the file list
+            if updated.visibility.is_none() {
+                room.send(ServerMessage::FileUpdated { file: updated.clone() });
+            }
+            let change = files::FileListChange {
+                updated: vec![updated],
+                ..Default::default()
+            };
+            files::publish_change(&room, &files, change, include_full_list);
+            drop(files);
+
+            // Transfers of the old content would deliver stale bytes
+            for (file_id, from, to) in state.relay.transfers.cancel_transfers(&file_id, "file replaced") {
+                for (recipient, counterpart) in [(&from, &to), (&to, &from)] {
+                    state.send_to(recipient, ServerMessage::TransferAborted {
+                        file_id: file_id.clone(),
+                        peer_session_id: counterpart.clone(),
+                        reason: "file_replaced".to_string(),
+                    }).await;
+                }
+            }
+            start_queued_downloads(state).await;
+        }
+        ClientMessage::RemoveFile {
+            session_id: _,
+            file_id,
+            folder_id,
+            batch_id,
+        } => {
+            let uploader = conn.session_id.clone().unwrap_or_default();
+            let removed = match (file_id, folder_id, batch_id) {
+                (Some(file_id), None, None) => {
+                    files::remove_files(state, &room, |file| file.id == file_id && file.uploader_id == uploader).await
+                }
+                (None, Some(folder_id), None) => {
+                    files::remove_files(state, &room, |file| {
+                        file.folder_id.as_ref() == Some(&folder_id) && file.uploader_id == uploader
+                    })
+                    .await
+                }
+                (None, None, Some(batch_id)) => {
+                    files::remove_files(state, &room, |file| {
+                        file.batch_id.as_ref() == Some(&batch_id) && file.uploader_id == uploader
+                    })
+                    .await
+                }
+                _ => {
+                    let _ = conn.tx.send(ServerMessage::Error {
+                        code: "invalid_message".to_string(),
+                        message: "Give one of file_id, folder_id or batch_id".to_string(),
+                        limit: None,
+                        retry_after_ms: None,
+                    });
+                    return Ok(());
+                }
+            };
+            if removed == 0 {
+                let _ = conn.tx.send(ServerMessage::Error {
+                    code: "not_uploader".to_string(),
+                    message: "Only the uploader can remove these files".to_string(),
+                    limit: None,
+                    retry_after_ms: None,
+                });
+            } else {
+                start_queued_downloads(state).await;
+            }
+        }
+        ClientMessage::RestoreFile { session_id: _, file_id } => {
+            let uploader = conn.session_id.clone().unwrap_or_default();
+            let Some((file, hosts)) = state.trash.take(&room.id, &file_id, &uploader) else {
+                let _ = conn.tx.send(ServerMessage::Error {
+                    code: "not_found".to_string(),
+                    message: "None of your files with this id is in the trash".to_string(),
+                    limit: None,
+                    retry_after_ms: None,
+                });
+                return Ok(());
+            };
+            // Only hosts still in the room can serve it again
+            let connected = {
+                let peers = room.peers.read().await;
+                hosts.iter().filter(|host| peers.contains_key(*host)).cloned().collect::<HashSet<_>>()
+            };
+            if connected.is_empty() {
+                state.trash.put_back(&room.id, file, hosts);
+                let _ = conn.tx.send(ServerMessage::Error {
+                    code: "no_hosts".to_string(),
+                    message: "None of the peers that hosted this file are connected".to_string(),
+                    limit: None,
+                    retry_after_ms: None,
+                });
+                return Ok(());
+            }
+
+            let include_full_list = room.has_full_list_peers().await;
+            let mut files = room.files.write().await;
+            let restored = match files::restore_file(&mut files, &file, connected, &state.file_limits) {
+                Ok(restored) => restored,
+                Err(error) => {
+                    drop(files);
+                    state.trash.put_back(&room.id, file, hosts);
+                    let _ = conn.tx.send(ServerMessage::Error {
+                        code: error.code().to_string(),
+                        message: error.message(),
+                        limit: None,
+                        retry_after_ms: None,
+                    });
+                    return Ok(());
+                }
+            };
+            tracing::info!(file_id, name = restored.name, room = room.id, "File restored");
+            state.events.record(EventKind::FileAdded, EventSubject {
+                session_id: conn.session_id.clone(),
+                ..EventSubject::file(&room.id, &file_id, "restored")
+            });
+            let change = files::FileListChange {
+                added: vec![restored],
+                ..Default::default()
+            };
+            files::publish_change(&room, &files, change, include_full_list);
+            drop(files);
+            trash::notify(state, &uploader).await;
+        }
+        ClientMessage::Rename { session_id: _, new_name } => {
+            if let Some(at) = conn.last_rename.filter(|at| at.elapsed() < peers::RENAME_INTERVAL) {
+                let _ = conn.tx.send(ServerMessage::Error {
+                    code: "rate_limited".to_string(),
+                    message: "Wait a few seconds before renaming again".to_string(),
+                    limit: None,
+                    retry_after_ms: Some(peers::RENAME_INTERVAL.saturating_sub(at.elapsed()).as_millis() as u64),
+                });
+                return Ok(());
+            }
+            let name = match peers::sanitize_name(&new_name) {
+                Some(name) => name,
+                None => {
+                    let _ = conn.tx.send(ServerMessage::Error {
+                        code: "invalid_name".to_string(),
+                        message: "Name must not be empty".to_string(),
+                        limit: None,
+                        retry_after_ms: None,
+                    });
+                    return Ok(());
+                }
+            };
+
+            let session_id = conn.session_id.clone().unwrap_or_default();
+            let peer = {
+                let mut peers = room.peers.write().await;
+                let name = peers::unique_name(&peers, &session_id, name);
+                match peers.get_mut(&session_id) {
+                    Some(peer) => {
+                        peer.name = Some(name);
+                        peer.clone()
+                    }
+                    None => return Ok(()),
+                }
+            };
+
+            conn.last_rename = Some(Instant::now());
+            if let Some(name) = &peer.name {
+                tracing::Span::current().record("name", name.as_str());
+            }
+            room.send(ServerMessage::PeerUpdated { peer });
+        }
+        ClientMessage::RequestSync { session_id: _, last_seq: _ } => {
+            if let Some(at) = conn.last_sync.filter(|at| at.elapsed() < SYNC_INTERVAL) {
+                let _ = conn.tx.send(ServerMessage::Error {
+                    code: "rate_limited".to_string(),
+                    message: "Wait a few seconds before asking for another sync".to_string(),
+                    limit: None,
+                    retry_after_ms: Some(SYNC_INTERVAL.saturating_sub(at.elapsed()).as_millis() as u64),
+                });
+                return Ok(());
+            }
+            conn.last_sync = Some(Instant::now());
+
+            // Progress moves on regardless, so transfers need not be part of the locked snapshot
+            let transfers = live_transfers(state, &room).await;
+            // Broadcasts are not buffered, so a full snapshot is sent whatever was missed.
+            // Holding every lock at once (in the order peers, files, messages) freezes the
+            // room; the seq is read under them, so every broadcast up to it is reflected.
+            let peers = room.peers.read().await;
+            let registry = room.files.read().await;
+            let messages = room.messages.read().await;
+            let seq = room.seq();
+            let file_list_version = room.file_list_version();
+            let visible = files::visible_files(&registry, conn.session_id.as_deref()).cloned().collect::<Vec<_>>();
+            // A peer that fetches pages gets an empty list and a summary to page through
+            let (files, summary) = if conn.file_list_pages && state.file_list_page_size.is_some_and(|size| visible.len() > size) {
+                let summary = ServerMessage::FileListSummary {
+                    total: visible.len(),
+                    version: file_list_version,
+                    category_counts: files::category_counts(&visible),
+                };
+                (Vec::new(), Some(summary))
+            } else {
+                (visible, None)
+            };
+            let history = chat::history_page(&messages, None, None).unwrap_or_default();
+            let snapshot = ServerMessage::SyncState {
+                seq,
+                peers: peers.values().cloned().collect(),
+                files,
+                file_list_version,
+                messages: history.messages,
+                has_more: history.has_more,
+                transfers,
+            };
+            drop((peers, registry, messages));
+
+            let _ = conn.tx.send(snapshot);
+            if let Some(summary) = summary {
+                let _ = conn.tx.send(summary);
+            }
+        }
+        ClientMessage::Subscribe { session_id: _, topics } => {
+            let mut subscribed = HashSet::new();
+            for name in &topics {
+                match rooms::Topic::parse(name) {
+                    Some(topic) => {
+                        subscribed.insert(topic);
+                    }
+                    None => {
+                        let _ = conn.tx.send(ServerMessage::Error {
+                            code: "unknown_topic".to_string(),
+                            message: format!("Unknown topic {name}; expected chat, files, peers or transfers"),
+                            limit: None,
+                            retry_after_ms: None,
+                        });
+                        return Ok(());
+                    }
+                }
+            }
+            // Whatever happened in a newly added topic meanwhile was never delivered
+            for topic in conn.topics.set(&subscribed) {
+                send_snapshot(state, conn, &room, topic).await;
+            }
+        }
+        ClientMessage::RequestFileList {
+            session_id: _,
+            offset,
+            limit,
+            sort,
+        } => {
+            let viewer = conn.session_id.clone().unwrap_or_default();
+            if offset.is_none() && limit.is_none() {
+                let _ = conn.tx.send(files::file_list(&room, &viewer, state.file_list_page_size, conn.file_list_pages).await);
+                return Ok(());
+            }
+
+            let offset = offset.unwrap_or(0);
+            let sort = sort.unwrap_or_default();
+            let limit = files::page_limit(limit, state.file_list_page_size);
+            let files = room.files.read().await;
+            let _ = conn.tx.send(ServerMessage::FileListPage {
+                files: files::file_page(&files, Some(&viewer), None, sort, offset, limit),
+                offset,
+                total: files::visible_files(&files, Some(&viewer)).count(),
+                sort,
+                version: room.file_list_version(),
+            });
+        }
+        ClientMessage::ShareSnippet {
+            session_id: _,
+            title,
+            content,
+        } => {
+            let creator_id = conn.session_id.clone().unwrap_or_default();
+            if let Err(e) = snippets::create_snippet(&room, state.max_snippets, creator_id, title, content).await {
+                let _ = conn.tx.send(ServerMessage::Error {
+                    code: e.code().to_string(),
+                    message: e.message(),
+                    limit: None,
+                    retry_after_ms: None,
+                });
+            }
+        }
+        ClientMessage::DeleteSnippet {
+            session_id: _,
+            snippet_id,
+        } => {
+            let deleted = {
+                let mut snippets = room.snippets.write().await;
+                match snippets.get(&snippet_id) {
+                    Some(snippet) if Some(&snippet.creator_id) == conn.session_id.as_ref() => {
+                        snippets.remove(&snippet_id);
+                        true
+                    }
+                    _ => false,
+                }
+            };
+
+            if deleted {
+                snippets::broadcast_snippet_list(&room).await;
+            } else {
+                let _ = conn.tx.send(ServerMessage::Error {
+                    code: "not_creator".to_string(),
+                    message: "Only the creator can delete this snippet".to_string(),
+                    limit: None,
+                    retry_after_ms: None,
+                });
+            }
+        }
+        ClientMessage::SearchMessages { session_id: _, query } => {
+            let query = query.trim().to_string();
+            if query.is_empty() {
+                let _ = conn.tx.send(ServerMessage::Error {
+                    code: "empty_query".to_string(),
+                    message: "Search query must not be empty".to_string(),
+                    limit: None,
+                    retry_after_ms: None,
+                });
+                return Ok(());
+            }
+
+            let messages = {
+                let messages = room.messages.read().await;
+                chat::search_messages(&messages, &query, None, None, true)
+            };
+
+            let _ = conn.tx.send(ServerMessage::SearchResults { query, messages });
+        }
+        ClientMessage::KeyExchange {
+            session_id: _,
+            target_session_id,
+            payload,
+        } => {
+            let from_session_id = conn.session_id.clone().unwrap_or_default();
+            let supported = {
+                let peers = room.peers.read().await;
+                peers.get(&target_session_id).is_some_and(|peer| peer.capabilities.contains(E2E_CAPABILITY))
+            };
+            if !conn.e2e || !supported {
+                let _ = conn.tx.send(ServerMessage::Error {
+                    code: "capability_mismatch".to_string(),
+                    message: format!("Both peers must join with the e2e capability; {target_session_id} did not"),
+                    limit: None,
+                    retry_after_ms: None,
+                });
+                return Ok(());
+            }
+            state.send_to(&target_session_id, ServerMessage::KeyExchange { from_session_id, payload }).await;
+        }
+        ClientMessage::RtcSignal {
+            session_id: _,
+            target_session_id,
+            kind,
+            file_id,
+            payload,
+        } => {
+            // Signaling stays within the room; the browser checks the payload itself
+            if !room.peers.read().await.contains_key(&target_session_id) {
+                let _ = conn.tx.send(ServerMessage::Error {
+                    code: "not_found".to_string(),
+                    message: format!("Peer {target_session_id} is not in this room"),
+                    limit: None,
+                    retry_after_ms: None,
+                });
+                return Ok(());
+            }
+            let from_session_id = conn.session_id.clone().unwrap_or_default();
+            // Peers whose direct connection failed recently go straight to the relay
+            if state.relay.is_relay_only(&from_session_id, &target_session_id) {
+                match (kind, file_id) {
+                    (RtcSignalKind::Offer, Some(file_id)) => {
+                        let relayed = fall_back_to_relay(state, &room, &file_id, &from_session_id, &target_session_id).await;
+                        if !relayed {
+                            let _ = conn.tx.send(fallback_failed(&file_id));
+                        }
+                    }
+                    (RtcSignalKind::Offer, None) => {
+                        let _ = conn.tx.send(ServerMessage::Error {
+                            code: "relay_only".to_string(),
+                            message: format!("A direct connection to {target_session_id} failed recently; use the relay"),
+                            limit: None,
+                            retry_after_ms: None,
+                        });
+                    }
+                    // Answers and candidates of the skipped attempt are dropped
+                    _ => {}
+                }
+                return Ok(());
+            }
+            state.send_to(&target_session_id, ServerMessage::RtcSignal {
+                from_session_id,
+                kind,
+                file_id,
+                payload,
+            }).await;
+        }
+        ClientMessage::P2PFailed {
+            session_id: _,
+            peer_session_id,
+            file_id,
+            reason,
+        } => {
+            let from_session_id = conn.session_id.clone().unwrap_or_default();
+            let reason = reason.as_deref().map(clean_reason).unwrap_or_default();
+            tracing::warn!(file_id, from = from_session_id, peer = peer_session_id, reason, "Direct connection failed");
+            if !room.peers.read().await.contains_key(&peer_session_id) {
+                let _ = conn.tx.send(ServerMessage::Error {
+                    code: "not_found".to_string(),
+                    message: format!("Peer {peer_session_id} is not in this room"),
+                    limit: None,
+                    retry_after_ms: None,
+                });
+                return Ok(());
+            }
+            state.relay.mark_relay_only(&from_session_id, &peer_session_id);
+            if !fall_back_to_relay(state, &room, &file_id, &from_session_id, &peer_session_id).await {
+                let _ = conn.tx.send(fallback_failed(&file_id));
+            }
+        }
+        ClientMessage::SetTransferRate {
+            session_id: _,
+            file_id,
+            peer_session_id,
+            max_bytes_per_sec,
+        } => {
+            let requester = conn.session_id.clone().unwrap_or_default();
+            let key = (file_id.clone(), peer_session_id.clone(), requester.clone());
+            if !state.relay.transfers.set_requested_rate(&key, max_bytes_per_sec) {
+                let _ = conn.tx.send(ServerMessage::Error {
+                    code: "not_found".to_string(),
+                    message: format!("No relayed download of {file_id} from {peer_session_id} is running"),
+                    limit: None,
+                    retry_after_ms: None,
+                });
+                return Ok(());
+            }
+            tracing::debug!(file_id, from = peer_session_id, to = requester, max_bytes_per_sec, "Transfer rate set");
+            state
+                .send_to(&peer_session_id, ServerMessage::TransferRateHint {
+                    file_id,
+                    requester_session_id: requester,
+                    max_bytes_per_sec: (max_bytes_per_sec > 0).then_some(max_bytes_per_sec),
+                })
+                .await;
+        }
+        ClientMessage::PeerPing {
+            session_id: _,
+            target_session_id,
+            nonce,
+            sent_at,
+        } => {
+            let from_session_id = conn.session_id.clone().unwrap_or_default();
+            if !room.peers.read().await.contains_key(&target_session_id) {
+                let _ = conn.tx.send(ServerMessage::Error {
+                    code: "not_found".to_string(),
+                    message: format!("Peer {target_session_id} is not in this room"),
+                    limit: None,
+                    retry_after_ms: None,
+                });
+                return Ok(());
+            }
+            if !state.latency.start_ping(&from_session_id, &target_session_id, nonce) {
+                let _ = conn.tx.send(ServerMessage::Error {
+                    code: "rate_limited".to_string(),
+                    message: format!("Ping {target_session_id} at most a few times per second"),
+                    limit: None,
+                    retry_after_ms: None,
+                });
+                return Ok(());
+            }
+            state.send_to(&target_session_id, ServerMessage::PeerPing {
+                from_session_id,
+                nonce,
+                sent_at,
+                relayed_at: chrono::Utc::now(),
+            }).await;
+        }
+        ClientMessage::PeerPong {
+            session_id: _,
+            target_session_id,
+            nonce,
+            sent_at,
+            ping_relayed_at,
+        } => {
+            let from_session_id = conn.session_id.clone().unwrap_or_default();
+            let Some(rtt) = state.latency.finish_ping(&target_session_id, &from_session_id, nonce) else {
+                let _ = conn.tx.send(ServerMessage::Error {
+                    code: "unknown_request".to_string(),
+                    message: format!("No ping {nonce} from {target_session_id} is awaiting a pong"),
+                    limit: None,
+                    retry_after_ms: None,
+                });
+                return Ok(());
+            };
+            tracing::debug!(from = target_session_id, to = from_session_id, rtt_ms = rtt.as_millis() as u64, "Peer ping answered");
+            let delivered = state.send_to(&target_session_id, ServerMessage::PeerPong {
+                from_session_id,
+                nonce,
+                sent_at,
+                ping_relayed_at,
+                pong_relayed_at: chrono::Utc::now(),
+            }).await;
+            if !delivered {
+                let _ = conn.tx.send(ServerMessage::Error {
+                    code: "not_found".to_string(),
+                    message: format!("Peer {target_session_id} is no longer connected"),
+                    limit: None,
+                    retry_after_ms: None,
+                });
+            }
+        }
+        ClientMessage::RequestHistory {
+            session_id: _,
+            before_message_id,
+            limit,
+        } => {
+            let page = chat::history_page(&room.messages.read().await, before_message_id.as_deref(), limit);
+            let _ = conn.tx.send(match page {
+                Some(page) => ServerMessage::HistoryPage {
+                    messages: page.messages,
+                    has_more: page.has_more,
+                },
+                None => ServerMessage::Error {
+                    code: "unknown_message".to_string(),
+                    message: "before_message_id is not in the room's history".to_string(),
+                    limit: None,
+                    retry_after_ms: None,
+                },
+            });
+        }
+        ClientMessage::ApproveDownload {
+            session_id: _,
+            request_id,
+            approve,
+        } => {
+            let host_id = conn.session_id.clone().unwrap_or_default();
+            let Some(pending) = state.relay.transfers.resolve_approval(&request_id, &host_id, approve) else {
+                let _ = conn.tx.send(ServerMessage::Error {
+                    code: "unknown_request".to_string(),
+                    message: "No such download request; it may have expired".to_string(),
+                    limit: None,
+                    retry_after_ms: None,
+                });
+                return Ok(());
+            };
+
+            let (file_id, _, requester_id) = pending.key.clone();
+            let reply = if approve {
+                match admit_download(state, &room, pending).await {
+                    Admitted::Routed(relay_id) => ServerMessage::DownloadStarting {
+                        file_id,
+                        host_session_id: host_id,
+                        relay_id,
+                    },
+                    Admitted::Held(reply) => *reply,
+                }
+            } else {
+                ServerMessage::DownloadDenied {
+                    file_id,
+                    host_session_id: host_id,
+                    reason: "declined".to_string(),
+                    message: None,
+                }
+            };
+            state.send_to(&requester_id, reply).await;
+        }
+        ClientMessage::DenyDownload {
+            session_id: _,
+            file_id,
+            requester_session_id,
+            reason,
+        } => {
+            let host_id = conn.session_id.clone().unwrap_or_default();
+            let key = (file_id.clone(), host_id.clone(), requester_session_id.clone());
+            if state.relay.transfers.deny(&key).is_none() {
+                let _ = conn.tx.send(ServerMessage::Error {
+                    code: "unknown_request".to_string(),
+                    message: "No unanswered download request for this file and requester".to_string(),
+                    limit: None,
+                    retry_after_ms: None,
+                });
+                return Ok(());
+            }
+
+            tracing::info!(file_id, to = requester_session_id, "Download refused by host");
+            let message = reason.as_deref().map(clean_reason).filter(|reason| !reason.is_empty());
+            state.send_to(&requester_session_id, ServerMessage::DownloadDenied {
+                file_id,
+                host_session_id: host_id,
+                reason: "declined".to_string(),
+                message,
+            }).await;
+            start_queued_downloads(state).await;
+        }
+    }
+    Ok(())
+}
+
+/// Answers a `RequestDownload` for one file: checks the connection may have it and asks
+/// the least busy of its hosts to send it there.
+async fn request_file(
+    state: &AppState,
+    conn: &Connection,
+    room: &RoomState,
+    file_id: String,
+    password: Option<String>,
+) {
+    // The file goes to the connection, whatever session id the client put in the message
+    let requester_id = conn.session_id.clone().unwrap_or_default();
+    // Find a host for this file
+    let (file_hosts, password_hash) = {
+        let files = room.files.read().await;
+        // A private file looks missing to peers outside its audience
+        match files.get(&file_id).filter(|file| file.is_visible_to(conn.session_id.as_deref())) {
+            None => {
+                let _ = conn.tx.send(ServerMessage::Error {
+                    code: "not_found".to_string(),
+                    message: "File not found".to_string(),
+                    limit: None,
+                    retry_after_ms: None,
+                });
+                return;
+            }
+            Some(file) if file.unavailable => {
+                let _ = conn.tx.send(ServerMessage::Error {
+                    code: "temporarily_unavailable".to_string(),
+                    message: "The file's host disconnected; it may be back shortly".to_string(),
+                    limit: None,
+                    retry_after_ms: None,
+                });
+                return;
+            }
+            Some(file) if file.e2e && !conn.e2e => {
+                let _ = conn.tx.send(ServerMessage::Error {
+                    code: "capability_mismatch".to_string(),
+                    message: "This file is end-to-end encrypted; join with the e2e capability to download it".to_string(),
+                    limit: None,
+                    retry_after_ms: None,
+                });
+                return;
+            }
+            Some(file) => (file.hosts.clone(), file.password_hash.clone()),
+        }
+    };
+
+    // The password is checked here, so hosts serve locked files like any other
+    if let Some(hash) = password_hash {
+        let Some(password) = password else {
+            let _ = conn.tx.send(ServerMessage::Error {
+                code: "password_required".to_string(),
+                message: "This file needs a password".to_string(),
+                limit: None,
+                retry_after_ms: None,
+            });
+            return;
+        };
+        if !files::check_password(hash, password).await {
+            tracing::info!(file_id, "Wrong file password");
+            state.events.record(EventKind::AuthFailed, EventSubject {
+                session_id: conn.session_id.clone(),
+                ip: conn.ip,
+                file_id: Some(file_id.clone()),
+                detail: Some("file password".to_string()),
+                ..Default::default()
+            });
+            tokio::time::sleep(files::PASSWORD_RETRY_DELAY).await;
+            let _ = conn.tx.send(ServerMessage::Error {
+                code: "wrong_password".to_string(),
+                message: "Wrong password for this file".to_string(),
+                limit: None,
+                retry_after_ms: None,
+            });
+            return;
+        }
+    }
+
+    let chunk_size = {
+        let peers = room.peers.read().await;
+        peers.get(&requester_id)
+            .map(|peer| peer.chunk_size)
+            .unwrap_or(DEFAULT_CHUNK_SIZE)
+    };
+
+    // Pick the least busy host; a host already serving its limit queues the request
+    let rtt = |host: &SessionId| state.latency.rtt(host, &requester_id);
+    if let Some(host_id) = state.relay.transfers.least_busy_host(&file_hosts, rtt) {
+        let pending = transfers::PendingDownload {
+            key: (file_id, host_id, requester_id),
+            room: room.id.clone(),
+            chunk_size,
+            tried_hosts: HashSet::new(),
+            http_relay: false,
+        };
+        if let Some(reply) = request_download(state, room, pending).await {
+            let _ = conn.tx.send(reply);
+        }
+    } else {
+        let _ = conn.tx.send(ServerMessage::Error {
+            code: "no_hosts".to_string(),
+            message: "No hosts available for this file".to_string(),
+            limit: None,
+            retry_after_ms: None,
+        });
+    }
+}
+
+/// The batch a single `FileUpload` joins, see `files::upload_batch`.
+fn single_upload_batch(state: &AppState, conn: &mut Connection) -> Option<String> {
+    files::upload_batch(&mut conn.upload_batch, state.batch_window, Instant::now())
+}
+
+/// Adds announced files to the room's registry under one write lock and publishes a
+/// single change for all of them, so a batch reaches peers as one list update. Returns
+/// the ids of the files accepted and the reasons the others were not.
+async fn announce_files(
+    state: &AppState,
+    conn: &Connection,
+    room: &RoomState,
+    announced: Vec<FileMetadata>,
+    batch_id: Option<String>,
+) -> (Vec<String>, Vec<RejectedFile>) {
+    // Hashing passwords is slow, so files are prepared on the blocking pool before the
+    // lock is taken
+    let uploader = conn.session_id.clone().unwrap_or_default();
+    let (default_ttl, e2e, local) = (state.default_file_ttl, conn.e2e, conn.local);
+    let prepare = {
+        let (uploader, batch_id) = (uploader.clone(), batch_id.clone());
+        move || {
+            announced
+                .into_iter()
+                .map(|file| {
+                    let file_id = file.id.clone();
+                    let prepared = files::prepare_upload(file, &uploader, default_ttl, e2e, local);
+                    (file_id, prepared.map(|file| FileMetadata { batch_id: batch_id.clone(), ..file }))
+                })
+                .collect::<Vec<_>>()
+        }
+    };
+    let prepared = match tokio::task::spawn_blocking(prepare).await {
+        Ok(prepared) => prepared,
+        Err(e) => {
+            tracing::error!(error = %e, "Could not prepare announced files");
+            Vec::new()
+        }
+    };
+
+    let include_full_list = room.has_full_list_peers().await;
+    let mut files = room.files.write().await;
+    let mut accepted = Vec::new();
+    let mut rejected = Vec::new();
+    let mut change = files::FileListChange::default();
+    let mut evicted_names = Vec::new();
+
+    for (file_id, file) in prepared {
+        let upload = file.and_then(|file| files::insert_upload(&mut files, file, &state.file_limits));
+        let upload = match upload {
+            Ok(upload) => upload,
+            Err(error) => {
+                rejected.push(RejectedFile {
+                    file_id,
+                    code: error.code().to_string(),
+                    message: error.message(),
+                });
+                continue;
+            }
+        };
+
+        for evicted in upload.evicted {
+            tracing::info!(file_id = evicted.id, reason = "evicted", "File removed");
+            state.events.record(EventKind::FileRemoved, EventSubject::file(&room.id, &evicted.id, "evicted"));
+            state.relay.transfers.forget_file(&evicted.id);
+            // A file evicted for a later one in the same batch was never published
+            if let Some(index) = change.added.iter().position(|added| added.id == evicted.id) {
+                change.added.remove(index);
+                accepted.retain(|file_id| *file_id != evicted.id);
+                let error = files::UploadError::RegistryFull;
+                rejected.push(RejectedFile {
+                    file_id: evicted.id.clone(),
+                    code: error.code().to_string(),
+                    message: error.message(),
+                });
+                continue;
+            }
+            change.updated.retain(|updated| updated.id != evicted.id);
+            change.removed.push(evicted.id.clone());
+            // Peers outside a private file's audience never heard of it
+            if evicted.visibility.is_none() {
+                room.send(ServerMessage::FileRemoved { file_id: evicted.id.clone() });
+                evicted_names.push(evicted.name.clone());
+            }
+        }
+
+        let file = upload.file;
+        if let Some(requested_id) = upload.requested_id {
+            tracing::info!(requested_id, assigned_id = file.id, "File id taken by another peer, assigned a new one");
+            let _ = conn.tx.send(ServerMessage::FileIdReassigned { requested_id, assigned_id: file.id.clone() });
+        }
+        conn.counters.files_uploaded.fetch_add(1, Ordering::Relaxed);
+        tracing::info!(file_id = file.id, name = file.name, size = file.size, room = room.id, "File announced");
+        state.events.record(EventKind::FileAdded, EventSubject {
+            session_id: conn.session_id.clone(),
+            ..EventSubject::file(&room.id, &file.id, "announced")
+        });
+        if !accepted.contains(&file.id) {
+            accepted.push(file.id.clone());
+        }
+        if let Some(added) = change.added.iter_mut().find(|added| added.id == file.id) {
+            *added = file;
+        } else if upload.replaced {
+            change.updated.retain(|updated| updated.id != file.id);
+            change.updated.push(file);
+        } else {
+            change.removed.retain(|file_id| *file_id != file.id);
+            change.added.push(file);
+        }
+    }
+
+    let shared = change
+        .added
+        .iter()
+        .filter(|file| file.visibility.is_none())
+        .map(|file| file.name.clone())
+        .collect::<Vec<_>>();
+    files::publish_change(room, &files, change, include_full_list);
+    if let Some(batch_id) = batch_id.filter(|_| !accepted.is_empty()) {
+        let (file_count, total_size) = files::batch_totals(&files, &batch_id);
+        if file_count >= 2 {
+            room.send(ServerMessage::BatchAnnounced {
+                batch_id,
+                uploader: uploader.clone(),
+                file_count,
+                total_size,
+            });
+        }
+    }
+    drop(files);
+
+    if !evicted_names.is_empty() {
+        start_queued_downloads(state).await;
+    }
+    chat::post_files_removed(state, room, &evicted_names).await;
+    if !shared.is_empty() {
+        let sharer = room.peers.read().await.get(&uploader).map(chat::display_name);
+        let sharer = sharer.unwrap_or_else(|| "Someone".to_string());
+        let content = match shared.as_slice() {
+            [name] => format!("{sharer} shared {name}"),
+            names => format!("{sharer} shared {} files", names.len()),
+        };
+        chat::post_system_message(state, room, content).await;
+    }
+    (accepted, rejected)
+}
+
+/// Sends a targeted error and returns true when the compression identifier is unknown
+/// or the target has not declared it can decode it, so it never receives data it can't read.
+async fn reject_unsupported_compression(
+    state: &AppState,
+    conn: &Connection,
+    target_session_id: &SessionId,
+    compression: Option<&str>,
+) -> bool {
+    let Some(compression) = compression else {
+        return false;
+    };
+    let error = if !is_supported_compression(compression) {
+        ServerMessage::Error {
+            code: "unsupported_compression".to_string(),
+            message: format!("Unsupported compression: {compression}"),
+            limit: None,
+            retry_after_ms: None,
+        }
+    } else if !peers::peer_supports(state, target_session_id, &compression_capability(compression)).await {
+        ServerMessage::Error {
+            code: "capability_mismatch".to_string(),
+            message: format!("{target_session_id} cannot decode {compression}; send uncompressed data"),
+            limit: None,
+            retry_after_ms: None,
+        }
+    } else {
+        return false;
+    };
+    let _ = conn.tx.send(error);
+    true
+}
+
+/// Sends a targeted error and returns true when encrypted data is addressed to a peer
+/// without the e2e capability, or plain data belongs to an end-to-end encrypted file.
+async fn reject_encryption_mismatch(
+    state: &AppState,
+    conn: &Connection,
+    room: &RoomState,
+    file_id: &str,
+    target_session_id: &SessionId,
+    encrypted: bool,
+) -> bool {
+    let error = if encrypted && !peers::peer_supports(state, target_session_id, E2E_CAPABILITY).await {
+        ServerMessage::Error {
+            code: "capability_mismatch".to_string(),
+            message: format!("{target_session_id} cannot decrypt end-to-end encrypted data"),
+            limit: None,
+            retry_after_ms: None,
+        }
+    } else if !encrypted && room.files.read().await.get(file_id).is_some_and(|file| file.e2e) {
+        ServerMessage::Error {
+            code: "encryption_required".to_string(),
+            message: format!("{file_id} is end-to-end encrypted; its data must be sent encrypted"),
+            limit: None,
+            retry_after_ms: None,
+        }
+    } else {
+        return false;
+    };
+    let _ = conn.tx.send(error);
+    true
+}
+
+/// Sends `Kicked` to a session, which closes its socket with `code`, and removes it as
+/// a peer. Returns false if the session is not connected.
+pub async fn kick_session(state: &AppState, session_id: &SessionId, code: u16, reason: &str) -> bool {
+    let handle = {
+        let sessions = state.sessions.read().await;
+        sessions.get(session_id).cloned()
+    };
+
+    match handle {
+        Some(handle) => {
+            let _ = handle.tx.send(ServerMessage::Kicked {
+                reason: reason.to_string(),
+                code,
+            });
+            if let Some(room) = state.room(&handle.room).await {
+                cleanup_peer(state, &room, session_id).await;
             }
-            
-            let _ = state.tx.send(ServerMessage::TextMessage { message });
+            true
         }
+        None => false,
     }
-    Ok(())
 }
 
-async fn cleanup_peer(state: &AppState, session_id: &SessionId) {
-    // Remove peer from peers map
-    let peers_count = {
-        let mut peers = state.peers.write().await;
-        peers.remove(session_id);
-        peers.len()
+/// Closes every connection with `code`, without cleaning up, as the server is going away.
+pub async fn disconnect_all(state: &AppState, code: u16, reason: &str) {
+    let sessions = state.sessions.read().await;
+    for handle in sessions.values() {
+        let _ = handle.tx.send(ServerMessage::Kicked {
+            reason: reason.to_string(),
+            code,
+        });
+    }
+}
+
+/// Queues a chunk for its target, waiting while the target's chunk queue is full; this
+/// holds up the host's connection, and with it the host. A host kept waiting is asked
+/// to slow down, and a target whose queue does not drain in time is disconnected.
+/// Returns whether the chunk was queued.
+async fn forward_chunk(state: &AppState, conn: &Connection, chunk: ServerMessage) -> bool {
+    let ServerMessage::FileChunk { file_id, target_session_id, .. } = &chunk else {
+        return false;
+    };
+    let (file_id, target) = (file_id.clone(), target_session_id.clone());
+    let chunks = match state.sessions.read().await.get(&target) {
+        Some(handle) => handle.chunks.clone(),
+        None => return false,
+    };
+
+    let permit = match tokio::time::timeout(SLOW_DOWN_AFTER, chunks.reserve()).await {
+        Ok(permit) => permit,
+        Err(_) => {
+            tracing::debug!(file_id, to = target, "Receiver is slow, asking host to slow down");
+            let _ = conn.tx.send(ServerMessage::SlowDown { file_id });
+            match tokio::time::timeout(CHUNK_QUEUE_TIMEOUT - SLOW_DOWN_AFTER, chunks.reserve()).await {
+                Ok(permit) => permit,
+                Err(_) => {
+                    tracing::warn!(to = target, "Chunk queue stayed full, disconnecting receiver");
+                    kick_session(state, &target, CLOSE_KICKED, "Connection too slow to receive files").await;
+                    return false;
+                }
+            }
+        }
     };
 
-    // Remove peer from file hosts and clean up files with no hosts
-    let files_to_remove = {
-        let mut files = state.files.write().await;
-        let mut to_remove = Vec::new();
-        
-        for (file_id, file) in files.iter_mut() {
-            file.hosts.remove(session_id);
-            if file.hosts.is_empty() {
-                to_remove.push(file_id.clone());
-            }
-        }
-        
-        for file_id in &to_remove {
-            files.remove(file_id);
-        }
-        
-        to_remove
+    match permit {
+        Ok(permit) => {
+            permit.send(chunk);
+            true
+        }
+        Err(_) => false,
+    }
+}
+
+/// Ends the transfers of a session that went away, telling the other side of each to
+/// give up on it, and lets queued downloads take the freed slots.
+pub async fn end_transfers(state: &AppState, session_id: &SessionId) {
+    for (file_id, from, to) in state.relay.transfers.on_peer_disconnected(session_id) {
+        let counterpart = if &from == session_id { to } else { from };
+        state.send_to(&counterpart, ServerMessage::TransferAborted {
+            file_id,
+            peer_session_id: session_id.clone(),
+            reason: "peer_disconnected".to_string(),
+        }).await;
+    }
+    start_queued_downloads(state).await;
+}
+
+/// Admits a download, or holds it until the host approves it when the host does not
+/// auto-accept downloads. Returns what to tell the requester, if anything.
+pub async fn request_download(state: &AppState, room: &RoomState, mut pending: transfers::PendingDownload) -> Option<ServerMessage> {
+    let (file_id, host_id, requester_id) = pending.key.clone();
+    let (auto_accept, host_name, requester_name) = {
+        let peers = room.peers.read().await;
+        let host = peers.get(&host_id);
+        let relays = |session_id: &SessionId| {
+            peers.get(session_id).is_some_and(|peer| peer.capabilities.contains(HTTP_RELAY_CAPABILITY))
+        };
+        pending.http_relay = relays(&host_id) && relays(&requester_id);
+        (
+            host.is_none_or(|host| host.auto_accept_downloads),
+            host.and_then(|host| host.name.clone()),
+            peers.get(&requester_id).and_then(|peer| peer.name.clone()),
+        )
+    };
+    // End-to-end encrypted chunks carry a nonce each, which a raw stream has no room for
+    if room.files.read().await.get(&file_id).is_some_and(|file| file.e2e) {
+        pending.http_relay = false;
+    }
+    if auto_accept {
+        return match admit_download(state, room, pending).await {
+            Admitted::Routed(_) => None,
+            Admitted::Held(reply) => Some(*reply),
+        };
+    }
+
+    let (file_name, size) = match room.files.read().await.get(&file_id) {
+        Some(file) => (Some(file.name.clone()), Some(file.size)),
+        None => (None, None),
+    };
+    let details = transfers::TransferDetails {
+        file_name,
+        size,
+        host_name,
+        requester_name: requester_name.clone(),
+        chunk_size: Some(pending.chunk_size),
+    };
+    let request_id = state.relay.transfers.request_approval(pending, details);
+    tracing::info!(file_id, host = host_id, to = requester_id, request_id, "Download awaiting approval");
+    state.send_to(&host_id, ServerMessage::DownloadApprovalRequest {
+        request_id: request_id.clone(),
+        file_id: file_id.clone(),
+        requester_session_id: requester_id,
+        requester_name,
+    }).await;
+    Some(ServerMessage::DownloadPending {
+        request_id,
+        file_id,
+        host_session_id: host_id,
+    })
+}
+
+/// What `admit_download` did with a download.
+enum Admitted {
+    /// Routed to its host, with the relay pipe opened for it if any
+    Routed(Option<String>),
+    /// Queued or refused, with what to tell the requester
+    Held(Box<ServerMessage>),
+}
+
+/// Reserves a slot for the download and routes it to its host, or queues it behind the
+/// host's other downloads.
+async fn admit_download(state: &AppState, room: &RoomState, pending: transfers::PendingDownload) -> Admitted {
+    match state.relay.transfers.request_download(pending.clone()) {
+        transfers::Admission::Start => Admitted::Routed(route_download(state, room, pending).await),
+        transfers::Admission::Queued(position) => {
+            let (file_id, host_id, _) = pending.key;
+            tracing::debug!(file_id, host = host_id, position, "Download queued");
+            Admitted::Held(Box::new(ServerMessage::DownloadQueued { file_id, position }))
+        }
+        transfers::Admission::Rejected => Admitted::Held(Box::new(ServerMessage::Error {
+            code: "too_many_transfers".to_string(),
+            message: "Too many transfers are running; try again later".to_string(),
+            limit: None,
+            retry_after_ms: None,
+        })),
+    }
+}
+
+/// Tells both sides of the download requests their hosts did not answer in time.
+pub async fn expire_approvals(state: &AppState) {
+    for pending in state.relay.transfers.expire_approvals() {
+        let (file_id, host_id, requester_id) = pending.key;
+        state.send_to(&requester_id, ServerMessage::DownloadDenied {
+            file_id: file_id.clone(),
+            host_session_id: host_id.clone(),
+            reason: "approval_expired".to_string(),
+            message: None,
+        }).await;
+        state.send_to(&host_id, ServerMessage::TransferAborted {
+            file_id,
+            peer_session_id: requester_id,
+            reason: "approval_expired".to_string(),
+        }).await;
+    }
+}
+
+/// Tells requesters whose host did not answer in time, and retries each download with
+/// another host of the file that has not been tried yet.
+pub async fn expire_download_requests(state: &AppState) {
+    let expired = state.relay.transfers.expire_requests();
+    if expired.is_empty() {
+        return;
+    }
+
+    for pending in expired {
+        let (file_id, host_id, requester_id) = pending.key;
+        tracing::info!(file_id, host = host_id, to = requester_id, "Download request unanswered");
+        state.send_to(&requester_id, ServerMessage::DownloadTimeout {
+            file_id: file_id.clone(),
+            host_session_id: host_id.clone(),
+        }).await;
+        // A host that wakes up later should not start sending
+        state.send_to(&host_id, ServerMessage::TransferAborted {
+            file_id: file_id.clone(),
+            peer_session_id: requester_id.clone(),
+            reason: "request_timeout".to_string(),
+        }).await;
+
+        let Some(room) = state.room(&pending.room).await else {
+            continue;
+        };
+        let mut tried_hosts = pending.tried_hosts;
+        tried_hosts.insert(host_id);
+        let hosts = match room.files.read().await.get(&file_id) {
+            Some(file) => file.hosts.difference(&tried_hosts).cloned().collect(),
+            None => HashSet::new(),
+        };
+        let rtt = |host: &SessionId| state.latency.rtt(host, &requester_id);
+        let reply = match state.relay.transfers.least_busy_host(&hosts, rtt) {
+            Some(host_id) => {
+                let retry = transfers::PendingDownload {
+                    key: (file_id, host_id, requester_id.clone()),
+                    room: room.id.clone(),
+                    chunk_size: pending.chunk_size,
+                    tried_hosts,
+                    http_relay: false,
+                };
+                request_download(state, &room, retry).await
+            }
+            None => Some(ServerMessage::Error {
+                code: "download_failed".to_string(),
+                message: "No host answered the download request".to_string(),
+                limit: None,
+                retry_after_ms: None,
+            }),
+        };
+        if let Some(reply) = reply {
+            state.send_to(&requester_id, reply).await;
+        }
+    }
+    start_queued_downloads(state).await;
+}
+
+/// Relayed transfers hosted in the room, limited to files everyone there can see.
+pub async fn live_transfers(state: &AppState, room: &RoomState) -> Vec<LiveTransfer> {
+    let transfers = state.relay.transfers.live_transfers();
+    if transfers.is_empty() {
+        return transfers;
+    }
+
+    let peers = room.peers.read().await;
+    let files = room.files.read().await;
+    transfers
+        .into_iter()
+        .filter(|transfer| peers.contains_key(&transfer.host_session_id))
+        .filter(|transfer| files.get(&transfer.file_id).is_some_and(|file| file.is_visible_to(None)))
+        .collect()
+}
+
+/// Sends the session what it needs to follow a topic from now on: the file list, the
+/// latest page of chat history and the snippets, the roster, or the transfers in flight.
+async fn send_snapshot(state: &AppState, conn: &Connection, room: &RoomState, topic: rooms::Topic) {
+    let viewer = conn.session_id.clone().unwrap_or_default();
+    match topic {
+        rooms::Topic::Files => {
+            let _ = conn.tx.send(files::file_list(room, &viewer, state.file_list_page_size, conn.file_list_pages).await);
+        }
+        rooms::Topic::Chat => {
+            let history = chat::history_page(&room.messages.read().await, None, None).unwrap_or_default();
+            if !history.messages.is_empty() {
+                let oldest_id = history.messages.first().map(|message| message.id.clone());
+                let _ = conn.tx.send(ServerMessage::MessageHistory {
+                    messages: history.messages,
+                    has_more: history.has_more,
+                    oldest_id,
+                });
+            }
+            let snippets = snippets::snippet_list(room).await;
+            if !snippets.is_empty() {
+                let _ = conn.tx.send(ServerMessage::SnippetListUpdate { snippets });
+            }
+        }
+        rooms::Topic::Peers => {
+            let peers = room.peers.read().await.values().cloned().collect::<Vec<_>>();
+            let _ = conn.tx.send(ServerMessage::PeerList {
+                total_peers: peers.len(),
+                peers,
+            });
+        }
+        rooms::Topic::Transfers => {
+            let _ = conn.tx.send(ServerMessage::TransferList { transfers: live_transfers(state, room).await });
+        }
+    }
+}
+
+/// Sends each room with transfers in flight its `TransferList`. Rooms in `previously_active`
+/// that have gone quiet get one empty list so clients clear their view; idle rooms get
+/// nothing. Returns the rooms that had transfers, for the next call.
+pub async fn broadcast_transfers(state: &AppState, previously_active: &HashSet<RoomId>) -> HashSet<RoomId> {
+    let mut active = HashSet::new();
+    if state.relay.transfers.live_transfers().is_empty() && previously_active.is_empty() {
+        return active;
+    }
+
+    let rooms = state.rooms.read().await.values().cloned().collect::<Vec<_>>();
+    for room in rooms {
+        let transfers = live_transfers(state, &room).await;
+        if !transfers.is_empty() {
+            active.insert(room.id.clone());
+        } else if !previously_active.contains(&room.id) {
+            continue;
+        }
+        room.send(ServerMessage::TransferList { transfers });
+    }
+    active
+}
+
+/// Re-issues a file between two peers through the relay after their direct connection
+/// failed. The peer hosting the file sends it, and both are told. Returns false when
+/// exactly one of them does not host a file the other can see.
+async fn fall_back_to_relay(state: &AppState, room: &RoomState, file_id: &str, a: &SessionId, b: &SessionId) -> bool {
+    let roles = match room.files.read().await.get(file_id) {
+        Some(file) => match (file.hosts.contains(a), file.hosts.contains(b)) {
+            (true, false) if file.is_visible_to(Some(b)) => Some((a.clone(), b.clone())),
+            (false, true) if file.is_visible_to(Some(a)) => Some((b.clone(), a.clone())),
+            _ => None,
+        },
+        None => None,
+    };
+    let Some((host_id, requester_id)) = roles else {
+        return false;
+    };
+
+    state.relay.record_fallback();
+    tracing::info!(file_id, host = host_id, to = requester_id, "Falling back to the relay");
+    for session_id in [&host_id, &requester_id] {
+        state.send_to(session_id, ServerMessage::FallingBackToRelay {
+            file_id: file_id.to_string(),
+        }).await;
+    }
+
+    let chunk_size = room.peers.read().await.get(&requester_id).map_or(DEFAULT_CHUNK_SIZE, |peer| peer.chunk_size);
+    let pending = transfers::PendingDownload {
+        key: (file_id.to_string(), host_id, requester_id.clone()),
+        room: room.id.clone(),
+        chunk_size,
+        tried_hosts: HashSet::new(),
+        http_relay: false,
+    };
+    if let Some(reply) = request_download(state, room, pending).await {
+        state.send_to(&requester_id, reply).await;
+    }
+    true
+}
+
+fn fallback_failed(file_id: &str) -> ServerMessage {
+    ServerMessage::Error {
+        code: "download_failed".to_string(),
+        message: format!("Cannot relay {file_id}: exactly one of the peers must host it"),
+        limit: None,
+        retry_after_ms: None,
+    }
+}
+
+/// Asks the host, through the room, to start sending the file to the requester, and
+/// starts waiting for its answer. Opens a relay pipe when both peers can use one,
+/// returning its id; only the two of them are told it. A download of a private file is
+/// not announced to the room at all.
+pub async fn route_download(state: &AppState, room: &RoomState, pending: transfers::PendingDownload) -> Option<String> {
+    let private = room.files.read().await.get(&pending.key.0).is_some_and(|file| file.visibility.is_some());
+    state.relay.transfers.forward(&pending);
+    let relay_id = pending.http_relay.then(|| state.pipes.open(&pending.key, pending.chunk_size));
+    let (file_id, host_id, requester_id) = pending.key;
+    let request = |relay_id: Option<String>| ServerMessage::DownloadRequest {
+        from_session_id: host_id.clone(),
+        file_id: file_id.clone(),
+        requester_session_id: requester_id.clone(),
+        chunk_size: pending.chunk_size,
+        relay_id,
+    };
+    // Sent to the two of them directly, as a broadcast only they receive would leave a
+    // gap in everyone else's sequence numbers
+    if private {
+        for session_id in [&host_id, &requester_id] {
+            state.send_to(session_id, request(relay_id.clone())).await;
+        }
+        return relay_id;
+    }
+    match &relay_id {
+        Some(id) => {
+            let parties = Arc::new(HashSet::from([host_id.clone(), requester_id.clone()]));
+            room.send_variants(vec![
+                (request(Some(id.clone())), rooms::Audience::Only(parties.clone())),
+                (request(None), rooms::Audience::Except(parties)),
+            ]);
+        }
+        None => room.send(request(None)),
+    }
+    relay_id
+}
+
+/// Passes the starts and ends of transfers on to their hosts, unless they turned the
+/// notices off.
+pub async fn deliver_upload_activity(state: AppState, mut notices: mpsc::UnboundedReceiver<(SessionId, ServerMessage)>) {
+    while let Some((host_id, notice)) = notices.recv().await {
+        let sessions = state.sessions.read().await;
+        if let Some(host) = sessions.get(&host_id).filter(|host| host.notify_upload_activity) {
+            let _ = host.tx.send(notice);
+        }
+    }
+}
+
+/// Routes queued downloads whose host has a free slot again and tells the requesters
+/// still waiting their new position. Call after anything that may end a transfer.
+pub async fn start_queued_downloads(state: &AppState) {
+    let progress = state.relay.transfers.drain_queues();
+
+    for (pending, position) in progress.positions {
+        let (file_id, _, requester_id) = pending.key;
+        state.send_to(&requester_id, ServerMessage::DownloadQueued { file_id, position }).await;
+    }
+
+    for pending in progress.started {
+        let (file_id, host_id, requester_id) = pending.key.clone();
+        tracing::debug!(file_id, host = host_id, to = requester_id, "Queued download starting");
+        let room = state.rooms.read().await.get(&pending.room).cloned();
+        let relay_id = match room {
+            Some(room) => route_download(state, &room, pending).await,
+            None => None,
+        };
+        state.send_to(&requester_id, ServerMessage::DownloadStarting {
+            file_id,
+            host_session_id: host_id,
+            relay_id,
+        }).await;
+    }
+}
+
+pub async fn cleanup_peer(state: &AppState, room: &RoomState, session_id: &SessionId) {
+    // Remove peer from peers map; a peer that was already cleaned up (e.g. kicked) is skipped
+    let (peer, peers_count) = {
+        let mut peers = room.peers.write().await;
+        let Some(peer) = peers.remove(session_id) else {
+            return;
+        };
+        (peer, peers.len())
     };
 
+    state.sessions.write().await.remove(session_id);
+
+    end_transfers(state, session_id).await;
+    state.latency.forget_session(session_id);
+    state.rate_limits.forget_session(session_id);
+    state.events.record(EventKind::Left, EventSubject {
+        session_id: Some(session_id.clone()),
+        room: Some(room.id.clone()),
+        ..Default::default()
+    });
+
     // Notify about peer leaving
-    let _ = state.tx.send(ServerMessage::PeerLeft {
+    room.send(ServerMessage::PeerLeft {
         session_id: session_id.clone(),
         total_peers: peers_count,
     });
+    chat::post_system_message(state, room, format!("{} left", chat::display_name(&peer))).await;
+
+    // Remove peer from file hosts and clean up files with no hosts
+    let include_full_list = room.has_full_list_peers().await;
+    let mut files = room.files.write().await;
+    let mut change = files::FileListChange::default();
 
-    // Notify about removed files
-    for file_id in files_to_remove {
-        let _ = state.tx.send(ServerMessage::FileRemoved { file_id });
+    for (file_id, file) in files.iter_mut() {
+        if file.hosts.contains(session_id) {
+            let updated = Arc::make_mut(file);
+            updated.hosts.remove(session_id);
+            if !updated.hosts.is_empty() {
+                change.updated.push(file.clone());
+            } else if state.orphan_grace.is_zero() {
+                change.removed.push(file_id.clone());
+            } else {
+                // Keep the file for a while in case the host is only reconnecting
+                updated.unavailable = true;
+                updated.orphaned = Some(Orphaned {
+                    since: std::time::Instant::now(),
+                    last_host: session_id.clone(),
+                });
+                change.updated.push(file.clone());
+            }
+        }
     }
 
-    // Send updated file list
-    let files = {
-        let files = state.files.read().await;
-        files.values().cloned().collect()
-    };
-    
-    let _ = state.tx.send(ServerMessage::FileListUpdate { files });
+    let mut removed_names = Vec::new();
+    let mut uploaders = HashSet::new();
+    for file_id in &change.removed {
+        tracing::info!(file_id, reason = "no hosts left", "File removed");
+        state.events.record(EventKind::FileRemoved, EventSubject::file(&room.id, file_id, "no hosts left"));
+        let Some(file) = files.remove(file_id) else {
+            continue;
+        };
+        // The file no longer lists the peer that just left as its host
+        let mut trashed = (*file).clone();
+        trashed.hosts.insert(session_id.clone());
+        uploaders.insert(file.uploader_id.clone());
+        state.trash.put(&room.id, Arc::new(trashed));
+        if file.visibility.is_none() {
+            removed_names.push(file.name.clone());
+            room.send(ServerMessage::FileRemoved { file_id: file_id.clone() });
+        }
+    }
+
+    tracing::info!(
+        files_removed = change.removed.len(),
+        files_updated = change.updated.len(),
+        "Peer cleaned up"
+    );
+    files::publish_change(room, &files, change, include_full_list);
+    drop(files);
+    chat::post_files_removed(state, room, &removed_names).await;
+    for uploader in &uploaders {
+        trash::notify(state, uploader).await;
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::files::tests::file;
+    use crate::rooms::tests::chat_message;
+    use std::net::Ipv4Addr;
+
+    /// A client as message handling sees one connected over a socket.
+    pub(crate) struct Client {
+        conn: Connection,
+        outbox: Outbox,
+    }
+
+    impl Client {
+        pub(crate) fn connect(state: &AppState) -> Client {
+            let (conn, outbox) = Connection::new(state, Some(IpAddr::V4(Ipv4Addr::LOCALHOST)));
+            Client { conn, outbox }
+        }
+
+        /// Connects and joins the default room as `session_id`.
+        pub(crate) async fn join(state: &AppState, session_id: &str) -> Client {
+            let mut client = Client::connect(state);
+            client.send(state, join(session_id, None)).await;
+            client
+        }
+
+        pub(crate) async fn send(&mut self, state: &AppState, msg: ClientMessage) {
+            handle_client_message(msg, state, &mut self.conn).await.unwrap();
+        }
+
+        pub(crate) fn session_id(&self) -> Option<&SessionId> {
+            self.conn.session_id.as_ref()
+        }
+
+        /// Everything delivered so far, room broadcasts included.
+        pub(crate) async fn received(&mut self) -> Vec<ServerMessage> {
+            let mut received = Vec::new();
+            while let Ok(Some(outgoing)) = tokio::time::timeout(Duration::from_millis(50), self.outbox.next()).await {
+                match outgoing {
+                    Outgoing::Message(msg) => received.push(*msg),
+                    Outgoing::Broadcast(msg) => received.push(serde_json::from_str(&msg.json).unwrap()),
+                }
+            }
+            received
+        }
+
+        pub(crate) async fn leave(self, state: &AppState) {
+            if let (Some(id), Some(room)) = (&self.conn.session_id, &self.conn.room) {
+                cleanup_peer(state, room, id).await;
+            }
+        }
+    }
+
+    pub(crate) fn join(session_id: &str, session_token: Option<&str>) -> ClientMessage {
+        serde_json::from_value(serde_json::json!({
+            "type": "join",
+            "session_id": session_id,
+            "session_token": session_token,
+        }))
+        .unwrap()
+    }
+
+    pub(crate) fn error_codes(received: &[ServerMessage]) -> Vec<&str> {
+        received
+            .iter()
+            .filter_map(|msg| match msg {
+                ServerMessage::Error { code, .. } => Some(code.as_str()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn session_token(received: &[ServerMessage]) -> String {
+        received
+            .iter()
+            .find_map(|msg| match msg {
+                ServerMessage::Welcome { session_token, .. } => Some(session_token.clone()),
+                _ => None,
+            })
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn refuses_a_session_id_another_connection_holds() {
+        let state = AppState::for_tests(&[]);
+        let mut alice = Client::join(&state, "alice").await;
+        let token = session_token(&alice.received().await);
+
+        // Not even the token takes a live session over
+        let mut mallory = Client::connect(&state);
+        mallory.send(&state, join("alice", Some(&token))).await;
+        assert_eq!(error_codes(&mallory.received().await), ["session_in_use"]);
+        assert_eq!(mallory.session_id(), None);
+        assert!(state.sessions.read().await["alice"].tx.same_channel(&alice.conn.tx));
+
+        // The connection holding the id may join with it again
+        alice.send(&state, join("alice", None)).await;
+        assert!(error_codes(&alice.received().await).is_empty());
+        assert_eq!(alice.session_id().map(String::as_str), Some("alice"));
+    }
+
+    #[tokio::test]
+    async fn departed_session_id_needs_its_token() {
+        let state = AppState::for_tests(&["--orphan-grace", "60"]);
+        let mut alice = Client::join(&state, "alice").await;
+        let token = session_token(&alice.received().await);
+        alice
+            .send(&state, ClientMessage::FileUpload {
+                session_id: "alice".to_string(),
+                file: Box::new(file("file_1", "alice", 10)),
+            })
+            .await;
+        let room = alice.conn.room.clone().unwrap();
+        alice.leave(&state).await;
+        assert!(room.files.read().await["file_1"].orphaned.is_some());
+
+        for token in [None, Some("forged")] {
+            let mut mallory = Client::connect(&state);
+            mallory.send(&state, join("alice", token)).await;
+            assert_eq!(error_codes(&mallory.received().await), ["session_in_use"]);
+            assert_eq!(mallory.session_id(), None);
+        }
+        assert!(room.files.read().await["file_1"].orphaned.is_some());
+
+        let mut alice = Client::connect(&state);
+        alice.send(&state, join("alice", Some(&token))).await;
+        assert!(error_codes(&alice.received().await).is_empty());
+        let file = room.files.read().await["file_1"].clone();
+        assert!(file.orphaned.is_none());
+        assert!(file.hosts.contains("alice"));
+    }
+
+    #[tokio::test]
+    async fn unused_session_ids_need_no_token() {
+        let state = AppState::for_tests(&[]);
+        let alice = Client::join(&state, "alice").await;
+        alice.leave(&state).await;
+
+        // Nothing was left behind under the id, so anyone may have it
+        let mut bob = Client::connect(&state);
+        bob.send(&state, join("alice", None)).await;
+        assert!(error_codes(&bob.received().await).is_empty());
+        assert_eq!(bob.session_id().map(String::as_str), Some("alice"));
+    }
+
+    fn upload(session_id: &str, file: FileMetadata) -> ClientMessage {
+        ClientMessage::FileUpload {
+            session_id: session_id.to_string(),
+            file: Box::new(file),
+        }
+    }
+
+    fn request(session_id: &str, file_id: &str) -> ClientMessage {
+        ClientMessage::RequestDownload {
+            session_id: session_id.to_string(),
+            file_id: Some(file_id.to_string()),
+            batch_id: None,
+            password: None,
+        }
+    }
+
+    fn download_requests(received: &[ServerMessage]) -> usize {
+        received.iter().filter(|msg| matches!(msg, ServerMessage::DownloadRequest { .. })).count()
+    }
+
+    #[tokio::test]
+    async fn only_the_parties_hear_of_a_private_download() {
+        let state = AppState::for_tests(&[]);
+        let mut alice = Client::join(&state, "alice").await;
+        let mut bob = Client::join(&state, "bob").await;
+        let mut carol = Client::join(&state, "carol").await;
+        let private = FileMetadata {
+            visibility: Some(vec!["bob".to_string()]),
+            ..file("private", "alice", 10)
+        };
+        alice.send(&state, upload("alice", private)).await;
+        alice.send(&state, upload("alice", file("public", "alice", 10))).await;
+        for client in [&mut alice, &mut bob, &mut carol] {
+            client.received().await;
+        }
+
+        bob.send(&state, request("bob", "private")).await;
+        assert_eq!(download_requests(&alice.received().await), 1);
+        assert_eq!(download_requests(&bob.received().await), 1);
+        assert_eq!(download_requests(&carol.received().await), 0);
+
+        bob.send(&state, request("bob", "public")).await;
+        assert_eq!(download_requests(&alice.received().await), 1);
+        assert_eq!(download_requests(&bob.received().await), 1);
+        assert_eq!(download_requests(&carol.received().await), 1);
+    }
+
+    #[tokio::test]
+    async fn sync_state_sends_the_latest_history_page() {
+        let state = AppState::for_tests(&[]);
+        let mut alice = Client::join(&state, "alice").await;
+        let room = alice.conn.room.clone().unwrap();
+        let history = (0..chat::HISTORY_PAGE_SIZE + 10).map(|n| chat_message(&n.to_string()));
+        room.messages.write().await.extend(history);
+        alice.received().await;
+
+        alice
+            .send(&state, ClientMessage::RequestSync {
+                session_id: "alice".to_string(),
+                last_seq: 0,
+            })
+            .await;
+        let received = alice.received().await;
+        let Some(ServerMessage::SyncState { messages, has_more, .. }) =
+            received.iter().find(|msg| matches!(msg, ServerMessage::SyncState { .. }))
+        else {
+            panic!("no sync_state in {received:?}");
+        };
+        assert_eq!(messages.len(), chat::HISTORY_PAGE_SIZE);
+        assert_eq!(messages.last().unwrap().content, (chat::HISTORY_PAGE_SIZE + 9).to_string());
+        assert!(has_more);
+    }
+
+    fn chunk(from: &str, to: &str, file_id: &str, chunk_index: u32, total_chunks: u32) -> ClientMessage {
+        serde_json::from_value(serde_json::json!({
+            "type": "file_chunk",
+            "session_id": from,
+            "file_id": file_id,
+            "chunk_index": chunk_index,
+            "total_chunks": total_chunks,
+            "data": "AAAA",
+            "target_session_id": to,
+            "compression": null,
+        }))
+        .unwrap()
+    }
+
+    fn aborted(received: &[ServerMessage]) -> Vec<(&str, &str, &str)> {
+        received
+            .iter()
+            .filter_map(|msg| match msg {
+                ServerMessage::TransferAborted { file_id, peer_session_id, reason } => {
+                    Some((file_id.as_str(), peer_session_id.as_str(), reason.as_str()))
+                }
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn either_side_leaving_mid_transfer_aborts_it_for_the_other() {
+        for host_leaves in [true, false] {
+            let state = AppState::for_tests(&[]);
+            let mut alice = Client::join(&state, "alice").await;
+            let mut bob = Client::join(&state, "bob").await;
+            alice.send(&state, upload("alice", file("file_1", "alice", 10))).await;
+            bob.send(&state, request("bob", "file_1")).await;
+            alice.send(&state, chunk("alice", "bob", "file_1", 0, 2)).await;
+            alice.received().await;
+            assert!(bob.received().await.iter().any(|msg| matches!(msg, ServerMessage::FileChunk { .. })));
+            assert_eq!(state.relay.transfers.counts(), (1, 0));
+
+            let (leaving, mut staying) = if host_leaves { (alice, bob) } else { (bob, alice) };
+            let leaving_id = leaving.session_id().unwrap().clone();
+            leaving.leave(&state).await;
+            assert_eq!(aborted(&staying.received().await), [("file_1", leaving_id.as_str(), "peer_disconnected")]);
+            assert_eq!(state.relay.transfers.counts(), (0, 0));
+        }
+    }
+
+    fn relayed_chunk(chunk_index: u32) -> ServerMessage {
+        ServerMessage::FileChunk {
+            file_id: "file_1".to_string(),
+            chunk_index,
+            total_chunks: 100,
+            offset: None,
+            len: None,
+            data: "AAAA".to_string(),
+            from_session_id: "alice".to_string(),
+            target_session_id: "bob".to_string(),
+            compression: None,
+            encrypted: false,
+            nonce: None,
+        }
+    }
+
+    fn slow_downs(received: &[ServerMessage]) -> usize {
+        received.iter().filter(|msg| matches!(msg, ServerMessage::SlowDown { .. })).count()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn chunks_queue_up_to_capacity_before_the_host_is_slowed_down() {
+        let state = AppState::for_tests(&[]);
+        let mut alice = Client::join(&state, "alice").await;
+        let mut bob = Client::join(&state, "bob").await;
+        alice.received().await;
+        bob.received().await;
+
+        // A full queue's worth goes through without waiting
+        let started = tokio::time::Instant::now();
+        for index in 0..CHUNK_QUEUE_CAPACITY as u32 {
+            assert!(forward_chunk(&state, &alice.conn, relayed_chunk(index)).await);
+        }
+        assert_eq!(started.elapsed(), Duration::ZERO);
+        assert_eq!(slow_downs(&alice.received().await), 0);
+
+        // The next waits for space, and the host hears it should slow down
+        let drain = async {
+            tokio::time::sleep(SLOW_DOWN_AFTER + Duration::from_secs(1)).await;
+            bob.outbox.next().await
+        };
+        let (queued, drained) = tokio::join!(forward_chunk(&state, &alice.conn, relayed_chunk(16)), drain);
+        assert!(queued);
+        assert!(matches!(drained, Some(Outgoing::Message(msg)) if matches!(*msg, ServerMessage::FileChunk { chunk_index: 0, .. })));
+        assert_eq!(slow_downs(&alice.received().await), 1);
+
+        let indexes = bob
+            .received()
+            .await
+            .into_iter()
+            .filter_map(|msg| match msg {
+                ServerMessage::FileChunk { chunk_index, .. } => Some(chunk_index),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(indexes, (1..=CHUNK_QUEUE_CAPACITY as u32).collect::<Vec<_>>());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn receivers_that_never_drain_their_queue_are_disconnected() {
+        let state = AppState::for_tests(&[]);
+        let mut alice = Client::join(&state, "alice").await;
+        let mut bob = Client::join(&state, "bob").await;
+        bob.received().await;
+        for index in 0..CHUNK_QUEUE_CAPACITY as u32 {
+            assert!(forward_chunk(&state, &alice.conn, relayed_chunk(index)).await);
+        }
+
+        let started = tokio::time::Instant::now();
+        assert!(!forward_chunk(&state, &alice.conn, relayed_chunk(16)).await);
+        assert!(started.elapsed() >= CHUNK_QUEUE_TIMEOUT);
+        assert_eq!(slow_downs(&alice.received().await), 1);
+        assert!(!state.sessions.read().await.contains_key("bob"));
+        assert!(bob.received().await.iter().any(|msg| matches!(msg, ServerMessage::Kicked { code: CLOSE_KICKED, .. })));
+    }
+
+    fn kicked(received: &[ServerMessage]) -> Vec<(u16, &str)> {
+        received
+            .iter()
+            .filter_map(|msg| match msg {
+                ServerMessage::Kicked { reason, code } => Some((*code, reason.as_str())),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn kicking_closes_only_that_session_with_the_given_code() {
+        let state = AppState::for_tests(&[]);
+        let mut alice = Client::join(&state, "alice").await;
+        let mut bob = Client::join(&state, "bob").await;
+        alice.received().await;
+        bob.received().await;
+
+        assert!(kick_session(&state, &"bob".to_string(), CLOSE_AUTH_REVOKED, "Banned").await);
+        assert_eq!(kicked(&bob.received().await), [(CLOSE_AUTH_REVOKED, "Banned")]);
+        assert!(kicked(&alice.received().await).is_empty());
+        assert!(!state.sessions.read().await.contains_key("bob"));
+        assert!(!kick_session(&state, &"bob".to_string(), CLOSE_KICKED, "Removed").await);
+    }
+
+    #[tokio::test]
+    async fn disconnecting_everyone_closes_every_session_with_the_given_code() {
+        let state = AppState::for_tests(&[]);
+        let mut clients = Vec::new();
+        for id in ["alice", "bob", "carol"] {
+            clients.push(Client::join(&state, id).await);
+        }
+
+        disconnect_all(&state, CLOSE_SERVER_SHUTDOWN, "Server shutting down").await;
+        for client in &mut clients {
+            assert_eq!(kicked(&client.received().await), [(CLOSE_SERVER_SHUTDOWN, "Server shutting down")]);
+        }
+    }
+
+    fn text_message(session_id: &str, content: &str) -> ClientMessage {
+        ClientMessage::TextMessage {
+            session_id: session_id.to_string(),
+            content: content.to_string(),
+            format: None,
+            kind: None,
+            attachments: None,
+            reply_to: None,
+            client_ref: None,
+            origin: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn text_messages_before_join_are_rejected() {
+        let state = AppState::for_tests(&[]);
+        let mut alice = Client::join(&state, "alice").await;
+        let room = alice.conn.room.clone().unwrap();
+        let history = room.messages.read().await.len();
+        alice.received().await;
+
+        let text = text_message("alice", "hello");
+        let mut mallory = Client::connect(&state);
+        mallory.send(&state, text.clone()).await;
+        assert_eq!(error_codes(&mallory.received().await), ["not_joined"]);
+        assert!(alice.received().await.is_empty());
+        assert_eq!(room.messages.read().await.len(), history);
+
+        // Each counts as malformed, so a client that keeps trying is disconnected
+        for _ in 1..MAX_MALFORMED_MESSAGES {
+            mallory.send(&state, text.clone()).await;
+        }
+        assert_eq!(kicked(&mallory.received().await), [(CLOSE_PROTOCOL_VIOLATION, "Too many malformed messages")]);
+    }
+
+    #[tokio::test]
+    async fn files_only_subscribers_get_no_chat() {
+        let state = AppState::for_tests(&[]);
+        let mut alice = Client::join(&state, "alice").await;
+        let mut bob = Client::join(&state, "bob").await;
+        alice
+            .send(&state, ClientMessage::Subscribe {
+                session_id: "alice".to_string(),
+                topics: HashSet::from(["files".to_string()]),
+            })
+            .await;
+        alice.received().await;
+
+        bob.send(&state, text_message("bob", "hello")).await;
+        bob.send(&state, upload("bob", file("file_1", "bob", 10))).await;
+        let received = alice.received().await;
+        assert!(!received.iter().any(|msg| matches!(msg, ServerMessage::TextMessage { .. })), "{received:?}");
+        assert!(received.iter().any(|msg| matches!(msg, ServerMessage::FileAdded { .. } | ServerMessage::FileListUpdate { .. })), "{received:?}");
+
+        // Everyone else still hears the chat
+        assert!(bob.received().await.iter().any(|msg| matches!(msg, ServerMessage::TextMessage { .. })));
+    }
+
+    #[tokio::test]
+    async fn download_requests_carry_the_requesters_negotiated_chunk_size() {
+        for (preferred, negotiated) in [
+            (None, DEFAULT_CHUNK_SIZE),
+            (Some(1), MIN_CHUNK_SIZE),
+            (Some(256 * 1024), 256 * 1024),
+            (Some(u32::MAX), MAX_CHUNK_SIZE),
+        ] {
+            let state = AppState::for_tests(&[]);
+            let mut alice = Client::join(&state, "alice").await;
+            let mut bob = Client::connect(&state);
+            let join = serde_json::from_value(serde_json::json!({
+                "type": "join",
+                "session_id": "bob",
+                "preferred_chunk_size": preferred,
+            }));
+            bob.send(&state, join.unwrap()).await;
+            alice.send(&state, upload("alice", file("file_1", "alice", 10))).await;
+            alice.received().await;
+
+            bob.send(&state, request("bob", "file_1")).await;
+            let chunk_sizes = alice
+                .received()
+                .await
+                .into_iter()
+                .filter_map(|msg| match msg {
+                    ServerMessage::DownloadRequest { chunk_size, .. } => Some(chunk_size),
+                    _ => None,
+                })
+                .collect::<Vec<_>>();
+            assert_eq!(chunk_sizes, [negotiated], "{preferred:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn chunks_are_only_relayed_in_compressions_the_target_decodes() {
+        let state = AppState::for_tests(&[]);
+        let mut alice = Client::join(&state, "alice").await;
+        let mut bob = Client::connect(&state);
+        let join = serde_json::json!({
+            "type": "join",
+            "session_id": "bob",
+            "capabilities": [compression_capability("gzip")],
+        });
+        bob.send(&state, serde_json::from_value(join).unwrap()).await;
+        let mut carol = Client::join(&state, "carol").await;
+        alice.send(&state, upload("alice", file("file_1", "alice", 10))).await;
+        bob.send(&state, request("bob", "file_1")).await;
+        carol.send(&state, request("carol", "file_1")).await;
+        for client in [&mut alice, &mut bob, &mut carol] {
+            client.received().await;
+        }
+
+        let compressed = |to: &str, compression: &str| -> ClientMessage {
+            serde_json::from_value(serde_json::json!({
+                "type": "file_chunk",
+                "session_id": "alice",
+                "file_id": "file_1",
+                "chunk_index": 0,
+                "total_chunks": 2,
+                // Compressed chunks say how long they were before compression
+                "len": 3,
+                "data": "AAAA",
+                "target_session_id": to,
+                "compression": compression,
+            }))
+            .unwrap()
+        };
+        let relayed = |received: Vec<ServerMessage>| {
+            received
+                .into_iter()
+                .filter_map(|msg| match msg {
+                    ServerMessage::FileChunk { compression, .. } => Some(compression),
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+        };
+
+        alice.send(&state, compressed("bob", "brotli")).await;
+        assert_eq!(error_codes(&alice.received().await), ["unsupported_compression"]);
+        assert!(relayed(bob.received().await).is_empty());
+
+        alice.send(&state, compressed("carol", "gzip")).await;
+        assert_eq!(error_codes(&alice.received().await), ["capability_mismatch"]);
+        assert!(relayed(carol.received().await).is_empty());
+
+        alice.send(&state, compressed("bob", "gzip")).await;
+        let received = alice.received().await;
+        assert!(error_codes(&received).is_empty(), "{received:?}");
+        assert_eq!(relayed(bob.received().await), [Some("gzip".to_string())]);
+    }
+
+    #[tokio::test]
+    async fn malformed_chunks_are_rejected_and_not_relayed() {
+        use base64::Engine;
+
+        let state = AppState::for_tests(&[]);
+        let mut alice = Client::join(&state, "alice").await;
+        let mut bob = Client::connect(&state);
+        let join = serde_json::json!({
+            "type": "join",
+            "session_id": "bob",
+            "preferred_chunk_size": MIN_CHUNK_SIZE,
+        });
+        bob.send(&state, serde_json::from_value(join).unwrap()).await;
+        alice.send(&state, upload("alice", file("file_1", "alice", 2 * MIN_CHUNK_SIZE as u64))).await;
+        bob.send(&state, request("bob", "file_1")).await;
+        alice.received().await;
+        bob.received().await;
+
+        let chunk = |file_id: &str, chunk_index: u32, total_chunks: u32, len: u32| -> ClientMessage {
+            serde_json::from_value(serde_json::json!({
+                "type": "file_chunk",
+                "session_id": "alice",
+                "file_id": file_id,
+                "chunk_index": chunk_index,
+                "total_chunks": total_chunks,
+                "data": base64::engine::general_purpose::STANDARD.encode(vec![0; len as usize]),
+                "target_session_id": "bob",
+                "compression": null,
+            }))
+            .unwrap()
+        };
+        let relayed = |received: &[ServerMessage]| received.iter().filter(|msg| matches!(msg, ServerMessage::FileChunk { .. })).count();
+
+        for (case, malformed) in [
+            ("no chunks", chunk("file_1", 0, 0, MIN_CHUNK_SIZE)),
+            ("index out of range", chunk("file_1", 2, 2, MIN_CHUNK_SIZE)),
+            ("oversize data", chunk("file_1", 0, 2, MIN_CHUNK_SIZE + 1)),
+            ("unknown file", chunk("file_2", 0, 2, MIN_CHUNK_SIZE)),
+        ] {
+            alice.send(&state, malformed).await;
+            assert_eq!(error_codes(&alice.received().await), ["invalid_chunk"], "{case}");
+            assert_eq!(relayed(&bob.received().await), 0, "{case}");
+        }
+
+        alice.send(&state, chunk("file_1", 0, 2, MIN_CHUNK_SIZE)).await;
+        assert!(error_codes(&alice.received().await).is_empty());
+        assert_eq!(relayed(&bob.received().await), 1);
+    }
+
+    #[tokio::test]
+    async fn errors_reach_only_the_client_that_caused_them() {
+        let state = AppState::for_tests(&[]);
+        let mut alice = Client::join(&state, "alice").await;
+        let mut bob = Client::join(&state, "bob").await;
+        alice.received().await;
+        bob.received().await;
+
+        alice.send(&state, request("alice", "missing")).await;
+        assert!(!error_codes(&alice.received().await).is_empty());
+        assert!(bob.received().await.is_empty());
+
+        bob.send(&state, chunk("bob", "alice", "missing", 0, 0)).await;
+        assert_eq!(error_codes(&bob.received().await), ["invalid_chunk"]);
+        assert!(alice.received().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn history_goes_only_to_the_joining_peer() {
+        let state = AppState::for_tests(&[]);
+        let mut alice = Client::join(&state, "alice").await;
+        alice.send(&state, text_message("alice", "hello")).await;
+        alice.received().await;
+
+        let histories = |received: &[ServerMessage]| {
+            received
+                .iter()
+                .filter_map(|msg| match msg {
+                    ServerMessage::MessageHistory { messages, .. } => Some(messages.iter().map(|message| message.content.clone()).collect::<Vec<_>>()),
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+        };
+        let mut bob = Client::join(&state, "bob").await;
+        let history = histories(&bob.received().await);
+        assert_eq!(history.len(), 1);
+        assert!(history[0].contains(&"hello".to_string()));
+        assert!(histories(&alice.received().await).is_empty());
+    }
+
+    #[tokio::test]
+    async fn welcome_and_peer_list_carry_the_negotiated_protocol_version() {
+        for (asked, negotiated) in [(None, 1), (Some(1), 1), (Some(PROTOCOL_VERSION), PROTOCOL_VERSION), (Some(99), PROTOCOL_VERSION)] {
+            let state = AppState::for_tests(&[]);
+            let mut alice = Client::connect(&state);
+            let join = serde_json::json!({
+                "type": "join",
+                "session_id": "alice",
+                "protocol_version": asked,
+            });
+            alice.send(&state, serde_json::from_value(join).unwrap()).await;
+
+            let received = alice.received().await;
+            let welcomed = received.iter().find_map(|msg| match msg {
+                ServerMessage::Welcome { protocol_version, .. } => Some(*protocol_version),
+                _ => None,
+            });
+            let listed = received.iter().find_map(|msg| match msg {
+                ServerMessage::PeerList { peers, .. } => peers.iter().find(|peer| peer.session_id == "alice").map(|peer| peer.protocol_version),
+                _ => None,
+            });
+            assert_eq!(welcomed, Some(negotiated), "{asked:?}");
+            assert_eq!(listed, Some(negotiated), "{asked:?}");
+        }
+    }
 }
@@ -0,0 +1,385 @@
+use crate::fetch::Fetch;
+use crate::rooms::RoomState;
+use crate::types::*;
+use crate::AppState;
+use chrono::{Datelike, Timelike};
+use std::collections::HashSet;
+use std::io;
+use std::sync::Arc;
+use tokio::sync::mpsc;
+
+/// Archives are written without ZIP64 records, so entries and offsets must fit in 32 bits.
+pub const MAX_ZIP_SIZE: u64 = u32::MAX as u64;
+/// Without ZIP64 the end record counts entries in 16 bits.
+pub const MAX_ZIP_ENTRIES: usize = u16::MAX as usize;
+
+const LOCAL_HEADER_SIGNATURE: u32 = 0x0403_4b50;
+const DATA_DESCRIPTOR_SIGNATURE: u32 = 0x0807_4b50;
+const CENTRAL_HEADER_SIGNATURE: u32 = 0x0201_4b50;
+const END_OF_CENTRAL_DIRECTORY_SIGNATURE: u32 = 0x0605_4b50;
+/// Sizes and CRC follow the data in a descriptor; names are UTF-8.
+const FLAGS: u16 = (1 << 3) | (1 << 11);
+const VERSION: u16 = 20;
+const LOCAL_HEADER_LEN: u64 = 30;
+const DATA_DESCRIPTOR_LEN: u64 = 16;
+const CENTRAL_HEADER_LEN: u64 = 46;
+const END_OF_CENTRAL_DIRECTORY_LEN: u64 = 22;
+
+struct Entry {
+    name: String,
+    offset: u32,
+    crc: u32,
+    size: u32,
+}
+
+/// Writes a ZIP archive of stored (uncompressed) entries as it goes. Each entry's size
+/// and CRC are written after its data, so nothing has to be known up front and no
+/// entry is buffered.
+pub struct ZipStream {
+    entries: Vec<Entry>,
+    offset: u64,
+    current: Option<(Entry, Crc32)>,
+    dos_time: u16,
+    dos_date: u16,
+}
+
+impl ZipStream {
+    pub fn new() -> Self {
+        let now = chrono::Local::now();
+        ZipStream {
+            entries: Vec::new(),
+            offset: 0,
+            current: None,
+            dos_time: ((now.hour() << 11) | (now.minute() << 5) | (now.second() / 2)) as u16,
+            dos_date: (((now.year().max(1980) - 1980) as u32) << 9 | (now.month() << 5) | now.day()) as u16,
+        }
+    }
+
+    /// Starts an entry, returning its local header. Fails once the archive has as many
+    /// entries, or is as large, as it can be without ZIP64.
+    pub fn begin_entry(&mut self, name: &str) -> io::Result<Vec<u8>> {
+        if self.entries.len() + usize::from(self.current.is_some()) >= MAX_ZIP_ENTRIES {
+            return Err(too_large(format!("more than {MAX_ZIP_ENTRIES} files")));
+        }
+        let name_len = u16::try_from(name.len()).map_err(|_| too_large(format!("file name {name} is too long")))?;
+        let entry = Entry {
+            name: name.to_string(),
+            offset: fits_u32(self.offset)?,
+            crc: 0,
+            size: 0,
+        };
+
+        let mut header = Vec::with_capacity(30 + name.len());
+        put_u32(&mut header, LOCAL_HEADER_SIGNATURE);
+        put_u16(&mut header, VERSION);
+        put_u16(&mut header, FLAGS);
+        put_u16(&mut header, 0); // stored
+        put_u16(&mut header, self.dos_time);
+        put_u16(&mut header, self.dos_date);
+        put_u32(&mut header, 0); // CRC, in the data descriptor
+        put_u32(&mut header, 0); // compressed size, in the data descriptor
+        put_u32(&mut header, 0); // size, in the data descriptor
+        put_u16(&mut header, name_len);
+        put_u16(&mut header, 0); // extra field length
+        header.extend_from_slice(name.as_bytes());
+
+        self.offset += header.len() as u64;
+        self.current = Some((entry, Crc32::new()));
+        Ok(header)
+    }
+
+    /// Accounts for data of the current entry, which the caller writes unchanged. Fails
+    /// if the entry would outgrow 32 bits.
+    pub fn entry_data(&mut self, data: &[u8]) -> io::Result<()> {
+        if let Some((entry, crc)) = &mut self.current {
+            entry.size = fits_u32(entry.size as u64 + data.len() as u64)?;
+            crc.update(data);
+            self.offset += data.len() as u64;
+        }
+        Ok(())
+    }
+
+    /// Ends the current entry, returning its data descriptor.
+    pub fn end_entry(&mut self) -> Vec<u8> {
+        let Some((mut entry, crc)) = self.current.take() else {
+            return Vec::new();
+        };
+        entry.crc = crc.finish();
+
+        let mut descriptor = Vec::with_capacity(16);
+        put_u32(&mut descriptor, DATA_DESCRIPTOR_SIGNATURE);
+        put_u32(&mut descriptor, entry.crc);
+        put_u32(&mut descriptor, entry.size);
+        put_u32(&mut descriptor, entry.size);
+
+        self.offset += descriptor.len() as u64;
+        self.entries.push(entry);
+        descriptor
+    }
+
+    /// Writes a whole entry at once, for small generated files.
+    pub fn entry(&mut self, name: &str, data: &[u8]) -> io::Result<Vec<u8>> {
+        let mut bytes = self.begin_entry(name)?;
+        self.entry_data(data)?;
+        bytes.extend_from_slice(data);
+        bytes.extend(self.end_entry());
+        Ok(bytes)
+    }
+
+    /// The central directory and end record that close the archive. Fails if the
+    /// directory would start or end beyond what 32-bit offsets reach.
+    pub fn finish(mut self) -> io::Result<Vec<u8>> {
+        let mut bytes = self.end_entry();
+        let start = self.offset;
+        let mut directory = Vec::new();
+
+        for entry in &self.entries {
+            put_u32(&mut directory, CENTRAL_HEADER_SIGNATURE);
+            put_u16(&mut directory, VERSION); // made by
+            put_u16(&mut directory, VERSION); // needed to extract
+            put_u16(&mut directory, FLAGS);
+            put_u16(&mut directory, 0); // stored
+            put_u16(&mut directory, self.dos_time);
+            put_u16(&mut directory, self.dos_date);
+            put_u32(&mut directory, entry.crc);
+            put_u32(&mut directory, entry.size);
+            put_u32(&mut directory, entry.size);
+            put_u16(&mut directory, entry.name.len() as u16);
+            put_u16(&mut directory, 0); // extra field length
+            put_u16(&mut directory, 0); // comment length
+            put_u16(&mut directory, 0); // disk number
+            put_u16(&mut directory, 0); // internal attributes
+            put_u32(&mut directory, 0); // external attributes
+            put_u32(&mut directory, entry.offset);
+            directory.extend_from_slice(entry.name.as_bytes());
+        }
+
+        let size = fits_u32(directory.len() as u64)?;
+        let start = fits_u32(start)?;
+        fits_u32(start as u64 + size as u64)?;
+        // begin_entry keeps the count within 16 bits
+        let count = self.entries.len() as u16;
+        put_u32(&mut directory, END_OF_CENTRAL_DIRECTORY_SIGNATURE);
+        put_u16(&mut directory, 0); // this disk
+        put_u16(&mut directory, 0); // disk with the central directory
+        put_u16(&mut directory, count);
+        put_u16(&mut directory, count);
+        put_u32(&mut directory, size);
+        put_u32(&mut directory, start);
+        put_u16(&mut directory, 0); // comment length
+        bytes.extend(directory);
+        Ok(bytes)
+    }
+}
+
+fn fits_u32(value: u64) -> io::Result<u32> {
+    u32::try_from(value).map_err(|_| too_large(format!("more than {MAX_ZIP_SIZE} bytes")))
+}
+
+fn too_large(what: String) -> io::Error {
+    io::Error::other(format!("Archive too large without ZIP64: {what}"))
+}
+
+/// The least an archive of these files can take, before any of their names is made
+/// unique or an error report is added.
+pub fn min_archive_size(files: &[Arc<FileMetadata>]) -> u64 {
+    let entries: u64 = files
+        .iter()
+        .map(|file| {
+            let name = file.name.replace(['/', '\\'], "_").len() as u64;
+            LOCAL_HEADER_LEN + name + file.size + DATA_DESCRIPTOR_LEN + CENTRAL_HEADER_LEN + name
+        })
+        .sum();
+    entries + END_OF_CENTRAL_DIRECTORY_LEN
+}
+
+fn put_u16(buf: &mut Vec<u8>, value: u16) {
+    buf.extend_from_slice(&value.to_le_bytes());
+}
+
+fn put_u32(buf: &mut Vec<u8>, value: u32) {
+    buf.extend_from_slice(&value.to_le_bytes());
+}
+
+/// CRC-32 as used by ZIP (IEEE polynomial, reflected).
+struct Crc32(u32);
+
+const CRC_TABLE: [u32; 256] = {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 { 0xedb8_8320 ^ (crc >> 1) } else { crc >> 1 };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+impl Crc32 {
+    fn new() -> Self {
+        Crc32(0xffff_ffff)
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        for &byte in data {
+            self.0 = CRC_TABLE[((self.0 ^ byte as u32) & 0xff) as usize] ^ (self.0 >> 8);
+        }
+    }
+
+    fn finish(&self) -> u32 {
+        self.0 ^ 0xffff_ffff
+    }
+}
+
+/// Fetches each file from one of its hosts and streams it into a ZIP archive on `tx`.
+/// Files that cannot be fetched are listed in `_errors.txt` at the end instead of
+/// failing the archive; stops early if the receiver goes away. An archive that outgrows
+/// what ZIP can hold without ZIP64 ends with an error rather than wrong offsets.
+pub async fn stream_files(
+    state: AppState,
+    room: RoomState,
+    files: Vec<Arc<FileMetadata>>,
+    mut errors: Vec<String>,
+    tx: mpsc::Sender<Result<Vec<u8>, std::io::Error>>,
+) {
+    let mut zip = ZipStream::new();
+    let mut names = HashSet::new();
+
+    for file in files {
+        let mut fetch = match Fetch::start(&state, &room, &file.id, DEFAULT_CHUNK_SIZE).await {
+            Ok(fetch) => fetch,
+            Err(e) => {
+                errors.push(format!("{}: {e}", file.name));
+                continue;
+            }
+        };
+
+        // Nothing is written until the host delivers, so a file that fails outright is left out
+        let first = match fetch.next_chunk().await {
+            Ok(chunk) => chunk,
+            Err(e) => {
+                errors.push(format!("{}: {e}", file.name));
+                fetch.close(&state).await;
+                continue;
+            }
+        };
+
+        let header = zip.begin_entry(&entry_name(&mut names, &file.name));
+        let failed = header.is_err();
+        if tx.send(header).await.is_err() || failed {
+            fetch.close(&state).await;
+            return;
+        }
+
+        let mut chunk = first;
+        while let Some(data) = chunk {
+            if let Err(e) = zip.entry_data(&data) {
+                let _ = tx.send(Err(e)).await;
+                fetch.close(&state).await;
+                return;
+            }
+            if tx.send(Ok(data)).await.is_err() {
+                fetch.close(&state).await;
+                return;
+            }
+            chunk = match fetch.next_chunk().await {
+                Ok(chunk) => chunk,
+                Err(e) => {
+                    errors.push(format!("{}: incomplete, {e}", file.name));
+                    None
+                }
+            };
+        }
+        fetch.close(&state).await;
+
+        if tx.send(Ok(zip.end_entry())).await.is_err() {
+            return;
+        }
+    }
+
+    if !errors.is_empty() {
+        let report = errors.join("\n") + "\n";
+        let entry = zip.entry(&entry_name(&mut names, "_errors.txt"), report.as_bytes());
+        let failed = entry.is_err();
+        if tx.send(entry).await.is_err() || failed {
+            return;
+        }
+    }
+    let _ = tx.send(zip.finish()).await;
+}
+
+/// A file name that is safe and unique within the archive.
+fn entry_name(names: &mut HashSet<String>, name: &str) -> String {
+    let name = name.replace(['/', '\\'], "_");
+    let (stem, extension) = match name.rfind('.') {
+        Some(dot) if dot > 0 => (&name[..dot], &name[dot..]),
+        _ => (name.as_str(), ""),
+    };
+
+    let mut candidate = name.clone();
+    let mut counter = 2;
+    while !names.insert(candidate.clone()) {
+        candidate = format!("{stem} ({counter}){extension}");
+        counter += 1;
+    }
+    candidate
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::files::tests::file;
+
+    #[test]
+    fn least_size_matches_an_archive_of_distinct_names() {
+        let files = [file("file_1", "alice", 10), file("file_2", "alice", 3)];
+        let mut zip = ZipStream::new();
+        let mut archive = Vec::new();
+        for file in &files {
+            archive.extend(zip.entry(&file.name, &vec![0; file.size as usize]).unwrap());
+        }
+        archive.extend(zip.finish().unwrap());
+
+        let files = files.map(Arc::new);
+        assert_eq!(archive.len() as u64, min_archive_size(&files));
+        assert_eq!(&archive[archive.len() - 12..archive.len() - 10], &2u16.to_le_bytes());
+    }
+
+    #[test]
+    fn refuses_more_entries_than_the_end_record_counts() {
+        let mut zip = ZipStream::new();
+        for n in 0..MAX_ZIP_ENTRIES {
+            zip.entry(&n.to_string(), b"").unwrap();
+        }
+        assert!(zip.begin_entry("one_too_many").is_err());
+        assert!(zip.finish().is_ok());
+    }
+
+    #[test]
+    fn refuses_offsets_beyond_32_bits() {
+        let mut zip = ZipStream::new();
+        zip.offset = MAX_ZIP_SIZE + 1;
+        assert!(zip.begin_entry("late").is_err());
+
+        // Entries that fit can still leave no room for the central directory
+        let mut zip = ZipStream::new();
+        zip.entry("early", b"").unwrap();
+        zip.offset = MAX_ZIP_SIZE - 10;
+        assert!(zip.finish().is_err());
+    }
+
+    #[test]
+    fn refuses_an_entry_larger_than_32_bits() {
+        let mut zip = ZipStream::new();
+        zip.begin_entry("huge").unwrap();
+        if let Some((entry, _)) = &mut zip.current {
+            entry.size = u32::MAX;
+        }
+        assert!(zip.entry_data(b"x").is_err());
+    }
+}
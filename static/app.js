@@ -1,3 +1,6 @@
+// Reported in join; keep in step with the crate version
+const CLIENT_VERSION = '0.1.0';
+
 class LADEXApp {
     constructor() {
         this.ws = null;
@@ -11,6 +14,12 @@ class LADEXApp {
         this.messages = [];
         this.unreadCount = 0;
         this.serverFiles = [];
+        this.kicked = false;
+        this.lastSeq = null;
+        this.pendingMessages = new Map();
+        this.abortedSends = new Set();
+        this.slowDownFiles = new Set();
+        this.sessionToken = undefined;
         
         this.init();
     }
@@ -91,7 +100,12 @@ class LADEXApp {
             console.log('Disconnected from LADEX server');
             console.log('Close code:', event.code, 'Close reason:', event.reason);
             this.updateConnectionStatus(false);
-            setTimeout(() => this.connectWebSocket(), 3000);
+            // Kicked, banned or misbehaving clients stay away; after a shutdown, retry slowly
+            if (event.code === 4004) {
+                setTimeout(() => this.connectWebSocket(), 10000);
+            } else if (!this.kicked && ![4001, 4002, 4003, 4005, 4006].includes(event.code)) {
+                setTimeout(() => this.connectWebSocket(), 3000);
+            }
         };
         
         this.ws.onerror = (error) => {
@@ -101,12 +115,21 @@ class LADEXApp {
     }
 
     joinSession() {
+        this.lastSeq = null;
         const message = {
             type: 'join',
             session_id: this.sessionId,
-            user_agent: navigator.userAgent
+            user_agent: navigator.userAgent,
+            protocol_version: 2,
+            client_version: CLIENT_VERSION,
+            session_token: this.sessionToken,
+            auto_accept_downloads: localStorage.getItem('autoAcceptDownloads') !== 'false',
+            room: new URLSearchParams(window.location.search).get('room') || undefined
         };
         this.sendMessage(message);
+
+        // Messages never acknowledged are retried; the server drops duplicates by client_ref
+        this.pendingMessages.forEach(pending => this.sendMessage(pending));
     }
 
     sendMessage(message) {
@@ -117,11 +140,23 @@ class LADEXApp {
 
     handleServerMessage(message) {
         console.log('Received message:', message);
+        this.checkSequence(message);
         
         switch (message.type) {
             case 'peer_joined':
                 this.handlePeerJoined(message);
                 break;
+            case 'peer_list':
+                this.handlePeerList(message);
+                break;
+            case 'peer_updated':
+                this.peers.set(message.peer.session_id, message.peer);
+                break;
+            case 'peer_activity':
+                if (this.peers.has(message.session_id)) {
+                    this.peers.get(message.session_id).activity = message.state;
+                }
+                break;
             case 'peer_left':
                 this.handlePeerLeft(message);
                 break;
@@ -138,7 +173,38 @@ class LADEXApp {
             case 'file_chunk':
                 this.handleFileChunk(message);
                 break;
+            case 'message_attachments_invalidated': {
+                const invalidated = this.messages.find(m => m.id === message.message_id);
+                if (invalidated && invalidated.attachments) {
+                    invalidated.attachments = invalidated.attachments.filter(id => !message.file_ids.includes(id));
+                    this.updateFileList(this.serverFiles);
+                }
+                break;
+            }
+            case 'server_info':
+                this.serverName = message.name;
+                document.title = `LADEX - ${message.name}`;
+                break;
+            case 'idle_warning':
+                this.showNotice(`Idle: you will be disconnected in ${message.disconnect_in_secs}s unless you do something`);
+                break;
+            case 'motd':
+                this.showMotd(message.text);
+                break;
+            case 'shutdown_countdown':
+                this.showNotice(`The server shuts down in ${Math.ceil(message.seconds_remaining / 60)} min; download what you need`);
+                break;
+            case 'welcome':
+                this.maxMessageLength = message.max_message_length;
+                this.rtcConfig.iceServers = message.ice_servers || [];
+                this.sessionToken = message.session_token;
+                break;
             case 'error':
+                // The server may not have noticed the previous connection dropping yet
+                if (message.code === 'session_in_use') {
+                    setTimeout(() => this.joinSession(), 3000);
+                    break;
+                }
                 this.showError(message.message);
                 break;
             case 'pong':
@@ -150,12 +216,134 @@ class LADEXApp {
             case 'message_history':
                 this.handleMessageHistory(message);
                 break;
+            case 'history_page':
+                this.handleHistoryPage(message);
+                break;
+            case 'kicked':
+                this.kicked = message.code !== 4004;
+                this.showError(`Disconnected: ${message.reason}`);
+                break;
+            case 'upgrade_required':
+                this.kicked = true;
+                this.showError(`This page is out of date (version ${message.min_version} or later is needed). ${message.download_hint}`);
+                break;
+            case 'sync_state':
+                this.handleSyncState(message);
+                break;
+            case 'message_ack':
+                this.pendingMessages.delete(message.client_ref);
+                break;
+            case 'slow_down':
+                this.slowDownFiles.add(message.file_id);
+                break;
+            case 'download_queued':
+                this.showNotice(`Host is busy, download queued (position ${message.position})`);
+                break;
+            case 'download_starting':
+                console.log(`Queued download ${message.file_id} starting`);
+                break;
+            case 'transfer_aborted':
+                this.handleTransferAborted(message);
+                break;
+            case 'download_approval_request':
+                this.handleDownloadApprovalRequest(message);
+                break;
+            case 'download_pending':
+                this.showNotice('Waiting for the host to approve the download');
+                break;
+            case 'download_denied':
+                this.pendingDownloads.delete(message.file_id);
+                this.activeDownloads.delete(message.file_id);
+                this.showError(message.reason === 'approval_expired' ?
+                    'The host did not answer the download request' :
+                    `The host declined the download${message.message ? `: ${message.message}` : ''}`);
+                break;
+            case 'download_timeout':
+                // The server tries another host if there is one; wait for its request again
+                this.activeDownloads.delete(message.file_id);
+                this.pendingDownloads.add(message.file_id);
+                this.showNotice('The host did not respond, trying another host');
+                break;
+            case 'peer_ping':
+                this.sendMessage({
+                    type: 'peer_pong',
+                    session_id: this.sessionId,
+                    target_session_id: message.from_session_id,
+                    nonce: message.nonce,
+                    sent_at: message.sent_at,
+                    ping_relayed_at: message.relayed_at
+                });
+                break;
+            case 'falling_back_to_relay':
+                this.showNotice('Direct connection failed, sending through the server instead');
+                break;
+            case 'file_downloaded_notice':
+                this.handleFileDownloadedNotice(message);
+                break;
+            case 'upload_activity':
+                this.handleUploadActivity(message);
+                break;
+            case 'batch_upload_result':
+                this.handleBatchUploadResult(message);
+                break;
+            case 'file_id_reassigned':
+                // Another peer's file had the id; requests come in under the new one
+                if (this.files.has(message.requested_id)) {
+                    this.files.set(message.assigned_id, this.files.get(message.requested_id));
+                    this.files.delete(message.requested_id);
+                }
+                break;
+            case 'file_updated':
+                if (message.file.uploader_id !== this.sessionId) {
+                    this.showNotice(`${message.file.name} was updated (v${message.file.version})`);
+                }
+                break;
         }
     }
 
+    checkSequence(message) {
+        if (message.seq === undefined) return;
+        if (this.lastSeq !== null && message.seq > this.lastSeq + 1) {
+            console.log('Missed broadcasts', this.lastSeq + 1, 'to', message.seq - 1, '- resyncing');
+            this.sendMessage({
+                type: 'request_sync',
+                session_id: this.sessionId,
+                last_seq: this.lastSeq
+            });
+        }
+        this.lastSeq = Math.max(this.lastSeq || 0, message.seq);
+    }
+
+    handleSyncState(message) {
+        this.peers = new Map(message.peers.map(peer => [peer.session_id, peer]));
+        this.updatePeerStatus(message.peers.length);
+        this.serverFiles = message.files || [];
+        this.messages = message.messages || [];
+        this.historyHasMore = message.has_more;
+        this.updateFileList(this.serverFiles);
+    }
+
     handlePeerJoined(message) {
         this.peers.set(message.peer.session_id, message.peer);
         this.updatePeerStatus(message.total_peers);
+        if (message.peer.session_id === this.sessionId) {
+            this.applyRole(message.peer.role);
+        }
+    }
+
+    handlePeerList(message) {
+        this.peers = new Map(message.peers.map(peer => [peer.session_id, peer]));
+        this.updatePeerStatus(message.total_peers);
+    }
+
+    applyRole(role) {
+        const readOnly = role === 'read_only';
+        ['upload-files-btn', 'upload-folder-btn', 'message-input', 'send-message-btn'].forEach(id => {
+            const element = document.getElementById(id);
+            if (element) {
+                element.style.display = readOnly ? 'none' : '';
+            }
+        });
     }
 
     handlePeerLeft(message) {
@@ -199,6 +387,12 @@ class LADEXApp {
     }
 
     setupEventListeners() {
+        const autoAccept = document.getElementById('auto-accept-downloads');
+        autoAccept.checked = localStorage.getItem('autoAcceptDownloads') !== 'false';
+        autoAccept.addEventListener('change', () => {
+            localStorage.setItem('autoAcceptDownloads', autoAccept.checked);
+        });
+
         document.getElementById('upload-files-btn').addEventListener('click', () => {
             document.getElementById('file-input-single').click();
         });
@@ -258,10 +452,10 @@ class LADEXApp {
         try {
             if (isFolder) {
                 await this.handleFolderUpload(files);
+            } else if (files.length === 1) {
+                await this.uploadFile(files[0]);
             } else {
-                for (const file of files) {
-                    await this.uploadFile(file);
-                }
+                this.uploadFiles(Array.from(files));
             }
         } catch (error) {
             this.showError(`Upload failed: ${error.message}`);
@@ -293,32 +487,53 @@ class LADEXApp {
         await this.uploadFile(zipFile);
     }
 
-    async uploadFile(file) {
+    announceFile(file) {
         const fileId = this.generateFileId();
-        const fileMetadata = {
+        this.storeFile(fileId, file);
+        return {
             id: fileId,
             name: file.name,
             size: file.size,
             mime_type: file.type || 'application/octet-stream',
             uploader_id: this.sessionId,
-            hosts: new Set([this.sessionId]),
+            hosts: [this.sessionId],
             uploaded_at: new Date().toISOString()
         };
+    }
 
-        this.storeFile(fileId, file);
-        
+    async uploadFile(file) {
         const message = {
             type: 'file_upload',
             session_id: this.sessionId,
-            file: {
-                ...fileMetadata,
-                hosts: [this.sessionId]
-            }
+            file: this.announceFile(file)
         };
         
         this.sendMessage(message);
     }
 
+    // Announces many files in batches, so peers get one list update per batch
+    uploadFiles(files) {
+        const batchSize = 500;
+        for (let i = 0; i < files.length; i += batchSize) {
+            this.sendMessage({
+                type: 'file_upload_batch',
+                session_id: this.sessionId,
+                files: files.slice(i, i + batchSize).map(file => this.announceFile(file))
+            });
+        }
+    }
+
+    handleBatchUploadResult(message) {
+        for (const rejection of message.rejected) {
+            this.files.delete(rejection.file_id);
+        }
+        if (message.rejected.length > 0) {
+            const first = message.rejected[0];
+            const more = message.rejected.length > 1 ? ` (and ${message.rejected.length - 1} more)` : '';
+            this.showError(`Some files were not shared: ${first.message}${more}`);
+        }
+    }
+
     generateFileId() {
         return 'file_' + Math.random().toString(36).substr(2, 12) + '_' + Date.now();
     }
@@ -364,10 +579,10 @@ class LADEXApp {
                 const isDownloadable = hosts.length > 0;
                 
                 return `
-                    <tr class="file-row">
-                        <td class="file-name">📄 ${file.name}</td>
+                    <tr class="file-row${file.unavailable ? ' unavailable' : ''}">
+                        <td class="file-name">📄 ${file.e2e ? '<span title="End-to-end encrypted">🔒</span> ' : ''}${file.visibility ? '<span title="Only listed for some peers">👁</span> ' : ''}${file.locked ? '<span title="Needs a password">🔑</span> ' : ''}${file.relative_path || file.name}${file.display_suffix || ''}${file.version > 1 ? ` <span class="version-badge">v${file.version}</span>` : ''}</td>
                         <td class="file-type">${file.mime_type}</td>
-                        <td class="file-size">${this.formatSize(file.size)}</td>
+                        <td class="file-size">${this.formatSize(file.size)}${file.download_count ? ` · ⬇ ${file.download_count}` : ''}</td>
                         <td>
                             <div class="file-hosts">
                                 ${hosts.map(host => {
@@ -380,8 +595,10 @@ class LADEXApp {
                             </div>
                         </td>
                         <td class="file-actions">
-                            ${isDownloadable ? 
-                                `<button class="btn download" onclick="app.downloadFile('${file.id}')">⬇️ Download</button>` :
+                            ${file.unavailable ?
+                                '<span style="color: #a0aec0;">Host reconnecting…</span>' :
+                                isDownloadable ? 
+                                `<button class="btn download" onclick="app.downloadFile('${file.id}', ${!!file.locked})">⬇️ Download</button>` :
                                 '<span style="color: #a0aec0;">No hosts</span>'
                             }
                         </td>
@@ -390,14 +607,21 @@ class LADEXApp {
             } else {
                 const message = item.data;
                 const isOwn = message.sender_id === this.sessionId;
-                const senderName = isOwn ? 'You' : `User ${message.sender_id.slice(-6)}`;
+                const isSystem = message.kind === 'system';
+                const senderName = isSystem ? 'Server' : isOwn ? 'You' : message.origin ? this.escapeHtml(this.bridgedSender(message.origin)) : `User ${message.sender_id.slice(-6)}`;
                 const preview = message.content.length > 50 ? message.content.substring(0, 50) + '...' : message.content;
                 const time = new Date(message.timestamp).toLocaleString();
+                const quoted = message.reply_to && this.messages.find(m => m.id === message.reply_to);
+                const attached = (message.attachments || [])
+                    .map(id => this.serverFiles.find(f => f.id === id))
+                    .filter(file => file);
                 
                 return `
-                    <tr class="message-row">
-                        <td class="file-name">💬 ${preview}</td>
-                        <td class="file-type">Text Message</td>
+                    <tr class="message-row${isSystem ? ' system' : ''}">
+                        <td class="file-name">💬 ${quoted ? `<span class="reply-quote">↪ ${quoted.content.substring(0, 30)}</span> ` : ''}${preview}${attached.map(file =>
+                            ` <button class="btn attachment" onclick="app.downloadFile('${file.id}', ${!!file.locked})">📎 ${file.name}</button>`
+                        ).join('')}</td>
+                        <td class="file-type">${isSystem ? 'System' : 'Text Message'}</td>
                         <td class="file-size">${message.content.length} chars</td>
                         <td><span class="host-badge">${senderName}</span></td>
                         <td class="file-actions">
@@ -408,7 +632,9 @@ class LADEXApp {
             }
         }).join('');
 
-        tbody.innerHTML = itemsHtml;
+        const loadOlder = this.historyHasMore ?
+            '<tr class="load-older"><td colspan="5"><button class="btn" onclick="app.loadOlderMessages()">Load older messages</button></td></tr>' : '';
+        tbody.innerHTML = itemsHtml + loadOlder;
     }
 
     formatSize(bytes) {
@@ -418,7 +644,12 @@ class LADEXApp {
         return Math.round(bytes / Math.pow(1024, i) * 100) / 100 + ' ' + sizes[i];
     }
 
-    async downloadFile(fileId) {
+    async downloadFile(fileId, locked = false) {
+        let password = null;
+        if (locked) {
+            password = prompt('This file needs a password:');
+            if (password === null) return;
+        }
         console.log(`Requesting download for file: ${fileId}`);
         this.pendingDownloads = this.pendingDownloads || new Set();
         this.pendingDownloads.add(fileId);
@@ -426,10 +657,11 @@ class LADEXApp {
         const message = {
             type: 'request_download',
             session_id: this.sessionId,
-            file_id: fileId
+            file_id: fileId,
+            password
         };
         this.sendMessage(message);
-        console.log('Download request sent:', message);
+        console.log('Download request sent:', fileId);
     }
 
     parseSizeString(sizeStr) {
@@ -468,26 +700,32 @@ class LADEXApp {
             const file = this.files.get(file_id);
             if (!file) {
                 console.error('Requested file not found:', file_id);
+                this.sendMessage({
+                    type: 'deny_download',
+                    session_id: this.sessionId,
+                    file_id,
+                    requester_session_id,
+                    reason: 'The file is no longer available on this device'
+                });
                 return;
             }
 
-            await this.sendFileToRequester(requester_session_id, file_id, file);
+            await this.sendFileToRequester(requester_session_id, file_id, file, message.chunk_size);
         }
     }
 
     setupRTC() {
+        // Filled in from the server's welcome; host candidates suffice on a flat LAN
         this.rtcConfig = {
-            iceServers: [
-                { urls: 'stun:stun.l.google.com:19302' }
-            ]
+            iceServers: []
         };
     }
 
-    async sendFileToRequester(requesterSessionId, fileId, file) {
+    async sendFileToRequester(requesterSessionId, fileId, file, requestedChunkSize) {
         try {
             console.log(`Sending file ${fileId} to ${requesterSessionId}`);
             
-            const chunkSize = 64 * 1024;
+            const chunkSize = requestedChunkSize || 64 * 1024;
             const totalChunks = Math.ceil(file.size / chunkSize);
 
             const metadataMessage = {
@@ -498,6 +736,7 @@ class LADEXApp {
                 file_size: file.size,
                 mime_type: file.type,
                 total_chunks: totalChunks,
+                chunk_size: chunkSize,
                 target_session_id: requesterSessionId
             };
             this.sendMessage(metadataMessage);
@@ -507,7 +746,13 @@ class LADEXApp {
             const arrayBuffer = await this.fileToArrayBuffer(file);
             const uint8Array = new Uint8Array(arrayBuffer);
             
+            const sendKey = `${fileId}:${requesterSessionId}`;
+            this.abortedSends.delete(sendKey);
             for (let chunkIndex = 0; chunkIndex < totalChunks; chunkIndex++) {
+                if (this.abortedSends.delete(sendKey)) {
+                    console.log(`Stopped sending ${fileId}: ${requesterSessionId} disconnected`);
+                    break;
+                }
                 const start = chunkIndex * chunkSize;
                 const end = Math.min(start + chunkSize, uint8Array.length);
                 const chunkData = uint8Array.slice(start, end);
@@ -520,6 +765,8 @@ class LADEXApp {
                     file_id: fileId,
                     chunk_index: chunkIndex,
                     total_chunks: totalChunks,
+                    offset: start,
+                    len: chunkData.length,
                     data: base64Data,
                     target_session_id: requesterSessionId
                 };
@@ -529,7 +776,9 @@ class LADEXApp {
                 const progress = Math.round(((chunkIndex + 1) / totalChunks) * 100);
                 this.showProgress(`Sending ${file.name}`, progress);
                 
-                await new Promise(resolve => setTimeout(resolve, 10));
+                // Back off when the server reports the receiver falling behind
+                const delay = this.slowDownFiles.delete(fileId) ? 500 : 10;
+                await new Promise(resolve => setTimeout(resolve, delay));
             }
             
             this.hideProgress();
@@ -714,6 +963,70 @@ class LADEXApp {
         this.hideProgress();
     }
 
+    handleDownloadApprovalRequest(message) {
+        const file = this.files.get(message.file_id);
+        const who = message.requester_name || message.requester_session_id.slice(-6);
+        const approve = confirm(`${who} wants to download ${file ? file.name : message.file_id}. Send it?`);
+        this.sendMessage({
+            type: 'approve_download',
+            session_id: this.sessionId,
+            request_id: message.request_id,
+            approve
+        });
+    }
+
+    handleTransferAborted(message) {
+        const download = this.activeDownloads.get(message.file_id);
+        if (this.pendingDownloads.has(message.file_id)) {
+            this.pendingDownloads.delete(message.file_id);
+            this.showError('Queued download cancelled: the host disconnected');
+        } else if (download && download.fromPeer === message.peer_session_id) {
+            this.activeDownloads.delete(message.file_id);
+            this.hideProgress();
+            this.showError(`Download of ${download.fileName} stopped: the host disconnected`);
+        } else {
+            this.abortedSends.add(`${message.file_id}:${message.peer_session_id}`);
+        }
+    }
+
+    handleFileDownloadedNotice(message) {
+        const file = this.serverFiles.find(f => f.id === message.file_id);
+        const by = message.by_name || `User ${message.by_session_id.slice(-6)}`;
+        this.showNotice(`${by} downloaded ${file ? file.name : 'your file'}`);
+    }
+
+    // Completions are shown by file_downloaded_notice
+    handleUploadActivity(message) {
+        const file = this.serverFiles.find(f => f.id === message.file_id);
+        const name = file ? file.name : 'your file';
+        const who = message.requester_name || 'Someone';
+        if (message.event === 'started') {
+            this.showNotice(`${who} started downloading ${name}`);
+        } else if (message.event === 'failed') {
+            this.showNotice(`${who} could not finish downloading ${name}`);
+        }
+    }
+
+    showNotice(text) {
+        const notice = document.createElement('div');
+        notice.className = 'notice';
+        notice.textContent = text;
+        document.body.appendChild(notice);
+        setTimeout(() => notice.remove(), 4000);
+    }
+
+    showMotd(text) {
+        let banner = document.getElementById('motd-banner');
+        if (!banner) {
+            banner = document.createElement('div');
+            banner.id = 'motd-banner';
+            banner.className = 'motd-banner';
+            banner.addEventListener('click', () => banner.remove());
+            document.body.prepend(banner);
+        }
+        banner.textContent = text;
+    }
+
     showError(message) {
         alert(`Error: ${message}`);
         console.error(message);
@@ -761,13 +1074,19 @@ LADEXApp.prototype.sendTextMessage = function() {
     const content = messageInput.value.trim();
     
     if (!content) return;
+    if (this.maxMessageLength && new TextEncoder().encode(content).length > this.maxMessageLength) {
+        this.showError(`Messages are limited to ${this.maxMessageLength} bytes`);
+        return;
+    }
 
     const message = {
         type: 'text_message',
         session_id: this.sessionId,
-        content: content
+        content: content,
+        client_ref: 'ref_' + Math.random().toString(36).substr(2, 9)
     };
 
+    this.pendingMessages.set(message.client_ref, message);
     this.sendMessage(message);
     messageInput.value = '';
     this.autoResizeTextarea(messageInput);
@@ -775,6 +1094,8 @@ LADEXApp.prototype.sendTextMessage = function() {
 };
 
 LADEXApp.prototype.handleTextMessage = function(message) {
+    // Broadcasts replayed after a resync may repeat a message
+    if (this.messages.some(m => m.id === message.message.id)) return;
     this.messages.push(message.message);
     this.updateFileList(this.serverFiles);
 };
@@ -783,6 +1104,23 @@ LADEXApp.prototype.handleMessageHistory = function(message) {
      console.log('Received message history:', message.messages.length, 'messages');
      
     this.messages = message.messages;
+    this.historyHasMore = message.has_more;
+    this.updateFileList(this.serverFiles);
+};
+
+LADEXApp.prototype.loadOlderMessages = function() {
+    const oldest = this.messages[0];
+    this.sendMessage({
+        type: 'request_history',
+        session_id: this.sessionId,
+        before_message_id: oldest ? oldest.id : null
+    });
+};
+
+LADEXApp.prototype.handleHistoryPage = function(message) {
+    const known = new Set(this.messages.map(m => m.id));
+    this.messages = message.messages.filter(m => !known.has(m.id)).concat(this.messages);
+    this.historyHasMore = message.has_more;
     this.updateFileList(this.serverFiles);
 };
 
@@ -791,7 +1129,7 @@ LADEXApp.prototype.viewMessage = function(messageId) {
     if (!message) return;
     
     const isOwn = message.sender_id === this.sessionId;
-    const senderName = isOwn ? 'You' : `User ${message.sender_id.slice(-6)}`;
+    const senderName = isOwn ? 'You' : message.origin ? this.bridgedSender(message.origin) : `User ${message.sender_id.slice(-6)}`;
     const time = new Date(message.timestamp).toLocaleString();
     
     document.getElementById('modal-sender').textContent = senderName;
@@ -873,6 +1211,11 @@ LADEXApp.prototype.updateSendButton = function() {
     sendButton.disabled = !hasContent;
 };
 
+// Messages passed on by a bridge name the sender and the server they were posted on
+LADEXApp.prototype.bridgedSender = function(origin) {
+    return `${origin.sender_name || 'Someone'} @ ${origin.server_name}`;
+};
+
 LADEXApp.prototype.escapeHtml = function(text) {
     const div = document.createElement('div');
     div.textContent = text;
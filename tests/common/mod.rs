@@ -0,0 +1,100 @@
+//! Runs the server binary and talks to it over a WebSocket, as a client would.
+
+use futures_util::{SinkExt, StreamExt};
+use serde_json::Value;
+use std::net::{IpAddr, SocketAddr, TcpListener, TcpStream};
+use std::process::{Child, Command, Stdio};
+use std::time::{Duration, Instant};
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+
+/// How long a test waits for the server to start or to send a message.
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A server process, killed when dropped.
+pub struct Server {
+    child: Child,
+    pub addr: SocketAddr,
+}
+
+impl Server {
+    /// Starts the server on a free port of `ip`, returning once it accepts connections.
+    pub fn start(ip: IpAddr, args: &[&str]) -> Server {
+        let port = TcpListener::bind((ip, 0)).unwrap().local_addr().unwrap().port();
+        let addr = SocketAddr::new(ip, port);
+        let child = Command::new(env!("CARGO_BIN_EXE_ladex"))
+            .arg("--bind")
+            .arg(addr.to_string())
+            .args(args)
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .unwrap();
+        let server = Server { child, addr };
+
+        let started = Instant::now();
+        while TcpStream::connect(addr).is_err() {
+            assert!(started.elapsed() < TIMEOUT, "Server did not listen on {addr}");
+            std::thread::sleep(Duration::from_millis(50));
+        }
+        server
+    }
+
+    pub async fn connect(&self) -> Client {
+        let (socket, _) = tokio_tungstenite::connect_async(format!("ws://{}/ws", self.addr)).await.unwrap();
+        Client { socket }
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+pub struct Client {
+    socket: WebSocketStream<MaybeTlsStream<tokio::net::TcpStream>>,
+}
+
+impl Client {
+    pub async fn send(&mut self, msg: Value) {
+        self.socket.send(Message::text(msg.to_string())).await.unwrap();
+    }
+
+    /// The next message, failing the test if none arrives in time or the socket closes.
+    pub async fn recv(&mut self) -> Value {
+        loop {
+            let msg = tokio::time::timeout(TIMEOUT, self.socket.next())
+                .await
+                .expect("No message from the server")
+                .expect("Server closed the socket")
+                .unwrap();
+            if let Message::Text(text) = msg {
+                return serde_json::from_str(&text).unwrap();
+            }
+        }
+    }
+
+    /// Skips messages up to the next one of type `kind`, and returns it.
+    pub async fn recv_type(&mut self, kind: &str) -> Value {
+        loop {
+            let msg = self.recv().await;
+            if msg["type"] == kind {
+                return msg;
+            }
+        }
+    }
+
+    /// Joins the default room as `session_id`, returning the `welcome`.
+    pub async fn join(&mut self, session_id: &str) -> Value {
+        self.send(serde_json::json!({
+            "type": "join",
+            "session_id": session_id,
+            "name": session_id,
+            "protocol_version": 2,
+        }))
+        .await;
+        self.recv_type("welcome").await
+    }
+}
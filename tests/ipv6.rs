@@ -0,0 +1,25 @@
+mod common;
+
+use common::Server;
+use std::net::{IpAddr, Ipv6Addr, TcpListener};
+
+#[tokio::test]
+async fn serves_websocket_clients_over_ipv6_loopback() {
+    if TcpListener::bind((Ipv6Addr::LOCALHOST, 0)).is_err() {
+        eprintln!("IPv6 loopback is unavailable, skipping");
+        return;
+    }
+    let server = Server::start(IpAddr::V6(Ipv6Addr::LOCALHOST), &[]);
+
+    let mut alice = server.connect().await;
+    let welcome = alice.join("alice").await;
+    assert_eq!(welcome["protocol_version"], 2);
+    let peers = alice.recv_type("peer_list").await;
+    assert_eq!(peers["peers"][0]["ip"], "::1");
+
+    // Peers on the same address family see each other
+    let mut bob = server.connect().await;
+    bob.join("bob").await;
+    let joined = alice.recv_type("peer_joined").await;
+    assert_eq!(joined["peer"]["session_id"], "bob");
+}
@@ -0,0 +1,185 @@
+//! Pins the protocol the web client and bridges rely on: the shape of what the server
+//! sends in answer to each kind of message.
+
+mod common;
+
+use common::Server;
+use serde_json::json;
+use std::net::{IpAddr, Ipv4Addr};
+
+fn start() -> Server {
+    Server::start(IpAddr::V4(Ipv4Addr::LOCALHOST), &[])
+}
+
+#[tokio::test]
+async fn join_is_answered_with_welcome_and_the_room_state() {
+    let server = start();
+    let mut alice = server.connect().await;
+
+    let welcome = alice.join("alice").await;
+    assert_eq!(welcome["protocol_version"], 2);
+    assert!(welcome["server_version"].is_string());
+    assert!(welcome["max_message_length"].is_u64());
+    assert!(welcome["ice_servers"].is_array());
+    assert!(!welcome["session_token"].as_str().unwrap().is_empty());
+    let features = welcome["features"].as_array().unwrap();
+    for feature in ["rooms", "sequence_numbers", "message_ack", "webrtc_signaling"] {
+        assert!(features.contains(&json!(feature)), "{feature}");
+    }
+
+    let peers = alice.recv_type("peer_list").await;
+    assert_eq!(peers["total_peers"], 1);
+    assert_eq!(peers["peers"][0]["session_id"], "alice");
+    assert_eq!(peers["peers"][0]["name"], "alice");
+    assert_eq!(peers["peers"][0]["protocol_version"], 2);
+    let files = alice.recv_type("file_list_update").await;
+    assert_eq!(files["files"], json!([]));
+}
+
+#[tokio::test]
+async fn clients_that_state_no_protocol_version_speak_version_1() {
+    let server = start();
+    let mut alice = server.connect().await;
+    alice.send(json!({ "type": "join", "session_id": "alice" })).await;
+    assert_eq!(alice.recv_type("welcome").await["protocol_version"], 1);
+}
+
+#[tokio::test]
+async fn messages_before_join_and_malformed_ones_are_refused() {
+    let server = start();
+    let mut alice = server.connect().await;
+    alice.send(json!({ "type": "text_message", "session_id": "alice", "content": "hello" })).await;
+    assert_eq!(alice.recv_type("error").await["code"], "not_joined");
+
+    alice.join("alice").await;
+    alice.send(json!({ "type": "no_such_message" })).await;
+    assert_eq!(alice.recv_type("error").await["code"], "invalid_message");
+}
+
+#[tokio::test]
+async fn peers_hear_of_each_other_joining_and_leaving() {
+    let server = start();
+    let mut alice = server.connect().await;
+    alice.join("alice").await;
+
+    let mut bob = server.connect().await;
+    bob.join("bob").await;
+    let joined = alice.recv_type("peer_joined").await;
+    assert_eq!(joined["peer"]["session_id"], "bob");
+    assert_eq!(joined["total_peers"], 2);
+
+    drop(bob);
+    let left = alice.recv_type("peer_left").await;
+    assert_eq!(left["session_id"], "bob");
+    assert_eq!(left["total_peers"], 1);
+}
+
+#[tokio::test]
+async fn chat_messages_reach_the_room_and_are_acknowledged_to_the_sender() {
+    let server = start();
+    let mut alice = server.connect().await;
+    alice.join("alice").await;
+    let mut bob = server.connect().await;
+    bob.join("bob").await;
+
+    alice
+        .send(json!({
+            "type": "text_message",
+            "session_id": "alice",
+            "content": "hello",
+            "client_ref": "ref_1",
+        }))
+        .await;
+    let ack = alice.recv_type("message_ack").await;
+    assert_eq!(ack["client_ref"], "ref_1");
+
+    loop {
+        let received = bob.recv_type("text_message").await;
+        if received["message"]["kind"] == "user" {
+            assert_eq!(received["message"]["id"], ack["message_id"]);
+            assert_eq!(received["message"]["content"], "hello");
+            assert_eq!(received["message"]["sender_id"], "alice");
+            assert_eq!(received["message"]["sender_name"], "alice");
+            assert!(received["seq"].is_u64());
+            break;
+        }
+    }
+}
+
+#[tokio::test]
+async fn download_requests_reach_the_host_of_the_file() {
+    let server = start();
+    let mut alice = server.connect().await;
+    alice.join("alice").await;
+    let mut bob = server.connect().await;
+    bob.join("bob").await;
+
+    alice
+        .send(json!({
+            "type": "file_upload",
+            "session_id": "alice",
+            "file": {
+                "id": "file_1",
+                "name": "notes.txt",
+                "size": 10,
+                "mime_type": "text/plain",
+                "uploader_id": "alice",
+                "hosts": ["alice"],
+                "uploaded_at": "2024-01-01T00:00:00Z",
+            },
+        }))
+        .await;
+    loop {
+        let files = bob.recv_type("file_list_update").await;
+        if let Some(file) = files["files"].as_array().unwrap().first() {
+            assert_eq!(file["id"], "file_1");
+            assert_eq!(file["name"], "notes.txt");
+            assert_eq!(file["hosts"], json!(["alice"]));
+            break;
+        }
+    }
+
+    bob.send(json!({ "type": "request_download", "session_id": "bob", "file_id": "file_1" })).await;
+    let request = alice.recv_type("download_request").await;
+    assert_eq!(request["file_id"], "file_1");
+    assert_eq!(request["from_session_id"], "alice");
+    assert_eq!(request["requester_session_id"], "bob");
+    assert_eq!(request["chunk_size"], 64 * 1024);
+}
+
+#[tokio::test]
+async fn rtc_signals_are_relayed_unchanged_to_their_target() {
+    let server = start();
+    let mut alice = server.connect().await;
+    alice.join("alice").await;
+    let mut bob = server.connect().await;
+    bob.join("bob").await;
+
+    let payload = json!({ "type": "offer", "sdp": "v=0" });
+    alice
+        .send(json!({
+            "type": "rtc_signal",
+            "session_id": "alice",
+            "target_session_id": "bob",
+            "kind": "offer",
+            "file_id": "file_1",
+            "payload": payload,
+        }))
+        .await;
+    let signal = bob.recv_type("rtc_signal").await;
+    assert_eq!(signal["from_session_id"], "alice");
+    assert_eq!(signal["kind"], "offer");
+    assert_eq!(signal["file_id"], "file_1");
+    assert_eq!(signal["payload"], payload);
+
+    alice
+        .send(json!({
+            "type": "rtc_signal",
+            "session_id": "alice",
+            "target_session_id": "nobody",
+            "kind": "ice_candidate",
+            "payload": {},
+        }))
+        .await;
+    assert_eq!(alice.recv_type("error").await["code"], "not_found");
+}